@@ -0,0 +1,193 @@
+// The crate is a binary, so the modules under benchmark are compiled in again here. They are
+// linted (and tested) as part of the binary
+#![allow(dead_code, deprecated, unused_imports, clippy::all)]
+
+#[macro_use]
+extern crate lazy_static;
+
+#[path = "../src/fp.rs"]
+mod fp;
+#[path = "../src/hash.rs"]
+mod hash;
+#[path = "../src/keys.rs"]
+mod keys;
+#[path = "../src/poseidon.rs"]
+mod poseidon;
+#[path = "../src/proof.rs"]
+mod proof;
+#[path = "../src/tree.rs"]
+mod tree;
+#[path = "../src/zkey.rs"]
+mod zkey;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ethers::types::{H160, U256};
+use fp::Fp;
+use keys::{PrivateKey, PublicKey};
+use proof::{ProveJob, WithdrawWitness, MERKLE_PROOF_LENGTH};
+use std::path::Path;
+use tree::SparseMerkleTree;
+
+const PARAMS_FILE: &str = "contracts/circuits/coin_withdraw_0001.zkey";
+const WITNESS_GENERATOR: &str = "contracts/circuits/coin_withdraw_cpp/coin_withdraw";
+
+fn bench_hash(c: &mut Criterion) {
+    let inputs = (1..=4).map(Fp::from).collect::<Vec<_>>();
+    c.bench_function("hash", |b| {
+        b.iter(|| hash::hash(black_box(inputs[0]), black_box(inputs[1])))
+    });
+    c.bench_function("hash4", |b| {
+        b.iter(|| hash::hash4(black_box([inputs[0], inputs[1], inputs[2], inputs[3]])))
+    });
+    // Poseidon takes 1 to 4 inputs
+    let mut group = c.benchmark_group("hash_many");
+    for arity in 1..=4 {
+        group.bench_with_input(BenchmarkId::from_parameter(arity), &arity, |b, &arity| {
+            b.iter(|| hash::hash_many(black_box(&inputs[..arity])).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// The tree is 4-ary, its 16 levels address 2^32 leaves
+fn bench_tree(c: &mut Criterion) {
+    let mut tree = SparseMerkleTree::new(MERKLE_PROOF_LENGTH);
+    let mut index = 0u64;
+    c.bench_function("tree_set", |b| {
+        b.iter(|| {
+            index = (index + 0x9e37_79b9) % (1 << 32);
+            tree.set(black_box(index), Fp::from(index)).unwrap()
+        })
+    });
+
+    // Every node above the leaves is computed again from scratch
+    let leaves = (0..1000).map(|i| (i, Fp::from(i + 1))).collect::<Vec<_>>();
+    c.bench_function("tree_root/1000_leaves", |b| {
+        b.iter_batched(
+            || SparseMerkleTree::new(MERKLE_PROOF_LENGTH),
+            |mut tree| {
+                tree.set_batch(&leaves).unwrap();
+                tree.root()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+// Looking for your coins among 32 Sent events, deriving each stealth key on its own or with `scan`
+fn bench_scan(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let priv_key = PrivateKey::generate(&mut rng);
+    let pub_key = PublicKey::from(&priv_key);
+    let outputs = (0..32)
+        .map(|_| pub_key.derive(&mut rng))
+        .collect::<Vec<_>>();
+    let ephemerals = outputs.iter().map(|(eph, _)| *eph).collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("scan/32_ephemerals");
+    group.sample_size(10);
+    group.bench_function("derive", |b| {
+        b.iter(|| {
+            ephemerals
+                .iter()
+                .zip(outputs.iter())
+                .filter(|(eph, (_, stealth_pub))| {
+                    PublicKey::from(priv_key.derive(**eph)) == *stealth_pub
+                })
+                .count()
+        })
+    });
+    group.bench_function("scan", |b| {
+        b.iter(|| {
+            priv_key.scan(black_box(&ephemerals), |i, stealth_pub, _| {
+                *stealth_pub == outputs[i].1
+            })
+        })
+    });
+    group.finish();
+}
+
+// Parsing the proving key, against reusing the cached one
+fn bench_zkey(c: &mut Criterion) {
+    if !Path::new(PARAMS_FILE).exists() {
+        eprintln!("Skipping the zkey benchmark, {} is missing", PARAMS_FILE);
+        return;
+    }
+    let data = std::fs::read(PARAMS_FILE).unwrap();
+    let mut group = c.benchmark_group("zkey");
+    group.sample_size(10);
+    group.bench_function("parse", |b| {
+        b.iter(|| zkey::ProvingKey::from_zkey(black_box(&data)).unwrap())
+    });
+    group.bench_function("cached", |b| {
+        b.iter(|| zkey::ProvingKey::cached(black_box(PARAMS_FILE)).unwrap())
+    });
+    group.finish();
+}
+
+// Witness generation and the Groth16 prover, skipped until the circuit is built
+fn bench_prove(c: &mut Criterion) {
+    if !Path::new(PARAMS_FILE).exists() || !Path::new(WITNESS_GENERATOR).exists() {
+        eprintln!(
+            "Skipping the prove benchmark, {} or {} is missing",
+            PARAMS_FILE, WITNESS_GENERATOR
+        );
+        return;
+    }
+    let priv_key = PrivateKey {
+        secret: Fp::from(12345),
+    };
+    let pub_key = PublicKey::from(&priv_key);
+    let commitment = hash::commitment(pub_key.point.x, pub_key.point.y, Fp::from(100), Fp::from(1));
+    let mut tree = SparseMerkleTree::new(MERKLE_PROOF_LENGTH);
+    tree.set(0, commitment).unwrap();
+    let witness = WithdrawWitness {
+        chain_id: 1337,
+        contract: H160::from_low_u64_be(7),
+        index: 0,
+        token_address: U256::from(1),
+        amount: U256::from(100),
+        new_amount1: U256::from(60),
+        new_amount2: U256::from(40),
+        address_1: pub_key,
+        address_2: pub_key,
+        secret: priv_key.secret,
+        proof: tree.get(0).proof,
+        commitment,
+        to: H160::zero(),
+        relayer: H160::zero(),
+        fee: U256::zero(),
+    };
+    // The first call loads and caches the proving key
+    proof::prove_from_zkey(PARAMS_FILE, &witness).unwrap();
+
+    let mut group = c.benchmark_group("prove");
+    group.sample_size(10);
+    group.bench_function("coin_withdraw", |b| {
+        b.iter(|| proof::prove_from_zkey(PARAMS_FILE, black_box(&witness)).unwrap())
+    });
+    // The jobs share the cached key, on as many threads as rayon has
+    for count in [1, 4, 16] {
+        let jobs = vec![
+            ProveJob {
+                params: PARAMS_FILE.into(),
+                witness: witness.clone(),
+            };
+            count
+        ];
+        group.bench_with_input(BenchmarkId::new("prove_many", count), &jobs, |b, jobs| {
+            b.iter(|| proof::prove_many(jobs.clone()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_hash,
+    bench_tree,
+    bench_scan,
+    bench_zkey,
+    bench_prove
+);
+criterion_main!(benches);
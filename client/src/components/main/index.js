@@ -180,7 +180,8 @@ const Main = () => {
             tokenContract,
             utils.toBigInt(to_wei_token_amount),
             address,
-            OwshenWallet.contract_address
+            OwshenWallet.contract_address,
+            0
           );
           await tx.wait();
           axios.get(`${coreEndpoint}/coins`).then((result) => {
@@ -199,7 +200,7 @@ const Main = () => {
     for (let coin of receivedcoins) {
       if (
         trueAmount(coin.amount) > tokenAmount &&
-        String(coin.uint_token) === String(tokenContract)
+        String(coin.token) === String(tokenContract)
       ) {
         return coin;
       }
@@ -274,6 +275,8 @@ const Main = () => {
               result.data.obfuscated_receiver_amount,
               result.data.obfuscated_sender_amount,
               true,
+              result.data.token,
+              result.data.denomination,
               options
             );
             console.log("Transaction response", txResponse);
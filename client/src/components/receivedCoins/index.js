@@ -42,6 +42,7 @@ const ReceivedCoinList = () => {
           index: index,
           address: owshen.wallet,
           desire_amount: "1",
+          to: address,
         },
       })
       .then(async (result) => {
@@ -77,6 +78,9 @@ const ReceivedCoinList = () => {
             toBigInt(1),
             result.data.obfuscated_remaining_amount,
             address,
+            result.data.relayer,
+            result.data.fee,
+            result.data.denomination,
             commitment,
             options
           );
@@ -26,7 +26,36 @@ template CSwap() {
     out[3] <== (v - in[2]) * s0_and_s1 + in[2];
 }
 
+// A coin's commitment, hash(pk_ax, pk_ay, amount, token) tagged with 1 as in `hash::commitment`
+template Commitment() {
+    signal input pk_ax;
+    signal input pk_ay;
+    signal input amount;
+    signal input token;
+    signal output out;
+
+    component inner = Poseidon(4);
+    inner.inputs[0] <== pk_ax;
+    inner.inputs[1] <== pk_ay;
+    inner.inputs[2] <== amount;
+    inner.inputs[3] <== token;
+
+    component tagged = Poseidon(4);
+    tagged.inputs[0] <== 1;
+    tagged.inputs[1] <== inner.out;
+    tagged.inputs[2] <== 0;
+    tagged.inputs[3] <== 0;
+    out <== tagged.out;
+}
+
 template CoinWithdraw() {
+    // Public, the deployment the nullifier is bound to, then the receiver of a withdrawal, the
+    // relayer submitting it and the fee it keeps (all zero for a send)
+    signal input chain_id;
+    signal input contract;
+    signal input to;
+    signal input relayer;
+    signal input fee;
     signal input index;
     signal input token_address;
     signal input amount;
@@ -53,32 +82,45 @@ template CoinWithdraw() {
 
     amount === new_amount1 + new_amount2;
 
-    component commiter_hasher = Poseidon(4);
-    commiter_hasher.inputs[0] <== pk.Ax;
-    commiter_hasher.inputs[1] <== pk.Ay;
-    commiter_hasher.inputs[2] <== amount;
-    commiter_hasher.inputs[3] <== token_address;
+    // Used by no other constraint, squared so that the proof still commits to them
+    signal to_square;
+    signal relayer_square;
+    signal fee_square;
+    to_square <== to * to;
+    relayer_square <== relayer * relayer;
+    fee_square <== fee * fee;
+
+    component commiter_hasher = Commitment();
+    commiter_hasher.pk_ax <== pk.Ax;
+    commiter_hasher.pk_ay <== pk.Ay;
+    commiter_hasher.amount <== amount;
+    commiter_hasher.token <== token_address;
     inters[0] <== commiter_hasher.out;
 
-    component new_commiter_hasher_1 = Poseidon(4);
-    new_commiter_hasher_1.inputs[0] <== pk_ax1;
-    new_commiter_hasher_1.inputs[1] <== pk_ay1;
-    new_commiter_hasher_1.inputs[2] <== new_amount1;
-    new_commiter_hasher_1.inputs[3] <== token_address;
+    component new_commiter_hasher_1 = Commitment();
+    new_commiter_hasher_1.pk_ax <== pk_ax1;
+    new_commiter_hasher_1.pk_ay <== pk_ay1;
+    new_commiter_hasher_1.amount <== new_amount1;
+    new_commiter_hasher_1.token <== token_address;
     new_commitment1 <== new_commiter_hasher_1.out;
 
-    component new_commiter_hasher_2 = Poseidon(4);
-    new_commiter_hasher_2.inputs[0] <== pk_ax2;
-    new_commiter_hasher_2.inputs[1] <== pk_ay2;
-    new_commiter_hasher_2.inputs[2] <== new_amount2;
-    new_commiter_hasher_2.inputs[3] <== token_address;
+    component new_commiter_hasher_2 = Commitment();
+    new_commiter_hasher_2.pk_ax <== pk_ax2;
+    new_commiter_hasher_2.pk_ay <== pk_ay2;
+    new_commiter_hasher_2.amount <== new_amount2;
+    new_commiter_hasher_2.token <== token_address;
     new_commitment2 <== new_commiter_hasher_2.out;
     
-    component nullifier_hasher = Poseidon(4); // TODO: Switch to Poseidon2
-    nullifier_hasher.inputs[0] <== secret;
-    nullifier_hasher.inputs[1] <== index;
-    nullifier_hasher.inputs[2] <== 0;
-    nullifier_hasher.inputs[3] <== 0;
+    // Tagged with 2, over the secret, the index and the deployment as in `PrivateKey::nullifier`
+    component deployment_hasher = Poseidon(2);
+    deployment_hasher.inputs[0] <== chain_id;
+    deployment_hasher.inputs[1] <== contract;
+
+    component nullifier_hasher = Poseidon(4);
+    nullifier_hasher.inputs[0] <== 2;
+    nullifier_hasher.inputs[1] <== secret;
+    nullifier_hasher.inputs[2] <== index;
+    nullifier_hasher.inputs[3] <== deployment_hasher.out;
     nullifier <== nullifier_hasher.out;
 
     component hashers[16];
@@ -98,4 +140,4 @@ template CoinWithdraw() {
     root <== inters[16];
  }
 
- component main = CoinWithdraw();
\ No newline at end of file
+ component main {public [chain_id, contract, to, relayer, fee]} = CoinWithdraw();
\ No newline at end of file
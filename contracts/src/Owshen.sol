@@ -5,6 +5,7 @@ import "./SparseMerkleTree.sol";
 import "./MiMC.sol";
 import "./CoinWithdrawVerifier.sol";
 import "openzeppelin-contracts/contracts/token/ERC20/IERC20.sol";
+import "openzeppelin-contracts/contracts/token/ERC20/extensions/IERC20Metadata.sol";
 import "openzeppelin-contracts/contracts/utils/Strings.sol";
 
 contract Owshen {
@@ -29,7 +30,9 @@ contract Owshen {
         uint256 timestamp,
         uint256 _hint_amount,
         uint256 _hint_tokenAddress,
-        uint256 _commitment
+        uint256 _commitment,
+        // Size of the deposit and so the tree the leaf is in, 0 for `tree`
+        uint256 _denomination
     );
 
     event Spend(uint256 nullifier);
@@ -40,6 +43,9 @@ contract Owshen {
 
     IHasher mimc;
     SparseMerkleTree tree;
+    // One tree per token and standard size, holding only the deposits of that size. Leaves take
+    // the same global index as in `tree`, so nullifiers and events need no tree of their own
+    mapping(address => mapping(uint256 => SparseMerkleTree)) denominationTrees;
     uint256 public depositIndex = 0;
 
     /**
@@ -57,13 +63,36 @@ contract Owshen {
         address _tokenAddress,
         uint256 _amount,
         address _from,
-        address _to
+        address _to,
+        uint256 _denomination
     ) public payable {
         uint256 uint_tokenaddress = getUintTokenAddress(_tokenAddress);
-        uint256 leaf = mimc.poseidon(
-            [_pub_key.x, _pub_key.y, _amount, uint_tokenaddress]
+        uint256 leaf = commitment(
+            _pub_key.x,
+            _pub_key.y,
+            _amount,
+            uint_tokenaddress
         );
-        tree.set(depositIndex, leaf);
+        if (_denomination == 0) {
+            tree.set(depositIndex, leaf);
+        } else {
+            require(
+                _amount == _denomination,
+                "Amount differs from the denomination"
+            );
+            require(
+                isDenomination(_tokenAddress, _denomination),
+                "Not a standard denomination"
+            );
+            SparseMerkleTree pool = denominationTrees[_tokenAddress][
+                _denomination
+            ];
+            if (address(pool) == address(0)) {
+                pool = new SparseMerkleTree(mimc);
+                denominationTrees[_tokenAddress][_denomination] = pool;
+            }
+            pool.set(depositIndex, leaf);
+        }
         _processDeposit(_from, _to, _tokenAddress, _amount);
         emit Sent(
             ephemeral,
@@ -71,7 +100,8 @@ contract Owshen {
             block.timestamp,
             _amount,
             uint_tokenaddress,
-            leaf
+            leaf,
+            _denomination
         );
         depositIndex += 1;
     }
@@ -97,10 +127,14 @@ contract Owshen {
     }
 
     function spend(
+        uint256 _root,
         uint256 nullifier,
         Proof calldata proof,
         uint256 _commitment,
-        uint256 _commitment2
+        uint256 _commitment2,
+        address _to,
+        address _relayer,
+        uint256 _fee
     ) internal {
         require(!nullifiers[nullifier], "Nullifier has been spent");
         nullifiers[nullifier] = true;
@@ -109,7 +143,17 @@ contract Owshen {
                 proof.a,
                 proof.b,
                 proof.c,
-                [root(), nullifier, _commitment, _commitment2]
+                [
+                    _root,
+                    nullifier,
+                    _commitment,
+                    _commitment2,
+                    block.chainid,
+                    uint256(uint160(address(this))),
+                    uint256(uint160(_to)),
+                    uint256(uint160(_relayer)),
+                    _fee
+                ]
             ),
             "Invalid proof"
         );
@@ -123,21 +167,38 @@ contract Owshen {
         uint256 _amount,
         uint256 _obfuscated_remaining_amount,
         address _to,
+        address _relayer,
+        uint256 _fee,
+        uint256 _denomination,
         uint256 _commitment
     ) public {
+        require(_fee <= _amount, "Fee exceeds the withdrawn amount");
+        require(_fee == 0 || _relayer != address(0), "A fee needs a relayer");
         uint256 uint_tokenaddress = getUintTokenAddress(_tokenAddress);
-        uint256 commitment2 = mimc.poseidon([0, 0, _amount, uint_tokenaddress]);
-        spend(nullifier, proof, commitment2, _commitment);
+        spend(
+            denominationRoot(_tokenAddress, _denomination),
+            nullifier,
+            proof,
+            commitment(0, 0, _amount, uint_tokenaddress),
+            _commitment,
+            _to,
+            _relayer,
+            _fee
+        );
         tree.set(depositIndex, _commitment);
-        IERC20 payToken = IERC20(_tokenAddress);
-        payToken.transfer(_to, _amount);
+        // The proof binds `_to`, `_relayer` and `_fee`, whoever submits it
+        IERC20(_tokenAddress).transfer(_to, _amount - _fee);
+        if (_fee > 0) {
+            IERC20(_tokenAddress).transfer(_relayer, _fee);
+        }
         emit Sent(
             _ephemeral,
             depositIndex,
             block.timestamp,
             _obfuscated_remaining_amount,
             uint_tokenaddress,
-            _commitment
+            _commitment,
+            0
         );
         emit Spend(nullifier);
         depositIndex += 1;
@@ -153,9 +214,20 @@ contract Owshen {
         uint256 _token_address_hint,
         uint256 _receiver_amount_hint,
         uint256 _sender_amount_hint,
-        bool isDualOutput
+        bool isDualOutput,
+        address _denomination_token,
+        uint256 _denomination
     ) public {
-        spend(nullifier, proof, _commitment2, _commitment1);
+        spend(
+            denominationRoot(_denomination_token, _denomination),
+            nullifier,
+            proof,
+            _commitment2,
+            _commitment1,
+            address(0),
+            address(0),
+            0
+        );
         tree.set(depositIndex, _commitment2);
         emit Sent(
             receiver_ephemeral,
@@ -163,7 +235,8 @@ contract Owshen {
             block.timestamp,
             _receiver_amount_hint,
             _token_address_hint,
-            _commitment2
+            _commitment2,
+            0
         );
         depositIndex += 1;
         if (isDualOutput) {
@@ -174,7 +247,8 @@ contract Owshen {
                 block.timestamp,
                 _sender_amount_hint,
                 _token_address_hint,
-                _commitment1
+                _commitment1,
+                0
             );
             depositIndex += 1;
         }
@@ -206,6 +280,43 @@ contract Owshen {
         return tree.root();
     }
 
+    /**
+     * @dev root of the tree of `_denomination` deposits of `_token`, or of `tree` for 0
+     */
+    function denominationRoot(
+        address _token,
+        uint256 _denomination
+    ) public view returns (uint256) {
+        if (_denomination == 0) {
+            return tree.root();
+        }
+        SparseMerkleTree pool = denominationTrees[_token][_denomination];
+        require(address(pool) != address(0), "No deposits of this denomination");
+        return pool.root();
+    }
+
+    // 10, 1 or 0.1 of the token
+    function isDenomination(
+        address _token,
+        uint256 _amount
+    ) private view returns (bool) {
+        uint256 unit = 10 ** IERC20Metadata(_token).decimals();
+        return _amount == unit * 10 || _amount == unit || _amount * 10 == unit;
+    }
+
+    // Same as `hash::commitment`, the tag 1 keeps a leaf from ever being read as a tree node
+    function commitment(
+        uint256 _x,
+        uint256 _y,
+        uint256 _amount,
+        uint256 _token
+    ) private view returns (uint256) {
+        return
+            mimc.poseidon(
+                [uint256(1), mimc.poseidon([_x, _y, _amount, _token]), 0, 0]
+            );
+    }
+
     function getUintTokenAddress(
         address _token_address
     ) private pure returns (uint256) {
@@ -2,34 +2,271 @@
 pragma solidity ^0.8.13;
 
 import "forge-std/Test.sol";
+import "openzeppelin-contracts/contracts/token/ERC20/ERC20.sol";
 import "../src/Owshen.sol";
 
+// Stands in for the deployed Poseidon, the tests only need distinct leaves and roots
+contract TestHasher is IHasher {
+    function poseidon(uint256[4] memory input) external pure returns (uint256) {
+        return uint256(keccak256(abi.encode(input)));
+    }
+}
+
+contract TestToken is ERC20 {
+    uint8 digits;
+
+    constructor(uint8 _decimals) ERC20("Test", "TST") {
+        digits = _decimals;
+        _mint(msg.sender, 1e24);
+    }
+
+    function decimals() public view override returns (uint8) {
+        return digits;
+    }
+}
+
 contract OwshenTest is Test {
     Owshen public owshen;
+    TestHasher hasher;
+    TestToken public token;
+    address verifier;
+    address alice = address(0xa11ce);
+    address relayer = address(0xbeef);
 
     function setUp() public {
-        address cont;
-        owshen = new Owshen(IHasher(cont), 0);
+        hasher = new TestHasher();
+        owshen = new Owshen(hasher);
+        token = newToken(18);
+        // The constructor deploys the tree first and the verifier second, every proof passes
+        verifier = vm.computeCreateAddress(address(owshen), 2);
+        vm.mockCall(
+            verifier,
+            abi.encodeWithSelector(CoinWithdrawVerifier.verifyProof.selector),
+            abi.encode(true)
+        );
     }
 
-    function testDeposit() public {
+    function newToken(uint8 _decimals) internal returns (TestToken) {
+        TestToken t = new TestToken(_decimals);
+        t.approve(address(owshen), type(uint256).max);
+        return t;
+    }
+
+    function deposit(uint256 _amount) internal {
+        deposit(token, _amount, 0);
+    }
+
+    function deposit(TestToken _token, uint256 _amount, uint256 _denomination) internal {
         owshen.deposit(
             Owshen.Point({x: 123, y: 234}),
             Owshen.Point({x: 123, y: 234}),
-            0xB4FBF271143F4FBf7B91A5ded31805e42b2208d6,
-            1000,
-            0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1,
-            0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1
+            address(_token),
+            _amount,
+            address(this),
+            address(owshen),
+            _denomination
         );
-        assertEq(owshen.depositIndex(), 1);
-        owshen.deposit(
-            Owshen.Point({x: 234, y: 345}),
-            Owshen.Point({x: 123, y: 234}),
-            0xB4FBF271143F4FBf7B91A5ded31805e42b2208d6,
-            2000, // Add the amount here
-            0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1,
-            0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1
+    }
+
+    function withdraw(uint256 _nullifier, uint256 _amount, address _relayer, uint256 _fee) internal {
+        withdraw(_nullifier, _amount, _relayer, _fee, 0);
+    }
+
+    function withdraw(
+        uint256 _nullifier,
+        uint256 _amount,
+        address _relayer,
+        uint256 _fee,
+        uint256 _denomination
+    ) internal {
+        Owshen.Proof memory proof;
+        owshen.withdraw(
+            _nullifier,
+            Owshen.Point({x: 0, y: 1}),
+            proof,
+            address(token),
+            _amount,
+            0,
+            alice,
+            _relayer,
+            _fee,
+            _denomination,
+            5
+        );
+    }
+
+    function send(uint256 _nullifier, uint256 _denomination) internal {
+        Owshen.Proof memory proof;
+        owshen.send(
+            _nullifier,
+            proof,
+            Owshen.Point({x: 0, y: 1}),
+            Owshen.Point({x: 0, y: 1}),
+            6,
+            7,
+            0,
+            0,
+            0,
+            true,
+            address(token),
+            _denomination
+        );
+    }
+
+    // The verifier is asked about a proof against `_root`
+    function expectRoot(
+        uint256 _root,
+        uint256 _nullifier,
+        uint256 _commitment,
+        uint256 _commitment2,
+        address _to,
+        address _relayer,
+        uint256 _fee
+    ) internal {
+        Owshen.Proof memory proof;
+        uint256[9] memory inputs = [
+            _root,
+            _nullifier,
+            _commitment,
+            _commitment2,
+            block.chainid,
+            uint256(uint160(address(owshen))),
+            uint256(uint160(_to)),
+            uint256(uint160(_relayer)),
+            _fee
+        ];
+        vm.expectCall(
+            verifier,
+            abi.encodeWithSelector(
+                CoinWithdrawVerifier.verifyProof.selector, proof.a, proof.b, proof.c, inputs
+            )
         );
+    }
+
+    // The payout of a withdrawal, as the contract rebuilds it
+    function payout(uint256 _amount) internal view returns (uint256) {
+        return hasher.poseidon(
+            [uint256(1), hasher.poseidon([uint256(0), 0, _amount, uint256(uint160(address(token)))]), 0, 0]
+        );
+    }
+
+    function testDeposit() public {
+        deposit(1000);
+        assertEq(owshen.depositIndex(), 1);
+        deposit(2000);
         assertEq(owshen.depositIndex(), 2);
+        assertEq(token.balanceOf(address(owshen)), 3000);
+    }
+
+    function testWithdrawSplitsFee() public {
+        deposit(100);
+        withdraw(1, 100, relayer, 10);
+        assertEq(token.balanceOf(alice), 90);
+        assertEq(token.balanceOf(relayer), 10);
+        assertEq(token.balanceOf(address(owshen)), 0);
+        assertTrue(owshen.isSpent(1));
+    }
+
+    function testWithdrawWithoutFee() public {
+        deposit(100);
+        withdraw(1, 100, address(0), 0);
+        assertEq(token.balanceOf(alice), 100);
+    }
+
+    function testWithdrawFeeAboveAmount() public {
+        deposit(100);
+        vm.expectRevert(bytes("Fee exceeds the withdrawn amount"));
+        withdraw(1, 50, relayer, 51);
+    }
+
+    function testWithdrawFeeWithoutRelayer() public {
+        deposit(100);
+        vm.expectRevert(bytes("A fee needs a relayer"));
+        withdraw(1, 100, address(0), 10);
+    }
+
+    function testWithdrawSpentNullifier() public {
+        deposit(100);
+        withdraw(1, 50, relayer, 0);
+        vm.expectRevert(bytes("Nullifier has been spent"));
+        withdraw(1, 50, relayer, 0);
+    }
+
+    function testStandardDepositHasItsOwnTree() public {
+        uint256 emptyRoot = owshen.root();
+        deposit(token, 1e18, 1e18);
+        assertEq(owshen.depositIndex(), 1);
+        assertEq(owshen.root(), emptyRoot);
+        uint256 poolRoot = owshen.denominationRoot(address(token), 1e18);
+        assertTrue(poolRoot != emptyRoot);
+        assertEq(owshen.denominationRoot(address(token), 0), emptyRoot);
+
+        // Another size, or the same size of another token, goes to yet another tree
+        deposit(token, 1e17, 1e17);
+        assertEq(owshen.denominationRoot(address(token), 1e18), poolRoot);
+        TestToken other = newToken(18);
+        vm.expectRevert(bytes("No deposits of this denomination"));
+        owshen.denominationRoot(address(other), 1e18);
+        assertEq(token.balanceOf(address(owshen)), 11e17);
+    }
+
+    function testDepositAmountDiffersFromDenomination() public {
+        vm.expectRevert(bytes("Amount differs from the denomination"));
+        deposit(token, 2e18, 1e18);
+    }
+
+    function testDepositNotStandardDenomination() public {
+        vm.expectRevert(bytes("Not a standard denomination"));
+        deposit(token, 2e18, 2e18);
+        vm.expectRevert(bytes("Not a standard denomination"));
+        deposit(token, 1e20, 1e20);
+    }
+
+    function testDenominationsFollowDecimals() public {
+        // 0.1 is the smallest unit of a token with one decimal
+        TestToken one = newToken(1);
+        deposit(one, 1, 1);
+        deposit(one, 10, 10);
+        deposit(one, 100, 100);
+
+        // Without decimals there is no 0.1, only 1 and 10
+        TestToken zero = newToken(0);
+        deposit(zero, 1, 1);
+        deposit(zero, 10, 10);
+        vm.expectRevert(bytes("Not a standard denomination"));
+        deposit(zero, 100, 100);
+        vm.expectRevert(bytes("No deposits of this denomination"));
+        owshen.denominationRoot(address(zero), 100);
+    }
+
+    function testDenominationRootUnknownSize() public {
+        vm.expectRevert(bytes("No deposits of this denomination"));
+        owshen.denominationRoot(address(token), 1e18);
+    }
+
+    function testWithdrawFromPool() public {
+        deposit(token, 1e18, 1e18);
+        uint256 poolRoot = owshen.denominationRoot(address(token), 1e18);
+        expectRoot(poolRoot, 1, payout(1e18), 5, alice, address(0), 0);
+        withdraw(1, 1e18, address(0), 0, 1e18);
+        assertEq(token.balanceOf(alice), 1e18);
+
+        // The change goes to the main tree, the pool is left as it was
+        assertEq(owshen.denominationRoot(address(token), 1e18), poolRoot);
+        vm.expectRevert(bytes("No deposits of this denomination"));
+        withdraw(2, 1e17, address(0), 0, 1e17);
+    }
+
+    function testSendFromPool() public {
+        deposit(token, 1e18, 1e18);
+        uint256 poolRoot = owshen.denominationRoot(address(token), 1e18);
+        expectRoot(poolRoot, 1, 7, 6, address(0), address(0), 0);
+        send(1, 1e18);
+        assertEq(owshen.depositIndex(), 3);
+        assertEq(owshen.denominationRoot(address(token), 1e18), poolRoot);
+        assertTrue(owshen.isSpent(1));
+
+        vm.expectRevert(bytes("No deposits of this denomination"));
+        send(2, 1e17);
     }
 }
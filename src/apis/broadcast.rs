@@ -0,0 +1,129 @@
+use axum::response::{IntoResponse, Json, Response};
+use bindings::owshen::Owshen;
+use ethers::prelude::*;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use super::bad_request;
+use crate::apply_fees;
+use crate::deposit_commitment;
+use crate::dry_run_tx;
+use crate::fp::Fp;
+use crate::history::{self, HistoryEntry, HistoryKind};
+use crate::keys::PublicKey;
+use crate::proof::{self, PublicInputs};
+use crate::require_owshen_code;
+use crate::resolve_fees;
+use crate::rpc_account;
+use crate::send_and_confirm;
+use crate::spent;
+use crate::Context;
+use crate::DryRun;
+use crate::GasOpt;
+use crate::PostBroadcastRequest;
+use crate::PostBroadcastResponse;
+
+// Submits a proof returned by /withdraw, the node's account pays for the gas, at the fees given
+// to `owshen wallet` (or estimated by the node). Returns once the transaction is mined, only then
+// is the nullifier recorded as spent and the withdrawal added to the history. A `dry_run` request
+// verifies the proof against the local tree and returns the transaction with its gas estimate
+// instead
+pub async fn broadcast<M: Middleware + 'static>(
+    req: PostBroadcastRequest,
+    context: Arc<RwLock<Context>>,
+    provider: Arc<M>,
+    owshen_contract: H160,
+    spent_path: &Path,
+    history_path: &Path,
+    gas: &GasOpt,
+) -> Result<Response, eyre::Report> {
+    let spent_on_chain = context
+        .read()
+        .unwrap()
+        .sync
+        .spent_nullifiers()
+        .contains(&req.nullifier);
+    if spent_on_chain || spent::load(spent_path)?.contains(&req.nullifier) {
+        return Ok(bad_request(&format!(
+            "Nullifier {} is already spent",
+            req.nullifier
+        )));
+    }
+
+    if req.dry_run {
+        // The payout goes to the null key, as the contract rebuilds it from `amount` and `token`
+        let public_inputs = {
+            let context = context.read().unwrap();
+            PublicInputs {
+                root: context.tree_for(req.token, req.denomination)?.root(),
+                nullifier: Fp::try_from(req.nullifier)?,
+                commitment1: deposit_commitment(PublicKey::null(), req.amount, req.token)?,
+                commitment2: Fp::try_from(req.commitment)?,
+                chain_id: context.sync.chain_id,
+                contract: owshen_contract,
+                to: req.to,
+                relayer: req.relayer,
+                fee: req.fee,
+            }
+            .to_vec()?
+        };
+        if proof::mismatched_inputs(&req.proof, &public_inputs).contains(&0) {
+            return Ok(bad_request(
+                "The tree changed since the proof was made, request a new one from /withdraw",
+            ));
+        }
+        if !proof::verify(&req.proof, &public_inputs)? {
+            return Ok(bad_request(
+                "The proof does not match this withdrawal and the local tree",
+            ));
+        }
+    }
+
+    let from = rpc_account(&provider).await?;
+    let fees = resolve_fees(provider.as_ref(), gas).await?;
+    let owshen = Owshen::new(owshen_contract, provider);
+    require_owshen_code(&owshen).await?;
+    let mut call = owshen
+        .withdraw(
+            req.nullifier,
+            req.ephemeral.into(),
+            req.proof.into(),
+            req.token,
+            req.amount,
+            req.obfuscated_remaining_amount,
+            req.to,
+            req.relayer,
+            req.fee,
+            req.denomination,
+            req.commitment,
+        )
+        .from(from);
+    apply_fees(&mut call.tx, &fees);
+    if req.dry_run {
+        return Ok(Json(DryRun {
+            commitment: req.commitment,
+            transactions: vec![dry_run_tx(&call, true).await?],
+        })
+        .into_response());
+    }
+    // Only a mined withdrawal spends the nullifier, one that reverts or is dropped can be retried
+    let receipt = send_and_confirm(call).await?;
+    tracing::info!(tx_hash = ?receipt.transaction_hash, "Withdraw mined");
+    spent::record(spent_path, req.nullifier)?;
+    history::append(
+        history_path,
+        &HistoryEntry::new(
+            HistoryKind::Withdraw,
+            req.amount,
+            req.token,
+            receipt.transaction_hash,
+            format!("{:?}", req.to),
+            receipt.block_number.map(|b| b.as_u64()),
+        ),
+    )?;
+    Ok(Json(PostBroadcastResponse {
+        tx_hash: receipt.transaction_hash,
+    })
+    .into_response())
+}
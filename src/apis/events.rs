@@ -0,0 +1,44 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::events::WalletEvent;
+
+// Browsers don't preflight WebSocket upgrades, the origin is checked here instead of by CORS
+pub async fn events(
+    ws: WebSocketUpgrade,
+    origin: Option<HeaderValue>,
+    allowed: &[HeaderValue],
+    events: broadcast::Receiver<WalletEvent>,
+) -> Response {
+    if let Some(origin) = origin {
+        if !allowed.contains(&origin) {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+    ws.on_upgrade(move |socket| forward(socket, events))
+}
+
+async fn forward(mut socket: WebSocket, mut events: broadcast::Receiver<WalletEvent>) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let msg = serde_json::to_string(&event).unwrap();
+                    if socket.send(Message::Text(msg)).await.is_err() {
+                        break;
+                    }
+                }
+                // Every event carries the latest counts, missing some is harmless
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use axum::http::StatusCode;
+use axum::Json;
+use ethers::prelude::*;
+use std::sync::{Arc, RwLock};
+
+use crate::{Context, GetHealthResponse};
+
+// Ready once the RPC node answers and the commitment tree has been synced at least once
+pub async fn health(
+    context: Arc<RwLock<Context>>,
+    provider: Arc<Provider<Http>>,
+) -> Result<(StatusCode, Json<GetHealthResponse>), eyre::Report> {
+    let synced_block = context.read().unwrap().sync.last_synced_block;
+    let status = if provider.get_block_number().await.is_err() {
+        "unreachable"
+    } else if synced_block.is_none() {
+        "syncing"
+    } else {
+        "ok"
+    };
+    let code = if status == "ok" {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((
+        code,
+        Json(GetHealthResponse {
+            status: status.to_string(),
+            synced_block,
+        }),
+    ))
+}
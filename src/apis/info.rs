@@ -2,6 +2,7 @@ use axum::Json;
 use ethers::abi::Abi;
 use ethers::types::H160;
 
+use super::ApiError;
 use crate::{keys::PublicKey, GetInfoResponse, TokenInfo};
 
 pub async fn info(
@@ -12,7 +13,7 @@ pub async fn info(
     owshen_abi: Abi,
     erc20_abi: Abi,
     is_test: bool,
-) -> Result<Json<GetInfoResponse>, eyre::Report> {
+) -> Result<Json<GetInfoResponse>, ApiError> {
     Ok(Json(GetInfoResponse {
         address,
         dive_contract,
@@ -1,11 +1,75 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Json, Response};
+
+mod broadcast;
 mod coins;
+mod events;
+mod health;
 mod info;
 mod send;
 mod stealth;
 mod withdraw;
 
+pub use broadcast::broadcast;
 pub use coins::coins;
+pub use events::events;
+pub use health::health;
 pub use info::info;
 pub use send::send;
 pub use stealth::stealth;
 pub use withdraw::withdraw;
+
+// Every failed request answers with `{ "error": { "code", "message" } }`, the code being a
+// stable name of the status
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    TooManyRequests,
+    Internal(eyre::Report),
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::TooManyRequests => "too_many_requests",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl From<eyre::Report> for ApiError {
+    fn from(err: eyre::Report) -> Self {
+        ApiError::Internal(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let message = match &self {
+            ApiError::BadRequest(msg) | ApiError::NotFound(msg) => msg.clone(),
+            ApiError::TooManyRequests => "Too many requests, try again later".to_string(),
+            ApiError::Internal(err) => {
+                tracing::error!("Request failed: {}", err);
+                format!("Internal server error: {}", err)
+            }
+        };
+        let body = serde_json::json!({ "error": { "code": self.code(), "message": message } });
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+fn bad_request(msg: &str) -> Response {
+    ApiError::BadRequest(msg.to_string()).into_response()
+}
@@ -1,18 +1,23 @@
 use axum::extract::Query;
 use axum::response::Json;
 use ethers::prelude::*;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::RwLock;
 
+use super::ApiError;
 use crate::fp::Fp;
 use crate::h160_to_u256;
-use crate::hash::hash4;
-use crate::keys::Point;
+use crate::hash;
 use crate::keys::PrivateKey;
 use crate::keys::PublicKey;
-use crate::proof::prove;
 use crate::proof::Proof;
+use crate::proof::ProofError;
+use crate::proof::WithdrawWitness;
+use crate::proof::MERKLE_PROOF_LENGTH;
+use crate::proof::{log_progress, prove_from_zkey_with_progress};
+use crate::spent;
 use crate::Context;
 use crate::GetSendRequest;
 use crate::GetSendResponse;
@@ -20,41 +25,66 @@ use crate::PARAMS_FILE;
 
 pub async fn send(
     Query(req): Query<GetSendRequest>,
-    context_send: Arc<Mutex<Context>>,
-    context_tree_send: Arc<Mutex<Context>>,
+    context_send: Arc<RwLock<Context>>,
+    context_tree_send: Arc<RwLock<Context>>,
     priv_key: PrivateKey,
-) -> Result<Json<GetSendResponse>, eyre::Report> {
+    spent_path: &Path,
+) -> Result<Json<GetSendResponse>, ApiError> {
     let index = req.index;
     let new_amount = req.new_amount;
     let receiver_address = req.receiver_address;
     let address = req.address;
-    let coins = context_send.lock().unwrap().coins.clone();
-    let merkle_root = context_tree_send.lock().unwrap().tree.clone();
+    let coins = context_send.read().unwrap().coins.clone();
+    let (chain_id, contract) = {
+        let sync = &context_tree_send.read().unwrap().sync;
+        (sync.chain_id, sync.owshen_contract)
+    };
     // Find a coin with the specified index
     let filtered_coin = coins.iter().find(|coin| coin.index == index);
 
     match filtered_coin {
         Some(coin) => {
+            if spent::load(spent_path)?.contains(&coin.nullifier) {
+                return Err(ApiError::BadRequest(format!(
+                    "Coin #{} was already spent by this wallet",
+                    index
+                )));
+            }
             let u32_index: u32 = index.low_u32();
             let u64_index: u64 = index.low_u64();
+            let merkle_root = context_tree_send
+                .read()
+                .unwrap()
+                .tree_for(coin.uint_token, coin.denomination)?;
             // get merkle proof
-            let merkle_proof = merkle_root.get(u64_index);
+            let (leaf, merkle_proof) =
+                merkle_root.get_with_proof::<MERKLE_PROOF_LENGTH>(u64_index)?;
 
-            let address_pub_key = PublicKey::from_str(&address)?;
+            let address_pub_key = PublicKey::from_str(&address)
+                .map_err(|_| ApiError::BadRequest("invalid address".into()))?;
             let (address_ephemeral, address_stealth_pub_key) =
                 address_pub_key.derive(&mut rand::thread_rng());
 
-            let receiver_address_pub_key = PublicKey::from_str(&receiver_address)?;
-            let (receiver_address_ephemeral, receiver_address_stealth_pub_key) =
-                receiver_address_pub_key.derive(&mut rand::thread_rng());
+            let receiver_address_pub_key = PublicKey::from_str(&receiver_address)
+                .map_err(|_| ApiError::BadRequest("invalid receiver_address".into()))?;
+            let (
+                receiver_address_ephemeral,
+                receiver_address_stealth_pub_key,
+                receiver_shared_secret,
+            ) = receiver_address_pub_key.derive_with_hint_secret(&mut rand::thread_rng());
 
             let stealth_priv: PrivateKey = priv_key.derive(address_ephemeral);
             let sender_shared_secret: Fp = stealth_priv.shared_secret(address_ephemeral);
-            let receiver_shared_secret: Fp = stealth_priv.shared_secret(receiver_address_ephemeral);
 
             let amount: U256 = coin.amount;
             let fp_amount = Fp::try_from(amount)?;
-            let u256_new_amount = U256::from_str(&new_amount)?;
+            let u256_new_amount = U256::from_str(&new_amount)
+                .map_err(|_| ApiError::BadRequest("invalid new_amount".into()))?;
+            if u256_new_amount > amount {
+                return Err(ApiError::BadRequest(
+                    "new_amount exceeds the coin amount".into(),
+                ));
+            }
             let fp_new_amount = Fp::try_from(u256_new_amount)?;
             let remaining_amount = fp_amount - fp_new_amount;
             let hint_token_address = h160_to_u256(coin.uint_token);
@@ -66,33 +96,48 @@ pub async fn send(
                 (fp_new_amount + receiver_shared_secret).into();
 
             // calc commitment one -> its for receiver
-            let calc_send_commitment = hash4([
+            let calc_send_commitment = hash::commitment(
                 receiver_address_stealth_pub_key.point.x,
                 receiver_address_stealth_pub_key.point.y,
                 fp_new_amount,
                 Fp::try_from(hint_token_address)?,
-            ]);
+            );
             let u256_calc_send_commitment = calc_send_commitment.into();
             // calc commitment two -> its for sender
-            let calc_sender_commitment: Fp = hash4([
+            let calc_sender_commitment: Fp = hash::commitment(
                 address_stealth_pub_key.point.x,
                 address_stealth_pub_key.point.y,
                 remaining_amount,
                 Fp::try_from(hint_token_address)?,
-            ]);
+            );
             let u256_calc_sender_commitment = calc_sender_commitment.into();
-            let proof: std::result::Result<Proof, eyre::Error> = prove(
-                PARAMS_FILE,
-                u32_index,
-                hint_token_address,
+            let witness = WithdrawWitness {
+                chain_id,
+                contract,
+                index: u32_index,
+                token_address: hint_token_address,
                 amount,
-                u256_new_amount,
-                remaining_amount.into(),
-                receiver_address_stealth_pub_key,
-                address_stealth_pub_key,
-                coin.priv_key.secret,
-                merkle_proof.proof.try_into().unwrap(),
-            );
+                new_amount1: u256_new_amount,
+                new_amount2: remaining_amount.into(),
+                address_1: receiver_address_stealth_pub_key,
+                address_2: address_stealth_pub_key,
+                secret: coin.priv_key.secret,
+                proof: merkle_proof.proof.to_vec(),
+                commitment: leaf,
+                to: H160::zero(),
+                relayer: H160::zero(),
+                fee: U256::zero(),
+            };
+            // Seconds of CPU, kept off the runtime's threads so that other requests go on
+            let span = tracing::Span::current();
+            let proof: std::result::Result<Proof, ProofError> =
+                tokio::task::spawn_blocking(move || {
+                    span.in_scope(|| {
+                        prove_from_zkey_with_progress(PARAMS_FILE, &witness, log_progress())
+                    })
+                })
+                .await
+                .map_err(eyre::Report::from)?;
             match proof {
                 Ok(proof) => Ok(Json(GetSendResponse {
                     proof,
@@ -105,32 +150,17 @@ pub async fn send(
                     sender_commitment: u256_calc_sender_commitment,
                     sender_ephemeral: address_ephemeral.point,
                     receiver_ephemeral: receiver_address_ephemeral.point,
+                    denomination: coin.denomination,
                 })),
-                Err(_e) => Err(eyre::Report::msg(
-                    "Something wrong while creating proof for send",
-                )),
+                Err(e) => Err(ApiError::Internal(eyre::Report::msg(format!(
+                    "Something wrong while creating proof for send: {}",
+                    e
+                )))),
             }
         }
-        None => {
-            println!("No coin with index {} found", index);
-            Ok(Json(GetSendResponse {
-                proof: Proof::default(),
-                token: H160::default(),
-                amount: U256::default(),
-                nullifier: U256::default(),
-                obfuscated_receiver_amount: U256::default(),
-                obfuscated_sender_amount: U256::default(),
-                receiver_commitment: U256::default(),
-                sender_commitment: U256::default(),
-                sender_ephemeral: Point {
-                    x: Fp::default(),
-                    y: Fp::default(),
-                },
-                receiver_ephemeral: Point {
-                    x: Fp::default(),
-                    y: Fp::default(),
-                },
-            }))
-        }
+        None => Err(ApiError::NotFound(format!(
+            "No coin with index {} found",
+            index
+        ))),
     }
 }
@@ -1,50 +1,107 @@
 use axum::extract::Query;
 use axum::response::Json;
 use ethers::prelude::*;
+use std::io::Write;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::RwLock;
+use tempfile::NamedTempFile;
 
+use super::ApiError;
+use crate::deposit_commitment;
 use crate::fp::Fp;
 use crate::h160_to_u256;
-use crate::hash::hash4;
-use crate::keys::Point;
+use crate::hash;
 use crate::keys::PrivateKey;
 use crate::keys::PublicKey;
-use crate::proof::prove;
+use crate::open_output;
+use crate::proof::FullProof;
 use crate::proof::Proof;
+use crate::proof::ProofError;
+use crate::proof::PublicInputs;
+use crate::proof::WithdrawWitness;
+use crate::proof::MERKLE_PROOF_LENGTH;
+use crate::proof::{log_progress, prove_from_zkey_with_progress};
+use crate::spent;
 use crate::Context;
 use crate::GetWithdrawRequest;
 use crate::GetWithdrawResponse;
+use crate::RelayerOpt;
 use crate::PARAMS_FILE;
 
+// Written to the wallet's `--proof-dir`, the same `{ proof, publicSignals }` as snarkjs
+fn write_full_proof(
+    mut file: NamedTempFile,
+    path: &Path,
+    full_proof: &FullProof,
+) -> eyre::Result<()> {
+    file.write_all(serde_json::to_string_pretty(full_proof)?.as_bytes())?;
+    file.persist(path)?;
+    Ok(())
+}
+
 pub async fn withdraw(
     Query(req): Query<GetWithdrawRequest>,
-    context_withdraw: Arc<Mutex<Context>>,
-    context_tree: Arc<Mutex<Context>>,
+    context_withdraw: Arc<RwLock<Context>>,
+    context_tree: Arc<RwLock<Context>>,
     priv_key: PrivateKey,
-) -> Result<Json<GetWithdrawResponse>, eyre::Report> {
+    spent_path: &Path,
+    proof_dir: Option<&Path>,
+    relayer_opt: &RelayerOpt,
+) -> Result<Json<GetWithdrawResponse>, ApiError> {
     let index = req.index;
-    let coins = context_withdraw.lock().unwrap().coins.clone();
+    let coins = context_withdraw.read().unwrap().coins.clone();
     let address = req.address;
-    let merkle_root = context_tree.lock().unwrap().tree.clone();
+    let (chain_id, contract) = {
+        let sync = &context_tree.read().unwrap().sync;
+        (sync.chain_id, sync.owshen_contract)
+    };
     // Find a coin with the specified index
     let filtered_coin = coins.iter().find(|coin| coin.index == index);
     match filtered_coin {
         Some(coin) => {
+            if spent::load(spent_path)?.contains(&coin.nullifier) {
+                return Err(ApiError::BadRequest(format!(
+                    "Coin #{} was already spent by this wallet",
+                    index
+                )));
+            }
             let u32_index: u32 = index.low_u32();
             let u64_index: u64 = index.low_u64();
+            let merkle_root = context_tree
+                .read()
+                .unwrap()
+                .tree_for(coin.uint_token, coin.denomination)?;
             // get merkle proof
-            let merkle_proof = merkle_root.get(u64_index);
+            let (leaf, merkle_proof) =
+                merkle_root.get_with_proof::<MERKLE_PROOF_LENGTH>(u64_index)?;
 
-            let pub_key: PublicKey = PublicKey::from_str(&address)?;
+            let pub_key: PublicKey = PublicKey::from_str(&address)
+                .map_err(|_| ApiError::BadRequest("invalid address".into()))?;
             let (ephemeral, stealth_pub_key) = pub_key.derive(&mut rand::thread_rng());
             let stealth_priv: PrivateKey = priv_key.derive(ephemeral);
             let shared_secret: Fp = stealth_priv.shared_secret(ephemeral);
 
             let amount: U256 = coin.amount;
 
-            let new_amount_num: i64 = req.desire_amount.parse()?;
+            let new_amount_num: i64 = req
+                .desire_amount
+                .parse()
+                .map_err(|_| ApiError::BadRequest("invalid desire_amount".into()))?;
+            if new_amount_num < 0 || U256::from(new_amount_num) > amount {
+                return Err(ApiError::BadRequest(
+                    "desire_amount exceeds the coin amount".into(),
+                ));
+            }
+            let relayer = req.relayer.or(relayer_opt.relayer).unwrap_or_default();
+            let fee = req.fee.or(relayer_opt.relayer_fee).unwrap_or_default();
+            if !fee.is_zero() && relayer.is_zero() {
+                return Err(ApiError::BadRequest("a fee needs a relayer".into()));
+            }
+            if fee > U256::from(new_amount_num) {
+                return Err(ApiError::BadRequest("fee exceeds desire_amount".into()));
+            }
             let obfuscated_remaining_amount: U256 = amount - new_amount_num;
 
             let obfuscated_remaining_amount_in_fp: Fp = Fp::try_from(obfuscated_remaining_amount)?;
@@ -57,56 +114,92 @@ pub async fn withdraw(
 
             let hint_token_address: U256 = h160_to_u256(coin.uint_token);
 
-            let calc_commitment: Fp = hash4([
+            let calc_commitment: Fp = hash::commitment(
                 stealth_pub_key.point.x,
                 stealth_pub_key.point.y,
                 Fp::from_str(&remaining_amount)?,
                 Fp::try_from(hint_token_address)?,
-            ]);
+            );
 
             let u256_calc_commitment: U256 = calc_commitment.into();
 
-            let proof: std::result::Result<Proof, eyre::Error> = prove(
-                PARAMS_FILE,
-                u32_index,
-                hint_token_address,
+            let witness = WithdrawWitness {
+                chain_id,
+                contract,
+                index: u32_index,
+                token_address: hint_token_address,
                 amount,
-                new_amount_num.into(),
-                obfuscated_remaining_amount,
-                PublicKey::null(),
-                stealth_pub_key,
-                coin.priv_key.secret,
-                merkle_proof.proof.try_into().unwrap(),
-            );
+                new_amount1: new_amount_num.into(),
+                new_amount2: obfuscated_remaining_amount,
+                address_1: PublicKey::null(),
+                address_2: stealth_pub_key,
+                secret: coin.priv_key.secret,
+                proof: merkle_proof.proof.to_vec(),
+                commitment: leaf,
+                to: req.to,
+                relayer,
+                fee,
+            };
+            let output = proof_dir
+                .map(|dir| dir.join(format!("withdraw-{}.json", coin.nullifier)))
+                .map(|path| open_output(&path).map(|file| (file, path)))
+                .transpose()?;
+            // Seconds of CPU, kept off the runtime's threads so that other requests go on
+            let span = tracing::Span::current();
+            let proof: std::result::Result<Proof, ProofError> =
+                tokio::task::spawn_blocking(move || {
+                    span.in_scope(|| {
+                        prove_from_zkey_with_progress(PARAMS_FILE, &witness, log_progress())
+                    })
+                })
+                .await
+                .map_err(eyre::Report::from)?;
             match proof {
-                Ok(proof) => Ok(Json(GetWithdrawResponse {
-                    proof,
-                    token: coin.uint_token,
-                    amount: coin.amount,
-                    obfuscated_remaining_amount: obfuscated_remaining_amount_with_secret,
-                    nullifier: coin.nullifier,
-                    commitment: u256_calc_commitment,
-                    ephemeral: ephemeral.point,
-                })),
-                Err(_e) => Err(eyre::Report::msg(
-                    "Something wrong while creating proof for withdraw",
-                )),
+                Ok(proof) => {
+                    if let Some((file, path)) = output {
+                        let public_inputs = PublicInputs {
+                            root: merkle_root.root(),
+                            nullifier: Fp::try_from(coin.nullifier)?,
+                            commitment1: deposit_commitment(
+                                PublicKey::null(),
+                                new_amount_num.into(),
+                                coin.uint_token,
+                            )?,
+                            commitment2: calc_commitment,
+                            chain_id,
+                            contract,
+                            to: req.to,
+                            relayer,
+                            fee,
+                        };
+                        let full_proof = proof.to_full_proof(&public_inputs.to_vec()?);
+                        write_full_proof(file, &path, &full_proof)?;
+                        tracing::info!(path = %path.display(), "Withdraw proof written");
+                    }
+                    Ok(Json(GetWithdrawResponse {
+                        proof,
+                        token: coin.uint_token,
+                        // The withdrawn part, `Owshen.withdraw` rebuilds the payout commitment from it
+                        amount: new_amount_num.into(),
+                        obfuscated_remaining_amount: obfuscated_remaining_amount_with_secret,
+                        nullifier: coin.nullifier,
+                        commitment: u256_calc_commitment,
+                        ephemeral: ephemeral.point,
+                        to: req.to,
+                        relayer,
+                        fee,
+                        denomination: coin.denomination,
+                    }))
+                }
+                Err(e) => Err(ApiError::Internal(eyre::Report::msg(format!(
+                    "Something wrong while creating proof for withdraw: {}",
+                    e
+                )))),
             }
         }
-        None => {
-            println!("No coin with index {} found", index);
-            Ok(Json(GetWithdrawResponse {
-                proof: Proof::default(),
-                token: H160::default(),
-                amount: U256::default(),
-                obfuscated_remaining_amount: U256::default(),
-                nullifier: U256::default(),
-                commitment: U256::default(),
-                ephemeral: Point {
-                    x: Fp::default(),
-                    y: Fp::default(),
-                },
-            }))
-        }
+        None => Err(ApiError::NotFound(format!(
+            "No coin with index {} found",
+            index
+        ))),
     }
 }
@@ -0,0 +1,167 @@
+use bindings::owshen::Owshen;
+use ethers::prelude::*;
+use eyre::Result;
+use futures::StreamExt;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::apis;
+use crate::keys::PrivateKey;
+use crate::Context;
+
+// How often the RPC is polled when no WebSocket endpoint is given
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// Pushed to the /events clients whenever a sync finds new commitments or spends
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct WalletEvent {
+    pub commitments: usize,
+    pub spends: usize,
+    pub coins: usize,
+}
+
+fn snapshot(context: &Arc<RwLock<Context>>) -> WalletEvent {
+    let ctx = context.read().unwrap();
+    WalletEvent {
+        commitments: ctx.sync.commitments.len(),
+        spends: ctx.sync.spends.len(),
+        coins: ctx.coins.len(),
+    }
+}
+
+// Syncs the shared context, the listeners are only notified when something changed
+pub async fn refresh<M: Middleware + 'static>(
+    context: &Arc<RwLock<Context>>,
+    contract: &Contract<M>,
+    priv_key: &PrivateKey,
+    events: &broadcast::Sender<WalletEvent>,
+) -> Result<()> {
+    let before = snapshot(context);
+    let _ = apis::coins(context.clone(), contract.clone(), priv_key.clone()).await?;
+    let after = snapshot(context);
+    if after != before {
+        // Only fails when no frontend is listening
+        let _ = events.send(after);
+    }
+    Ok(())
+}
+
+// Keeps the context up to date for as long as the wallet runs. With a WebSocket endpoint every
+// Owshen event triggers a sync, otherwise the (HTTP) contract is polled
+pub async fn watch<M: Middleware + 'static>(
+    ws: Option<String>,
+    context: Arc<RwLock<Context>>,
+    contract: Contract<M>,
+    priv_key: PrivateKey,
+    events: broadcast::Sender<WalletEvent>,
+) {
+    if let Err(e) = refresh(&context, &contract, &priv_key, &events).await {
+        tracing::error!("Initial sync failed: {}", e);
+    }
+    match ws {
+        Some(url) => subscribe(&url, &context, &contract, &priv_key, &events).await,
+        None => loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            if let Err(e) = refresh(&context, &contract, &priv_key, &events).await {
+                tracing::warn!("Sync failed: {}", e);
+            }
+        },
+    }
+}
+
+// The events only wake the sync up, which reads them again through `contract`: nothing is lost
+// while the subscription is down, a reconnection catches up from the last synced block
+async fn subscribe<M: Middleware + 'static>(
+    url: &str,
+    context: &Arc<RwLock<Context>>,
+    contract: &Contract<M>,
+    priv_key: &PrivateKey,
+    events: &broadcast::Sender<WalletEvent>,
+) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match Provider::<Ws>::connect(url).await {
+            Ok(provider) => {
+                let owshen = Owshen::new(contract.address(), Arc::new(provider));
+                let filter = owshen.events();
+                match filter.subscribe().await {
+                    Ok(mut stream) => {
+                        tracing::info!("Subscribed to the Owshen events at {}", url);
+                        delay = Duration::from_secs(1);
+                        if let Err(e) = refresh(context, contract, priv_key, events).await {
+                            tracing::warn!("Sync failed: {}", e);
+                        }
+                        while let Some(event) = stream.next().await {
+                            if let Err(e) = event {
+                                tracing::warn!("Cannot decode an Owshen event: {}", e);
+                            }
+                            if let Err(e) = refresh(context, contract, priv_key, events).await {
+                                tracing::warn!("Sync failed: {}", e);
+                            }
+                        }
+                        tracing::warn!("Event subscription at {} dropped", url);
+                    }
+                    Err(e) => tracing::warn!("Cannot subscribe to the Owshen events: {}", e),
+                };
+            }
+            Err(e) => tracing::warn!("Cannot connect to {}: {}", url, e),
+        }
+        tracing::info!("Reconnecting to {} in {:?}", url, delay);
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{deposit_event, sent_log, MockNode};
+    use crate::sync::SyncState;
+    use crate::tree::SparseMerkleTree;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let priv_key = PrivateKey {
+            secret: 34567.into(),
+        };
+        let owshen_contract = H160::from_low_u64_be(2);
+        let log = sent_log(owshen_contract, &deposit_event(4, 0, (&priv_key).into()));
+        let (provider, mock) = MockNode::new()
+            .chain_id(1337)
+            .block_number(4)
+            .logs(vec![log])
+            .logs(vec![])
+            .block(4, H256::from_low_u64_be(4))
+            .provider();
+        let contract = Owshen::new(owshen_contract, Arc::new(provider));
+        let context = Arc::new(RwLock::new(Context {
+            coins: vec![],
+            tree: SparseMerkleTree::new(16),
+            sync: SyncState::new(owshen_contract),
+        }));
+        let (tx, mut rx) = broadcast::channel(16);
+
+        refresh(&context, &contract, &priv_key, &tx).await.unwrap();
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            WalletEvent {
+                commitments: 1,
+                spends: 0,
+                coins: 1,
+            }
+        );
+
+        // Nothing new on chain: the chain id, the reorg check, then the block number
+        MockNode::new()
+            .chain_id(1337)
+            .block(4, H256::from_low_u64_be(4))
+            .block_number(4)
+            .push_to(&mock);
+        refresh(&context, &contract, &priv_key, &tx).await.unwrap();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    }
+}
@@ -1,11 +1,15 @@
 use ethers::prelude::*;
-use ff::PrimeField;
+use ethers::utils::hex;
+use ff::derive::subtle::{Choice, ConstantTimeEq};
+use ff::{Field, PrimeField};
 use num_bigint::BigUint;
 use num_traits::{Euclid, Num};
+use rand::Rng;
 use serde::{de, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 
 use std::str::FromStr;
+use zeroize::Zeroize;
 
 #[derive(PrimeField)]
 #[PrimeFieldModulus = "21888242871839275222246405745257275088548364400416034343698204186575808495617"]
@@ -13,29 +17,66 @@ use std::str::FromStr;
 #[PrimeFieldReprEndianness = "little"]
 pub struct Fp([u64; 4]);
 
-impl Into<U256> for Fp {
-    fn into(self) -> U256 {
-        U256::from_str_radix(
-            &BigUint::from_bytes_le(self.to_repr().as_ref()).to_str_radix(16),
-            16,
-        )
-        .unwrap()
+impl Zeroize for Fp {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl From<Fp> for U256 {
+    fn from(value: Fp) -> U256 {
+        U256::from_big_endian(&value.to_bytes())
+    }
+}
+
+// 160 bits always fit below the modulus
+impl From<H160> for Fp {
+    fn from(value: H160) -> Self {
+        let mut bytes = [0u8; 32];
+        bytes[12..].copy_from_slice(value.as_bytes());
+        Fp::from_bytes(&bytes).expect("An address is below the field modulus!")
     }
 }
 
+// On-chain values may exceed the modulus, they are rejected instead of silently reduced
 impl TryFrom<U256> for Fp {
     type Error = eyre::Report;
 
     fn try_from(value: U256) -> Result<Self, Self::Error> {
-        Fp::from_str_vartime(&value.to_string()).ok_or(eyre::Report::msg("Invalid U256 value!"))
+        let mut bytes = [0u8; 32];
+        value.to_big_endian(&mut bytes);
+        Fp::from_bytes(&bytes)
+            .map_err(|_| eyre::Report::msg(format!("{} is not below the field modulus!", value)))
     }
 }
 
+// Decimal or 0x-prefixed hex, values not below the modulus are rejected instead of reduced
 impl FromStr for Fp {
     type Err = eyre::Report;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Fp::from_str_vartime(s).ok_or(eyre::Report::msg("Invalid Fp!"))?)
+        let (digits, radix) = match s.strip_prefix("0x") {
+            Some(hex_str) => (hex_str, 16),
+            None => (s, 10),
+        };
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err(eyre::Report::msg("Invalid Fp!"));
+        }
+        let value = BigUint::from_str_radix(digits, radix)?;
+        if value >= BigUint::from_str_radix(&Fp::MODULUS[2..], 16).unwrap() {
+            return Err(eyre::Report::msg("Fp value is out of range!"));
+        }
+        let mut bytes = [0u8; 32];
+        let be = value.to_bytes_be();
+        bytes[32 - be.len()..].copy_from_slice(&be);
+        Fp::from_bytes(&bytes)
+    }
+}
+
+// Canonical decimal, `Debug` prints the hex
+impl fmt::Display for Fp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", BigUint::from_bytes_be(&self.to_bytes()))
     }
 }
 
@@ -44,13 +85,38 @@ impl Serialize for Fp {
     where
         S: Serializer,
     {
-        let num = BigUint::from_bytes_le(self.to_repr().as_ref()).to_string();
-        serializer.serialize_str(&num.to_string())
+        serializer.serialize_str(&format!("0x{}", hex::encode(self.to_bytes())))
     }
 }
 
 impl Fp {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, eyre::Report> {
+    // Equality without branching on the limbs. Used wherever one side is secret or derived from
+    // a secret: private keys, shared secrets, the commitments a wallet recomputes to recognize
+    // its coins, nullifiers and the Schnorr verification equation. Plain `==` is fine for public
+    // values such as roots, indices and on-chain commitments compared with each other
+    pub fn ct_eq(&self, other: &Fp) -> Choice {
+        ConstantTimeEq::ct_eq(self, other)
+    }
+
+    // Canonical 32-byte big-endian encoding
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(self.to_repr().as_ref());
+        bytes.reverse();
+        bytes
+    }
+
+    // Inverse of `to_bytes`, values not below the modulus are rejected
+    pub fn from_bytes(bytes: &[u8; 32]) -> Result<Self, eyre::Report> {
+        let mut repr = <Fp as PrimeField>::Repr::default();
+        repr.as_mut().copy_from_slice(bytes);
+        repr.as_mut().reverse();
+        Option::<Fp>::from(Fp::from_repr(repr))
+            .ok_or(eyre::Report::msg("Non-canonical field element!"))
+    }
+
+    // Little-endian bytes of any length, reduced modulo the field size
+    pub fn from_bytes_mod_order(bytes: &[u8]) -> Result<Self, eyre::Report> {
         Ok(Fp::from_str(
             &BigUint::from_bytes_le(bytes)
                 .rem_euclid(&BigUint::from_str_radix(&Fp::MODULUS[2..], 16).unwrap())
@@ -58,6 +124,46 @@ impl Fp {
         )
         .unwrap())
     }
+
+    // Uniform below the modulus: 254-bit candidates are drawn until one is canonical, which takes
+    // less than 2 tries on average. Deterministic for a seeded rng (e.g. `StdRng::seed_from_u64`)
+    pub fn random<R: Rng>(rng: &mut R) -> Fp {
+        loop {
+            let mut repr = <Fp as PrimeField>::Repr::default();
+            rng.fill(repr.as_mut());
+            repr.as_mut()[31] &= 0x3f;
+            if let Some(value) = Option::<Fp>::from(Fp::from_repr(repr)) {
+                return value;
+            }
+        }
+    }
+
+    // None for zero
+    pub fn inverse(&self) -> Option<Fp> {
+        Option::from(self.invert())
+    }
+
+    // Inverts every element in place with a single field inversion (Montgomery's trick), zeros
+    // are left untouched
+    pub fn batch_inverse(values: &mut [Fp]) {
+        let mut prefix = Vec::with_capacity(values.len());
+        let mut acc = Fp::ONE;
+        for v in values.iter() {
+            prefix.push(acc);
+            if !bool::from(v.is_zero()) {
+                acc *= v;
+            }
+        }
+        // `acc` is a product of non-zero elements, hence invertible
+        let mut inv = acc.inverse().unwrap();
+        for (v, prefix) in values.iter_mut().zip(prefix).rev() {
+            if !bool::from(v.is_zero()) {
+                let v_inv = inv * prefix;
+                inv *= *v;
+                *v = v_inv;
+            }
+        }
+    }
 }
 
 struct FpStr;
@@ -75,13 +181,151 @@ impl<'de> Visitor<'de> for FpStr {
     type Value = Fp;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "expecting a string")
+        write!(formatter, "expecting a 32-byte hex or a decimal string")
     }
 
+    // Decimal strings are still accepted, they were the encoding of older versions
     fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        Fp::from_str_vartime(s).ok_or(de::Error::invalid_value(de::Unexpected::Str(s), &self))
+        let invalid = || de::Error::invalid_value(de::Unexpected::Str(s), &self);
+        match s.strip_prefix("0x") {
+            Some(hex_str) => {
+                let bytes: [u8; 32] = hex::decode(hex_str)
+                    .map_err(|_| invalid())?
+                    .try_into()
+                    .map_err(|_| invalid())?;
+                Fp::from_bytes(&bytes).map_err(|_| invalid())
+            }
+            None => Fp::from_str(s).map_err(|_| invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ct_eq() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let a = Fp::random(&mut rng);
+            let b = if rng.gen() { a } else { Fp::random(&mut rng) };
+            assert_eq!(bool::from(a.ct_eq(&b)), a == b);
+            assert!(bool::from(a.ct_eq(&a)));
+            assert!(!bool::from(a.ct_eq(&(a + Fp::ONE))));
+        }
+    }
+
+    #[test]
+    fn test_fp_bytes() {
+        let value = Fp::from(0x1234);
+        let bytes = value.to_bytes();
+        assert_eq!(bytes[30..], [0x12, 0x34]);
+        assert_eq!(Fp::from_bytes(&bytes).unwrap(), value);
+        assert_eq!(Fp::from_bytes(&(-Fp::ONE).to_bytes()).unwrap(), -Fp::ONE);
+
+        let modulus = BigUint::from_str_radix(&Fp::MODULUS[2..], 16).unwrap();
+        let modulus: [u8; 32] = modulus.to_bytes_be().try_into().unwrap();
+        assert!(Fp::from_bytes(&modulus).is_err());
+        assert!(Fp::from_bytes(&[0xff; 32]).is_err());
+    }
+
+    #[test]
+    fn test_u256_conversion() {
+        let modulus = U256::from_str_radix(&Fp::MODULUS[2..], 16).unwrap();
+        let max = Fp::try_from(modulus - 1).unwrap();
+        assert_eq!(max, -Fp::ONE);
+        assert_eq!(U256::from(max), modulus - 1);
+        assert_eq!(U256::from(Fp::from(1234)), U256::from(1234));
+        assert_eq!(Fp::try_from(U256::zero()).unwrap(), Fp::ZERO);
+
+        // Not reduced: the modulus would otherwise turn into zero
+        for value in [modulus, modulus + 1, U256::MAX] {
+            let err = Fp::try_from(value).unwrap_err();
+            assert!(err.to_string().contains("not below the field modulus"));
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let modulus = BigUint::from_str_radix(&Fp::MODULUS[2..], 16).unwrap();
+        let half = &modulus / 2u32;
+
+        let mut rng = StdRng::seed_from_u64(1234);
+        let values = (0..1000).map(|_| Fp::random(&mut rng)).collect::<Vec<_>>();
+        let mut above_half = 0;
+        for v in values.iter() {
+            let v = BigUint::from_bytes_be(&v.to_bytes());
+            assert!(v < modulus);
+            if v > half {
+                above_half += 1;
+            }
+        }
+        assert!((400..600).contains(&above_half));
+
+        let mut rng = StdRng::seed_from_u64(1234);
+        assert_eq!(Fp::random(&mut rng), values[0]);
+        assert_ne!(values[0], values[1]);
+    }
+
+    #[test]
+    fn test_inverse() {
+        let mut rng = rand::thread_rng();
+        assert_eq!(Fp::ZERO.inverse(), None);
+        let mut values = (0..16).map(|_| Fp::random(&mut rng)).collect::<Vec<_>>();
+        for v in values.iter() {
+            assert_eq!(*v * v.inverse().unwrap(), Fp::ONE);
+        }
+
+        values[3] = Fp::ZERO;
+        let expected = values
+            .iter()
+            .map(|v| v.inverse().unwrap_or(Fp::ZERO))
+            .collect::<Vec<_>>();
+        Fp::batch_inverse(&mut values);
+        assert_eq!(values, expected);
+
+        let mut empty: Vec<Fp> = vec![];
+        Fp::batch_inverse(&mut empty);
+    }
+
+    #[test]
+    fn test_fp_serde() {
+        let value = Fp::from(0x1234);
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(
+            json,
+            "\"0x0000000000000000000000000000000000000000000000000000000000001234\""
+        );
+        assert_eq!(serde_json::from_str::<Fp>(&json).unwrap(), value);
+        assert_eq!(serde_json::from_str::<Fp>("\"4660\"").unwrap(), value);
+        assert!(serde_json::from_str::<Fp>("\"0x1234\"").is_err());
+    }
+
+    #[test]
+    fn test_fp_from_str() {
+        let modulus = BigUint::from_str_radix(&Fp::MODULUS[2..], 16).unwrap();
+        let max = (&modulus - 1u32).to_string();
+        assert_eq!(Fp::from_str(&max).unwrap(), -Fp::ONE);
+        assert_eq!(Fp::from_str(&max).unwrap().to_string(), max);
+        assert_eq!(
+            Fp::from_str(&format!("0x{}", (&modulus - 1u32).to_str_radix(16))).unwrap(),
+            -Fp::ONE
+        );
+        assert_eq!(Fp::from_str("0").unwrap(), Fp::ZERO);
+        assert_eq!(Fp::from_str("0x1234").unwrap(), Fp::from(0x1234));
+        assert_eq!(Fp::from_str("0x00ABcd").unwrap(), Fp::from(0xabcd));
+        assert_eq!(Fp::from(4660).to_string(), "4660");
+
+        assert!(Fp::from_str(&modulus.to_string()).is_err());
+        assert!(Fp::from_str(&(&modulus + 1u32).to_string()).is_err());
+        assert!(Fp::from_str(&format!("0x{}", modulus.to_str_radix(16))).is_err());
+        for invalid in ["", "0x", "-1", "+1", "1_000", "12a", "0xg1", " 1"] {
+            assert!(Fp::from_str(invalid).is_err(), "{:?}", invalid);
+        }
     }
 }
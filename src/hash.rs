@@ -1,15 +1,62 @@
 use crate::fp::Fp;
 use crate::poseidon::poseidon;
+use eyre::Result;
 
+// circomlib (and so the circuits and the Solidity hasher) only has round constants up to this
+// arity
+pub const MAX_ARITY: usize = 4;
 
 pub fn hash4(vals: [Fp; 4]) -> Fp {
-    poseidon(vals)
+    poseidon(&vals)
+}
+
+#[allow(dead_code)]
+pub fn hash(a: Fp, b: Fp) -> Fp {
+    poseidon(&[a, b])
+}
+
+// Poseidon of 1 to 4 inputs, matching circomlib's Poseidon(n) of the same arity. Any other
+// number of inputs is an error
+#[allow(dead_code)]
+pub fn hash_many(inputs: &[Fp]) -> Result<Fp> {
+    if inputs.is_empty() || inputs.len() > MAX_ARITY {
+        return Err(eyre::Report::msg(format!(
+            "Poseidon takes 1 to {} inputs, got {}",
+            MAX_ARITY,
+            inputs.len()
+        )));
+    }
+    Ok(poseidon(inputs))
+}
+
+// Tags of domain separated hashes, so that a value of one kind can never be reinterpreted as
+// another (a commitment as a tree node, a nullifier or a stealth tweak)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashDomain {
+    Commitment = 1,
+    Nullifier = 2,
+    Stealth = 3,
+}
+
+// Poseidon4 of the domain tag followed by 3 inputs, which is all the room a tag leaves in the
+// largest hash the circuit and the contract have. Shorter hashes are padded with zeros
+pub fn hash_domain(domain: HashDomain, vals: [Fp; 3]) -> Fp {
+    hash4([Fp::from(domain as u64), vals[0], vals[1], vals[2]])
+}
+
+// A coin's commitment, its owner, amount and token hashed together and then tagged, the same as
+// `commitment` in Owshen.sol and the `Commitment` template of coin_withdraw.circom
+pub fn commitment(pk_x: Fp, pk_y: Fp, amount: Fp, token: Fp) -> Fp {
+    hash_domain(
+        HashDomain::Commitment,
+        [hash4([pk_x, pk_y, amount, token]), Fp::from(0), Fp::from(0)],
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use crate::fp::Fp;
-    use crate::hash::hash4;
+    use crate::hash::{commitment, hash, hash4, hash_domain, hash_many, HashDomain};
     use ff::PrimeField;
     #[test]
     fn poseidon_hash() {
@@ -20,4 +67,66 @@ mod tests {
         .unwrap();
         assert_eq!(out, expected);
     }
+
+    #[test]
+    fn poseidon_hash_many() {
+        // Test vectors of circomlibjs' poseidon
+        let vectors = [
+            "18586133768512220936620570745912940619677854269274689475585506675881198879027",
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530",
+            "6542985608222806190361240322586112750744169038454362455181422643027100751666",
+            "18821383157269793795438455681495246036402687001665670618754263018637548127333",
+        ];
+        let inputs: Vec<Fp> = (1..=4).map(Fp::from).collect();
+        for (n, expected) in vectors.iter().enumerate() {
+            assert_eq!(
+                hash_many(&inputs[..n + 1]).unwrap(),
+                Fp::from_str_vartime(expected).unwrap()
+            );
+        }
+        assert_eq!(hash(inputs[0], inputs[1]), hash_many(&inputs[..2]).unwrap());
+        assert_eq!(
+            hash4([inputs[0], inputs[1], inputs[2], inputs[3]]),
+            hash_many(&inputs).unwrap()
+        );
+
+        // No round constants for these, an error instead of a panic
+        assert!(hash_many(&[]).is_err());
+        let five = (1..=5).map(Fp::from).collect::<Vec<_>>();
+        assert!(hash_many(&five).is_err());
+    }
+
+    #[test]
+    fn poseidon_hash_domain() {
+        let inputs = [Fp::from(2), Fp::from(3), Fp::from(4)];
+        // Same as an untagged hash with the tag as first input
+        assert_eq!(
+            hash_domain(HashDomain::Commitment, inputs),
+            Fp::from_str_vartime(
+                "18821383157269793795438455681495246036402687001665670618754263018637548127333"
+            )
+            .unwrap()
+        );
+        let outs = [
+            hash_domain(HashDomain::Commitment, inputs),
+            hash_domain(HashDomain::Nullifier, inputs),
+            hash_domain(HashDomain::Stealth, inputs),
+        ];
+        assert_ne!(outs[0], outs[1]);
+        assert_ne!(outs[0], outs[2]);
+        assert_ne!(outs[1], outs[2]);
+        assert_ne!(outs[0], hash_many(&inputs).unwrap());
+    }
+
+    #[test]
+    fn poseidon_commitment() {
+        let vals = [Fp::from(1), Fp::from(2), Fp::from(3), Fp::from(4)];
+        let out = commitment(vals[0], vals[1], vals[2], vals[3]);
+        assert_eq!(
+            out,
+            hash4([Fp::from(1), hash4(vals), Fp::from(0), Fp::from(0)])
+        );
+        // A leaf can't be read as a tree node over the same values
+        assert_ne!(out, hash4(vals));
+    }
 }
@@ -0,0 +1,142 @@
+use ethers::prelude::*;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryKind {
+    Deposit,
+    Withdraw,
+    Send,
+}
+
+impl std::fmt::Display for HistoryKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HistoryKind::Deposit => write!(f, "deposit"),
+            HistoryKind::Withdraw => write!(f, "withdraw"),
+            HistoryKind::Send => write!(f, "send"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub kind: HistoryKind,
+    pub amount: U256,
+    pub token: H160,
+    pub tx_hash: H256,
+    pub counterparty: String,
+    // Block of the transaction, unknown in entries written by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_number: Option<u64>,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        kind: HistoryKind,
+        amount: U256,
+        token: H160,
+        tx_hash: H256,
+        counterparty: String,
+        block_number: Option<u64>,
+    ) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            timestamp,
+            kind,
+            amount,
+            token,
+            tx_hash,
+            counterparty,
+            block_number,
+        }
+    }
+}
+
+// The log is append-only, one JSON entry per line
+pub fn append(path: &Path, entry: &HistoryEntry) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+// Newest entries first
+pub fn load(path: &Path) -> Result<Vec<HistoryEntry>> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<HistoryEntry>)
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.timestamp));
+    Ok(entries)
+}
+
+// Entries of `block` or later. Those whose block is unknown are left out, they can't be placed
+pub fn since_block(entries: Vec<HistoryEntry>, block: u64) -> Vec<HistoryEntry> {
+    entries
+        .into_iter()
+        .filter(|entry| entry.block_number.is_some_and(|b| b >= block))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        assert!(load(&path).unwrap().is_empty());
+
+        let mut first = HistoryEntry::new(
+            HistoryKind::Deposit,
+            U256::from(100),
+            H160::from_low_u64_be(1),
+            H256::from_low_u64_be(2),
+            "OoOo".to_string(),
+            Some(10),
+        );
+        first.timestamp = 1000;
+        let mut second = first.clone();
+        second.kind = HistoryKind::Withdraw;
+        second.timestamp = 2000;
+        second.block_number = Some(20);
+        append(&path, &first).unwrap();
+        append(&path, &second).unwrap();
+        // Written before the block was recorded
+        let mut old = serde_json::to_value(&first).unwrap();
+        old["timestamp"] = 500.into();
+        old.as_object_mut().unwrap().remove("block_number");
+        std::fs::write(
+            &path,
+            format!("{}{}\n", std::fs::read_to_string(&path).unwrap(), old),
+        )
+        .unwrap();
+
+        let entries = load(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].block_number, None);
+        assert_eq!(
+            since_block(entries.clone(), 10),
+            vec![second.clone(), first.clone()]
+        );
+        assert_eq!(since_block(entries.clone(), 11), vec![second]);
+        assert!(since_block(entries, 21).is_empty());
+    }
+}
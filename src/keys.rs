@@ -1,16 +1,22 @@
 use crate::fp::Fp;
-use crate::hash::hash4;
+use crate::hash::{hash, hash4, hash_domain, HashDomain};
+use bech32::{FromBase32, ToBase32, Variant};
 use bip39::Mnemonic;
+use ethers::signers::{coins_bip39::English, MnemonicBuilder, Signer};
+use ethers::types::Address;
 
+use ff::derive::subtle::Choice;
 use ff::{Field, PrimeField, PrimeFieldBits};
 use num_bigint::{BigUint, RandBigInt};
-use num_traits::{Num, Zero};
+use num_traits::Zero;
 use rand::Rng;
 use serde::{de, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 use std::ops::{Add, Mul, Neg, Sub};
 use std::str::FromStr;
+use zeroize::Zeroize;
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Point {
     pub x: Fp,
@@ -44,6 +50,28 @@ lazy_static! {
     };
     pub static ref A: Fp = 168700.into();
     pub static ref D: Fp = 168696.into();
+    // BASE * 2^i, so that multiplying the base point only takes additions
+    static ref BASE_POWERS: Vec<Point> = {
+        let mut powers = vec![*BASE];
+        for i in 1..256 {
+            powers.push(powers[i - 1] + powers[i - 1]);
+        }
+        powers
+    };
+}
+
+fn mul_base(scalar: Fp) -> Point {
+    scalar
+        .to_le_bits()
+        .iter()
+        .zip(BASE_POWERS.iter())
+        .filter(|(bit, _)| **bit)
+        .fold(*INF, |acc, (_, power)| acc + *power)
+}
+
+// Hash of a Diffie-Hellman point, the tweak of a stealth key and the mask of its hints
+fn stealth_hash(point: Point) -> Fp {
+    hash_domain(HashDomain::Stealth, [point.x, point.y, Fp::from(0)])
 }
 
 impl Point {
@@ -52,6 +80,10 @@ impl Point {
         let y2 = self.y * self.y;
         *A * x2 + y2 == Fp::ONE + *D * x2 * y2
     }
+
+    pub fn ct_eq(&self, other: &Point) -> Choice {
+        self.x.ct_eq(&other.x) & self.y.ct_eq(&other.y)
+    }
 }
 
 impl Neg for Point {
@@ -98,19 +130,72 @@ impl Mul<Fp> for Point {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PrivateKey {
     pub secret: Fp,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+// Secrets are scrubbed from memory once dropped and never printed
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+// Constant-time, and deliberately not `Hash`: a secret must not end up keying a map
+impl PartialEq for PrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.secret.ct_eq(&other.secret).into()
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PrivateKey")
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct PublicKey {
     pub point: Point,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Copy, PartialEq)]
+// Compared without branching on the coordinates, so that grouping coins by owner doesn't time
+// which of them match
+impl PartialEq for PublicKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.point.ct_eq(&other.point).into()
+    }
+}
+
+impl Eq for PublicKey {}
+
+impl Hash for PublicKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.point.x.to_repr().as_ref().hash(state);
+        self.point.y.to_repr().as_ref().hash(state);
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct Entropy {
-    pub value: [u8; 16],
+    pub value: Vec<u8>,
+}
+
+impl Drop for Entropy {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
+impl fmt::Debug for Entropy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Entropy")
+            .field("value", &"<redacted>")
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -118,14 +203,50 @@ pub struct EphemeralKey {
     pub point: Point,
 }
 
-impl From<PrivateKey> for PublicKey {
-    fn from(sk: PrivateKey) -> Self {
+impl From<&PrivateKey> for PublicKey {
+    fn from(sk: &PrivateKey) -> Self {
         Self {
             point: *BASE * sk.secret,
         }
     }
 }
 
+impl From<PrivateKey> for PublicKey {
+    fn from(sk: PrivateKey) -> Self {
+        Self::from(&sk)
+    }
+}
+
+// EdDSA signature over Baby Jubjub, the challenge is computed with Poseidon
+#[derive(Clone, Debug, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Signature {
+    pub r: Point,
+    pub s: Fp,
+}
+
+// Message bytes packed in 31-byte chunks (so that each of them fits in a field element) and
+// chained through Poseidon
+fn hash_message(msg: &[u8]) -> Fp {
+    msg.chunks(31)
+        .fold(Fp::from(msg.len() as u64), |acc, chunk| {
+            let mut bytes = [0u8; 32];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            hash(acc, Fp::from_bytes_mod_order(&bytes).unwrap())
+        })
+}
+
+fn challenge(r: &Point, pub_key: &PublicKey, msg: Fp) -> Fp {
+    hash4([r.x, r.y, hash(pub_key.point.x, pub_key.point.y), msg])
+}
+
+fn to_scalar(v: Fp) -> BigUint {
+    BigUint::from_bytes_le(v.to_repr().as_ref()) % ORDER.clone()
+}
+
+fn from_scalar(v: BigUint) -> Fp {
+    Fp::from_str_vartime(&(v % ORDER.clone()).to_string()).unwrap()
+}
+
 #[derive(Clone, Debug, Copy, Serialize, Deserialize)]
 pub struct Cipher {
     a: Point,
@@ -141,12 +262,35 @@ impl PublicKey {
     }
 
     pub fn derive<R: Rng>(&self, rng: &mut R) -> (EphemeralKey, PublicKey) {
+        let (ephemeral, pub_key, _) = self.derive_with_hint_secret(rng);
+        (ephemeral, pub_key)
+    }
+
+    // Also returns the secret that obfuscates the hints of an output sent to the stealth address,
+    // which the receiver gets back with `stealth_priv.shared_secret(ephemeral)`
+    pub fn derive_with_hint_secret<R: Rng>(&self, rng: &mut R) -> (EphemeralKey, PublicKey, Fp) {
         let r = Fp::random(rng);
         let ephemeral = *BASE * r;
         let shared_secret = self.point * r;
-        let shared_secret_hash = hash4([shared_secret.x, shared_secret.y, 0.into(), 0.into()]);
+        let shared_secret_hash = stealth_hash(shared_secret);
         let pub_key = self.point + *BASE * shared_secret_hash;
-        (EphemeralKey { point: ephemeral }, Self { point: pub_key })
+        let hint_secret = pub_key * r;
+        (
+            EphemeralKey { point: ephemeral },
+            Self { point: pub_key },
+            stealth_hash(hint_secret),
+        )
+    }
+
+    pub fn verify(&self, msg: &[u8], sig: &Signature) -> bool {
+        if !self.point.is_on_curve()
+            || !sig.r.is_on_curve()
+            || BigUint::from_bytes_le(sig.s.to_repr().as_ref()) >= *ORDER
+        {
+            return false;
+        }
+        let h = challenge(&sig.r, self, hash_message(msg));
+        (*BASE * sig.s).ct_eq(&(sig.r + self.point * h)).into()
     }
 
     pub fn null() -> Self {
@@ -161,7 +305,10 @@ impl PublicKey {
 
 impl Entropy {
     pub fn generate<R: Rng>(rng: &mut R) -> Self {
-        Self { value: rng.gen() }
+        let value: [u8; 32] = rng.gen();
+        Self {
+            value: value.to_vec(),
+        }
     }
 
     pub fn to_mnemonic(&self) -> Result<String, bip39::Error> {
@@ -174,12 +321,48 @@ impl Entropy {
 
     pub fn from_mnemonic(mnemonic: Mnemonic) -> Entropy {
         Entropy {
-            value: mnemonic.to_entropy().try_into().unwrap(),
+            value: mnemonic.to_entropy(),
         }
     }
+
+    // The Ethereum account of the same mnemonic, the first one of BIP-44 path m/44'/60'/0'/0, as
+    // MetaMask and most wallets derive it. A secp256k1 key, unrelated to the Owshen (Baby Jubjub)
+    // keys
+    pub fn eth_address(&self) -> Result<Address, eyre::Report> {
+        let wallet = MnemonicBuilder::<English>::default()
+            .phrase(self.to_mnemonic()?.as_str())
+            .build()
+            .map_err(|e| eyre::Report::msg(format!("Cannot derive the Ethereum key: {}", e)))?;
+        Ok(wallet.address())
+    }
 }
 
 impl PrivateKey {
+    // The raw scalar, 0x-prefixed and zero-padded to 32 bytes. Unlike `Debug` this discloses the
+    // key, it is only for `export-key`
+    pub fn to_hex(&self) -> String {
+        let mut hex = String::from("0x");
+        for byte in self.secret.to_bytes() {
+            hex.push_str(&format!("{:02x}", byte));
+        }
+        hex
+    }
+
+    // Inverse of `to_hex`, any nonzero canonical field element: mnemonic wallets reduce their seed
+    // modulo the field modulus, so their scalars are often above the subgroup order
+    pub fn from_hex(s: &str) -> Result<Self, eyre::Report> {
+        let s = s.trim();
+        if !s.starts_with("0x") {
+            return Err(eyre::Report::msg("Private key must be 0x-prefixed hex!"));
+        }
+        let secret = Fp::from_str(s)
+            .map_err(|e| eyre::Report::msg(format!("Invalid private key: {}", e)))?;
+        if bool::from(secret.is_zero()) {
+            return Err(eyre::Report::msg("Private key must not be zero!"));
+        }
+        Ok(Self { secret })
+    }
+
     pub fn generate<R: Rng>(_rng: &mut R) -> Self {
         let rnd = rand::thread_rng().gen_biguint_range(&BigUint::zero(), &*ORDER);
         Self {
@@ -197,8 +380,7 @@ impl PrivateKey {
     }
 
     pub fn shared_secret(&self, eph: EphemeralKey) -> Fp {
-        let shared_secret = eph.point * self.secret;
-        hash4([shared_secret.x, shared_secret.y, 0.into(), 0.into()])
+        stealth_hash(eph.point * self.secret)
     }
 
     pub fn derive(&self, eph: EphemeralKey) -> Self {
@@ -215,11 +397,71 @@ impl PrivateKey {
             secret: stealth_secret,
         }
     }
+
+    // The receiver's side of `PublicKey::derive`: the stealth address an ephemeral key points to,
+    // without deriving its private key
+    pub fn recover_stealth(&self, eph: &EphemeralKey) -> PublicKey {
+        PublicKey {
+            point: PublicKey::from(self).point + mul_base(self.shared_secret(*eph)),
+        }
+    }
+
     pub fn decrypt(&self, cipher: Cipher) -> Point {
         cipher.b - cipher.a * self.secret
     }
-    pub fn nullifier(&self, index: u32) -> Fp {
-        hash4([self.secret, Fp::from(index as u64), 0.into(), 0.into()])
+
+    // Deterministic nonce, derived from the secret and the message
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        let msg = hash_message(msg);
+        let nonce = from_scalar(to_scalar(hash(self.secret, msg)));
+        let r = *BASE * nonce;
+        let h = challenge(&r, &PublicKey::from(self), msg);
+        let s = from_scalar(to_scalar(nonce) + to_scalar(h) * to_scalar(self.secret));
+        Signature { r, s }
+    }
+
+    // Finds the outputs sent to this key among a batch of ephemeral keys. `is_output(i,
+    // stealth_pub, shared_secret)` checks the output of the `i`th ephemeral key (e.g. against its
+    // commitment). The public key is only computed once and the base point multiples are
+    // precomputed, so each entry costs a single variable-base multiplication plus a hash
+    pub fn scan<F: FnMut(usize, &PublicKey, Fp) -> bool>(
+        &self,
+        ephemerals: &[EphemeralKey],
+        mut is_output: F,
+    ) -> Vec<usize> {
+        let pub_key = PublicKey::from(self);
+        ephemerals
+            .iter()
+            .enumerate()
+            .filter_map(|(i, eph)| {
+                let shared_secret = self.shared_secret(*eph);
+                let stealth_pub = PublicKey {
+                    point: pub_key.point + mul_base(shared_secret),
+                };
+                is_output(i, &stealth_pub, shared_secret).then_some(i)
+            })
+            .collect()
+    }
+
+    // Deterministic per (key, index, deployment): spending the same leaf with the same key always
+    // reveals the same nullifier, which is what makes double-spends detectable. The chain id and
+    // the contract address bind it to one Owshen deployment, as in the `coin_withdraw` circuit
+    pub fn nullifier(&self, index: u64, chain_id: u64, contract: Address) -> Fp {
+        hash_domain(
+            HashDomain::Nullifier,
+            [
+                self.secret,
+                Fp::from(index),
+                hash(Fp::from(chain_id), Fp::from(contract)),
+            ],
+        )
+    }
+
+    pub fn nullifier_batch(&self, indices: &[u64], chain_id: u64, contract: Address) -> Vec<Fp> {
+        indices
+            .iter()
+            .map(|index| self.nullifier(*index, chain_id, contract))
+            .collect()
     }
 }
 
@@ -227,18 +469,109 @@ impl From<Entropy> for PrivateKey {
     fn from(entropy: Entropy) -> Self {
         let mnemonic: Mnemonic = Mnemonic::from_entropy(&entropy.value).unwrap();
         let seed = mnemonic.to_seed("");
-        let secret = Fp::from_bytes(&seed).unwrap();
+        let secret = Fp::from_bytes_mod_order(&seed).unwrap();
         PrivateKey { secret }
     }
 }
 
+const BECH32_HRP: &str = "owshen";
+
+impl PublicKey {
+    // The x coordinate and the parity of y, every textual form is built from it
+    pub fn to_compressed(self) -> (Fp, bool) {
+        (self.point.x, self.point.y.is_odd().into())
+    }
+
+    // Recover the point from its x coordinate and the parity of y, x values with no point on
+    // the curve are rejected. y == 0 is even and has no odd counterpart
+    pub fn from_compressed(x: Fp, is_odd: bool) -> Result<Self, eyre::Report> {
+        let div = Option::<Fp>::from((*D * x * x - Fp::ONE).invert())
+            .ok_or(eyre::Report::msg("Invalid point!"))?;
+        let mut y = Option::<Fp>::from(((*A * x * x - Fp::ONE) * div).sqrt())
+            .ok_or(eyre::Report::msg("Invalid point!"))?;
+        if Into::<bool>::into(y.is_odd()) != is_odd {
+            if y.is_zero().into() {
+                return Err(eyre::Report::msg("Invalid point!"));
+            }
+            y = -y;
+        }
+        Ok(Self {
+            point: Point { x, y },
+        })
+    }
+
+    // Parity of y (2 or 3), then x in big-endian
+    pub fn to_compressed_bytes(self) -> [u8; 33] {
+        let (x, is_odd) = self.to_compressed();
+        let mut bytes = [0u8; 33];
+        bytes[0] = if is_odd { 3u8 } else { 2u8 };
+        bytes[1..].copy_from_slice(&x.to_bytes());
+        bytes
+    }
+
+    pub fn from_compressed_bytes(bytes: &[u8]) -> Result<Self, eyre::Report> {
+        if bytes.len() != 33 {
+            return Err(eyre::Report::msg(format!(
+                "Invalid compressed point: expected 33 bytes, got {}!",
+                bytes.len()
+            )));
+        }
+        let is_odd = match bytes[0] {
+            3 => true,
+            2 => false,
+            _ => return Err(eyre::Report::msg("Invalid compressed point!")),
+        };
+        let x = Fp::from_bytes(bytes[1..].try_into()?)
+            .map_err(|_| eyre::Report::msg("Invalid compressed point!"))?;
+        Self::from_compressed(x, is_odd)
+    }
+
+    // Checksummed textual form, e.g. `owshen1...`
+    pub fn to_bech32(self) -> String {
+        bech32::encode(
+            BECH32_HRP,
+            self.to_compressed_bytes().to_base32(),
+            Variant::Bech32m,
+        )
+        .expect("The human-readable prefix is valid!")
+    }
+
+    pub fn from_bech32(s: &str) -> Result<Self, eyre::Report> {
+        let (hrp, data, variant) = bech32::decode(s)
+            .map_err(|e| eyre::Report::msg(format!("Invalid Owshen address: {}!", e)))?;
+        if hrp != BECH32_HRP {
+            return Err(eyre::Report::msg(format!(
+                "Invalid Owshen address prefix {:?}, expected {:?}!",
+                hrp, BECH32_HRP
+            )));
+        }
+        if variant != Variant::Bech32m {
+            return Err(eyre::Report::msg(
+                "Invalid Owshen address: expected bech32m encoding!",
+            ));
+        }
+        let bytes = Vec::<u8>::from_base32(&data)?;
+        if bytes.len() != 33 {
+            return Err(eyre::Report::msg(format!(
+                "Invalid Owshen address: expected 33 bytes, got {}!",
+                bytes.len()
+            )));
+        }
+        Self::from_compressed_bytes(&bytes)
+            .map_err(|_| eyre::Report::msg("Invalid Owshen address!"))
+    }
+}
+
 impl FromStr for PublicKey {
     type Err = eyre::Report;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() != 69 || !s.starts_with("OoOo") {
+        if !s.starts_with("OoOo") {
+            return Self::from_bech32(s);
+        }
+        if s.len() != 69 {
             return Err(eyre::Report::msg("Invalid Owshen address!"));
         }
-        if let Some(x) = Fp::from_str_vartime(&BigUint::from_str_radix(&s[5..], 16)?.to_string()) {
+        if let Ok(x) = Fp::from_str(&format!("0x{}", &s[5..])) {
             let is_odd = if &s[4..5] == "3" {
                 true
             } else if &s[4..5] == "2" {
@@ -246,16 +579,7 @@ impl FromStr for PublicKey {
             } else {
                 return Err(eyre::Report::msg("Invalid Owshen address!"));
             };
-            let div = Option::<Fp>::from((*D * x * x - Fp::ONE).invert())
-                .ok_or(eyre::Report::msg("Invalid point!"))?;
-            let mut y = Option::<Fp>::from(((*A * x * x - Fp::ONE) * div).sqrt())
-                .ok_or(eyre::Report::msg("Invalid point!"))?;
-            if Into::<bool>::into(y.is_odd()) != is_odd {
-                y = -y;
-            }
-            Ok(Self {
-                point: Point { x, y },
-            })
+            Self::from_compressed(x, is_odd)
         } else {
             Err(eyre::Report::msg("Invalid Owshen address!"))
         }
@@ -264,10 +588,9 @@ impl FromStr for PublicKey {
 
 impl Display for PublicKey {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "OoOo")?;
-        let is_odd: bool = self.point.y.is_odd().into();
-        write!(f, "{}", if is_odd { "3" } else { "2" })?;
-        for byte in self.point.x.to_repr().as_ref().iter().rev() {
+        let (x, is_odd) = self.to_compressed();
+        write!(f, "OoOo{}", if is_odd { "3" } else { "2" })?;
+        for byte in x.to_bytes() {
             write!(f, "{:02x}", byte)?;
         }
         Ok(())
@@ -321,11 +644,224 @@ mod tests {
             )
             .unwrap(),
         };
-        let master_pub_key: PublicKey = master_priv_key.into();
+        let master_pub_key = PublicKey::from(&master_priv_key);
         let (stealth_eph, stealth_pub_key) = master_pub_key.derive(&mut rand::thread_rng());
         assert!(master_pub_key != stealth_pub_key);
         let stealth_priv_key = master_priv_key.derive(stealth_eph);
-        assert_eq!(PublicKey::from(stealth_priv_key), stealth_pub_key);
+        assert_eq!(PublicKey::from(&stealth_priv_key), stealth_pub_key);
+
+        let (eph, stealth_pub_key, hint_secret) =
+            master_pub_key.derive_with_hint_secret(&mut rand::thread_rng());
+        let stealth_priv_key = master_priv_key.derive(eph);
+        assert_eq!(PublicKey::from(&stealth_priv_key), stealth_pub_key);
+        assert_eq!(stealth_priv_key.shared_secret(eph), hint_secret);
+    }
+
+    #[test]
+    fn test_recover_stealth() {
+        let mut rng = rand::thread_rng();
+        let priv_key = PrivateKey::generate(&mut rng);
+        let pub_key = PublicKey::from(&priv_key);
+        let other = PrivateKey::generate(&mut rng);
+        for _ in 0..4 {
+            let (eph, stealth_pub_key) = pub_key.derive(&mut rng);
+            assert_eq!(priv_key.recover_stealth(&eph), stealth_pub_key);
+            assert_eq!(
+                priv_key.recover_stealth(&eph),
+                priv_key.recover_stealth(&eph)
+            );
+            assert_eq!(
+                PublicKey::from(priv_key.derive(eph)),
+                priv_key.recover_stealth(&eph)
+            );
+            assert!(other.recover_stealth(&eph) != stealth_pub_key);
+        }
+    }
+
+    #[test]
+    fn test_nullifier_batch() {
+        let priv_key = PrivateKey::generate(&mut rand::thread_rng());
+        let other = PrivateKey::generate(&mut rand::thread_rng());
+        let contract = Address::from_low_u64_be(7);
+        let indices = (0..64).collect::<Vec<u64>>();
+        let nullifiers = priv_key.nullifier_batch(&indices, 1, contract);
+        assert_eq!(nullifiers, priv_key.nullifier_batch(&indices, 1, contract));
+        assert_eq!(nullifiers[7], priv_key.nullifier(7, 1, contract));
+        assert_ne!(nullifiers[7], other.nullifier(7, 1, contract));
+        for i in 0..nullifiers.len() {
+            for j in 0..i {
+                assert_ne!(nullifiers[i], nullifiers[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nullifier_deployment() {
+        let priv_key = PrivateKey::generate(&mut rand::thread_rng());
+        let contract = Address::from_low_u64_be(7);
+        let nullifier = priv_key.nullifier(3, 1, contract);
+        // A spend on one deployment says nothing about the same leaf on another
+        assert_ne!(
+            nullifier,
+            priv_key.nullifier(3, 1, Address::from_low_u64_be(8))
+        );
+        assert_ne!(nullifier, priv_key.nullifier(3, 11155111, contract));
+        assert_eq!(
+            nullifier,
+            hash4([
+                Fp::from(2),
+                priv_key.secret,
+                Fp::from(3),
+                hash(Fp::from(1), Fp::from(7))
+            ])
+        );
+    }
+
+    #[test]
+    fn test_mnemonic_derivation() {
+        let entropy = Entropy::generate(&mut rand::thread_rng());
+        let phrase = entropy.to_mnemonic().unwrap();
+        assert_eq!(phrase.split(' ').count(), 24);
+
+        let restored = Entropy::from_mnemonic(Mnemonic::from_str(&phrase).unwrap());
+        assert_eq!(restored, entropy);
+        assert_eq!(
+            PublicKey::from(PrivateKey::from(restored)),
+            PublicKey::from(PrivateKey::from(entropy))
+        );
+    }
+
+    #[test]
+    fn test_eth_address() {
+        let mnemonic = Mnemonic::from_str(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon about",
+        )
+        .unwrap();
+        let entropy = Entropy::from_mnemonic(mnemonic);
+        assert_eq!(
+            entropy.eth_address().unwrap(),
+            Address::from_str("0x9858EfFD232B4033E47d90003D41EC34EcaEda94").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_mnemonic_checksum() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon";
+        assert!(Mnemonic::from_str(phrase).is_err());
+    }
+
+    #[test]
+    fn test_bech32_address() {
+        for _ in 0..5 {
+            let pub_key = PublicKey::from(PrivateKey::generate(&mut rand::thread_rng()));
+            let encoded = pub_key.to_bech32();
+            assert!(encoded.starts_with("owshen1"));
+            assert_eq!(PublicKey::from_str(&encoded).unwrap(), pub_key);
+            assert_eq!(PublicKey::from_str(&pub_key.to_string()).unwrap(), pub_key);
+        }
+
+        let encoded = PublicKey::from(PrivateKey {
+            secret: 23456.into(),
+        })
+        .to_bech32();
+        let last = encoded.chars().last().unwrap();
+        let typo = format!(
+            "{}{}",
+            &encoded[..encoded.len() - 1],
+            if last == 'q' { 'p' } else { 'q' }
+        );
+        let err = PublicKey::from_str(&typo).unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+    }
+
+    #[test]
+    fn test_compressed() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..32 {
+            let pub_key = PublicKey::from(PrivateKey::generate(&mut rng));
+            let (x, is_odd) = pub_key.to_compressed();
+            assert_eq!(PublicKey::from_compressed(x, is_odd).unwrap(), pub_key);
+            assert_eq!(
+                PublicKey::from_compressed(x, !is_odd).unwrap().point.y,
+                -pub_key.point.y
+            );
+            let bytes = pub_key.to_compressed_bytes();
+            assert_eq!(PublicKey::from_compressed_bytes(&bytes).unwrap(), pub_key);
+            assert_eq!(PublicKey::from_str(&pub_key.to_string()).unwrap(), pub_key);
+        }
+
+        // a * x^2 == 1, a point of order 4
+        let x = Option::<Fp>::from(A.invert().unwrap().sqrt()).unwrap();
+        let edge = PublicKey::from_compressed(x, false).unwrap();
+        assert_eq!(edge.point.y, Fp::ZERO);
+        assert!(PublicKey::from_compressed(x, true).is_err());
+        assert_eq!(
+            PublicKey::from_compressed_bytes(&edge.to_compressed_bytes()).unwrap(),
+            edge
+        );
+
+        // No point has x == 1
+        assert!(PublicKey::from_compressed(Fp::ONE, false).is_err());
+        let mut bytes = [0u8; 33];
+        bytes[0] = 2;
+        bytes[32] = 1;
+        assert!(PublicKey::from_compressed_bytes(&bytes).is_err());
+        bytes[1..].copy_from_slice(&[0xff; 32]);
+        assert!(PublicKey::from_compressed_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let priv_key = PrivateKey::generate(&mut rand::thread_rng());
+        let pub_key = PublicKey::from(&priv_key);
+        let msg = b"withdraw 100 DIVE to 0x2222222222222222222222222222222222222222";
+        let sig = priv_key.sign(msg);
+        assert!(pub_key.verify(msg, &sig));
+        assert_eq!(priv_key.sign(msg), sig);
+        assert!(pub_key.verify(b"", &priv_key.sign(b"")));
+
+        assert!(!pub_key.verify(
+            b"withdraw 900 DIVE to 0x2222222222222222222222222222222222222222",
+            &sig
+        ));
+        assert!(!pub_key.verify(&msg[..msg.len() - 1], &sig));
+        let other = PublicKey::from(PrivateKey::generate(&mut rand::thread_rng()));
+        assert!(!other.verify(msg, &sig));
+        let forged = Signature {
+            r: sig.r,
+            s: sig.s + Fp::ONE,
+        };
+        assert!(!pub_key.verify(msg, &forged));
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut rng = rand::thread_rng();
+        let priv_key = PrivateKey::generate(&mut rng);
+        let pub_key = PublicKey::from(&priv_key);
+        let other = PublicKey::from(PrivateKey::generate(&mut rng));
+        let outputs = (0..32)
+            .map(|i| {
+                if i % 5 == 0 {
+                    pub_key.derive(&mut rng)
+                } else {
+                    other.derive(&mut rng)
+                }
+            })
+            .collect::<Vec<_>>();
+        let ephemerals = outputs.iter().map(|(eph, _)| *eph).collect::<Vec<_>>();
+
+        let expected = (0..outputs.len())
+            .filter(|i| PublicKey::from(priv_key.derive(ephemerals[*i])) == outputs[*i].1)
+            .collect::<Vec<_>>();
+        let found = priv_key.scan(&ephemerals, |i, stealth_pub, _| {
+            *stealth_pub == outputs[i].1
+        });
+
+        assert_eq!(found, vec![0, 5, 10, 15, 20, 25, 30]);
+        assert_eq!(found, expected);
+        assert_eq!(mul_base(Fp::from(12345)), *BASE * Fp::from(12345));
     }
 
     #[test]
@@ -333,7 +869,7 @@ mod tests {
         let priv_key = PrivateKey {
             secret: 23456.into(),
         };
-        let pub_key: PublicKey = priv_key.into();
+        let pub_key = PublicKey::from(&priv_key);
         let msg = G.mul(123456.into());
         let rnd: Fp = 987654.into();
         let enc = pub_key.encrypt(rnd, msg);
@@ -342,6 +878,66 @@ mod tests {
         assert_eq!(dec, msg);
     }
 
+    #[test]
+    fn test_secrets_redacted() {
+        let mut priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        assert!(!format!("{:?}", priv_key).contains("23456"));
+        let entropy = Entropy {
+            value: vec![0xab; 32],
+        };
+        assert!(!format!("{:?}", entropy).contains("171"));
+
+        priv_key.secret.zeroize();
+        assert_eq!(priv_key.secret, Fp::ZERO);
+    }
+
+    #[test]
+    fn test_private_key_hex() {
+        let priv_key = PrivateKey {
+            secret: 0x1234.into(),
+        };
+        let hex = priv_key.to_hex();
+        assert_eq!(hex.len(), 66);
+        assert!(hex.ends_with("001234"));
+        assert!(PrivateKey::from_hex(&hex).unwrap() == priv_key);
+        assert!(PrivateKey::from_hex("0x1234").unwrap() == priv_key);
+
+        for invalid in ["1234", "0x", "0xzz", "0x0", Fp::MODULUS] {
+            assert!(PrivateKey::from_hex(invalid).is_err(), "{}", invalid);
+        }
+        // Scalars above the subgroup order are what mnemonic wallets derive most of the time
+        let above_order = format!("0x{}", (&*ORDER + 1u32).to_str_radix(16));
+        assert!(PrivateKey::from_hex(&above_order).is_ok());
+        let modulus = BigUint::parse_bytes(&Fp::MODULUS.as_bytes()[2..], 16).unwrap();
+        let largest = format!("0x{}", (modulus - 1u32).to_str_radix(16));
+        assert!(PrivateKey::from_hex(&largest).is_ok());
+    }
+
+    #[test]
+    fn test_key_equality() {
+        let priv_key = PrivateKey {
+            secret: 12345.into(),
+        };
+        let other = PrivateKey {
+            secret: 54321.into(),
+        };
+        assert!(priv_key == priv_key.clone());
+        assert!(priv_key != other);
+
+        let by_owner = [&priv_key, &other, &priv_key]
+            .into_iter()
+            .map(PublicKey::from)
+            .fold(std::collections::HashMap::new(), |mut map, pub_key| {
+                *map.entry(pub_key).or_insert(0) += 1;
+                map
+            });
+        assert_eq!(by_owner.len(), 2);
+        assert_eq!(by_owner[&PublicKey::from(&priv_key)], 2);
+        assert_eq!(by_owner[&PublicKey::from(&other)], 1);
+    }
+
     #[test]
     fn test_generator() {
         assert!(G.is_on_curve());
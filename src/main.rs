@@ -1,61 +1,90 @@
 mod apis;
+mod events;
 mod fp;
 mod hash;
+mod history;
 mod keys;
+#[cfg(test)]
+mod mock;
+mod payment;
 mod poseidon;
 mod proof;
+mod rpc;
+mod spent;
+mod sync;
 mod tree;
+mod vault;
+mod zkey;
 
+use apis::ApiError;
 use axum::{
     // body::Bytes,
     body::Body,
-    extract::{self, Query},
-    http::{Response, StatusCode},
+    extract::{ws::WebSocketUpgrade, Query, State},
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::{self, Next},
     response::{Html, IntoResponse, Json},
-    routing::{get, get_service},
+    routing::{get, get_service, post},
     Router,
 };
-use bindings::owshen::{Owshen, Point as OwshenPoint};
+use bindings::owshen::{Owshen, Point as OwshenPoint, Proof as OwshenProof, SentFilter};
 use bindings::simple_erc_20::SimpleErc20;
 use bip39::Mnemonic;
 use colored::Colorize;
 use ethers::prelude::*;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::utils::to_checksum;
 use eyre::Result;
 use fp::Fp;
-use hash::hash4;
+use history::{HistoryEntry, HistoryKind};
 use keys::Point;
 use keys::{PrivateKey, PublicKey};
-use proof::Proof;
+use payment::PaymentRequest;
+use proof::{Proof, MERKLE_PROOF_LENGTH};
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use std::{fs::read_to_string, process::Command};
 use structopt::StructOpt;
-use tokio::fs::File;
-use tokio::task;
-use tokio_util::codec::{BytesCodec, FramedRead};
-use tower_http::cors::CorsLayer;
-use tower_http::services::ServeFile;
+use sync::SyncState;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::services::{ServeDir, ServeFile};
+use tower_http::trace::TraceLayer;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 use tree::SparseMerkleTree;
+use vault::Vault;
 use webbrowser;
 
 #[macro_use]
 extern crate lazy_static;
 
 const GOERLI_ENDPOINT: &str = "https://ethereum-goerli.publicnode.com";
-// Initialize wallet, TODO: let secret be derived from a BIP-39 mnemonic code
+// Initialize wallet, the secret is derived from a 24-word BIP-39 mnemonic
 #[derive(StructOpt, Debug)]
 pub struct InitOpt {
     #[structopt(long, default_value = GOERLI_ENDPOINT)]
     endpoint: String,
     #[structopt(long)]
     db: Option<PathBuf>,
-    #[structopt(long)]
+    #[structopt(long, parse(try_from_str = parse_mnemonic))]
     mnemonic: Option<Mnemonic>,
+    #[structopt(
+        long,
+        parse(try_from_str = PrivateKey::from_hex),
+        conflicts_with = "mnemonic",
+        help = "Import a raw 0x-hex secret scalar instead of a mnemonic"
+    )]
+    private_key: Option<PrivateKey>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
     #[structopt(long)]
     test: bool,
+    #[structopt(long, help = "Overwrite the existing (e.g. corrupt) wallet")]
+    force: bool,
 }
 
 // Open web wallet interface
@@ -63,37 +92,681 @@ pub struct InitOpt {
 pub struct WalletOpt {
     #[structopt(long)]
     db: Option<PathBuf>,
-    #[structopt(long, default_value = "8000")]
-    port: u16,
+    #[structopt(long, alias = "port", default_value = "9000")]
+    api_port: u16,
+    #[structopt(
+        long,
+        default_value = "3000",
+        help = "Port of the frontend in test mode"
+    )]
+    front_port: u16,
     #[structopt(long, default_value = GOERLI_ENDPOINT)]
     endpoint: String,
     #[structopt(long, help = "Enable test mode")]
     test: bool,
     #[structopt(long)]
     config: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_origin),
+        help = "Extra origin allowed to call the wallet API, can be repeated"
+    )]
+    allow_origin: Vec<HeaderValue>,
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "Requests per minute allowed on each of /withdraw, /send and /broadcast, 0 for no limit"
+    )]
+    rate_limit: u32,
+    #[structopt(
+        long,
+        help = "WebSocket endpoint (ws:// or wss://) streaming the contract events, the RPC is polled otherwise"
+    )]
+    ws: Option<String>,
+    #[structopt(
+        long,
+        help = "Also write the proof of each /withdraw to <dir>/withdraw-<nullifier>.json (snarkjs { proof, publicSignals })"
+    )]
+    proof_dir: Option<PathBuf>,
+    #[structopt(flatten)]
+    relayer: RelayerOpt,
+    // Fees of the withdrawals sent by /broadcast
+    #[structopt(flatten)]
+    gas: GasOpt,
 }
+// Create the config file, or read and change its settings
 #[derive(StructOpt, Debug)]
 pub struct ConfigOpt {
     #[structopt(long, default_value = GOERLI_ENDPOINT)]
     endpoint: String,
-    #[structopt(long)]
-    name: String,
+    #[structopt(long, help = "Required to create the config")]
+    name: Option<String>,
     #[structopt(long)]
     config: Option<PathBuf>,
     #[structopt(long)]
     test: bool,
+    #[structopt(subcommand)]
+    action: Option<ConfigAction>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ConfigAction {
+    // Print a setting
+    Get { key: ConfigKey },
+    // Validate and store a setting
+    Set { key: ConfigKey, value: String },
+}
+
+// The settings `config get`/`config set` may touch, the ABIs are only written by `config`/`deploy`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigKey {
+    Name,
+    Endpoint,
+    DiveContractAddress,
+    OwshenContractAddress,
+    VerifierContractAddress,
+    Network,
+}
+
+const CONFIG_KEYS: [(&str, ConfigKey); 6] = [
+    ("name", ConfigKey::Name),
+    ("endpoint", ConfigKey::Endpoint),
+    ("dive_contract_address", ConfigKey::DiveContractAddress),
+    ("owshen_contract_address", ConfigKey::OwshenContractAddress),
+    (
+        "verifier_contract_address",
+        ConfigKey::VerifierContractAddress,
+    ),
+    ("network", ConfigKey::Network),
+];
+
+impl FromStr for ConfigKey {
+    type Err = eyre::Report;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CONFIG_KEYS
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, key)| *key)
+            .ok_or(eyre::Report::msg(format!(
+                "Unknown config key {:?}, expected one of: {}",
+                s,
+                CONFIG_KEYS.map(|(name, _)| name).join(", ")
+            )))
+    }
 }
 
 // Show wallet info
 #[derive(StructOpt, Debug)]
-pub struct InfoOpt {}
+pub struct InfoOpt {
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+    #[structopt(long)]
+    qr: bool,
+}
+
+// Show the total value of owned coins
+#[derive(StructOpt, Debug)]
+pub struct BalanceOpt {
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+    #[structopt(long, help = "Sync and redraw the balance until Ctrl-C")]
+    watch: bool,
+    #[structopt(
+        long,
+        default_value = "12",
+        help = "Seconds between two refreshes of --watch"
+    )]
+    interval: u64,
+}
+
+// Deploy the Owshen contract (and its withdraw verifier) and record their addresses in the config
+#[derive(StructOpt, Debug)]
+pub struct DeployOpt {
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+    #[structopt(long, help = "Redeploy even if a contract is already configured")]
+    force: bool,
+    #[structopt(flatten)]
+    gas: GasOpt,
+}
+
+// The relayer paid by the /withdraw proofs that don't name one, out of the withdrawn token
+#[derive(StructOpt, Debug, Clone, Default, PartialEq)]
+pub struct RelayerOpt {
+    #[structopt(long, help = "Relayer address paid by the withdrawals")]
+    relayer: Option<H160>,
+    #[structopt(
+        long,
+        parse(try_from_str = U256::from_dec_str),
+        requires = "relayer",
+        help = "Fee the relayer keeps, in the smallest unit of the withdrawn token"
+    )]
+    relayer_fee: Option<U256>,
+}
+
+// Fees are estimated by the node unless given, in wei
+#[derive(StructOpt, Debug, Clone, Default, PartialEq)]
+pub struct GasOpt {
+    #[structopt(
+        long,
+        parse(try_from_str = U256::from_dec_str),
+        conflicts_with_all = &["max-fee", "priority-fee"],
+        help = "Legacy gas price, implies --legacy"
+    )]
+    gas_price: Option<U256>,
+    #[structopt(long, parse(try_from_str = U256::from_dec_str), help = "EIP-1559 max fee per gas")]
+    max_fee: Option<U256>,
+    #[structopt(
+        long,
+        parse(try_from_str = U256::from_dec_str),
+        help = "EIP-1559 priority fee per gas"
+    )]
+    priority_fee: Option<U256>,
+    #[structopt(
+        long,
+        help = "Send legacy transactions, e.g. to Ganache or older nodes"
+    )]
+    legacy: bool,
+}
+
+// Deposit tokens into the Owshen pool for an Owshen address
+#[derive(StructOpt, Debug)]
+pub struct DepositOpt {
+    #[structopt(long, help = "Owshen address of the recipient")]
+    to: PublicKey,
+    #[structopt(
+        long,
+        required_unless = "denomination",
+        help = "Amount, e.g. 1.5eth (whole tokens of the token's decimals) or 500gwei"
+    )]
+    amount: Option<String>,
+    #[structopt(long, help = "Address of the ERC-20 token, or DIVE")]
+    token: String,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+    #[structopt(flatten)]
+    gas: GasOpt,
+    #[structopt(long, help = "Print the transactions instead of sending them")]
+    dry_run: bool,
+    #[structopt(
+        long,
+        help = "Deposit coins of this standard size (10eth, 1eth or 0.1eth) into its own tree, one by default"
+    )]
+    denomination: Option<String>,
+}
+
+// Privately pay an Owshen address from one of your coins
+#[derive(StructOpt, Debug)]
+pub struct SendOpt {
+    #[structopt(long, help = "Owshen address of the recipient")]
+    to: PublicKey,
+    #[structopt(
+        long,
+        help = "Amount, e.g. 1.5eth (whole tokens of the token's decimals) or 500gwei"
+    )]
+    amount: String,
+    #[structopt(long, help = "Address of the ERC-20 token, or DIVE")]
+    token: String,
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+    #[structopt(flatten)]
+    gas: GasOpt,
+    #[structopt(
+        long,
+        help = "Generate and verify the proof, then print the transaction instead of sending it"
+    )]
+    dry_run: bool,
+    #[structopt(
+        long,
+        help = "Write the proof (snarkjs format) and the transaction to this file, for someone else to send"
+    )]
+    output: Option<PathBuf>,
+}
+
+// Write an encrypted backup of the wallet, along with the configured contracts
+#[derive(StructOpt, Debug)]
+pub struct ExportOpt {
+    #[structopt(long)]
+    out: PathBuf,
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+}
+
+// Restore a wallet from a backup written by `owshen export`
+#[derive(StructOpt, Debug)]
+pub struct RestoreOpt {
+    backup: PathBuf,
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+    #[structopt(long, help = "Overwrite the existing wallet")]
+    force: bool,
+}
+
+// Print the raw secret scalar, for other tools holding Owshen keys. Import it back with
+// `owshen init --private-key`
+#[derive(StructOpt, Debug)]
+pub struct ExportKeyOpt {
+    #[structopt(long, default_value = "hex", possible_values = &["hex"])]
+    format: String,
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+    #[structopt(long, help = "Confirm printing the private key")]
+    yes: bool,
+}
+
+// Offline recovery: find your coins in a dump of the `Sent` events, without any RPC node
+#[derive(StructOpt, Debug)]
+pub struct ScanOpt {
+    #[structopt(
+        long,
+        help = "JSON array of {block_number, index, ephemeral, hint_amount, hint_token_address, commitment}, or a sync cache"
+    )]
+    ephemerals: PathBuf,
+    #[structopt(long, help = "Chain the dump comes from, recorded in a sync cache")]
+    chain_id: Option<u64>,
+    #[structopt(
+        long,
+        help = "Owshen contract the dump comes from, recorded in a sync cache"
+    )]
+    contract: Option<H160>,
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+}
+
+// Merge the commitments found by an external indexer into the local sync cache, instead of
+// scanning the events with `owshen sync`
+#[derive(StructOpt, Debug)]
+pub struct ImportCommitmentsOpt {
+    #[structopt(
+        help = "JSON array of {block_number, index, ephemeral, hint_amount, hint_token_address, commitment}"
+    )]
+    commitments: PathBuf,
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+}
+
+// Print the Merkle branch of a leaf of the locally synced tree, e.g. to check it against the
+// contract or to debug the withdraw circuit
+#[derive(StructOpt, Debug)]
+pub struct MerkleProofOpt {
+    #[structopt(long, help = "Index of the leaf, i.e. of the coin")]
+    index: u64,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+}
+
+// Locate a coin sent to you, from the payment request shared by the sender
+#[derive(StructOpt, Debug)]
+pub struct ImportOpt {
+    #[structopt(help = "Payment request printed by `owshen deposit`")]
+    request: PaymentRequest,
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+}
+
+// Check a Groth16 proof of the withdraw circuit against its verifying key, without the contract
+#[derive(StructOpt, Debug)]
+pub struct VerifyOpt {
+    #[structopt(
+        long,
+        help = "snarkjs proof.json, or a proof returned by the wallet API"
+    )]
+    proof: PathBuf,
+    #[structopt(
+        long,
+        help = "JSON array of the public signals, e.g. snarkjs public.json"
+    )]
+    public_inputs: PathBuf,
+    #[structopt(
+        long,
+        help = "snarkjs verification key, defaults to the withdraw circuit's"
+    )]
+    verifying_key: Option<PathBuf>,
+}
+
+// Check the proving key, wallet, config, RPC endpoint and contract, exits nonzero if any critical
+// check fails
+#[derive(StructOpt, Debug)]
+pub struct DoctorOpt {
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+}
+
+// Local development only: send ETH from the node's first unlocked account, e.g. to the account
+// the frontend deposits from
+#[derive(StructOpt, Debug)]
+pub struct FundOpt {
+    #[structopt(
+        long,
+        help = "Ethereum address to fund, defaults to the Ethereum account of the wallet's mnemonic"
+    )]
+    to: Option<H160>,
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    #[structopt(long)]
+    password_file: Option<PathBuf>,
+    #[structopt(long, parse(try_from_str = parse_amount), help = "Amount, e.g. 1.5eth or 500gwei")]
+    amount: U256,
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+}
+
+// Amounts carry their unit, e.g. 1.5eth, 500gwei or 1000wei. A bare number is refused since it
+// could mean either wei or ETH, and a fraction of a wei is refused rather than rounded
+fn parse_amount(amount: &str) -> Result<U256> {
+    parse_token_amount(amount, 18)
+}
+
+// Same for an ERC-20 token of `decimals` decimals, where `eth` stands for one whole token (e.g.
+// 10^6 of the smallest unit of a 6 decimals token) while `gwei` and `wei` keep their size
+fn parse_token_amount(amount: &str, token_decimals: usize) -> Result<U256> {
+    let invalid = |why: &str| eyre::Report::msg(format!("Invalid amount {}: {}", amount, why));
+    let lower = amount.trim().to_lowercase();
+    let (number, decimals) = if let Some(number) = lower.strip_suffix("gwei") {
+        (number, 9)
+    } else if let Some(number) = lower.strip_suffix("wei") {
+        (number, 0)
+    } else if let Some(number) = lower.strip_suffix("eth") {
+        (number, token_decimals)
+    } else {
+        return Err(invalid("missing unit, e.g. 1.5eth, 500gwei or 1000wei"));
+    };
+    let number = number.trim();
+    if number.starts_with('-') {
+        return Err(invalid("negative amount"));
+    }
+    let (int, frac) = number.split_once('.').unwrap_or((number, ""));
+    if int.is_empty() && frac.is_empty()
+        || !int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid("not a decimal number"));
+    }
+    let frac = frac.trim_end_matches('0');
+    if frac.len() > decimals {
+        return Err(invalid("smaller than 1 wei"));
+    }
+    let wei = format!("0{}{}{}", int, frac, "0".repeat(decimals - frac.len()));
+    U256::from_dec_str(&wei).map_err(|_| invalid("larger than 2^256 - 1 wei"))
+}
+
+// Standard deposit sizes, in whole tokens. Owshen.sol keeps a tree for the deposits of each size
+// and token, so that a coin spent from one is only linked to the deposits of its size
+const DENOMINATIONS: [&str; 3] = ["10eth", "1eth", "0.1eth"];
+
+// A `deposit --denomination` makes at most this many deposits
+const MAX_DENOMINATION_DEPOSITS: u64 = 100;
+
+// The standard sizes of a token of `decimals` decimals, 0.1 needs at least one
+fn denominations(decimals: usize) -> Vec<U256> {
+    DENOMINATIONS
+        .iter()
+        .filter_map(|d| parse_token_amount(d, decimals).ok())
+        .collect()
+}
+
+// How many deposits of the standard size `denomination` make up `amount`
+fn denomination_deposits(amount: U256, denomination: U256, decimals: usize) -> Result<u64> {
+    let format = |amount: U256| {
+        ethers::utils::format_units(amount, decimals as u32).unwrap_or(amount.to_string())
+    };
+    if !denominations(decimals).contains(&denomination) {
+        return Err(eyre::Report::msg(format!(
+            "{} is not a standard denomination (10, 1 or 0.1 of the token)!",
+            format(denomination)
+        )));
+    }
+    if amount.is_zero() || !(amount % denomination).is_zero() {
+        return Err(eyre::Report::msg(format!(
+            "{} is not a multiple of the denomination {}!",
+            format(amount),
+            format(denomination)
+        )));
+    }
+    let count = amount / denomination;
+    if count > U256::from(MAX_DENOMINATION_DEPOSITS) {
+        return Err(eyre::Report::msg(format!(
+            "{} would take {} deposits of {}, use a larger denomination!",
+            format(amount),
+            count,
+            format(denomination)
+        )));
+    }
+    Ok(count.as_u64())
+}
+
+// How many coins of each token are in the tree of each denomination (`None` for the main tree),
+// largest denominations first
+fn denomination_counts(coins: &[Coin]) -> Vec<(H160, Option<U256>, usize)> {
+    let mut counts: Vec<(H160, Option<U256>, usize)> = Vec::new();
+    for coin in coins.iter() {
+        let size = (!coin.denomination.is_zero()).then_some(coin.denomination);
+        match counts
+            .iter_mut()
+            .find(|(token, d, _)| *token == coin.uint_token && *d == size)
+        {
+            Some((_, _, count)) => *count += 1,
+            None => counts.push((coin.uint_token, size, 1)),
+        }
+    }
+    counts.sort_by_key(|(token, d, _)| (*token, std::cmp::Reverse(*d)));
+    counts
+}
+
+// Scan the Owshen contract events and cache them locally
+#[derive(StructOpt, Debug)]
+pub struct SyncOpt {
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "Override the configured RPC endpoint")]
+    rpc: Option<String>,
+    #[structopt(
+        long,
+        help = "Rescan everything from this block on, reorgs are otherwise detected automatically"
+    )]
+    from_block: Option<u64>,
+    #[structopt(
+        long,
+        conflicts_with = "from-block",
+        help = "Never scan blocks before this one, e.g. the contract's deployment block"
+    )]
+    since_block: Option<u64>,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Leave the blocks less than this many blocks deep for a later sync"
+    )]
+    confirmations: u64,
+}
+
+// Show past deposits and withdrawals, newest first
+#[derive(StructOpt, Debug)]
+pub struct HistoryOpt {
+    #[structopt(long, help = "Only show the transactions of this block or later")]
+    since_block: Option<u64>,
+}
+
+#[derive(StructOpt, Debug)]
+struct OwshenCliOpt {
+    #[structopt(
+        long,
+        global = true,
+        help = "Print machine-readable JSON instead of text"
+    )]
+    json: bool,
+    #[structopt(
+        short,
+        long,
+        global = true,
+        parse(from_occurrences),
+        help = "Log more details (-v info, -vv debug, -vvv trace), RUST_LOG takes precedence"
+    )]
+    verbose: u8,
+    #[structopt(
+        long,
+        global = true,
+        default_value = "30",
+        help = "Seconds to wait for each RPC request, failed reads are retried 3 times"
+    )]
+    rpc_timeout: u64,
+    #[structopt(
+        long,
+        global = true,
+        default_value = "10000",
+        help = "Blocks fetched per eth_getLogs call when syncing, halved when the provider refuses"
+    )]
+    sync_chunk: u64,
+    #[structopt(
+        long,
+        global = true,
+        default_value = DEFAULT_PROFILE,
+        help = "Wallet profile to use, stored in ~/.owshen/<name>.json"
+    )]
+    wallet: String,
+    #[structopt(
+        long,
+        global = true,
+        help = "mainnet, sepolia or localhost: use its public endpoint (unless --rpc is given) and refuse RPCs of another chain"
+    )]
+    network: Option<Network>,
+    #[structopt(subcommand)]
+    command: OwshenCommand,
+}
+
+const DEFAULT_PROFILE: &str = "default";
+
+fn log_level(verbose: u8) -> LevelFilter {
+    match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+// Logs go to stderr, so that they never mix with the (possibly JSON) output
+fn init_logging(verbose: u8) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(format!(
+            "owshen={level},tower_http={level}",
+            level = log_level(verbose)
+        ))
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+// Files of a wallet profile: each profile has its own endpoint, contracts, sync cache, history
+// and spent log, not only its own keys
+#[derive(Debug, Clone, PartialEq)]
+struct ProfilePaths {
+    wallet: PathBuf,
+    config: PathBuf,
+    sync: PathBuf,
+    history: PathBuf,
+    spent: PathBuf,
+}
+
+// `~/.owshen/<name>.json` and its `<name>.config.json`, `<name>.sync.json`... siblings. The
+// default profile keeps using each global file of older versions that exists
+fn profile_paths(home: &Path, name: &str) -> Result<ProfilePaths> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(eyre::Report::msg(format!(
+            "Invalid wallet name {:?}, use letters, digits, - and _ only!",
+            name
+        )));
+    }
+    let file = |suffix: &str, legacy: &str| {
+        let path = home.join(".owshen").join(format!("{}{}", name, suffix));
+        let legacy_path = home.join(legacy);
+        if name == DEFAULT_PROFILE && !path.exists() && legacy_path.exists() {
+            legacy_path
+        } else {
+            path
+        }
+    };
+    Ok(ProfilePaths {
+        wallet: file(".json", ".owshen-wallet.json"),
+        config: file(".config.json", ".config-wallet.json"),
+        sync: file(".sync.json", ".owshen-sync.json"),
+        history: file(".history.jsonl", ".owshen-history.jsonl"),
+        spent: file(".spent.jsonl", ".owshen-spent.jsonl"),
+    })
+}
 
 #[derive(StructOpt, Debug)]
-enum OwshenCliOpt {
+enum OwshenCommand {
     Init(InitOpt),
     Info(InfoOpt),
     Wallet(WalletOpt),
     Config(ConfigOpt),
+    Balance(BalanceOpt),
+    Deploy(DeployOpt),
+    Deposit(DepositOpt),
+    Send(SendOpt),
+    Import(ImportOpt),
+    Scan(ScanOpt),
+    ImportCommitments(ImportCommitmentsOpt),
+    MerkleProof(MerkleProofOpt),
+    Export(ExportOpt),
+    Restore(RestoreOpt),
+    ExportKey(ExportKeyOpt),
+    Sync(SyncOpt),
+    Verify(VerifyOpt),
+    Fund(FundOpt),
+    Doctor(DoctorOpt),
+    History(HistoryOpt),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -109,7 +782,33 @@ pub struct GetInfoResponse {
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct GetCoinsResponse {
-    coins: Vec<Coin>,
+    coins: Vec<CoinInfo>,
+}
+
+// Public view of an owned coin, without its stealth private key
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CoinInfo {
+    pub index: U256,
+    pub amount: U256,
+    pub token: H160,
+    pub nullifier: U256,
+}
+
+impl From<&Coin> for CoinInfo {
+    fn from(coin: &Coin) -> Self {
+        CoinInfo {
+            index: coin.index,
+            amount: coin.amount,
+            token: coin.uint_token,
+            nullifier: coin.nullifier,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetHealthResponse {
+    status: String,
+    synced_block: Option<u64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -128,6 +827,11 @@ pub struct GetWithdrawRequest {
     index: U256,
     pub address: String,
     pub desire_amount: String,
+    // Receiver of the withdrawal, and the relayer paid `fee` out of it (the wallet's `--relayer`
+    // and `--relayer-fee` when not given), all bound by the proof
+    pub to: H160,
+    pub relayer: Option<H160>,
+    pub fee: Option<U256>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -139,6 +843,39 @@ pub struct GetWithdrawResponse {
     pub nullifier: U256,
     pub commitment: U256,
     pub ephemeral: Point,
+    pub to: H160,
+    pub relayer: H160,
+    pub fee: U256,
+    // Of the tree the spent coin is in, which `Owshen.withdraw` takes the root of
+    pub denomination: U256,
+}
+
+// A /withdraw response, `amount` being the withdrawn part of the coin, which `to` receives minus
+// the `fee` paid to `relayer`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PostBroadcastRequest {
+    proof: Proof,
+    pub token: H160,
+    pub amount: U256,
+    pub obfuscated_remaining_amount: U256,
+    pub nullifier: U256,
+    pub commitment: U256,
+    pub ephemeral: Point,
+    pub to: H160,
+    #[serde(default)]
+    pub relayer: H160,
+    #[serde(default)]
+    pub fee: U256,
+    #[serde(default)]
+    pub denomination: U256,
+    // Checks the proof against the local tree and estimates the gas, without sending
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PostBroadcastResponse {
+    pub tx_hash: H256,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -161,8 +898,10 @@ pub struct GetSendResponse {
     pub receiver_ephemeral: Point,
     pub obfuscated_receiver_amount: U256,
     pub obfuscated_sender_amount: U256,
+    // Of the tree the spent coin is in, `Owshen.send` takes it along with `token`
+    pub denomination: U256,
 }
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Coin {
     pub index: U256,
     pub uint_token: H160,
@@ -171,6 +910,9 @@ pub struct Coin {
     pub pub_key: PublicKey,
     pub nullifier: U256,
     pub commitment: U256,
+    // Zero unless the coin is a deposit in the tree of its size, see `SyncState::denomination_tree`
+    #[serde(default)]
+    pub denomination: U256,
 }
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Send {
@@ -187,9 +929,36 @@ pub struct TokenInfo {
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Wallet {
-    entropy: Entropy,
+    #[serde(flatten)]
+    key: WalletKey,
     token_contracts: Vec<TokenInfo>,
 }
+// Stored as an `entropy` or a `private_key` field. A wallet imported from a raw scalar has no
+// mnemonic, and no Ethereum account derived from it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum WalletKey {
+    Entropy(Entropy),
+    PrivateKey(PrivateKey),
+}
+
+impl Wallet {
+    fn priv_key(&self) -> PrivateKey {
+        match &self.key {
+            WalletKey::Entropy(entropy) => PrivateKey::from(entropy.clone()),
+            WalletKey::PrivateKey(priv_key) => priv_key.clone(),
+        }
+    }
+
+    fn eth_address(&self) -> Result<H160> {
+        match &self.key {
+            WalletKey::Entropy(entropy) => entropy.eth_address(),
+            WalletKey::PrivateKey(_) => Err(eyre::Report::msg(
+                "Wallet was imported from a private key, it has no Ethereum account!",
+            )),
+        }
+    }
+}
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Config {
     name: String,
@@ -198,6 +967,83 @@ struct Config {
     owshen_contract_address: H160,
     owshen_contract_abi: Abi,
     erc20_abi: Abi,
+    #[serde(default)]
+    verifier_contract_address: H160,
+    #[serde(default)]
+    network: Option<Network>,
+}
+
+// Known chains, selecting one picks its public endpoint and pins its chain id
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Sepolia,
+    Localhost,
+}
+
+const NETWORKS: [(&str, Network); 3] = [
+    ("mainnet", Network::Mainnet),
+    ("sepolia", Network::Sepolia),
+    ("localhost", Network::Localhost),
+];
+
+impl Network {
+    fn endpoint(self) -> &'static str {
+        match self {
+            Network::Mainnet => "https://ethereum.publicnode.com",
+            Network::Sepolia => "https://ethereum-sepolia.publicnode.com",
+            Network::Localhost => "http://127.0.0.1:8545",
+        }
+    }
+
+    // Ganache and Anvil both listen on localhost:8545
+    fn chain_ids(self) -> &'static [u64] {
+        match self {
+            Network::Mainnet => &[1],
+            Network::Sepolia => &[11155111],
+            Network::Localhost => &DEV_CHAIN_IDS,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = eyre::Report;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NETWORKS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, network)| *network)
+            .ok_or(eyre::Report::msg(format!(
+                "Unknown network {:?}, expected one of: {}",
+                s,
+                NETWORKS.map(|(name, _)| name).join(", ")
+            )))
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (name, _) = NETWORKS.iter().find(|(_, n)| n == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+// A transaction meant for one chain must not be sent (or replayed) on another
+fn check_chain_id(network: Network, chain_id: U256) -> Result<()> {
+    if !network
+        .chain_ids()
+        .iter()
+        .any(|id| U256::from(*id) == chain_id)
+    {
+        return Err(eyre::Report::msg(format!(
+            "The RPC endpoint is on chain {}, not on {} ({:?}), refusing to continue!",
+            chain_id,
+            network,
+            network.chain_ids()
+        )));
+    }
+    Ok(())
 }
 
 impl Default for Config {
@@ -209,13 +1055,107 @@ impl Default for Config {
             owshen_contract_address: H160::default(),
             owshen_contract_abi: Abi::default(),
             erc20_abi: Abi::default(),
+            verifier_contract_address: H160::default(),
+            network: None,
+        }
+    }
+}
+
+impl Config {
+    fn get(&self, key: ConfigKey) -> String {
+        match key {
+            ConfigKey::Name => self.name.clone(),
+            ConfigKey::Endpoint => self.endpoint.clone(),
+            ConfigKey::DiveContractAddress => to_checksum(&self.dive_contract_address, None),
+            ConfigKey::OwshenContractAddress => to_checksum(&self.owshen_contract_address, None),
+            ConfigKey::VerifierContractAddress => {
+                to_checksum(&self.verifier_contract_address, None)
+            }
+            ConfigKey::Network => self.network.map(|n| n.to_string()).unwrap_or_default(),
         }
     }
+
+    // Nothing is changed when the value is invalid
+    fn set(&mut self, key: ConfigKey, value: &str) -> Result<()> {
+        match key {
+            ConfigKey::Name => self.name = value.to_string(),
+            ConfigKey::Endpoint => {
+                Provider::<Http>::try_from(value).map_err(|e| {
+                    eyre::Report::msg(format!("Invalid RPC endpoint {}: {}", value, e))
+                })?;
+                self.endpoint = value.to_string();
+            }
+            ConfigKey::DiveContractAddress => self.dive_contract_address = parse_address(value)?,
+            ConfigKey::OwshenContractAddress => {
+                self.owshen_contract_address = parse_address(value)?
+            }
+            ConfigKey::VerifierContractAddress => {
+                self.verifier_contract_address = parse_address(value)?
+            }
+            ConfigKey::Network => self.network = Some(Network::from_str(value)?),
+        }
+        Ok(())
+    }
+
+    // `--rpc` wins over `--network`, which wins over the configured endpoint. A `--network` flag
+    // also replaces the configured network for the chain id checks
+    fn select_endpoint(&mut self, rpc: Option<String>, network: Option<Network>) -> String {
+        if network.is_some() {
+            self.network = network;
+        }
+        rpc.or(network.map(|n| n.endpoint().to_string()))
+            .unwrap_or(self.endpoint.clone())
+    }
+}
+
+// Mixed-case addresses must carry a valid EIP-55 checksum, all-lowercase (or uppercase) ones
+// have none
+fn parse_address(value: &str) -> Result<H160> {
+    let address = H160::from_str(value)
+        .map_err(|_| eyre::Report::msg(format!("Invalid address {}!", value)))?;
+    let hex = value.trim_start_matches("0x");
+    let mixed_case =
+        hex.chars().any(|c| c.is_ascii_lowercase()) && hex.chars().any(|c| c.is_ascii_uppercase());
+    if mixed_case && to_checksum(&address, None).trim_start_matches("0x") != hex {
+        return Err(eyre::Report::msg(format!(
+            "Invalid checksum of address {}!",
+            value
+        )));
+    }
+    Ok(address)
 }
 
 pub struct Context {
     coins: Vec<Coin>,
     tree: SparseMerkleTree,
+    sync: SyncState,
+}
+
+impl Context {
+    // The tree a coin of `denomination` is in: the kept `tree`, or the one of its size, built
+    // from the synced deposits
+    fn tree_for(&self, token: H160, denomination: U256) -> Result<SparseMerkleTree> {
+        if denomination.is_zero() {
+            return Ok(self.tree.clone());
+        }
+        self.sync.denomination_tree(token, denomination)
+    }
+}
+
+fn parse_origin(origin: &str) -> Result<HeaderValue> {
+    let origin = origin.trim_end_matches('/');
+    if !origin.starts_with("http://") && !origin.starts_with("https://") {
+        return Err(eyre::Report::msg(format!(
+            "Invalid origin {}, expected e.g. http://127.0.0.1:3000",
+            origin
+        )));
+    }
+    Ok(HeaderValue::from_str(origin)?)
+}
+
+fn parse_mnemonic(phrase: &str) -> Result<Mnemonic> {
+    Mnemonic::parse_normalized(phrase)
+        .map_err(|e| eyre::Report::msg(format!("Invalid BIP-39 mnemonic phrase: {}", e)))
 }
 
 const PARAMS_FILE: &str = "contracts/circuits/coin_withdraw_0001.zkey";
@@ -244,44 +1184,53 @@ fn extract_token_amount(
     let amount = Fp::try_from(hint_amount)? - shared_secret;
     let token_address = Fp::try_from(hint_token_address)? - shared_secret;
 
-    let calc_commitment1 = hash4([
+    let calc_commitment1 = hash::commitment(
         stealth_pub.point.x,
         stealth_pub.point.y,
         Fp::try_from(hint_amount)?,
         Fp::try_from(hint_token_address)?,
-    ]);
+    );
 
-    let calc_commitment2 = hash4([
+    let calc_commitment2 = hash::commitment(
         stealth_pub.point.x,
         stealth_pub.point.y,
         amount,
         token_address,
-    ]);
+    );
 
-    let calc_commitment3 = hash4([
+    let calc_commitment3 = hash::commitment(
         stealth_pub.point.x,
         stealth_pub.point.y,
         amount,
         Fp::try_from(hint_token_address)?,
-    ]);
+    );
 
-    let calc_commitment4 = hash4([
+    let calc_commitment4 = hash::commitment(
         stealth_pub.point.x,
         stealth_pub.point.y,
         Fp::try_from(hint_amount)?,
         token_address,
-    ]);
+    );
 
-    if calc_commitment1 == commitment {
+    // All four candidates are compared before branching, they are derived from the shared secret
+    let matches = [
+        calc_commitment1,
+        calc_commitment2,
+        calc_commitment3,
+        calc_commitment4,
+    ]
+    .map(|calc| bool::from(calc.ct_eq(&commitment)));
+
+    if matches[0] {
         let fp_hint_token_address = Fp::try_from(hint_token_address)?;
         let fp_hint_amount = Fp::try_from(hint_amount)?;
         return Ok(Some((fp_hint_token_address, fp_hint_amount)));
-    } else if calc_commitment2 == commitment {
+    } else if matches[1] {
         return Ok(Some((token_address, amount)));
-    } else if calc_commitment3 == commitment {
+    } else if matches[2] {
         let fp_hint_token_address = Fp::try_from(hint_token_address)?;
         return Ok(Some((fp_hint_token_address, amount)));
-    } else if calc_commitment4 == commitment {
+    } else if matches[3] {
         let fp_hint_amount = Fp::try_from(hint_amount)?;
         return Ok(Some((token_address, fp_hint_amount)));
     }
@@ -290,13 +1239,7 @@ fn extract_token_amount(
 }
 
 fn handle_error<T: IntoResponse>(result: Result<T, eyre::Report>) -> impl IntoResponse {
-    match result {
-        Ok(a) => a.into_response(),
-        Err(e) => {
-            let error_message = format!("Internal server error: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_message)).into_response()
-        }
-    }
+    result.map_err(ApiError::from)
 }
 
 async fn serve_index(test: bool) -> impl IntoResponse {
@@ -307,69 +1250,58 @@ async fn serve_index(test: bool) -> impl IntoResponse {
         format!("{}/usr/share/owshen/client/index.html", app_dir_path)
     };
 
-    println!("index path {}", index_path);
+    tracing::debug!("Serving {}", index_path);
     match read_to_string(index_path) {
         Ok(contents) => Html(contents),
         Err(_) => Html("<h1>Error: Unable to read the index file</h1>".to_string()),
     }
 }
 
-async fn serve_file(file_path: PathBuf) -> impl IntoResponse {
-    if let Ok(file) = File::open(file_path).await {
-        let stream = FramedRead::new(file, BytesCodec::new());
+// Settings of the server itself, given by the `wallet` flags
+struct ServeOpts {
+    api_port: u16,
+    front_port: u16,
+    allow_origin: Vec<HeaderValue>,
+    rate_limit: u32,
+    ws: Option<String>,
+}
 
-        Response::new(Body::wrap_stream(stream))
-    } else {
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from("File not found"))
-            .unwrap()
-    }
-}
+async fn serve_wallet(mut state: AppState, opts: ServeOpts) -> Result<()> {
+    let ServeOpts {
+        api_port,
+        front_port,
+        allow_origin,
+        rate_limit,
+        ws,
+    } = opts;
+    let test = state.test;
 
-async fn serve_wallet(
-    provider: Arc<Provider<Http>>,
-    _port: u16,
-    priv_key: PrivateKey,
-    pub_key: PublicKey,
-    owshen_contract: H160,
-    dive_contract: H160,
-    abi: Abi,
-    erc20_abi: Abi,
-    token_contracts: Vec<TokenInfo>,
-    test: bool,
-) -> Result<()> {
-    let tree: SparseMerkleTree = SparseMerkleTree::new(16);
-    let context = Arc::new(Mutex::new(Context {
-        coins: vec![],
-        tree,
-    }));
-
-    let info_addr: PublicKey = pub_key.clone();
-    let coins_owshen_abi = abi.clone();
-    let coins_owshen_address = owshen_contract.clone();
-    let context_coin = context.clone();
-    let context_tree = context.clone();
-    let context_tree_send = context.clone();
-    let context_withdraw = context.clone();
-    let context_send = context.clone();
-    let contract = Contract::new(coins_owshen_address, coins_owshen_abi, provider);
-    let contract_clone = contract.clone();
+    // Load the tree in the background, so that /health turns ready without waiting for /coins,
+    // then keep it in sync and notify the /events clients
+    tokio::spawn(events::watch(
+        ws,
+        state.context.clone(),
+        state.contract.clone(),
+        state.priv_key.clone(),
+        state.events.clone(),
+    ));
 
     let app_dir_path = std::env::var("APPDIR").unwrap_or_else(|_| "".to_string());
     let root_files_path = format!("{}/usr/share/owshen/client", app_dir_path);
     let static_files_path = format!("{}/usr/share/owshen/client/static", app_dir_path);
 
+    let mut origins = vec![
+        parse_origin(&format!("http://127.0.0.1:{}", front_port))?,
+        parse_origin(&format!("http://localhost:{}", front_port))?,
+    ];
+    origins.extend(allow_origin);
+    state.origins = Arc::new(origins.clone());
+
+    // The client assets are served by the wallet itself, no external static file server needed
     let app = Router::new()
         .route("/", get(move || serve_index(test)))
-        .route(
-            "/static/*file",
-            get(|params: extract::Path<String>| async move {
-                let file_path = PathBuf::from(static_files_path).join(params.as_str());
-                println!("file path {:?}", file_path);
-                serve_file(file_path).await
-            }),
-        )
+        .nest_service("/html", ServeDir::new(&root_files_path))
+        .nest_service("/static", ServeDir::new(static_files_path))
         .route(
             "/manifest.json",
             get_service(ServeFile::new(format!("{}/manifest.json", root_files_path))),
@@ -385,100 +1317,345 @@ async fn serve_wallet(
             "/robots.txt",
             get_service(ServeFile::new(format!("{}/robots.txt", root_files_path))),
         )
+        .merge(wallet_api(state, rate_limit));
+
+    // Only the method and path are recorded, queries carry addresses and amounts
+    let app = with_cors(app, origins).layer(TraceLayer::new_for_http().make_span_with(
+        |req: &Request<Body>| {
+            tracing::info_span!("request", method = %req.method(), path = %req.uri().path())
+        },
+    ));
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], api_port));
+    let server = axum::Server::try_bind(&addr)
+        .map_err(|e| eyre::Report::msg(format!("Cannot listen on API port {}: {}", api_port, e)))?;
+
+    if test {
+        std::net::TcpListener::bind(("127.0.0.1", front_port)).map_err(|e| {
+            eyre::Report::msg(format!(
+                "Cannot listen on frontend port {}: {}",
+                front_port, e
+            ))
+        })?;
+        let mut frontend = Command::new("npm")
+            .arg("start")
+            .env("PORT", front_port.to_string())
+            .env(
+                "REACT_APP_OWSHEN_ENDPOINT",
+                format!("http://127.0.0.1:{}", api_port),
+            )
+            .current_dir("client")
+            .spawn()
+            .map_err(|e| eyre::Report::msg(format!("Failed to start the frontend: {}", e)))?;
+
+        let backend = server
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(shutdown_signal())
+            .await;
+
+        // The frontend goes down with the backend, whether it stopped on Ctrl-C or failed
+        println!("Stopping the frontend...");
+        if let Err(e) = frontend.kill() {
+            eprintln!("Failed to stop the frontend: {}", e);
+        }
+        frontend.wait()?;
+
+        backend?;
+        Ok(())
+    } else {
+        let server = server
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(shutdown_signal());
+
+        // Attempt to open the web browser
+        if webbrowser::open(&format!("http://{}", addr)).is_err() {
+            println!(
+                "Failed to open web browser. Please navigate to http://{} manually",
+                addr
+            );
+        }
+
+        server.await.map_err(eyre::Report::new)?;
+        Ok(())
+    }
+}
+
+// Shared by every handler of the wallet API, cloning it only clones handles
+#[derive(Clone)]
+pub struct AppState {
+    context: Arc<RwLock<Context>>,
+    provider: Arc<Provider<Http>>,
+    contract: Contract<Provider<Http>>,
+    priv_key: PrivateKey,
+    pub_key: PublicKey,
+    owshen_contract: H160,
+    dive_contract: H160,
+    abi: Abi,
+    erc20_abi: Abi,
+    token_contracts: Arc<Vec<TokenInfo>>,
+    events: tokio::sync::broadcast::Sender<events::WalletEvent>,
+    origins: Arc<Vec<HeaderValue>>,
+    spent_path: Arc<PathBuf>,
+    history_path: Arc<PathBuf>,
+    gas: Arc<GasOpt>,
+    proof_dir: Arc<Option<PathBuf>>,
+    relayer: Arc<RelayerOpt>,
+    test: bool,
+}
+
+impl AppState {
+    // The contract handles and an empty context, which the background sync of `serve_wallet`
+    // fills in
+    fn new(
+        provider: Arc<Provider<Http>>,
+        config: &Config,
+        wallet: &Wallet,
+        spent_path: PathBuf,
+        history_path: PathBuf,
+        gas: GasOpt,
+        test: bool,
+    ) -> Self {
+        let priv_key = wallet.priv_key();
+        let owshen_contract = config.owshen_contract_address;
+        let abi = config.owshen_contract_abi.clone();
+        Self {
+            context: Arc::new(RwLock::new(Context {
+                coins: vec![],
+                tree: SparseMerkleTree::new(MERKLE_PROOF_LENGTH),
+                sync: SyncState::new(owshen_contract),
+            })),
+            provider: provider.clone(),
+            contract: Contract::new(owshen_contract, abi.clone(), provider),
+            pub_key: PublicKey::from(&priv_key),
+            priv_key,
+            owshen_contract,
+            dive_contract: config.dive_contract_address,
+            abi,
+            erc20_abi: config.erc20_abi.clone(),
+            token_contracts: Arc::new(wallet.token_contracts.clone()),
+            events: tokio::sync::broadcast::channel(16).0,
+            origins: Arc::new(vec![]),
+            spent_path: Arc::new(spent_path),
+            history_path: Arc::new(history_path),
+            gas: Arc::new(gas),
+            proof_dir: Arc::new(None),
+            relayer: Arc::new(RelayerOpt::default()),
+            test,
+        }
+    }
+}
+
+// The routes generating (or relaying) proofs are rate limited, any local webpage can call them
+fn wallet_api(state: AppState, rate_limit: u32) -> Router {
+    Router::new()
         .route(
             "/coins",
-            get(move || async move {
-                handle_error(apis::coins(context_coin, contract_clone, priv_key).await)
+            get(|State(state): State<AppState>| async move {
+                handle_error(apis::coins(state.context, state.contract, state.priv_key).await)
             }),
         )
-        .route(
-            "/withdraw",
-            get(
-                move |extract::Query(req): extract::Query<GetWithdrawRequest>| async move {
-                    handle_error(
-                        apis::withdraw(Query(req), context_withdraw, context_tree, priv_key).await,
-                    )
-                },
+        .merge(with_rate_limit(
+            Router::new().route(
+                "/withdraw",
+                get(
+                    |State(state): State<AppState>, Query(req): Query<GetWithdrawRequest>| async move {
+                        apis::withdraw(
+                            Query(req),
+                            state.context.clone(),
+                            state.context,
+                            state.priv_key,
+                            &state.spent_path,
+                            state.proof_dir.as_deref(),
+                            &state.relayer,
+                        )
+                        .await
+                    },
+                ),
             ),
-        )
-        .route(
-            "/send",
-            get(
-                move |extract::Query(req): extract::Query<GetSendRequest>| async move {
-                    handle_error(
-                        apis::send(Query(req), context_send, context_tree_send, priv_key).await,
-                    )
-                },
+            rate_limit,
+        ))
+        .merge(with_rate_limit(
+            Router::new().route(
+                "/send",
+                get(
+                    |State(state): State<AppState>, Query(req): Query<GetSendRequest>| async move {
+                        apis::send(
+                            Query(req),
+                            state.context.clone(),
+                            state.context,
+                            state.priv_key,
+                            &state.spent_path,
+                        )
+                        .await
+                    },
+                ),
             ),
+            rate_limit,
+        ))
+        .route(
+            "/health",
+            get(|State(state): State<AppState>| async move {
+                handle_error(apis::health(state.context, state.provider).await)
+            }),
         )
+        .merge(with_rate_limit(
+            Router::new().route(
+                "/broadcast",
+                post(
+                    |State(state): State<AppState>, Json(req): Json<PostBroadcastRequest>| async move {
+                        handle_error(
+                            apis::broadcast(
+                                req,
+                                state.context,
+                                state.provider,
+                                state.owshen_contract,
+                                &state.spent_path,
+                                &state.history_path,
+                                &state.gas,
+                            )
+                            .await,
+                        )
+                    },
+                ),
+            ),
+            rate_limit,
+        ))
         .route(
-            "/stealth",
+            "/events",
             get(
-                |extract::Query(req): extract::Query<GetStealthRequest>| async move {
-                    handle_error(apis::stealth(Query(req)).await)
+                |State(state): State<AppState>,
+                 ws: WebSocketUpgrade,
+                 headers: header::HeaderMap| async move {
+                    let origin = headers.get(header::ORIGIN).cloned();
+                    apis::events(ws, origin, &state.origins, state.events.subscribe()).await
                 },
             ),
         )
+        .route("/stealth", get(apis::stealth))
         .route(
             "/info",
-            get(move || async move {
-                handle_error(
-                    apis::info(
-                        info_addr,
-                        dive_contract,
-                        owshen_contract,
-                        token_contracts,
-                        abi,
-                        erc20_abi,
-                        test,
-                    )
-                    .await,
+            get(|State(state): State<AppState>| async move {
+                apis::info(
+                    state.pub_key,
+                    state.dive_contract,
+                    state.owshen_contract,
+                    state.token_contracts.to_vec(),
+                    state.abi,
+                    state.erc20_abi,
+                    state.test,
                 )
+                .await
             }),
         )
-        .layer(CorsLayer::permissive());
+        .with_state(state)
+}
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 9000));
+// Only the local frontend (and explicitly allowed origins) may call the API from a browser,
+// preflights of any other origin are refused
+fn with_cors(app: Router, origins: Vec<HeaderValue>) -> Router {
+    let cors = CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins.clone()))
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE]);
+    let origins = Arc::new(origins);
+    app.layer(cors).layer(middleware::from_fn(
+        move |req: Request<Body>, next: Next<Body>| {
+            let origins = origins.clone();
+            async move {
+                let disallowed = req
+                    .headers()
+                    .get(header::ORIGIN)
+                    .map(|origin| !origins.contains(origin))
+                    .unwrap_or(false);
+                if req.method() == Method::OPTIONS && disallowed {
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                next.run(req).await
+            }
+        },
+    ))
+}
 
-    if test {
-        let frontend = async {
-            task::spawn_blocking(move || {
-                let _output = Command::new("npm")
-                    .arg("start")
-                    .env(
-                        "REACT_APP_OWSHEN_ENDPOINT",
-                        format!("http://127.0.0.1:{}", 9000),
-                    )
-                    .current_dir("client")
-                    .spawn()
-                    .expect("failed to execute process");
-            });
-            Ok::<(), eyre::Error>(())
-        };
-        let backend = async {
-            axum::Server::bind(&addr)
-                .serve(app.into_make_service())
-                .await?;
-            Ok::<(), eyre::Error>(())
-        };
+// Fixed windows, shared by every client of a route
+#[derive(Debug)]
+struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    state: Mutex<(Instant, u32)>,
+}
 
-        tokio::try_join!(backend, frontend)?;
-        Ok(())
-    } else {
-        let server = axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .with_graceful_shutdown(shutdown_signal());
+impl RateLimiter {
+    fn new(limit: u32, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            state: Mutex::new((Instant::now(), 0)),
+        }
+    }
 
-        // Attempt to open the web browser
-        if webbrowser::open(&format!("http://{}", addr)).is_err() {
-            println!(
-                "Failed to open web browser. Please navigate to http://{} manually",
-                addr
-            );
+    // Counts the request if it still fits in the current window
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(state.0) >= self.window {
+            *state = (now, 0);
+        }
+        if state.1 >= self.limit {
+            return false;
         }
+        state.1 += 1;
+        true
+    }
+}
 
-        server.await.map_err(eyre::Report::new)?;
-        Ok(())
+// Requests beyond `per_minute` are answered with 429 until the minute is over, 0 disables the
+// limit. Every call gets its own limiter, so routes wrapped separately are limited separately
+fn with_rate_limit<S: Clone + std::marker::Send + Sync + 'static>(
+    router: Router<S>,
+    per_minute: u32,
+) -> Router<S> {
+    if per_minute == 0 {
+        return router;
+    }
+    let limiter = Arc::new(RateLimiter::new(per_minute, Duration::from_secs(60)));
+    router.route_layer(middleware::from_fn(
+        move |req: Request<Body>, next: Next<Body>| {
+            let limiter = limiter.clone();
+            async move {
+                if !limiter.allow() {
+                    return ApiError::TooManyRequests.into_response();
+                }
+                next.run(req).await
+            }
+        },
+    ))
+}
+
+// Refreshes every `interval` until `stop` resolves, which also cuts a refresh short (e.g. one
+// stuck on a slow node). A failed refresh (e.g. the RPC node being unreachable for a moment) is
+// reported, and retried on the next tick
+async fn watch_loop<F, Fut>(
+    interval: Duration,
+    stop: impl std::future::Future<Output = ()>,
+    mut refresh: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            _ = &mut stop => return,
+            result = refresh() => {
+                if let Err(err) = result {
+                    eprintln!("{} {}", "Refresh failed, retrying:".bright_red(), err);
+                }
+            }
+        }
+        tokio::select! {
+            _ = &mut stop => return,
+            _ = tokio::time::sleep(interval) => {}
+        }
     }
 }
 
@@ -488,6 +1665,16 @@ async fn shutdown_signal() {
         .expect("failed to listen for event");
 }
 
+impl From<Proof> for OwshenProof {
+    fn from(proof: Proof) -> Self {
+        OwshenProof {
+            a: proof.a,
+            b: proof.b,
+            c: proof.c,
+        }
+    }
+}
+
 impl Into<OwshenPoint> for Point {
     fn into(self) -> OwshenPoint {
         OwshenPoint {
@@ -497,23 +1684,677 @@ impl Into<OwshenPoint> for Point {
     }
 }
 
-async fn initialize_config(endpoint: String, name: String, is_test: bool) -> Config {
-    if is_test {
-        let provider = Provider::<Http>::try_from(endpoint.clone()).unwrap();
-        let provider = Arc::new(provider);
-        println!("Deploying hash function...");
-        let poseidon4_addr = deploy(
-            provider.clone(),
-            include_str!("assets/poseidon4.abi"),
-            include_str!("assets/poseidon4.evm"),
-        )
+#[tracing::instrument(skip_all)]
+async fn connect(endpoint: &str) -> Result<Arc<Provider<Http>>> {
+    let provider = Provider::<Http>::try_from(endpoint)
+        .map_err(|e| eyre::Report::msg(format!("Invalid RPC endpoint {}: {}", endpoint, e)))?;
+    rpc::retry("Connecting to the RPC endpoint", || provider.get_chainid())
+        .await
+        .map_err(|e| {
+            eyre::Report::msg(format!(
+                "Cannot connect to RPC endpoint {}: {}",
+                endpoint, e
+            ))
+        })?;
+    Ok(Arc::new(provider))
+}
+
+// `connect`, also refusing an endpoint on another chain than `network` (when known). Used before
+// sending transactions
+async fn connect_checked(endpoint: &str, network: Option<Network>) -> Result<Arc<Provider<Http>>> {
+    let provider = connect(endpoint).await?;
+    if let Some(network) = network {
+        let chain_id = rpc::retry("Getting the chain id", || provider.get_chainid()).await?;
+        check_chain_id(network, chain_id)?;
+    }
+    Ok(provider)
+}
+
+// The first unlocked account of the node pays for the transactions. Hosted endpoints (Infura,
+// Alchemy...) hold no keys and return none, which is reported instead of indexing into them
+async fn rpc_account<M: Middleware>(provider: &M) -> Result<H160> {
+    rpc::retry("Getting the RPC accounts", || provider.get_accounts())
+        .await?
+        .first()
+        .copied()
+        .ok_or(eyre::Report::msg(
+            "The RPC node has no unlocked accounts to sign transactions with! Hosted endpoints \
+            don't hold keys, use a node with an unlocked account (e.g. Ganache, Anvil or geth \
+            with --unlock) through --rpc",
+        ))
+}
+
+// Ganache, and Anvil/Hardhat
+const DEV_CHAIN_IDS: [u64; 2] = [1337, 31337];
+
+// A faucet must never move the node's funds on a real network
+fn require_dev_chain(chain_id: U256) -> Result<()> {
+    if !DEV_CHAIN_IDS.iter().any(|id| U256::from(*id) == chain_id) {
+        return Err(eyre::Report::msg(format!(
+            "Refusing to fund on chain {}, only local dev chains ({:?}) are supported!",
+            chain_id, DEV_CHAIN_IDS
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct FundResult {
+    tx_hash: H256,
+    from: H160,
+    to: H160,
+    amount: U256,
+}
+
+#[tracing::instrument(skip_all)]
+async fn fund<M: Middleware + 'static>(provider: &M, to: H160, amount: U256) -> Result<FundResult> {
+    let chain_id = rpc::retry("Getting the chain id", || provider.get_chainid()).await?;
+    require_dev_chain(chain_id)?;
+    let from = rpc_account(provider).await?;
+    let tx = TransactionRequest::new().from(from).to(to).value(amount);
+    let pending_tx = provider.send_transaction(tx, None).await?;
+    let tx_hash = pending_tx.tx_hash();
+    eprintln!("Waiting for {:?} to be mined...", tx_hash);
+    let receipt = pending_tx.await?.ok_or(eyre::Report::msg(format!(
+        "Transaction {:?} was dropped!",
+        tx_hash
+    )))?;
+    if receipt.status != Some(U64::one()) {
+        return Err(eyre::Report::msg(format!(
+            "Transaction {:?} reverted!",
+            tx_hash
+        )));
+    }
+    Ok(FundResult {
+        tx_hash,
+        from,
+        to,
+        amount,
+    })
+}
+
+// Explicit fees are kept, the node estimates the missing ones. Legacy pricing is used when asked
+// for or when the chain has no base fee (no EIP-1559)
+async fn resolve_fees<M: Middleware>(client: &M, gas: &GasOpt) -> Result<GasOpt> {
+    if let (Some(max_fee), Some(priority_fee)) = (gas.max_fee, gas.priority_fee) {
+        if priority_fee > max_fee {
+            return Err(eyre::Report::msg(
+                "The priority fee cannot exceed the max fee!",
+            ));
+        }
+    }
+    let legacy = gas.legacy
+        || gas.gas_price.is_some()
+        || client
+            .get_block(BlockNumber::Latest)
+            .await
+            .map_err(|e| eyre::Report::msg(format!("Cannot get the latest block: {}", e)))?
+            .and_then(|block| block.base_fee_per_gas)
+            .is_none();
+    Ok(GasOpt {
+        legacy,
+        ..gas.clone()
+    })
+}
+
+fn apply_fees(tx: &mut TypedTransaction, fees: &GasOpt) {
+    if fees.legacy {
+        if let TypedTransaction::Eip1559(inner) = tx {
+            *tx = TypedTransaction::Legacy(inner.clone().into());
+        }
+    }
+    match tx {
+        TypedTransaction::Eip1559(inner) => {
+            if fees.max_fee.is_some() {
+                inner.max_fee_per_gas = fees.max_fee;
+            }
+            if fees.priority_fee.is_some() {
+                inner.max_priority_fee_per_gas = fees.priority_fee;
+            }
+        }
+        _ => {
+            if let Some(gas_price) = fees.gas_price {
+                tx.set_gas_price(gas_price);
+            }
+        }
+    }
+}
+
+// Calls to an address without the expected Owshen contract (nothing deployed, an account, another
+// contract or version) would otherwise fail with cryptic ABI decoding errors. `root()` stands in
+// for a version getter, which the contract doesn't have
+async fn require_owshen_code<M: Middleware + 'static>(contract: &Contract<M>) -> Result<()> {
+    let address = contract.address();
+    let incompatible = |why: String| {
+        eyre::Report::msg(format!(
+            "Owshen contract not found or incompatible at {:?}: {}",
+            address, why
+        ))
+    };
+    let client = contract.client();
+    let code = rpc::retry("Getting the Owshen contract code", || {
+        client.get_code(address, None)
+    })
+    .await?;
+    if code.is_empty() {
+        return Err(incompatible("no code, is this an account?".into()));
+    }
+    contract
+        .method::<_, U256>("root", ())?
+        .call()
+        .await
+        .map_err(|e| incompatible(format!("root() failed: {}", e)))?;
+    Ok(())
+}
+
+// The Owshen constructor creates its tree first and then its `CoinWithdrawVerifier`, contract
+// nonces start at 1
+fn verifier_address(owshen: H160) -> H160 {
+    ethers::utils::get_contract_address(owshen, 2)
+}
+
+async fn deploy_owshen(
+    provider: Arc<Provider<Http>>,
+    from: H160,
+    fees: &GasOpt,
+) -> Result<Owshen<Provider<Http>>> {
+    let balance = rpc::retry("Getting the deployer balance", || {
+        provider.get_balance(from, None)
+    })
+    .await?;
+    if balance.is_zero() {
+        return Err(eyre::Report::msg(format!(
+            "Account {:?} has no balance to pay for the deployment!",
+            from
+        )));
+    }
+
+    eprintln!("Deploying hash function...");
+    let poseidon4_addr = deploy(
+        provider.clone(),
+        include_str!("assets/poseidon4.abi"),
+        include_str!("assets/poseidon4.evm"),
+        fees,
+    )
+    .await?
+    .address();
+
+    // `send` waits for the deployment receipts
+    eprintln!("Deploying Owshen contract and its verifier...");
+    let mut deployer = Owshen::deploy(provider.clone(), poseidon4_addr)?.from(from);
+    apply_fees(&mut deployer.deployer.tx, fees);
+    let owshen = deployer
+        .send()
         .await
-        .address();
+        .map_err(|e| eyre::Report::msg(format!("Failed to deploy Owshen: {}", e)))?;
+    let verifier_code = rpc::retry("Getting the verifier code", || {
+        provider.get_code(verifier_address(owshen.address()), None)
+    })
+    .await?;
+    if verifier_code.is_empty() {
+        return Err(eyre::Report::msg("The withdraw verifier was not deployed!"));
+    }
+    Ok(owshen)
+}
+
+fn deposit_commitment(pub_key: PublicKey, amount: U256, token: H160) -> Result<Fp> {
+    Ok(hash::commitment(
+        pub_key.point.x,
+        pub_key.point.y,
+        Fp::try_from(amount)?,
+        Fp::try_from(h160_to_u256(token))?,
+    ))
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct DepositResult {
+    tx_hash: H256,
+    block_number: Option<u64>,
+    index: U256,
+    commitment: U256,
+    ephemeral: Point,
+    payment_request: String,
+}
+
+// A transaction printed by `--dry-run` instead of being sent. `gas` is unknown when the estimate
+// depends on an earlier transaction of the same command (a deposit waiting for its approval)
+#[derive(Clone, Debug, Serialize)]
+struct DryRunTx {
+    to: Option<H160>,
+    value: U256,
+    data: Bytes,
+    gas: Option<U256>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct DryRun {
+    commitment: U256,
+    transactions: Vec<DryRunTx>,
+}
+
+enum Outcome<T> {
+    Sent(T),
+    DryRun(DryRun),
+}
+
+async fn dry_run_tx<M: Middleware + 'static, D: ethers::abi::Detokenize>(
+    call: &ContractCall<M, D>,
+    estimate: bool,
+) -> Result<DryRunTx> {
+    let gas = match estimate {
+        true => Some(call.estimate_gas().await.map_err(revert_reason)?),
+        false => None,
+    };
+    Ok(DryRunTx {
+        to: call.tx.to_addr().copied(),
+        value: call.tx.value().copied().unwrap_or_default(),
+        data: call.tx.data().cloned().unwrap_or_default(),
+        gas,
+    })
+}
+
+fn print_dry_run(json: bool, dry_run: &DryRun) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(dry_run)?);
+        return Ok(());
+    }
+    println!("{}", "Dry run, nothing was sent".bright_yellow());
+    println!("Commitment: {}", dry_run.commitment);
+    for tx in dry_run.transactions.iter() {
+        let gas = tx
+            .gas
+            .map(|gas| gas.to_string())
+            .unwrap_or("unknown until the previous transaction is mined".into());
+        println!("To: {:?}", tx.to.unwrap_or_default());
+        println!("Value: {}", tx.value);
+        println!("Gas: {}", gas);
+        println!("Calldata: {}", tx.data);
+    }
+    Ok(())
+}
+
+// What to do about the `require` failures of the Owshen contracts
+fn revert_hint(reason: &str) -> Option<&'static str> {
+    match reason {
+        "Nullifier has been spent" => {
+            Some("this coin was already spent, run `owshen sync` to refresh your coins")
+        }
+        "Invalid proof" => {
+            Some("the proof does not match the on-chain tree, run `owshen sync` and retry")
+        }
+        "Index out of bounds" => Some("the Owshen tree is full"),
+        "ETH value is supposed to be 0 for ERC20 instance" => {
+            Some("no ETH should be sent along with a token deposit")
+        }
+        _ => None,
+    }
+}
+
+// Solidity `require` messages are returned as `Error(string)`
+fn revert_reason<M: Middleware + 'static>(err: ContractError<M>) -> eyre::Report {
+    match err.decode_revert::<String>() {
+        Some(reason) => match revert_hint(&reason) {
+            Some(hint) => eyre::Report::msg(format!("Transaction reverted: {} ({})", reason, hint)),
+            None => eyre::Report::msg(format!("Transaction reverted: {}", reason)),
+        },
+        None => err.into(),
+    }
+}
+
+// A nonzero `denomination` puts the coin in the contract's tree of that size, `amount` must then
+// be the same
+#[tracing::instrument(skip_all, fields(%amount, ?token))]
+#[allow(clippy::too_many_arguments)]
+async fn send_deposit(
+    provider: Arc<Provider<Http>>,
+    config: &Config,
+    to: PublicKey,
+    amount: U256,
+    token: H160,
+    denomination: U256,
+    fees: &GasOpt,
+    dry_run: bool,
+) -> Result<Outcome<DepositResult>> {
+    let from = rpc_account(&provider).await?;
+    let owshen = Owshen::new(config.owshen_contract_address, provider.clone());
+    require_owshen_code(&owshen).await?;
+    let erc20 = SimpleErc20::new(token, provider);
+    let symbol =
+        erc20.symbol().call().await.map_err(|_| {
+            eyre::Report::msg(format!("{:?} is not an ERC-20 token contract!", token))
+        })?;
+
+    let balance = rpc::retry("Getting the token balance", || async {
+        erc20.balance_of(from).call().await.map_err(rpc::CallError)
+    })
+    .await?;
+    if balance < amount {
+        return Err(eyre::Report::msg(format!(
+            "Insufficient {} balance: have {}, need {}!",
+            symbol, balance, amount
+        )));
+    }
+
+    let (ephemeral, stealth_pub) = to.derive(&mut rand::thread_rng());
+    let commitment = deposit_commitment(stealth_pub, amount, token)?;
+
+    // The pool pulls the tokens with transferFrom, so it needs an allowance first
+    let allowance = rpc::retry("Getting the token allowance", || async {
+        erc20
+            .allowance(from, owshen.address())
+            .call()
+            .await
+            .map_err(rpc::CallError)
+    })
+    .await?;
+    let mut approve = erc20.approve(owshen.address(), amount).from(from);
+    apply_fees(&mut approve.tx, fees);
+    let needs_approval = allowance < amount;
+    let mut deposit = owshen
+        .deposit(
+            stealth_pub.point.into(),
+            ephemeral.point.into(),
+            token,
+            amount,
+            from,
+            owshen.address(),
+            denomination,
+        )
+        .from(from);
+    apply_fees(&mut deposit.tx, fees);
+
+    if dry_run {
+        let mut transactions = vec![];
+        if needs_approval {
+            transactions.push(dry_run_tx(&approve, true).await?);
+        }
+        transactions.push(dry_run_tx(&deposit, !needs_approval).await?);
+        return Ok(Outcome::DryRun(DryRun {
+            commitment: commitment.into(),
+            transactions,
+        }));
+    }
+
+    if needs_approval {
+        eprintln!("Approving {} {}...", amount, symbol);
+        approve.send().await.map_err(revert_reason)?.await?;
+    }
+    eprintln!("Depositing {} {}...", amount, symbol);
+    let receipt = send_and_confirm(deposit).await?;
+    let tx_hash = receipt.transaction_hash;
+    let receipt_block = receipt.block_number.map(|b| b.as_u64());
+
+    let sent = receipt
+        .logs
+        .into_iter()
+        .filter(|log| log.address == owshen.address())
+        .find_map(|log| ethers::contract::parse_log::<SentFilter>(log).ok())
+        .ok_or(eyre::Report::msg(format!(
+            "Transaction {:?} did not emit a Sent event!",
+            tx_hash
+        )))?;
+    let commitment: U256 = commitment.into();
+    if sent.commitment != commitment {
+        return Err(eyre::Report::msg(format!(
+            "The contract stored commitment {}, expected {}!",
+            sent.commitment, commitment
+        )));
+    }
+
+    let payment_request = PaymentRequest {
+        stealth_address: stealth_pub.point,
+        ephemeral: ephemeral.point,
+        amount,
+        token,
+    };
+    Ok(Outcome::Sent(DepositResult {
+        tx_hash,
+        block_number: receipt_block,
+        index: sent.index,
+        commitment,
+        ephemeral: ephemeral.point,
+        payment_request: payment_request.to_string(),
+    }))
+}
+
+// Sends the transaction and waits for it to be mined, failures are reported with their revert
+// reason
+#[tracing::instrument(skip_all)]
+async fn send_and_confirm<M: Middleware + 'static, D: ethers::abi::Detokenize>(
+    call: ContractCall<M, D>,
+) -> Result<TransactionReceipt> {
+    let pending_tx = call.send().await.map_err(revert_reason)?;
+    let tx_hash = pending_tx.tx_hash();
+    eprintln!("Waiting for {:?} to be mined...", tx_hash);
+    tracing::info!(?tx_hash, "Transaction sent");
+    let receipt = pending_tx.await?.ok_or(eyre::Report::msg(format!(
+        "Transaction {:?} was dropped!",
+        tx_hash
+    )))?;
+
+    if receipt.status != Some(U64::one()) {
+        // Replay the transaction on its block to find out why it failed
+        let replay = match receipt.block_number {
+            Some(block) => call.block(block).call().await.err(),
+            None => None,
+        };
+        return Err(match replay {
+            Some(err) => revert_reason(err),
+            None => eyre::Report::msg(format!("Transaction {:?} reverted!", tx_hash)),
+        });
+    }
+    Ok(receipt)
+}
+
+// Written by `send --output`: everything needed to relay the spend from another machine
+#[derive(Serialize)]
+struct RelayFile {
+    #[serde(flatten)]
+    full_proof: proof::FullProof,
+    contract: H160,
+    calldata: Bytes,
+}
+
+// Fails before proving if `path` cannot be written. The file is written next to it first and
+// renamed over it once complete
+fn open_output(path: &Path) -> Result<tempfile::NamedTempFile> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if path.is_dir() {
+        return Err(eyre::Report::msg(format!(
+            "{} is a directory!",
+            path.display()
+        )));
+    }
+    tempfile::NamedTempFile::new_in(dir)
+        .map_err(|e| eyre::Report::msg(format!("Cannot write {}: {}", path.display(), e)))
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct SendResult {
+    tx_hash: H256,
+    block_number: Option<u64>,
+    spent_index: U256,
+    nullifier: U256,
+    commitment: U256,
+    change_commitment: Option<U256>,
+}
+
+// Spends the smallest coin that covers `amount` into a new coin for `to`, plus a change coin
+// for ourselves. In the coin-withdraw circuit the receiver output is (pk_ax1, pk_ay1,
+// new_amount1) and the change is (pk_ax2, pk_ay2, new_amount2), which the contract inserts as
+// `_commitment2` and `_commitment1` respectively
+#[tracing::instrument(skip_all, fields(%amount, ?token))]
+#[allow(clippy::too_many_arguments)]
+async fn send_coin(
+    config: &Config,
+    endpoint: &str,
+    sync_path: &Path,
+    spent_path: &Path,
+    priv_key: &PrivateKey,
+    to: PublicKey,
+    amount: U256,
+    token: H160,
+    gas: &GasOpt,
+    dry_run: bool,
+    output: Option<&Path>,
+) -> Result<Outcome<SendResult>> {
+    let output_file = output.map(open_output).transpose()?;
+    let state = sync_state(config, endpoint, sync_path, None, None, 0).await?;
+    let coin = state
+        .coins(priv_key)
+        .into_iter()
+        .filter(|coin| coin.uint_token == token && coin.amount >= amount)
+        .min_by_key(|coin| coin.amount)
+        .ok_or(eyre::Report::msg(format!(
+            "None of your {:?} coins holds {} or more!",
+            token, amount
+        )))?;
+    spent::check_unspent(spent_path, coin.index, coin.nullifier)?;
+
+    let provider = connect_checked(endpoint, config.network).await?;
+    let from = rpc_account(&provider).await?;
+    let fees = resolve_fees(provider.as_ref(), gas).await?;
+    let owshen = Owshen::new(config.owshen_contract_address, provider);
+    if owshen.is_spent(coin.nullifier).call().await? {
+        return Err(eyre::Report::msg(format!(
+            "Coin #{} was already spent, run `owshen sync` to refresh your coins!",
+            coin.index
+        )));
+    }
+    // A deposit of a standard size is only in the tree of its size
+    let tree = state.denomination_tree(token, coin.denomination)?;
+    let local_root: U256 = tree.root().into();
+    if owshen
+        .denomination_root(token, coin.denomination)
+        .call()
+        .await?
+        != local_root
+    {
+        return Err(eyre::Report::msg(
+            "The local Merkle tree does not match the contract, run `owshen sync` and retry!",
+        ));
+    }
+    let (leaf, merkle_proof) = tree.get_with_proof::<MERKLE_PROOF_LENGTH>(coin.index.low_u64())?;
+
+    let remaining = coin.amount - amount;
+    let token_u256 = h160_to_u256(token);
+    let (receiver_ephemeral, receiver_stealth, receiver_secret) =
+        to.derive_with_hint_secret(&mut rand::thread_rng());
+    let (change_ephemeral, change_stealth, change_secret) =
+        PublicKey::from(priv_key).derive_with_hint_secret(&mut rand::thread_rng());
+    let commitment = deposit_commitment(receiver_stealth, amount, token)?;
+    let change_commitment = deposit_commitment(change_stealth, remaining, token)?;
+
+    eprintln!("Generating the proof...");
+    let witness = proof::WithdrawWitness {
+        chain_id: state.chain_id,
+        contract: state.owshen_contract,
+        index: coin.index.low_u32(),
+        token_address: token_u256,
+        amount: coin.amount,
+        new_amount1: amount,
+        new_amount2: remaining,
+        address_1: receiver_stealth,
+        address_2: change_stealth,
+        secret: coin.priv_key.secret,
+        proof: merkle_proof.proof.to_vec(),
+        commitment: leaf,
+        to: H160::zero(),
+        relayer: H160::zero(),
+        fee: U256::zero(),
+    };
+    let start = Instant::now();
+    let proof = proof::prove_from_zkey_with_progress(PARAMS_FILE, &witness, move |stage| {
+        eprintln!("  {} ({:.1?})", stage, start.elapsed())
+    })?;
+    let public_inputs = proof::PublicInputs {
+        root: tree.root(),
+        nullifier: Fp::try_from(coin.nullifier)?,
+        commitment1: commitment,
+        commitment2: change_commitment,
+        chain_id: state.chain_id,
+        contract: state.owshen_contract,
+        to: H160::zero(),
+        relayer: H160::zero(),
+        fee: U256::zero(),
+    }
+    .to_vec()?;
+    let full_proof = proof.to_full_proof(&public_inputs);
+    if dry_run || output.is_some() {
+        if !proof::verify(&proof, &public_inputs)? {
+            return Err(eyre::Report::msg(
+                "The generated proof does not verify, check the witness!",
+            ));
+        }
+        eprintln!("The proof verifies locally");
+    }
+
+    let has_change = !remaining.is_zero();
+    let mut send = owshen
+        .send(
+            coin.nullifier,
+            proof.into(),
+            receiver_ephemeral.point.into(),
+            change_ephemeral.point.into(),
+            change_commitment.into(),
+            commitment.into(),
+            token_u256,
+            (Fp::try_from(amount)? + receiver_secret).into(),
+            (Fp::try_from(remaining)? + change_secret).into(),
+            has_change,
+            token,
+            coin.denomination,
+        )
+        .from(from);
+    apply_fees(&mut send.tx, &fees);
+    if let (Some(mut file), Some(path)) = (output_file, output) {
+        let relay = RelayFile {
+            full_proof,
+            contract: config.owshen_contract_address,
+            calldata: send.calldata().unwrap_or_default(),
+        };
+        file.write_all(serde_json::to_string_pretty(&relay)?.as_bytes())?;
+        file.persist(path)?;
+        eprintln!("Proof written to {}", path.display());
+    }
+    if dry_run || output.is_some() {
+        return Ok(Outcome::DryRun(DryRun {
+            commitment: commitment.into(),
+            transactions: vec![dry_run_tx(&send, true).await?],
+        }));
+    }
+    eprintln!("Sending {} of coin #{}...", amount, coin.index);
+    let receipt = send_and_confirm(send).await?;
+    spent::record(spent_path, coin.nullifier)?;
+
+    Ok(Outcome::Sent(SendResult {
+        tx_hash: receipt.transaction_hash,
+        block_number: receipt.block_number.map(|b| b.as_u64()),
+        spent_index: coin.index,
+        nullifier: coin.nullifier,
+        commitment: commitment.into(),
+        change_commitment: has_change.then(|| change_commitment.into()),
+    }))
+}
+
+fn require_owshen(config: &Config) -> Result<()> {
+    if config.owshen_contract_address == H160::zero() {
+        return Err(eyre::Report::msg(
+            "Owshen contract is not configured, run `owshen deploy` first!",
+        ));
+    }
+    Ok(())
+}
 
-        let accounts = provider.get_accounts().await.unwrap();
-        let from = accounts[0];
+async fn initialize_config(endpoint: String, name: String, is_test: bool) -> Result<Config> {
+    if is_test {
+        let provider = connect(&endpoint).await?;
+        let from = rpc_account(&provider).await?;
 
-        println!("Deploying DIVE token...");
+        eprintln!("Deploying DIVE token...");
         let dive = SimpleErc20::deploy(
             provider.clone(),
             (
@@ -528,7 +2369,7 @@ async fn initialize_config(endpoint: String, name: String, is_test: bool) -> Con
         .send()
         .await
         .unwrap();
-        println!("Deploying test tokens...");
+        eprintln!("Deploying test tokens...");
         let test_token = SimpleErc20::deploy(
             provider.clone(),
             (
@@ -559,14 +2400,11 @@ async fn initialize_config(endpoint: String, name: String, is_test: bool) -> Con
         .await
         .unwrap();
 
-        println!("Deploying Owshen contract...");
-        let owshen = Owshen::deploy(provider.clone(), poseidon4_addr)
-            .unwrap()
-            .legacy()
-            .from(from)
-            .send()
-            .await
-            .unwrap();
+        let ganache = GasOpt {
+            legacy: true,
+            ..Default::default()
+        };
+        let owshen = deploy_owshen(provider.clone(), from, &ganache).await?;
         let mut token_contracts: Vec<TokenInfo> = Vec::new();
 
         token_contracts.push(TokenInfo {
@@ -578,27 +2416,32 @@ async fn initialize_config(endpoint: String, name: String, is_test: bool) -> Con
             symbol: "USDC".to_string(),
         });
 
-        return Config {
+        return Ok(Config {
             name,
             endpoint,
             owshen_contract_address: owshen.address(),
             owshen_contract_abi: owshen.abi().clone(),
             dive_contract_address: dive.address(),
             erc20_abi: dive.abi().clone(),
-        };
+            verifier_contract_address: verifier_address(owshen.address()),
+            network: None,
+        });
     } else {
-        return Config::default();
+        return Ok(Config::default());
     }
 }
 
-async fn initialize_wallet(endpoint: String, mnemonic: Option<Mnemonic>, is_test: bool) -> Wallet {
+async fn initialize_wallet(
+    endpoint: String,
+    key: Option<WalletKey>,
+    is_test: bool,
+) -> Result<Wallet> {
     let mut token_contracts: Vec<TokenInfo> = Vec::new();
-    let provider = Provider::<Http>::try_from(endpoint.clone()).unwrap();
-    let provider = Arc::new(provider);
-    let accounts = provider.get_accounts().await.unwrap();
 
+    // Only the test tokens need the node, a wallet is created offline
     if is_test {
-        let from = accounts[0];
+        let provider = connect(&endpoint).await?;
+        let from = rpc_account(&provider).await?;
         let test_token = SimpleErc20::deploy(
             provider.clone(),
             (
@@ -639,192 +2482,3039 @@ async fn initialize_wallet(endpoint: String, mnemonic: Option<Mnemonic>, is_test
         });
     }
 
-    let entropy = if let Some(m) = mnemonic {
-        Entropy::from_mnemonic(m)
-    } else {
-        Entropy::generate(&mut rand::thread_rng())
+    Ok(Wallet {
+        key: key.unwrap_or_else(|| WalletKey::Entropy(Entropy::generate(&mut rand::thread_rng()))),
+        token_contracts,
+    })
+}
+
+// The mnemonic is only shown when it was generated, instead of given by the user
+fn report_new_wallet(
+    json: bool,
+    wallet_path: &Path,
+    wallet: &Wallet,
+    generated: bool,
+) -> Result<()> {
+    let phrase = match &wallet.key {
+        WalletKey::Entropy(entropy) if generated => Some(entropy.to_mnemonic()?),
+        _ => None,
     };
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "wallet": wallet_path, "mnemonic": phrase })
+        );
+    } else if let Some(phrase) = phrase {
+        println!(
+            "{} {}",
+            "Your 24-word mnemonic phrase is:".bright_green(),
+            phrase
+        );
+        println!(
+            "{}",
+            "PLEASE KEEP YOUR MNEMONIC PHRASE IN A SAFE PLACE OR YOU WILL LOSE YOUR FUNDS!"
+                .bold()
+                .bright_red()
+        );
+    }
+    Ok(())
+}
 
-    let wallet = Wallet {
-        entropy,
-        token_contracts,
+fn print_message(json: bool, message: &str) {
+    if json {
+        println!("{}", serde_json::json!({ "message": message }));
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn read_passphrase(password_file: &Option<PathBuf>, confirm: bool) -> Result<String> {
+    if let Some(path) = password_file {
+        let passphrase = std::fs::read_to_string(path)?;
+        return Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
+    let passphrase = rpassword::prompt_password("Wallet passphrase: ")?;
+    if confirm && passphrase != rpassword::prompt_password("Repeat passphrase: ")? {
+        return Err(eyre::Report::msg("Passphrases do not match!"));
+    }
+    Ok(passphrase)
+}
+
+fn write_wallet(wallet_path: &Path, wallet: &Wallet, passphrase: &str) -> Result<()> {
+    if let Some(dir) = wallet_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let vault = Vault::encrypt(serde_json::to_string(wallet)?.as_bytes(), passphrase)?;
+    std::fs::write(wallet_path, serde_json::to_string(&vault)?)?;
+    Ok(())
+}
+
+fn read_wallet(wallet_path: &Path, password_file: &Option<PathBuf>) -> Result<Option<Wallet>> {
+    let contents = match std::fs::read_to_string(wallet_path) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(None),
     };
 
-    println!(
-        "{} {}",
-        "Your 12-word mnemonic phrase is:".bright_green(),
-        wallet.entropy.to_mnemonic().unwrap()
-    );
-    println!(
+    if let Ok(vault) = serde_json::from_str::<Vault>(&contents) {
+        let passphrase = read_passphrase(password_file, false)?;
+        let wallet: Wallet = serde_json::from_slice(&vault.decrypt(&passphrase)?)
+            .map_err(|e| corrupt_wallet(wallet_path, e))?;
+        return Ok(Some(wallet));
+    }
+
+    // Wallets created by older versions are stored as plaintext JSON
+    let wallet: Wallet =
+        serde_json::from_str(&contents).map_err(|e| corrupt_wallet(wallet_path, e))?;
+    eprintln!(
         "{}",
-        "PLEASE KEEP YOUR MNEMONIC PHRASE IN A SAFE PLACE OR YOU WILL LOSE YOUR FUNDS!"
-            .bold()
-            .bright_red()
+        "Your wallet file is not encrypted! Encrypt it now? [y/N]".bright_red()
     );
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if answer.trim().eq_ignore_ascii_case("y") {
+        let passphrase = read_passphrase(password_file, true)?;
+        write_wallet(wallet_path, &wallet, &passphrase)?;
+        eprintln!("Wallet encrypted!");
+    }
+    Ok(Some(wallet))
+}
 
-    wallet
+// Anyone who sees the key can spend every coin of the wallet, so it is only printed with `--yes`
+fn export_key(wallet: &Wallet, format: &str, yes: bool) -> Result<String> {
+    if !yes {
+        return Err(eyre::Report::msg(
+            "This prints your private key, anyone who sees it can spend all of your coins! Pass \
+             --yes if you really want to export it",
+        ));
+    }
+    match format {
+        "hex" => Ok(wallet.priv_key().to_hex()),
+        _ => Err(eyre::Report::msg(format!(
+            "Unsupported key format {}!",
+            format
+        ))),
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let wallet_path = home::home_dir().unwrap().join(".owshen-wallet.json");
-    let config_path = home::home_dir().unwrap().join(".config-wallet.json");
+fn corrupt_wallet(wallet_path: &Path, err: serde_json::Error) -> eyre::Report {
+    eyre::Report::msg(format!(
+        "Wallet file {} is corrupt ({}), restore it from a backup or run `owshen init --force` to \
+         replace it!",
+        wallet_path.display(),
+        err
+    ))
+}
+
+// Whether `init` should write a new wallet: always with `force`, otherwise only when there is none
+// yet. A corrupt wallet is reported, without asking for the passphrase of a valid one
+fn init_wallet_needed(wallet_path: &Path, force: bool) -> Result<bool> {
+    if force || !wallet_path.exists() {
+        return Ok(true);
+    }
+    let contents = std::fs::read_to_string(wallet_path)?;
+    if serde_json::from_str::<Vault>(&contents).is_err() {
+        serde_json::from_str::<Wallet>(&contents).map_err(|e| corrupt_wallet(wallet_path, e))?;
+    }
+    Ok(false)
+}
+
+const BACKUP_VERSION: u32 = 1;
+
+// The wallet stays encrypted with its passphrase, the contracts are public
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Backup {
+    version: u32,
+    wallet: Vault,
+    endpoint: String,
+    dive_contract_address: H160,
+    owshen_contract_address: H160,
+    #[serde(default)]
+    verifier_contract_address: H160,
+}
+
+fn export_backup(wallet_path: &Path, config: &Config) -> Result<Backup> {
+    let contents = std::fs::read_to_string(wallet_path)
+        .map_err(|_| eyre::Report::msg("Wallet is not initialized!"))?;
+    let wallet = serde_json::from_str::<Vault>(&contents).map_err(|_| {
+        eyre::Report::msg(
+            "Only encrypted wallets can be exported, run `owshen info` to encrypt it!",
+        )
+    })?;
+    Ok(Backup {
+        version: BACKUP_VERSION,
+        wallet,
+        endpoint: config.endpoint.clone(),
+        dive_contract_address: config.dive_contract_address,
+        owshen_contract_address: config.owshen_contract_address,
+        verifier_contract_address: config.verifier_contract_address,
+    })
+}
+
+fn read_backup(path: &Path) -> Result<Backup> {
+    let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    match value.get("version").and_then(|v| v.as_u64()) {
+        Some(version) if version <= BACKUP_VERSION as u64 => Ok(serde_json::from_value(value)?),
+        Some(version) => Err(eyre::Report::msg(format!(
+            "Backup version {} is not supported, upgrade owshen!",
+            version
+        ))),
+        None => Err(eyre::Report::msg("Not an owshen backup file!")),
+    }
+}
+
+// The contracts of the backup are only recorded if none are configured yet
+fn restore_backup(
+    backup: &Backup,
+    wallet_path: &Path,
+    config: &mut Config,
+    force: bool,
+) -> Result<()> {
+    if wallet_path.exists() && !force {
+        return Err(eyre::Report::msg(format!(
+            "A wallet already exists at {}, pass --force to overwrite it!",
+            wallet_path.display()
+        )));
+    }
+    if let Some(dir) = wallet_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(wallet_path, serde_json::to_string(&backup.wallet)?)?;
+    if config.owshen_contract_address == H160::zero() {
+        config.endpoint = backup.endpoint.clone();
+        config.dive_contract_address = backup.dive_contract_address;
+        config.owshen_contract_address = backup.owshen_contract_address;
+        config.verifier_contract_address = backup.verifier_contract_address;
+    }
+    Ok(())
+}
+
+// None when there is no config yet, a corrupt one is reported like a corrupt wallet
+fn read_config(config_path: &Path) -> Result<Option<Config>> {
+    let data = match std::fs::read_to_string(config_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_str(&data).map(Some).map_err(|err| {
+        eyre::Report::msg(format!(
+            "Config file {} is corrupt ({}), fix it or remove it and run `owshen config` or \
+             `owshen deploy` again!",
+            config_path.display(),
+            err
+        ))
+    })
+}
 
-    println!(
-        "{} {}",
-        "Your wallet path:".bright_green(),
-        wallet_path.to_string_lossy()
+fn resolve_token(config: &Config, token: &str) -> Result<H160> {
+    if token.eq_ignore_ascii_case("dive") {
+        return Ok(config.dive_contract_address);
+    }
+    H160::from_str(token).map_err(|_| {
+        eyre::Report::msg(format!(
+            "Invalid token {:?}, expected an ERC-20 address or DIVE!",
+            token
+        ))
+    })
+}
+
+// Which amounts of the token `1eth` stands for
+async fn token_decimals<M: Middleware + 'static>(provider: Arc<M>, token: H160) -> Result<usize> {
+    let decimals = SimpleErc20::new(token, provider)
+        .decimals()
+        .call()
+        .await
+        .map_err(|_| eyre::Report::msg(format!("{:?} is not an ERC-20 token contract!", token)))?;
+    Ok(decimals as usize)
+}
+
+// Two modules per character, so that the code stays square in a terminal
+fn render_qr(data: &str) -> Result<String> {
+    let code = qrcode::QrCode::new(data.as_bytes())?;
+    Ok(code
+        .render::<qrcode::render::unicode::Dense1x2>()
+        .quiet_zone(true)
+        .build())
+}
+
+fn token_symbol(token_contracts: &[TokenInfo], token: H160) -> String {
+    token_contracts
+        .iter()
+        .find(|t| t.token_address == token)
+        .map(|t| t.symbol.clone())
+        .unwrap_or_else(|| format!("{:?}", token))
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+struct ImportResult {
+    index: U256,
+    commitment: U256,
+    spent: bool,
+}
+
+fn import_payment(
+    state: &SyncState,
+    priv_key: &PrivateKey,
+    request: &PaymentRequest,
+) -> Result<ImportResult> {
+    if !request.is_for(priv_key) {
+        return Err(eyre::Report::msg(
+            "The payment request is not addressed to this wallet!",
+        ));
+    }
+    let commitment: U256 = request.commitment()?.into();
+    let event = state
+        .commitments
+        .iter()
+        .find(|event| event.commitment == commitment)
+        .ok_or(eyre::Report::msg(format!(
+            "Commitment {} is not on chain (yet), retry once the deposit is mined!",
+            commitment
+        )))?;
+    let spent = !state
+        .coins(priv_key)
+        .iter()
+        .any(|coin| coin.index == event.index);
+    Ok(ImportResult {
+        index: event.index,
+        commitment,
+        spent,
+    })
+}
+
+// The siblings are ordered from the leaves up, each holding the 3 other children of the node
+// on the path, in the order the circuit and the contract hash them
+#[derive(Clone, Debug, Serialize)]
+struct MerkleProofResult {
+    index: u64,
+    leaf: Fp,
+    siblings: Vec<[Fp; 3]>,
+    root: Fp,
+}
+
+fn merkle_proof(tree: &SparseMerkleTree, index: u64) -> Result<MerkleProofResult> {
+    if let Some(capacity) = tree.capacity() {
+        if index >= capacity {
+            return Err(tree::TreeError::IndexOutOfRange { index, capacity }.into());
+        }
+    }
+    if !tree.iter_set().any(|(i, _)| i == index) {
+        return Err(eyre::Report::msg(format!(
+            "Leaf #{} is empty, run `owshen sync` if the commitment was added since",
+            index
+        )));
+    }
+    let (leaf, proof) = tree.get_with_proof::<MERKLE_PROOF_LENGTH>(index)?;
+    Ok(MerkleProofResult {
+        index,
+        leaf,
+        siblings: proof.proof.to_vec(),
+        root: tree.root(),
+    })
+}
+
+// The events as cached by `owshen sync` (`~/.owshen/<name>.sync.json`) work as well as a bare
+// array, only the cache also records the deployment the events come from
+fn read_commitment_dump(path: &Path) -> Result<SyncState> {
+    let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let invalid =
+        |e: serde_json::Error| eyre::Report::msg(format!("Invalid dump {}: {}", path.display(), e));
+    match value.get("commitments") {
+        Some(_) => serde_json::from_value(value).map_err(invalid),
+        None => Ok(SyncState {
+            commitments: serde_json::from_value(value).map_err(invalid)?,
+            ..Default::default()
+        }),
+    }
+}
+
+// Whether these coins were spent since is only known to the chain. Their nullifiers are bound to
+// the deployment, `chain_id` and `contract` override the one of the dump
+fn scan_commitments(
+    mut dump: SyncState,
+    priv_key: &PrivateKey,
+    chain_id: Option<u64>,
+    contract: Option<H160>,
+) -> Result<Vec<CoinInfo>> {
+    dump.chain_id = chain_id.unwrap_or(dump.chain_id);
+    dump.owshen_contract = contract.unwrap_or(dump.owshen_contract);
+    if dump.chain_id == 0 || dump.owshen_contract.is_zero() {
+        return Err(eyre::Report::msg(
+            "The dump doesn't say which Owshen deployment it comes from, pass --chain-id and \
+            --contract to compute the nullifiers of your coins!",
+        ));
+    }
+    Ok(dump.coins(priv_key).iter().map(CoinInfo::from).collect())
+}
+
+async fn sync_state(
+    config: &Config,
+    endpoint: &str,
+    sync_path: &Path,
+    from_block: Option<u64>,
+    since_block: Option<u64>,
+    confirmations: u64,
+) -> Result<SyncState> {
+    let provider = connect(endpoint).await?;
+    let contract = Contract::new(
+        config.owshen_contract_address,
+        config.owshen_contract_abi.clone(),
+        provider,
     );
+    require_owshen_code(&contract).await?;
+    let mut state = SyncState::load(sync_path, config.owshen_contract_address)?;
+    sync::sync(
+        &contract,
+        &mut state,
+        from_block,
+        since_block,
+        confirmations,
+    )
+    .await?;
+    state.save(sync_path)?;
+    Ok(state)
+}
+
+async fn print_balance(
+    wallet: &Wallet,
+    config: &Config,
+    endpoint: &str,
+    sync_path: &Path,
+    json: bool,
+) -> Result<()> {
+    let state = sync_state(config, endpoint, sync_path, None, None, 0).await?;
+    let priv_key = wallet.priv_key();
+    let coins = state.coins(&priv_key);
+
+    let denominations = denomination_counts(&coins);
+    let mut totals: Vec<(H160, U256)> = Vec::new();
+    for coin in coins.iter() {
+        match totals
+            .iter_mut()
+            .find(|(token, _)| *token == coin.uint_token)
+        {
+            Some((_, total)) => *total += coin.amount,
+            None => totals.push((coin.uint_token, coin.amount)),
+        }
+    }
+
+    if json {
+        let balance = totals
+            .iter()
+            .map(|(token, amount)| {
+                serde_json::json!({
+                    "token": token,
+                    "symbol": token_symbol(&wallet.token_contracts, *token),
+                    "amount": amount,
+                })
+            })
+            .collect::<Vec<_>>();
+        let denominations = denominations
+            .iter()
+            .map(|(token, denomination, count)| {
+                serde_json::json!({
+                    "token": token,
+                    "denomination": denomination,
+                    "coins": count,
+                })
+            })
+            .collect::<Vec<_>>();
+        let coins = coins.iter().map(CoinInfo::from).collect::<Vec<_>>();
+        println!(
+            "{}",
+            serde_json::json!({
+                "balance": balance,
+                "denominations": denominations,
+                "coins": coins,
+            })
+        );
+        return Ok(());
+    }
+
+    if totals.is_empty() {
+        println!("Balance: 0");
+    }
+    // Amounts are shown in whole tokens of each token's decimals
+    let provider = connect_checked(endpoint, config.network).await?;
+    let mut decimals = std::collections::HashMap::new();
+    for (token, _) in totals.iter() {
+        decimals.insert(*token, token_decimals(provider.clone(), *token).await?);
+    }
+    let format = |token: &H160, amount: U256| {
+        ethers::utils::format_units(amount, decimals[token] as u32).unwrap_or(amount.to_string())
+    };
+    for (token, total) in totals.iter() {
+        println!(
+            "Balance: {} {}",
+            format(token, *total),
+            token_symbol(&wallet.token_contracts, *token)
+        );
+        let sizes = denominations
+            .iter()
+            .filter(|(t, _, _)| t == token)
+            .map(|(_, denomination, count)| match denomination {
+                Some(d) => format!("{} × {}", count, format(token, *d)),
+                None => format!("{} other", count),
+            })
+            .collect::<Vec<_>>();
+        println!("  Denominations: {}", sizes.join(", "));
+    }
+    for coin in coins.iter() {
+        println!(
+            "  Coin #{}: {} {}",
+            coin.index,
+            format(&coin.uint_token, coin.amount),
+            token_symbol(&wallet.token_contracts, coin.uint_token)
+        );
+    }
+    Ok(())
+}
+
+struct Check {
+    name: &'static str,
+    // Only sending needs the proving key, balances and deposits work without it
+    critical: bool,
+    result: Result<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, critical: bool, result: Result<String>) -> Self {
+        Self {
+            name,
+            critical,
+            result,
+        }
+    }
+}
+
+// Readable without the passphrase: an encrypted wallet only has to be a well-formed vault
+fn check_wallet(wallet_path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(wallet_path).map_err(|e| {
+        eyre::Report::msg(format!(
+            "Cannot read {}: {}, run `owshen init`",
+            wallet_path.display(),
+            e
+        ))
+    })?;
+    if serde_json::from_str::<Vault>(&contents).is_ok() {
+        return Ok(format!("{} (encrypted)", wallet_path.display()));
+    }
+    serde_json::from_str::<Wallet>(&contents).map_err(|e| corrupt_wallet(wallet_path, e))?;
+    Ok(format!("{} (not encrypted!)", wallet_path.display()))
+}
+
+// Later checks are skipped when the ones they depend on failed
+async fn doctor(
+    wallet_path: &Path,
+    config_path: &Path,
+    rpc: Option<String>,
+    network: Option<Network>,
+) -> Vec<Check> {
+    let mut checks = vec![
+        Check::new(
+            "Proving key",
+            false,
+            zkey::ProvingKey::cached(PARAMS_FILE)
+                .map(|_| PARAMS_FILE.to_string())
+                .map_err(|e| eyre::Report::msg(format!("Cannot load {}: {}", PARAMS_FILE, e))),
+        ),
+        Check::new("Wallet", true, check_wallet(wallet_path)),
+    ];
+
+    let config = std::fs::read_to_string(config_path)
+        .map_err(|e| {
+            eyre::Report::msg(format!(
+                "Cannot read {}: {}, run `owshen config` or `owshen deploy`",
+                config_path.display(),
+                e
+            ))
+        })
+        .and_then(|s| {
+            serde_json::from_str::<Config>(&s)
+                .map_err(|e| eyre::Report::msg(format!("Invalid {}: {}", config_path.display(), e)))
+        });
+    let mut config = match config {
+        Ok(config) => {
+            let found = require_owshen(&config).map(|_| config_path.display().to_string());
+            checks.push(Check::new("Config", true, found));
+            Some(config)
+        }
+        Err(err) => {
+            checks.push(Check::new("Config", true, Err(err)));
+            None
+        }
+    };
+
+    let (endpoint, network) = match config.as_mut() {
+        Some(config) => (Some(config.select_endpoint(rpc, network)), config.network),
+        None => (rpc.or(network.map(|n| n.endpoint().to_string())), network),
+    };
+    let provider = match endpoint {
+        Some(endpoint) => match connect_checked(&endpoint, network).await {
+            Ok(provider) => {
+                let chain_id = provider.get_chainid().await.unwrap_or_default();
+                checks.push(Check::new(
+                    "RPC endpoint",
+                    true,
+                    Ok(format!("{} (chain {})", endpoint, chain_id)),
+                ));
+                Some(provider)
+            }
+            Err(err) => {
+                checks.push(Check::new("RPC endpoint", true, Err(err)));
+                None
+            }
+        },
+        None => {
+            checks.push(Check::new(
+                "RPC endpoint",
+                true,
+                Err(eyre::Report::msg("Skipped, no endpoint without a config")),
+            ));
+            None
+        }
+    };
+
+    let contract = match (provider, config) {
+        (Some(provider), Some(config)) if !config.owshen_contract_address.is_zero() => {
+            let owshen = Owshen::new(config.owshen_contract_address, provider);
+            require_owshen_code(&owshen)
+                .await
+                .map(|_| format!("{:?}", config.owshen_contract_address))
+        }
+        (Some(_), _) => Err(eyre::Report::msg("Skipped, no contract is configured")),
+        (None, _) => Err(eyre::Report::msg(
+            "Skipped, the RPC endpoint is unreachable",
+        )),
+    };
+    checks.push(Check::new("Owshen contract", true, contract));
+    checks
+}
+
+fn print_checks(json: bool, checks: &[Check]) {
+    if json {
+        let checks = checks
+            .iter()
+            .map(|check| {
+                serde_json::json!({
+                    "check": check.name,
+                    "critical": check.critical,
+                    "ok": check.result.is_ok(),
+                    "detail": match &check.result {
+                        Ok(detail) => detail.clone(),
+                        Err(err) => err.to_string(),
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::Value::Array(checks));
+        return;
+    }
+    for check in checks.iter() {
+        match &check.result {
+            Ok(detail) => println!("{} {}: {}", "✔".bright_green(), check.name, detail),
+            Err(err) if check.critical => {
+                println!("{} {}: {}", "✘".bright_red(), check.name, err)
+            }
+            Err(err) => println!("{} {}: {}", "!".bright_yellow(), check.name, err),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let OwshenCliOpt {
+        json,
+        verbose,
+        rpc_timeout,
+        sync_chunk,
+        wallet,
+        network,
+        command,
+    } = OwshenCliOpt::from_args();
+    init_logging(verbose);
+    rpc::set_policy(rpc::RetryPolicy {
+        timeout: Duration::from_secs(rpc_timeout),
+        ..Default::default()
+    });
+    sync::set_chunk(sync_chunk);
+    let ProfilePaths {
+        wallet: wallet_path,
+        config: config_path,
+        sync: sync_path,
+        history: history_path,
+        spent: spent_path,
+    } = profile_paths(&home::home_dir().unwrap(), &wallet)?;
+    for path in [&config_path, &sync_path, &history_path, &spent_path] {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
 
-    let opt = OwshenCliOpt::from_args();
+    if !json {
+        println!(
+            "{} {}",
+            "Your wallet path:".bright_green(),
+            wallet_path.to_string_lossy()
+        );
+    }
 
-    match opt {
-        OwshenCliOpt::Init(InitOpt {
+    match command {
+        OwshenCommand::Init(InitOpt {
             endpoint,
             db,
             mnemonic,
+            private_key,
+            password_file,
             test,
+            force,
         }) => {
             let wallet_path = db.unwrap_or(wallet_path.clone());
-            let wallet = std::fs::read_to_string(&wallet_path)
-                .map(|s| {
-                    let w: Wallet = serde_json::from_str(&s).expect("Invalid wallet file!");
-                    w
-                })
-                .ok();
-            if wallet.is_none() {
-                let wallet = initialize_wallet(endpoint, mnemonic, test).await;
-                std::fs::write(wallet_path, serde_json::to_string(&wallet).unwrap()).unwrap();
+            if init_wallet_needed(&wallet_path, force)? {
+                let passphrase = read_passphrase(&password_file, true)?;
+                let key = match (mnemonic, private_key) {
+                    (Some(mnemonic), _) => {
+                        Some(WalletKey::Entropy(Entropy::from_mnemonic(mnemonic)))
+                    }
+                    (None, Some(priv_key)) => Some(WalletKey::PrivateKey(priv_key)),
+                    (None, None) => None,
+                };
+                let generated = key.is_none();
+                let wallet = initialize_wallet(endpoint, key, test).await?;
+                write_wallet(&wallet_path, &wallet, &passphrase)?;
+                report_new_wallet(json, &wallet_path, &wallet, generated)?;
             } else {
-                println!("Wallet is already initialized!");
+                print_message(json, "Wallet is already initialized!");
             }
         }
-        OwshenCliOpt::Config(ConfigOpt {
+        OwshenCommand::Config(ConfigOpt {
             endpoint,
             name,
             config,
             test,
+            action,
         }) => {
             let config_path = config.unwrap_or(config_path.clone());
-            let config = std::fs::read_to_string(&config_path)
-                .map(|s| {
-                    let c: Config = serde_json::from_str(&s).expect("Invalid config file!");
-                    c
-                })
-                .ok();
-            if config.is_none() {
-                let config = initialize_config(endpoint, name, test).await;
-                std::fs::write(config_path, serde_json::to_string(&config).unwrap()).unwrap();
+            let config = read_config(&config_path)?;
+            if let Some(action) = action {
+                let mut config = config.ok_or(eyre::Report::msg("Config is not initialized!"))?;
+                let key = match action {
+                    ConfigAction::Get { key } => key,
+                    ConfigAction::Set { key, value } => {
+                        config.set(key, &value)?;
+                        std::fs::write(&config_path, serde_json::to_string(&config)?)?;
+                        key
+                    }
+                };
+                let value = config.get(key);
+                if json {
+                    println!("{}", serde_json::json!({ "value": value }));
+                } else {
+                    println!("{}", value);
+                }
+            } else if config.is_none() {
+                let name = name.ok_or(eyre::Report::msg(
+                    "Pass --name to create the config, or get/set one of its settings!",
+                ))?;
+                let config = initialize_config(endpoint, name, test).await?;
+                std::fs::write(&config_path, serde_json::to_string(&config)?)?;
+                if json {
+                    println!("{}", serde_json::json!({ "config": config_path }));
+                }
             } else {
-                println!("Config is already initialized!");
+                print_message(json, "Config is already initialized!");
             }
         }
-        OwshenCliOpt::Wallet(WalletOpt {
+        OwshenCommand::Wallet(WalletOpt {
             db,
-            port,
+            api_port,
+            front_port,
             endpoint,
             test,
             config,
+            password_file,
+            rpc,
+            allow_origin,
+            rate_limit,
+            ws,
+            proof_dir,
+            relayer,
+            gas,
         }) => {
+            if let Some(dir) = proof_dir.as_ref().filter(|dir| !dir.is_dir()) {
+                return Err(eyre::Report::msg(format!(
+                    "--proof-dir {} is not a directory!",
+                    dir.display()
+                )));
+            }
             let wallet_path = db.unwrap_or(wallet_path.clone());
-            let wallet = std::fs::read_to_string(&wallet_path)
-                .map(|s| {
-                    let w: Wallet = serde_json::from_str(&s).expect("Invalid wallet file!");
-                    w
-                })
-                .ok();
+            let wallet = read_wallet(&wallet_path, &password_file)?;
 
             let config_path = config.unwrap_or(config_path.clone());
-            let config = std::fs::read_to_string(&config_path)
-                .map(|s| {
-                    let c: Config = serde_json::from_str(&s).expect("Invalid config file!");
-                    c
-                })
-                .ok();
+            let config = read_config(&config_path)?;
 
             if let Some(wallet) = &wallet {
-                let config = config.clone().unwrap_or_default();
-                let provider = Provider::<Http>::try_from(config.endpoint.clone()).unwrap();
-                let provider = Arc::new(provider);
-                let priv_key = wallet.entropy.clone().into();
-                let pub_key = PublicKey::from(priv_key);
-
-                serve_wallet(
+                let mut config = config.clone().unwrap_or_default();
+                require_owshen(&config)?;
+                let endpoint = config.select_endpoint(rpc, network);
+                let provider = connect_checked(&endpoint, config.network).await?;
+                let mut state = AppState::new(
                     provider,
-                    port,
-                    priv_key,
-                    pub_key,
-                    config.owshen_contract_address,
-                    config.dive_contract_address,
-                    config.owshen_contract_abi.clone(),
-                    config.erc20_abi.clone(),
-                    wallet.token_contracts.clone(),
+                    &config,
+                    wallet,
+                    spent_path,
+                    history_path,
+                    gas,
                     test,
+                );
+                state.proof_dir = Arc::new(proof_dir);
+                state.relayer = Arc::new(relayer);
+                serve_wallet(
+                    state,
+                    ServeOpts {
+                        api_port,
+                        front_port,
+                        allow_origin,
+                        rate_limit,
+                        ws,
+                    },
                 )
                 .await?;
             } else {
                 if wallet.is_none() {
-                    let wallet = initialize_wallet(endpoint, None, test).await;
-                    std::fs::write(wallet_path, serde_json::to_string(&wallet).unwrap()).unwrap();
+                    let passphrase = read_passphrase(&password_file, true)?;
+                    let wallet = initialize_wallet(rpc.unwrap_or(endpoint), None, test).await?;
+                    write_wallet(&wallet_path, &wallet, &passphrase)?;
+                    report_new_wallet(json, &wallet_path, &wallet, true)?;
                 } else {
-                    println!("Wallet is already initialized!");
+                    print_message(json, "Wallet is already initialized!");
                 }
             }
         }
-        OwshenCliOpt::Info(InfoOpt {}) => {
-            let wallet = std::fs::read_to_string(&wallet_path)
-                .map(|s| {
-                    let w: Wallet = serde_json::from_str(&s).expect("Invalid wallet file!");
-                    w
-                })
-                .ok();
+        OwshenCommand::Info(InfoOpt { password_file, qr }) => {
+            let wallet = read_wallet(&wallet_path, &password_file)?;
             if let Some(wallet) = &wallet {
+                let pub_key = PublicKey::from(wallet.priv_key());
+                let eth_address = wallet.eth_address().ok().map(|a| to_checksum(&a, None));
+                if json {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "address": pub_key.to_bech32(),
+                            "legacy_address": pub_key.to_string(),
+                            "eth_address": eth_address,
+                        })
+                    );
+                } else {
+                    println!("Owshen Address: {}", pub_key.to_bech32());
+                    println!("Legacy Address: {}", pub_key);
+                    if let Some(eth_address) = eth_address {
+                        println!("Ethereum Address (m/44'/60'/0'/0/0): {}", eth_address);
+                    }
+                    if qr {
+                        println!("{}", render_qr(&pub_key.to_bech32())?);
+                    }
+                }
+            } else {
+                print_message(json, "Wallet is not initialized!");
+            }
+        }
+        OwshenCommand::Balance(BalanceOpt {
+            db,
+            config,
+            password_file,
+            rpc,
+            watch,
+            interval,
+        }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            let config_path = config.unwrap_or(config_path.clone());
+            if let Some(wallet) = read_wallet(&wallet_path, &password_file)? {
+                let mut config = read_config(&config_path)?.unwrap_or_default();
+                require_owshen(&config)?;
+                let endpoint = config.select_endpoint(rpc, network);
+                if watch {
+                    let refresh = || async {
+                        if !json {
+                            // Clear the terminal and move the cursor home
+                            print!("\x1B[2J\x1B[H");
+                        }
+                        print_balance(&wallet, &config, &endpoint, &sync_path, json).await
+                    };
+                    watch_loop(Duration::from_secs(interval), shutdown_signal(), refresh).await;
+                } else {
+                    print_balance(&wallet, &config, &endpoint, &sync_path, json).await?;
+                }
+            } else {
+                print_message(json, "Wallet is not initialized!");
+            }
+        }
+        OwshenCommand::Deposit(DepositOpt {
+            to,
+            amount,
+            token,
+            config,
+            rpc,
+            gas,
+            dry_run,
+            denomination,
+        }) => {
+            let config_path = config.unwrap_or(config_path.clone());
+            let mut config = read_config(&config_path)?.unwrap_or_default();
+            require_owshen(&config)?;
+            let token = resolve_token(&config, &token)?;
+            let endpoint = config.select_endpoint(rpc, network);
+            let provider = connect_checked(&endpoint, config.network).await?;
+            let decimals = token_decimals(provider.clone(), token).await?;
+            let amount = amount
+                .map(|amount| parse_token_amount(&amount, decimals))
+                .transpose()?;
+            // One deposit of the standard size for each coin of it the amount makes up
+            let deposits = match denomination {
+                Some(size) => {
+                    let size = parse_token_amount(&size, decimals)?;
+                    let count = denomination_deposits(amount.unwrap_or(size), size, decimals)?;
+                    vec![(size, size); count as usize]
+                }
+                None => vec![(
+                    amount.ok_or(eyre::Report::msg("Missing --amount!"))?,
+                    U256::zero(),
+                )],
+            };
+            let fees = resolve_fees(provider.as_ref(), &gas).await?;
+            for (amount, denomination) in deposits {
+                let deposit = match send_deposit(
+                    provider.clone(),
+                    &config,
+                    to,
+                    amount,
+                    token,
+                    denomination,
+                    &fees,
+                    dry_run,
+                )
+                .await?
+                {
+                    Outcome::Sent(deposit) => deposit,
+                    Outcome::DryRun(dry_run) => {
+                        print_dry_run(json, &dry_run)?;
+                        continue;
+                    }
+                };
+                history::append(
+                    &history_path,
+                    &HistoryEntry::new(
+                        HistoryKind::Deposit,
+                        amount,
+                        token,
+                        deposit.tx_hash,
+                        to.to_bech32(),
+                        deposit.block_number,
+                    ),
+                )?;
+                if json {
+                    println!("{}", serde_json::to_string(&deposit)?);
+                } else {
+                    let (eph_x, eph_y): (U256, U256) =
+                        (deposit.ephemeral.x.into(), deposit.ephemeral.y.into());
+                    println!("{} {:?}", "Transaction:".bright_green(), deposit.tx_hash);
+                    println!("Commitment #{}: {}", deposit.index, deposit.commitment);
+                    println!("Ephemeral key: ({}, {})", eph_x, eph_y);
+                    println!(
+                        "Payment request (share it with the recipient): {}",
+                        deposit.payment_request
+                    );
+                }
+            }
+        }
+        OwshenCommand::Send(SendOpt {
+            to,
+            amount,
+            token,
+            db,
+            config,
+            password_file,
+            rpc,
+            gas,
+            dry_run,
+            output,
+        }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            let config_path = config.unwrap_or(config_path.clone());
+            if let Some(wallet) = read_wallet(&wallet_path, &password_file)? {
+                let mut config = read_config(&config_path)?.unwrap_or_default();
+                require_owshen(&config)?;
+                let token = resolve_token(&config, &token)?;
+                let endpoint = config.select_endpoint(rpc, network);
+                let provider = connect_checked(&endpoint, config.network).await?;
+                let amount = parse_token_amount(&amount, token_decimals(provider, token).await?)?;
+                let priv_key = wallet.priv_key();
+                let sent = match send_coin(
+                    &config,
+                    &endpoint,
+                    &sync_path,
+                    &spent_path,
+                    &priv_key,
+                    to,
+                    amount,
+                    token,
+                    &gas,
+                    dry_run,
+                    output.as_deref(),
+                )
+                .await?
+                {
+                    Outcome::Sent(sent) => sent,
+                    Outcome::DryRun(dry_run) => return print_dry_run(json, &dry_run),
+                };
+                history::append(
+                    &history_path,
+                    &HistoryEntry::new(
+                        HistoryKind::Send,
+                        amount,
+                        token,
+                        sent.tx_hash,
+                        to.to_bech32(),
+                        sent.block_number,
+                    ),
+                )?;
+                if json {
+                    println!("{}", serde_json::to_string(&sent)?);
+                } else {
+                    println!("{} {:?}", "Transaction:".bright_green(), sent.tx_hash);
+                    println!("Spent coin #{}", sent.spent_index);
+                }
+            } else {
+                print_message(json, "Wallet is not initialized!");
+            }
+        }
+        OwshenCommand::Export(ExportOpt { out, db, config }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            let config = read_config(&config.unwrap_or(config_path.clone()))?.unwrap_or_default();
+            let backup = export_backup(&wallet_path, &config)?;
+            std::fs::write(&out, serde_json::to_string_pretty(&backup)?)?;
+            if json {
+                println!("{}", serde_json::json!({ "backup": out }));
+            } else {
+                println!("Wallet exported to {}", out.display());
+            }
+        }
+        OwshenCommand::ExportKey(ExportKeyOpt {
+            format,
+            db,
+            password_file,
+            yes,
+        }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            let wallet = read_wallet(&wallet_path, &password_file)?
+                .ok_or(eyre::Report::msg("Wallet is not initialized!"))?;
+            let key = export_key(&wallet, &format, yes)?;
+            if json {
+                println!("{}", serde_json::json!({ "private_key": key }));
+            } else {
+                eprintln!(
+                    "{}",
+                    "Never share this key, anyone who has it can spend your coins!".bright_red()
+                );
+                println!("{}", key);
+            }
+        }
+        OwshenCommand::Restore(RestoreOpt {
+            backup,
+            db,
+            config,
+            password_file,
+            force,
+        }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            let config_path = config.unwrap_or(config_path.clone());
+            let backup = read_backup(&backup)?;
+            // Check the passphrase before touching the current wallet
+            let passphrase = read_passphrase(&password_file, false)?;
+            let wallet: Wallet = serde_json::from_slice(&backup.wallet.decrypt(&passphrase)?)?;
+            let mut config = read_config(&config_path)?.unwrap_or_default();
+            restore_backup(&backup, &wallet_path, &mut config, force)?;
+            std::fs::write(&config_path, serde_json::to_string(&config)?)?;
+
+            let address = PublicKey::from(wallet.priv_key());
+            if json {
                 println!(
-                    "Owshen Address: {}",
-                    PublicKey::from(PrivateKey::from(wallet.entropy.clone()))
+                    "{}",
+                    serde_json::json!({ "wallet": wallet_path, "address": address })
                 );
             } else {
-                println!("Wallet is not initialized!");
+                println!("Restored wallet {} to {}", address, wallet_path.display());
             }
         }
-    }
-
-    Ok(())
-}
-use ethers::abi::Abi;
-use ethers::types::H160;
-
-use crate::keys::Entropy;
-
-async fn deploy(
-    client: Arc<Provider<Http>>,
-    abi: &str,
-    bytecode: &str,
-) -> ContractInstance<Arc<Provider<Http>>, Provider<Http>> {
-    let from = client.get_accounts().await.unwrap()[0];
-    let abi = serde_json::from_str::<Abi>(abi).unwrap();
-    let bytecode = Bytes::from_str(bytecode).unwrap();
-    let factory = ContractFactory::new(abi, bytecode, client);
-    let mut deployer = factory.deploy(()).unwrap().legacy();
-    deployer.tx.set_from(from);
-    let contract = deployer.send().await.unwrap();
-    contract
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use ethers::abi::Abi;
-    use ethers::utils::Ganache;
-
-    use std::sync::Arc;
-
-    use ethers::core::types::Bytes;
-    use ethers::middleware::contract::ContractFactory;
-    use std::str::FromStr;
+        OwshenCommand::Import(ImportOpt {
+            request,
+            db,
+            config,
+            password_file,
+            rpc,
+        }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            let config_path = config.unwrap_or(config_path.clone());
+            if let Some(wallet) = read_wallet(&wallet_path, &password_file)? {
+                let mut config = read_config(&config_path)?.unwrap_or_default();
+                require_owshen(&config)?;
+                let endpoint = config.select_endpoint(rpc, network);
+                let state = sync_state(&config, &endpoint, &sync_path, None, None, 0).await?;
+                let priv_key = wallet.priv_key();
+                let found = import_payment(&state, &priv_key, &request)?;
+                if json {
+                    println!("{}", serde_json::to_string(&found)?);
+                } else {
+                    println!(
+                        "Coin #{}: {} {}{}",
+                        found.index,
+                        ethers::utils::format_ether(request.amount),
+                        token_symbol(&wallet.token_contracts, request.token),
+                        if found.spent { " (spent)" } else { "" }
+                    );
+                }
+            } else {
+                print_message(json, "Wallet is not initialized!");
+            }
+        }
+        OwshenCommand::Scan(ScanOpt {
+            ephemerals,
+            chain_id,
+            contract,
+            db,
+            password_file,
+        }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            if let Some(wallet) = read_wallet(&wallet_path, &password_file)? {
+                let priv_key = wallet.priv_key();
+                let coins = scan_commitments(
+                    read_commitment_dump(&ephemerals)?,
+                    &priv_key,
+                    chain_id,
+                    contract,
+                )?;
+                if json {
+                    println!("{}", serde_json::to_string(&coins)?);
+                } else if coins.is_empty() {
+                    println!("None of these commitments is yours!");
+                } else {
+                    for coin in coins.iter() {
+                        println!(
+                            "Coin #{}: {} {}",
+                            coin.index,
+                            ethers::utils::format_ether(coin.amount),
+                            token_symbol(&wallet.token_contracts, coin.token)
+                        );
+                    }
+                    println!(
+                        "Spent coins are listed too, `owshen balance` only shows unspent ones"
+                    );
+                }
+            } else {
+                print_message(json, "Wallet is not initialized!");
+            }
+        }
+        OwshenCommand::ImportCommitments(ImportCommitmentsOpt {
+            commitments,
+            db,
+            config,
+            password_file,
+        }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            let config_path = config.unwrap_or(config_path.clone());
+            let config = read_config(&config_path)?.unwrap_or_default();
+            require_owshen(&config)?;
+            let wallet = read_wallet(&wallet_path, &password_file)?
+                .ok_or(eyre::Report::msg("Wallet is not initialized!"))?;
+            let records = read_commitment_dump(&commitments)?.commitments;
+            let mut state = SyncState::load(&sync_path, config.owshen_contract_address)?;
+            let added = state.import_commitments(records)?;
+            state.save(&sync_path)?;
+            let coins = state.coins(&wallet.priv_key());
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "imported": added,
+                        "commitments": state.commitments.len(),
+                        "coins": coins.iter().map(CoinInfo::from).collect::<Vec<_>>(),
+                    })
+                );
+            } else {
+                println!(
+                    "Imported {} new commitments, {} are known",
+                    added,
+                    state.commitments.len()
+                );
+                println!(
+                    "{} of them are your coins, spends are only known after a sync",
+                    coins.len()
+                );
+            }
+        }
+        OwshenCommand::MerkleProof(MerkleProofOpt { index, config }) => {
+            let config_path = config.unwrap_or(config_path.clone());
+            let config = read_config(&config_path)?.unwrap_or_default();
+            require_owshen(&config)?;
+            let state = SyncState::load(&sync_path, config.owshen_contract_address)?;
+            let result = merkle_proof(&state.tree()?, index)?;
+            if json {
+                println!("{}", serde_json::to_string(&result)?);
+            } else {
+                println!("Leaf #{}: {:?}", result.index, result.leaf);
+                for (level, siblings) in result.siblings.iter().enumerate() {
+                    println!(
+                        "Level {}: {:?} {:?} {:?}",
+                        level, siblings[0], siblings[1], siblings[2]
+                    );
+                }
+                println!("Root: {:?}", result.root);
+            }
+        }
+        OwshenCommand::Sync(SyncOpt {
+            config,
+            rpc,
+            from_block,
+            since_block,
+            confirmations,
+        }) => {
+            let config_path = config.unwrap_or(config_path.clone());
+            let mut config = read_config(&config_path)?.unwrap_or_default();
+            require_owshen(&config)?;
+            let endpoint = config.select_endpoint(rpc, network);
+            let state = sync_state(
+                &config,
+                &endpoint,
+                &sync_path,
+                from_block,
+                since_block,
+                confirmations,
+            )
+            .await?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "commitments": state.commitments.len(),
+                        "synced_block": state.last_synced_block,
+                    })
+                );
+            } else {
+                println!(
+                    "Synced {} commitments up to block {}",
+                    state.commitments.len(),
+                    state.last_synced_block.unwrap_or_default()
+                );
+            }
+        }
+        OwshenCommand::Verify(VerifyOpt {
+            proof,
+            public_inputs,
+            verifying_key,
+        }) => {
+            let proof = proof::read_proof_file(&proof)?;
+            let public_inputs = proof::read_public_inputs(&public_inputs)?;
+            let vk = proof::VerifyingKey::load(
+                verifying_key.unwrap_or(proof::VERIFYING_KEY_FILE.into()),
+            )?;
+            let valid = proof::verify_with_key(&vk, &proof, &public_inputs)?;
+            let mismatched = proof::mismatched_inputs(&proof, &public_inputs);
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "valid": valid, "mismatched_inputs": mismatched })
+                );
+            } else if valid {
+                println!("{}", "Proof is valid".bright_green());
+            } else {
+                for i in mismatched.iter() {
+                    println!("Public input #{} differs from the proven one", i);
+                }
+            }
+            if !valid {
+                return Err(eyre::Report::msg("Proof is invalid!"));
+            }
+        }
+        OwshenCommand::Fund(FundOpt {
+            to,
+            db,
+            password_file,
+            amount,
+            config,
+            rpc,
+        }) => {
+            let to = match to {
+                Some(to) => to,
+                None => {
+                    let wallet_path = db.unwrap_or(wallet_path.clone());
+                    read_wallet(&wallet_path, &password_file)?
+                        .ok_or(eyre::Report::msg(
+                            "Wallet is not initialized, pass the address to fund with --to!",
+                        ))?
+                        .eth_address()?
+                }
+            };
+            let config_path = config.unwrap_or(config_path.clone());
+            let mut config = read_config(&config_path)?.unwrap_or_default();
+            let endpoint = config.select_endpoint(rpc, network);
+            let provider = connect_checked(&endpoint, config.network).await?;
+            let funded = fund(&provider, to, amount).await?;
+            if json {
+                println!("{}", serde_json::to_string(&funded)?);
+            } else {
+                println!("{} {:?}", "Transaction:".bright_green(), funded.tx_hash);
+                println!(
+                    "Sent {} ETH from {:?} to {:?}",
+                    ethers::utils::format_ether(funded.amount),
+                    funded.from,
+                    funded.to
+                );
+            }
+        }
+        OwshenCommand::Doctor(DoctorOpt { db, config, rpc }) => {
+            let wallet_path = db.unwrap_or(wallet_path.clone());
+            let config_path = config.unwrap_or(config_path.clone());
+            let checks = doctor(&wallet_path, &config_path, rpc, network).await;
+            print_checks(json, &checks);
+            let failed = checks
+                .iter()
+                .filter(|check| check.critical && check.result.is_err())
+                .count();
+            if failed > 0 {
+                return Err(eyre::Report::msg(format!(
+                    "{} critical check(s) failed!",
+                    failed
+                )));
+            }
+        }
+        OwshenCommand::History(HistoryOpt { since_block }) => {
+            let mut entries = history::load(&history_path)?;
+            if let Some(block) = since_block {
+                entries = history::since_block(entries, block);
+            }
+            if json {
+                println!("{}", serde_json::to_string(&entries)?);
+            } else if entries.is_empty() {
+                println!("No transactions yet!");
+            } else {
+                for entry in entries.iter() {
+                    let time = chrono::DateTime::from_timestamp(entry.timestamp as i64, 0)
+                        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                        .unwrap_or_default();
+                    println!(
+                        "{} {:<8} {} of {:?} to {} ({:?})",
+                        time,
+                        entry.kind,
+                        ethers::utils::format_ether(entry.amount),
+                        entry.token,
+                        entry.counterparty,
+                        entry.tx_hash
+                    );
+                }
+            }
+        }
+        OwshenCommand::Deploy(DeployOpt {
+            config,
+            rpc,
+            force,
+            gas,
+        }) => {
+            let config_path = config.unwrap_or(config_path.clone());
+            let mut config = read_config(&config_path)?.unwrap_or_default();
+            if config.owshen_contract_address != H160::zero() && !force {
+                print_message(
+                    json,
+                    &format!(
+                        "Owshen is already deployed at {:?}!",
+                        config.owshen_contract_address
+                    ),
+                );
+            } else {
+                // The deployment's endpoint and network are kept for the next commands
+                let endpoint = config.select_endpoint(rpc, network);
+                let provider = connect_checked(&endpoint, config.network).await?;
+                let from = rpc_account(&provider).await?;
+                let fees = resolve_fees(provider.as_ref(), &gas).await?;
+                let owshen = deploy_owshen(provider, from, &fees).await?;
+
+                config.endpoint = endpoint;
+                config.owshen_contract_address = owshen.address();
+                config.owshen_contract_abi = owshen.abi().clone();
+                config.verifier_contract_address = verifier_address(owshen.address());
+                std::fs::write(&config_path, serde_json::to_string(&config)?)?;
+                if json {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "owshen_contract_address": owshen.address(),
+                            "verifier_contract_address": config.verifier_contract_address,
+                        })
+                    );
+                } else {
+                    println!(
+                        "{} {:?}",
+                        "Owshen deployed at:".bright_green(),
+                        owshen.address()
+                    );
+                    println!(
+                        "{} {:?}",
+                        "Withdraw verifier deployed at:".bright_green(),
+                        config.verifier_contract_address
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+use ethers::abi::Abi;
+use ethers::types::H160;
+
+use crate::keys::Entropy;
+
+async fn deploy(
+    client: Arc<Provider<Http>>,
+    abi: &str,
+    bytecode: &str,
+    fees: &GasOpt,
+) -> Result<ContractInstance<Arc<Provider<Http>>, Provider<Http>>> {
+    let from = rpc_account(&client).await?;
+    let abi = serde_json::from_str::<Abi>(abi)?;
+    let bytecode = Bytes::from_str(bytecode)?;
+    let factory = ContractFactory::new(abi, bytecode, client);
+    let mut deployer = factory.deploy(())?;
+    deployer.tx.set_from(from);
+    apply_fees(&mut deployer.tx, fees);
+    let contract = deployer
+        .send()
+        .await
+        .map_err(|e| eyre::Report::msg(format!("Deployment failed: {}", e)))?;
+    Ok(contract)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ethers::abi::Abi;
+    use ethers::utils::Ganache;
+
+    use std::sync::Arc;
+
+    use ethers::core::types::Bytes;
+    use ethers::middleware::contract::ContractFactory;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_deposit_commitment() {
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let pub_key = PublicKey::from(&priv_key);
+        let token = H160::from_low_u64_be(0x1234);
+        let amount = U256::from(1000);
+
+        let (ephemeral, stealth_pub) = pub_key.derive(&mut rand::thread_rng());
+        let commitment = deposit_commitment(stealth_pub, amount, token).unwrap();
+
+        // The recipient only sees the ephemeral key, the hints and the commitment
+        let stealth_priv = priv_key.derive(ephemeral);
+        let recovered = extract_token_amount(
+            h160_to_u256(token),
+            amount,
+            stealth_priv.shared_secret(ephemeral),
+            commitment,
+            (&stealth_priv).into(),
+        )
+        .unwrap();
+        assert_eq!(
+            recovered,
+            Some((
+                Fp::try_from(h160_to_u256(token)).unwrap(),
+                Fp::try_from(amount).unwrap()
+            ))
+        );
+
+        // The coin's nullifier is bound to the deployment the deposit was made on
+        let owshen_contract = H160::from_low_u64_be(7);
+        let mut state = SyncState::new(owshen_contract);
+        state.chain_id = 1337;
+        state.commitments.push(sync::CommitmentEvent {
+            block_number: 1,
+            index: U256::from(3),
+            ephemeral: ephemeral.point,
+            hint_amount: amount,
+            hint_token_address: h160_to_u256(token),
+            commitment: commitment.into(),
+            denomination: U256::zero(),
+        });
+        let nullifier = state.coins(&priv_key)[0].nullifier;
+        assert_eq!(
+            nullifier,
+            stealth_priv.nullifier(3, 1337, owshen_contract).into()
+        );
+        state.owshen_contract = H160::from_low_u64_be(8);
+        assert_ne!(state.coins(&priv_key)[0].nullifier, nullifier);
+    }
+
+    #[test]
+    fn test_denominations() {
+        // Sizes are whole tokens of the token's decimals
+        let usdc = |amount: &str| parse_token_amount(amount, 6).unwrap();
+        assert_eq!(usdc("0.1eth"), U256::from(100_000));
+        assert_eq!(
+            denominations(6),
+            vec![usdc("10eth"), usdc("1eth"), usdc("0.1eth")]
+        );
+        assert_eq!(denominations(0), vec![U256::from(10), U256::from(1)]);
+
+        // An amount snaps to that many deposits of the size
+        assert_eq!(
+            denomination_deposits(usdc("3eth"), usdc("1eth"), 6).unwrap(),
+            3
+        );
+        assert_eq!(
+            denomination_deposits(usdc("0.1eth"), usdc("0.1eth"), 6).unwrap(),
+            1
+        );
+        let err = |amount: &str, size: &str| {
+            denomination_deposits(usdc(amount), usdc(size), 6)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err("1.5eth", "1.5eth").contains("not a standard denomination"));
+        assert!(err("1eth", "0.5eth").contains("not a standard denomination"));
+        assert!(err("2.5eth", "1eth").contains("not a multiple"));
+        assert!(err("0eth", "1eth").contains("not a multiple"));
+        assert!(err("10.1eth", "0.1eth").contains("101 deposits"));
+
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let coin = |token: u64, amount: &str, denomination: &str| Coin {
+            index: U256::zero(),
+            uint_token: H160::from_low_u64_be(token),
+            amount: parse_amount(amount).unwrap(),
+            pub_key: PublicKey::from(&priv_key),
+            priv_key: priv_key.clone(),
+            nullifier: U256::zero(),
+            commitment: U256::zero(),
+            denomination: parse_amount(denomination).unwrap(),
+        };
+        // Counted by the tree the coin is in, a coin of the main tree is "other" whatever its
+        // amount
+        let coins = [
+            coin(1, "0.1eth", "0.1eth"),
+            coin(1, "1.5eth", "0eth"),
+            coin(1, "10eth", "10eth"),
+            coin(1, "0.1eth", "0.1eth"),
+            coin(1, "1eth", "0eth"),
+            coin(2, "1eth", "1eth"),
+        ];
+        let one = |amount: &str| Some(parse_amount(amount).unwrap());
+        assert_eq!(
+            denomination_counts(&coins),
+            vec![
+                (H160::from_low_u64_be(1), one("10eth"), 1),
+                (H160::from_low_u64_be(1), one("0.1eth"), 2),
+                (H160::from_low_u64_be(1), None, 2),
+                (H160::from_low_u64_be(2), one("1eth"), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_coin_info() {
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let coin = Coin {
+            index: U256::from(3),
+            uint_token: H160::from_low_u64_be(0x1234),
+            amount: U256::from(1000),
+            pub_key: PublicKey::from(&priv_key),
+            priv_key,
+            nullifier: U256::from(42),
+            commitment: U256::from(43),
+            denomination: U256::zero(),
+        };
+        let json = serde_json::to_value(GetCoinsResponse {
+            coins: vec![CoinInfo::from(&coin)],
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"coins": [{
+                "index": "0x3",
+                "amount": "0x3e8",
+                "token": "0x0000000000000000000000000000000000001234",
+                "nullifier": "0x2a",
+            }]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stealth_invalid_address() {
+        use axum::body::HttpBody;
+        use tower::ServiceExt;
+
+        let app = Router::new().route("/stealth", get(apis::stealth));
+        let get_stealth = |address: &str| {
+            Request::builder()
+                .uri(format!("/stealth?address={}", address))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.clone().oneshot(get_stealth("garbage")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"error": {"code": "bad_request", "message": "invalid address"}})
+        );
+
+        let address = PublicKey::from(PrivateKey::generate(&mut rand::thread_rng())).to_bech32();
+        let res = app.oneshot(get_stealth(&address)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_spent() {
+        use axum::body::HttpBody;
+        use tower::ServiceExt;
+
+        let mut sync = SyncState::new(H160::default());
+        sync.spends.push(sync::SpendEvent {
+            block_number: 2,
+            nullifier: U256::from(42),
+        });
+        let context = Arc::new(RwLock::new(Context {
+            coins: vec![],
+            tree: SparseMerkleTree::new(16),
+            sync,
+        }));
+        // Never reached, the nullifier is rejected before any RPC call
+        let provider = Arc::new(Provider::<Http>::try_from("http://127.0.0.1:1").unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let spent_path = dir.path().join("spent.jsonl");
+        let history_path = dir.path().join("history.jsonl");
+        let app = Router::new().route(
+            "/broadcast",
+            post(move |Json(req): Json<PostBroadcastRequest>| async move {
+                handle_error(
+                    apis::broadcast(
+                        req,
+                        context,
+                        provider,
+                        H160::default(),
+                        &spent_path,
+                        &history_path,
+                        &GasOpt::default(),
+                    )
+                    .await,
+                )
+            }),
+        );
+        let req = PostBroadcastRequest {
+            proof: Proof::default(),
+            token: H160::default(),
+            amount: U256::from(1),
+            obfuscated_remaining_amount: U256::zero(),
+            nullifier: U256::from(42),
+            commitment: U256::zero(),
+            ephemeral: Point {
+                x: Fp::from(0),
+                y: Fp::from(1),
+            },
+            to: H160::default(),
+            relayer: H160::default(),
+            fee: U256::zero(),
+            denomination: U256::zero(),
+            dry_run: false,
+        };
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/broadcast")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&req).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("already spent"));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reverts() {
+        let dir = tempfile::tempdir().unwrap();
+        let spent_path = dir.path().join("spent.jsonl");
+        let history_path = dir.path().join("history.jsonl");
+        let context = Arc::new(RwLock::new(Context {
+            coins: vec![],
+            tree: SparseMerkleTree::new(16),
+            sync: SyncState::new(H160::default()),
+        }));
+        let req = PostBroadcastRequest {
+            proof: Proof::default(),
+            token: H160::from_low_u64_be(1),
+            amount: U256::from(60),
+            obfuscated_remaining_amount: U256::zero(),
+            nullifier: U256::from(42),
+            commitment: U256::zero(),
+            ephemeral: Point {
+                x: Fp::from(0),
+                y: Fp::from(1),
+            },
+            to: H160::from_low_u64_be(0xbeef),
+            relayer: H160::default(),
+            fee: U256::zero(),
+            denomination: U256::zero(),
+            dry_run: false,
+        };
+        // A legacy gas price, so that only the gas limit is estimated
+        let gas = GasOpt {
+            gas_price: Some(U256::from(5)),
+            ..Default::default()
+        };
+        let owshen_node = || {
+            mock::MockNode::new()
+                .accounts(&[H160::from_low_u64_be(0x1234)])
+                .code(Bytes::from(vec![0x60, 0x80]))
+                .call(Bytes::from([0u8; 32]))
+        };
+        let (provider, mock) = owshen_node()
+            .estimate_gas(100000)
+            .revert("Nullifier has been spent")
+            .provider();
+        // Poll for the receipts without the default 7s delay
+        let provider = Arc::new(provider.interval(Duration::from_millis(1)));
+        let broadcast = || {
+            apis::broadcast(
+                req.clone(),
+                context.clone(),
+                provider.clone(),
+                H160::from_low_u64_be(7),
+                &spent_path,
+                &history_path,
+                &gas,
+            )
+        };
+
+        // The nullifier was spent by a transaction the sync hasn't seen yet
+        let err = broadcast().await.unwrap_err();
+        assert!(
+            err.to_string().contains("this coin was already spent"),
+            "{}",
+            err
+        );
+
+        // The tree changed since the proof was made, the gas estimation reverts already
+        owshen_node().revert("Invalid proof").push_to(&mock);
+        let err = broadcast().await.unwrap_err();
+        assert!(err.to_string().contains("proof does not match"), "{}", err);
+        assert!(spent::load(&spent_path).unwrap().is_empty());
+        assert!(history::load(&history_path).unwrap().is_empty());
+
+        // Mined but reverted, e.g. another withdrawal of the coin got in first
+        owshen_node()
+            .estimate_gas(100000)
+            .tx_hash(H256::from_low_u64_be(0xabc))
+            .mined(H256::from_low_u64_be(0xabc), 10, 0)
+            .revert("Nullifier has been spent")
+            .push_to(&mock);
+        let err = broadcast().await.unwrap_err();
+        assert!(
+            err.to_string().contains("this coin was already spent"),
+            "{}",
+            err
+        );
+        assert!(spent::load(&spent_path).unwrap().is_empty());
+        assert!(history::load(&history_path).unwrap().is_empty());
+
+        owshen_node()
+            .estimate_gas(100000)
+            .tx_hash(H256::from_low_u64_be(0xabd))
+            .mined(H256::from_low_u64_be(0xabd), 11, 1)
+            .push_to(&mock);
+        broadcast().await.unwrap();
+        assert!(spent::load(&spent_path).unwrap().contains(&req.nullifier));
+        let history = history::load(&history_path).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].kind, HistoryKind::Withdraw);
+        assert_eq!(history[0].amount, U256::from(60));
+        assert_eq!(history[0].tx_hash, H256::from_low_u64_be(0xabd));
+        assert_eq!(history[0].block_number, Some(11));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_dry_run() {
+        use axum::body::HttpBody;
+
+        let dir = tempfile::tempdir().unwrap();
+        let spent_path = dir.path().join("spent.jsonl");
+        let history_path = dir.path().join("history.jsonl");
+        let tree = SparseMerkleTree::new(16);
+        let root = U256::from(tree.root());
+        let context = Arc::new(RwLock::new(Context {
+            coins: vec![],
+            tree,
+            sync: SyncState::new(H160::default()),
+        }));
+        // Never reached, the proof is checked before any RPC call
+        let provider = Arc::new(Provider::<Http>::try_from("http://127.0.0.1:1").unwrap());
+        let mut req = PostBroadcastRequest {
+            proof: Proof {
+                public: vec![root + 1, U256::from(42), U256::zero(), U256::zero()],
+                ..Default::default()
+            },
+            token: H160::from_low_u64_be(1),
+            amount: U256::from(60),
+            obfuscated_remaining_amount: U256::zero(),
+            nullifier: U256::from(42),
+            commitment: U256::zero(),
+            ephemeral: Point {
+                x: Fp::from(0),
+                y: Fp::from(1),
+            },
+            to: H160::from_low_u64_be(0xbeef),
+            relayer: H160::default(),
+            fee: U256::zero(),
+            denomination: U256::zero(),
+            dry_run: true,
+        };
+        let gas = GasOpt::default();
+        let broadcast = |req: PostBroadcastRequest| {
+            apis::broadcast(
+                req,
+                context.clone(),
+                provider.clone(),
+                H160::from_low_u64_be(7),
+                &spent_path,
+                &history_path,
+                &gas,
+            )
+        };
+
+        // The proof was made against an older root
+        let res = broadcast(req.clone()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("tree changed"));
+
+        // Without the dry run the stale proof is left for the contract to reject
+        req.dry_run = false;
+        assert!(broadcast(req).await.is_err());
+        assert!(spent::load(&spent_path).unwrap().is_empty());
+        assert!(history::load(&history_path).unwrap().is_empty());
+    }
+
+    // Handles of a wallet on an unreachable node
+    fn app_state(context: Arc<RwLock<Context>>, priv_key: PrivateKey, dir: &Path) -> AppState {
+        let owshen_contract = H160::from_low_u64_be(7);
+        let provider = Arc::new(Provider::<Http>::try_from("http://127.0.0.1:1").unwrap());
+        AppState {
+            context: context.clone(),
+            provider: provider.clone(),
+            contract: Contract::new(owshen_contract, Abi::default(), provider),
+            priv_key: priv_key.clone(),
+            pub_key: PublicKey::from(priv_key),
+            owshen_contract,
+            dive_contract: H160::default(),
+            abi: Abi::default(),
+            erc20_abi: Abi::default(),
+            token_contracts: Arc::new(vec![]),
+            events: tokio::sync::broadcast::channel(1).0,
+            origins: Arc::new(vec![]),
+            spent_path: Arc::new(dir.join("spent.jsonl")),
+            history_path: Arc::new(dir.join("history.jsonl")),
+            gas: Arc::new(GasOpt::default()),
+            proof_dir: Arc::new(None),
+            relayer: Arc::new(RelayerOpt::default()),
+            test: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_app_state() {
+        use axum::body::HttpBody;
+        use tower::ServiceExt;
+
+        let owshen_contract = H160::from_low_u64_be(7);
+        let context = Arc::new(RwLock::new(Context {
+            coins: vec![],
+            tree: SparseMerkleTree::new(MERKLE_PROOF_LENGTH),
+            sync: SyncState::new(owshen_contract),
+        }));
+        let priv_key = PrivateKey::generate(&mut rand::thread_rng());
+        let dir = tempfile::tempdir().unwrap();
+        let app = wallet_api(app_state(context.clone(), priv_key, dir.path()), 0);
+        let json = |res: axum::response::Response| async move {
+            let body = res.into_body().data().await.unwrap().unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let res = app.clone().oneshot(get("/info")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            json(res).await["owshen_contract"],
+            serde_json::json!(owshen_contract)
+        );
+
+        // A sync done through one handle is seen by the other routes
+        context.write().unwrap().sync.last_synced_block = Some(5);
+        let res = app.oneshot(get("/health")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            json(res).await,
+            serde_json::json!({"status": "unreachable", "synced_block": 5})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_loop() {
+        tokio::time::pause();
+        let calls = std::cell::Cell::new(0);
+        let refresh = || {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move {
+                if call == 1 {
+                    return Err(eyre::Report::msg("RPC node unreachable"));
+                }
+                Ok(())
+            }
+        };
+        watch_loop(
+            Duration::from_millis(10),
+            tokio::time::sleep(Duration::from_millis(55)),
+            refresh,
+        )
+        .await;
+        // Kept refreshing past the failure at 0, 10, ... 50 ms, and stopped with the signal
+        assert_eq!(calls.get(), 6);
+
+        // A refresh that never finishes doesn't keep the loop from stopping
+        let calls = std::cell::Cell::new(0);
+        let refresh = || {
+            calls.set(calls.get() + 1);
+            futures::future::pending::<Result<()>>()
+        };
+        watch_loop(
+            Duration::from_millis(10),
+            tokio::time::sleep(Duration::from_secs(60)),
+            refresh,
+        )
+        .await;
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_api_errors() {
+        use axum::body::HttpBody;
+        use tower::ServiceExt;
+
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let coin = Coin {
+            index: U256::from(3),
+            uint_token: H160::from_low_u64_be(0x1234),
+            amount: U256::from(1000),
+            pub_key: PublicKey::from(&priv_key),
+            priv_key: priv_key.clone(),
+            nullifier: U256::from(42),
+            commitment: U256::from(43),
+            denomination: U256::zero(),
+        };
+        let context = Arc::new(RwLock::new(Context {
+            coins: vec![coin],
+            tree: SparseMerkleTree::new(MERKLE_PROOF_LENGTH),
+            sync: SyncState::new(H160::from_low_u64_be(7)),
+        }));
+        let dir = tempfile::tempdir().unwrap();
+        let app = wallet_api(app_state(context, priv_key.clone(), dir.path()), 4);
+        let request = |uri: String| {
+            let app = app.clone();
+            async move {
+                let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                let res = app.oneshot(req).await.unwrap();
+                let status = res.status();
+                let body = res.into_body().data().await.unwrap().unwrap();
+                let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+                (status, body["error"]["code"].clone())
+            }
+        };
+        let address = PublicKey::from(&priv_key).to_bech32();
+        let withdraw = |index: u64, address: &str, amount: &str| {
+            format!(
+                "/withdraw?index={:#x}&address={}&desire_amount={}&to={:?}",
+                index,
+                address,
+                amount,
+                H160::from_low_u64_be(0xbeef)
+            )
+        };
+
+        assert_eq!(
+            request(withdraw(5, &address, "10")).await,
+            (StatusCode::NOT_FOUND, "not_found".into())
+        );
+        assert_eq!(
+            request(withdraw(3, &address, "ten")).await,
+            (StatusCode::BAD_REQUEST, "bad_request".into())
+        );
+        assert_eq!(
+            request(withdraw(3, "garbage", "10")).await,
+            (StatusCode::BAD_REQUEST, "bad_request".into())
+        );
+        assert_eq!(
+            request(withdraw(3, &address, "1001")).await,
+            (StatusCode::BAD_REQUEST, "bad_request".into())
+        );
+        assert_eq!(
+            request(withdraw(3, &address, "10")).await,
+            (StatusCode::TOO_MANY_REQUESTS, "too_many_requests".into())
+        );
+        assert_eq!(
+            request("/stealth?address=garbage".into()).await,
+            (StatusCode::BAD_REQUEST, "bad_request".into())
+        );
+
+        let send = |index: u64, address: &str, receiver: &str, amount: &str| {
+            format!(
+                "/send?index={:#x}&address={}&receiver_address={}&new_amount={}",
+                index, address, receiver, amount
+            )
+        };
+        assert_eq!(
+            request(send(5, &address, &address, "10")).await,
+            (StatusCode::NOT_FOUND, "not_found".into())
+        );
+        assert_eq!(
+            request(send(3, &address, &address, "ten")).await,
+            (StatusCode::BAD_REQUEST, "bad_request".into())
+        );
+        assert_eq!(
+            request(send(3, "garbage", &address, "10")).await,
+            (StatusCode::BAD_REQUEST, "bad_request".into())
+        );
+        assert_eq!(
+            request(send(3, &address, "garbage", "10")).await,
+            (StatusCode::BAD_REQUEST, "bad_request".into())
+        );
+        assert_eq!(
+            request(send(3, &address, &address, "10")).await,
+            (StatusCode::TOO_MANY_REQUESTS, "too_many_requests".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_proof_dir() {
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let coin = Coin {
+            index: U256::from(3),
+            uint_token: H160::from_low_u64_be(0x1234),
+            amount: U256::from(1000),
+            pub_key: PublicKey::from(&priv_key),
+            priv_key: priv_key.clone(),
+            nullifier: U256::from(42),
+            commitment: U256::from(43),
+            denomination: U256::zero(),
+        };
+        let context = Arc::new(RwLock::new(Context {
+            coins: vec![coin],
+            tree: SparseMerkleTree::new(MERKLE_PROOF_LENGTH),
+            sync: SyncState::new(H160::from_low_u64_be(7)),
+        }));
+        let dir = tempfile::tempdir().unwrap();
+        let req = GetWithdrawRequest {
+            index: U256::from(3),
+            address: PublicKey::from(&priv_key).to_bech32(),
+            desire_amount: "10".into(),
+            to: H160::from_low_u64_be(0xbeef),
+            relayer: None,
+            fee: None,
+        };
+
+        // An unwritable directory fails the request before the proof is generated
+        let res = apis::withdraw(
+            Query(req),
+            context.clone(),
+            context.clone(),
+            priv_key.clone(),
+            &dir.path().join("spent.jsonl"),
+            Some(&dir.path().join("missing")),
+            &RelayerOpt::default(),
+        )
+        .await;
+        match res {
+            Err(apis::ApiError::Internal(err)) => {
+                assert!(err.to_string().contains("Cannot write"), "{}", err)
+            }
+            _ => panic!("Expected the proof directory to be rejected"),
+        }
+
+        // A fee needs a relayer to pay and cannot exceed the withdrawn amount
+        for (relayer, fee) in [
+            (None, U256::from(1)),
+            (Some(H160::from_low_u64_be(0xfee)), U256::from(11)),
+        ] {
+            let req = GetWithdrawRequest {
+                index: U256::from(3),
+                address: PublicKey::from(&priv_key).to_bech32(),
+                desire_amount: "10".into(),
+                to: H160::from_low_u64_be(0xbeef),
+                relayer,
+                fee: Some(fee),
+            };
+            let res = apis::withdraw(
+                Query(req),
+                context.clone(),
+                context.clone(),
+                priv_key.clone(),
+                &dir.path().join("spent.jsonl"),
+                None,
+                &RelayerOpt::default(),
+            )
+            .await;
+            assert!(matches!(res, Err(apis::ApiError::BadRequest(_))));
+        }
+
+        // Nor can a send take more than the coin holds
+        let req = GetSendRequest {
+            index: U256::from(3),
+            new_amount: "1000000".into(),
+            receiver_address: PublicKey::from(&priv_key).to_bech32(),
+            address: PublicKey::from(&priv_key).to_bech32(),
+        };
+        let res = apis::send(
+            Query(req),
+            context.clone(),
+            context.clone(),
+            priv_key.clone(),
+            &dir.path().join("spent.jsonl"),
+        )
+        .await;
+        assert!(matches!(res, Err(apis::ApiError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_send_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proof.json");
+        let opt = OwshenCliOpt::from_iter([
+            "owshen",
+            "send",
+            "--to",
+            &PublicKey::from(&PrivateKey {
+                secret: 12345.into(),
+            })
+            .to_bech32(),
+            "--amount",
+            "10wei",
+            "--token",
+            "dive",
+            "--output",
+            path.to_str().unwrap(),
+        ]);
+        match opt.command {
+            OwshenCommand::Send(send) => assert_eq!(send.output, Some(path.clone())),
+            _ => panic!("Expected the send command"),
+        }
+
+        // Checked before any proving time is spent
+        let mut file = open_output(&path).unwrap();
+        file.write_all(b"{}").unwrap();
+        file.persist(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}");
+        assert!(open_output(dir.path())
+            .unwrap_err()
+            .to_string()
+            .contains("is a directory"));
+        assert!(open_output(&dir.path().join("missing").join("proof.json"))
+            .unwrap_err()
+            .to_string()
+            .contains("Cannot write"));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let opt = OwshenCliOpt::from_iter([
+            "owshen",
+            "send",
+            "--to",
+            &PublicKey::from(&PrivateKey {
+                secret: 12345.into(),
+            })
+            .to_bech32(),
+            "--amount",
+            "10wei",
+            "--token",
+            "dive",
+            "--dry-run",
+        ]);
+        assert!(matches!(
+            opt.command,
+            OwshenCommand::Send(SendOpt { dry_run: true, .. })
+        ));
+
+        let (provider, mock) = Provider::mocked();
+        let owshen = Owshen::new(H160::from_low_u64_be(0x1234), Arc::new(provider));
+        let call = owshen.is_spent(U256::from(7));
+        mock.push(U256::from(21000)).unwrap();
+        let tx = dry_run_tx(&call, true).await.unwrap();
+        assert_eq!(tx.to, Some(owshen.address()));
+        assert_eq!(tx.value, U256::zero());
+        assert_eq!(tx.data, call.calldata().unwrap());
+        assert_eq!(tx.gas, Some(U256::from(21000)));
+        // Nothing left in the mock, so this would fail if it made a request
+        assert_eq!(dry_run_tx(&call, false).await.unwrap().gas, None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .merge(with_rate_limit(
+                Router::new().route("/withdraw", get(|| async { "ok" })),
+                2,
+            ))
+            .merge(with_rate_limit(
+                Router::new().route("/send", get(|| async { "ok" })),
+                0,
+            ))
+            .route("/info", get(|| async { "ok" }));
+        let status = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                app.oneshot(req).await.unwrap().status()
+            }
+        };
+        assert_eq!(status("/withdraw").await, StatusCode::OK);
+        assert_eq!(status("/withdraw").await, StatusCode::OK);
+        assert_eq!(status("/withdraw").await, StatusCode::TOO_MANY_REQUESTS);
+        for _ in 0..5 {
+            assert_eq!(status("/send").await, StatusCode::OK);
+            assert_eq!(status("/info").await, StatusCode::OK);
+        }
+
+        let limiter = RateLimiter::new(1, Duration::from_millis(50));
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.allow());
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        use tower::ServiceExt;
+
+        let app = with_cors(
+            Router::new().route("/info", get(|| async { "ok" })),
+            vec![parse_origin("http://127.0.0.1:3000/").unwrap()],
+        );
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/info")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app
+            .clone()
+            .oneshot(preflight("http://127.0.0.1:3000"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://127.0.0.1:3000"
+        );
+
+        let res = app.oneshot(preflight("http://evil.example")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(parse_origin("127.0.0.1:3000").is_err());
+    }
+
+    #[test]
+    fn test_revert_reason() {
+        let mut data = vec![0x08, 0xc3, 0x79, 0xa0];
+        data.extend(ethers::abi::encode(&[ethers::abi::Token::String(
+            "Insufficient allowance".into(),
+        )]));
+        let err = ContractError::<Provider<Http>>::Revert(Bytes::from(data));
+        assert_eq!(
+            revert_reason(err).to_string(),
+            "Transaction reverted: Insufficient allowance"
+        );
+        let mut data = vec![0x08, 0xc3, 0x79, 0xa0];
+        data.extend(ethers::abi::encode(&[ethers::abi::Token::String(
+            "Nullifier has been spent".into(),
+        )]));
+        let err = ContractError::<Provider<Http>>::Revert(Bytes::from(data));
+        assert!(revert_reason(err)
+            .to_string()
+            .contains("this coin was already spent"));
+        let err = ContractError::<Provider<Http>>::Revert(Bytes::from(vec![1, 2, 3]));
+        assert!(!revert_reason(err)
+            .to_string()
+            .contains("Transaction reverted"));
+    }
+
+    #[test]
+    fn test_profile_paths() {
+        let home = tempfile::tempdir().unwrap();
+        let owshen_dir = home.path().join(".owshen");
+        let testnet = profile_paths(home.path(), "testnet").unwrap();
+        assert_eq!(
+            testnet,
+            ProfilePaths {
+                wallet: owshen_dir.join("testnet.json"),
+                config: owshen_dir.join("testnet.config.json"),
+                sync: owshen_dir.join("testnet.sync.json"),
+                history: owshen_dir.join("testnet.history.jsonl"),
+                spent: owshen_dir.join("testnet.spent.jsonl"),
+            }
+        );
+        let default = profile_paths(home.path(), "default").unwrap();
+        assert_eq!(default.wallet, owshen_dir.join("default.json"));
+        assert_eq!(default.config, owshen_dir.join("default.config.json"));
+        assert!(profile_paths(home.path(), "../evil").is_err());
+        assert!(profile_paths(home.path(), "").is_err());
+
+        // The files of older versions stay with the default profile only
+        let legacy_wallet = home.path().join(".owshen-wallet.json");
+        let legacy_config = home.path().join(".config-wallet.json");
+        std::fs::write(&legacy_wallet, "{}").unwrap();
+        std::fs::write(&legacy_config, "{}").unwrap();
+        let default = profile_paths(home.path(), "default").unwrap();
+        assert_eq!(default.wallet, legacy_wallet);
+        assert_eq!(default.config, legacy_config);
+        assert_eq!(default.sync, owshen_dir.join("default.sync.json"));
+        assert_eq!(profile_paths(home.path(), "testnet").unwrap(), testnet);
+
+        let opt = OwshenCliOpt::from_iter(["owshen", "init", "--wallet", "testnet"]);
+        assert_eq!(opt.wallet, "testnet");
+        assert_eq!(
+            OwshenCliOpt::from_iter(["owshen", "info"]).wallet,
+            "default"
+        );
+    }
+
+    #[test]
+    fn test_verbose_flag() {
+        let opt = OwshenCliOpt::from_iter(["owshen", "info"]);
+        assert_eq!(log_level(opt.verbose), LevelFilter::WARN);
+        let opt = OwshenCliOpt::from_iter(["owshen", "-vv", "info"]);
+        assert_eq!(log_level(opt.verbose), LevelFilter::DEBUG);
+        let opt = OwshenCliOpt::from_iter(["owshen", "sync", "--verbose"]);
+        assert_eq!(log_level(opt.verbose), LevelFilter::INFO);
+        assert_eq!(log_level(5), LevelFilter::TRACE);
+    }
+
+    #[test]
+    fn test_render_qr() {
+        let opt = OwshenCliOpt::from_iter(["owshen", "info", "--qr"]);
+        assert!(matches!(
+            opt.command,
+            OwshenCommand::Info(InfoOpt { qr: true, .. })
+        ));
+
+        let address = PublicKey::from(&PrivateKey {
+            secret: 12345.into(),
+        })
+        .to_bech32();
+        let qr = render_qr(&address).unwrap();
+        let lines = qr.lines().collect::<Vec<_>>();
+        let width = lines[0].chars().count();
+        assert!(lines.iter().all(|l| l.chars().count() == width));
+        // Square modules, two rows per line
+        assert_eq!(lines.len(), width.div_ceil(2));
+        assert!(qr.contains('▀') || qr.contains('▄'));
+    }
+
+    #[test]
+    fn test_json_flag() {
+        let opt = OwshenCliOpt::from_iter(["owshen", "info"]);
+        assert!(!opt.json);
+        let opt = OwshenCliOpt::from_iter(["owshen", "--json", "history"]);
+        assert!(opt.json && matches!(opt.command, OwshenCommand::History(_)));
+        let opt = OwshenCliOpt::from_iter(["owshen", "info", "--json"]);
+        assert!(opt.json && matches!(opt.command, OwshenCommand::Info(_)));
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        let eth = U256::exp10(18);
+        assert_eq!(parse_amount("1.5eth").unwrap(), eth * 3 / 2);
+        assert_eq!(parse_amount("1.5 ETH").unwrap(), eth * 3 / 2);
+        assert_eq!(
+            parse_amount("500gwei").unwrap(),
+            U256::from(500) * U256::exp10(9)
+        );
+        assert_eq!(parse_amount("0.000000001gwei").unwrap(), U256::one());
+        assert_eq!(parse_amount(".5eth").unwrap(), eth / 2);
+        assert_eq!(parse_amount("1000wei").unwrap(), U256::from(1000));
+        // Amounts above the u64 range that raw wei used to overflow at
+        assert_eq!(parse_amount("100eth").unwrap(), eth * 100);
+
+        // Never rounded: trailing zeros are fine, a fraction of a wei is not
+        assert_eq!(
+            parse_amount("0.000000000000000001000eth").unwrap(),
+            U256::one()
+        );
+        assert!(parse_amount("0.0000000000000000015eth").is_err());
+        assert!(parse_amount("1.5wei").is_err());
+
+        let max = U256::MAX.to_string();
+        assert_eq!(parse_amount(&format!("{}wei", max)).unwrap(), U256::MAX);
+        let above = format!("{}6wei", &max[..max.len() - 1]);
+        assert!(parse_amount(&above)
+            .unwrap_err()
+            .to_string()
+            .contains("2^256"));
+        assert!(parse_amount(&format!("{}eth", max)).is_err());
+
+        for amount in [
+            "10", "1.5", "-1eth", "eth", ".eth", "1e18wei", "0x10wei", "1.2.3eth",
+        ] {
+            assert!(parse_amount(amount).is_err(), "{}", amount);
+        }
+        assert!(parse_amount("10")
+            .unwrap_err()
+            .to_string()
+            .contains("missing unit"));
+
+        // `eth` is a whole token of the given decimals, `gwei` and `wei` keep their size
+        assert_eq!(
+            parse_token_amount("0.1eth", 6).unwrap(),
+            U256::from(100_000)
+        );
+        assert_eq!(
+            parse_token_amount("500gwei", 6).unwrap(),
+            U256::from(500_000_000_000u64)
+        );
+        assert_eq!(parse_token_amount("3eth", 0).unwrap(), U256::from(3));
+        assert!(parse_token_amount("0.0000001eth", 6).is_err());
+        assert!(parse_token_amount("1.5eth", 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fund_guard() {
+        let opt = OwshenCliOpt::from_iter([
+            "owshen",
+            "fund",
+            "--to",
+            "0x90f8bf6a479f320ead074411a4b0e7944ea8c9c1",
+            "--amount",
+            "1.5eth",
+        ]);
+        match opt.command {
+            OwshenCommand::Fund(fund) => {
+                assert_eq!(fund.amount, U256::from(15) * U256::exp10(17))
+            }
+            _ => panic!("Expected the fund command"),
+        }
+
+        assert!(require_dev_chain(1337.into()).is_ok());
+        assert!(require_dev_chain(31337.into()).is_ok());
+        for chain_id in [1, 5, 11155111] {
+            let err = require_dev_chain(chain_id.into()).unwrap_err();
+            assert!(err.to_string().contains("Refusing"));
+        }
+
+        // Refused before the node's account is even looked up
+        let to = H160::from_low_u64_be(1);
+        let (provider, _) = mock::MockNode::new().chain_id(1).provider();
+        assert!(fund(&provider, to, U256::one())
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("Refusing"));
+        let (provider, _) = mock::MockNode::new()
+            .chain_id(1337)
+            .accounts(&[])
+            .provider();
+        assert!(fund(&provider, to, U256::one())
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("no unlocked accounts"));
+    }
+
+    #[tokio::test]
+    async fn test_no_rpc_accounts() {
+        let (provider, _) = mock::MockNode::new().accounts(&[]).provider();
+        let err = rpc_account(&provider).await.unwrap_err();
+        assert!(err.to_string().contains("no unlocked accounts"));
+
+        let accounts = [H160::from_low_u64_be(1), H160::from_low_u64_be(2)];
+        let (provider, _) = mock::MockNode::new().accounts(&accounts).provider();
+        assert_eq!(rpc_account(&provider).await.unwrap(), accounts[0]);
+
+        // Nothing listens there, creating a wallet doesn't need the node nor its accounts
+        let wallet = initialize_wallet("http://127.0.0.1:1".into(), None, false)
+            .await
+            .unwrap();
+        assert!(wallet.token_contracts.is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_wallet() {
+        let dir = tempfile::tempdir().unwrap();
+        let wallet_path = dir.path().join("wallet.json");
+        assert!(init_wallet_needed(&wallet_path, false).unwrap());
+
+        std::fs::write(&wallet_path, "{\"entropy\": [1, 2,").unwrap();
+        let err = read_wallet(&wallet_path, &None).unwrap_err();
+        assert!(err.to_string().contains("is corrupt"));
+        let err = init_wallet_needed(&wallet_path, false).unwrap_err();
+        assert!(err.to_string().contains("init --force"));
+
+        assert!(init_wallet_needed(&wallet_path, true).unwrap());
+        let wallet = Wallet {
+            key: WalletKey::Entropy(Entropy::generate(&mut rand::thread_rng())),
+            token_contracts: vec![],
+        };
+        write_wallet(&wallet_path, &wallet, "secret").unwrap();
+        assert!(!init_wallet_needed(&wallet_path, false).unwrap());
+    }
+
+    #[test]
+    fn test_private_key_wallet() {
+        let priv_key = PrivateKey::from_hex("0x1234").unwrap();
+        let wallet = Wallet {
+            key: WalletKey::PrivateKey(priv_key.clone()),
+            token_contracts: vec![],
+        };
+        let json = serde_json::to_value(&wallet).unwrap();
+        assert!(json.get("private_key").is_some());
+        assert!(json.get("entropy").is_none());
+        let wallet: Wallet = serde_json::from_value(json).unwrap();
+        assert!(wallet.priv_key() == priv_key);
+        assert!(wallet.eth_address().is_err());
+
+        assert!(export_key(&wallet, "hex", false)
+            .unwrap_err()
+            .to_string()
+            .contains("--yes"));
+        let exported = export_key(&wallet, "hex", true).unwrap();
+        assert!(PrivateKey::from_hex(&exported).unwrap() == priv_key);
+
+        // Older wallets only have their entropy
+        let entropy = Entropy::generate(&mut rand::thread_rng());
+        let old = serde_json::json!({ "entropy": entropy, "token_contracts": [] });
+        let wallet: Wallet = serde_json::from_value(old).unwrap();
+        assert!(wallet.priv_key() == PrivateKey::from(entropy));
+
+        // A mnemonic wallet's key survives an export and re-import, including the scalars above
+        // the subgroup order its seed reduction gives about 7 times in 8
+        let mut above_order = 0;
+        for i in 0..16u8 {
+            let wallet = Wallet {
+                key: WalletKey::Entropy(Entropy { value: vec![i; 16] }),
+                token_contracts: vec![],
+            };
+            let exported = export_key(&wallet, "hex", true).unwrap();
+            assert!(PrivateKey::from_hex(&exported).unwrap() == wallet.priv_key());
+            let secret = num_bigint::BigUint::parse_bytes(&exported.as_bytes()[2..], 16).unwrap();
+            if secret >= *keys::ORDER {
+                above_order += 1;
+            }
+        }
+        assert!(above_order > 0);
+        assert!(
+            serde_json::from_value::<Wallet>(serde_json::json!({ "token_contracts": [] })).is_err()
+        );
+
+        assert!(OwshenCliOpt::from_iter_safe(["owshen", "init", "--private-key", "0x0"]).is_err());
+        let opt = OwshenCliOpt::from_iter(["owshen", "init", "--private-key", &exported]);
+        assert!(matches!(
+            opt.command,
+            OwshenCommand::Init(InitOpt {
+                private_key: Some(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_config_settings() {
+        let mut config = Config::default();
+        config
+            .set(ConfigKey::Endpoint, "http://127.0.0.1:8545")
+            .unwrap();
+        assert_eq!(config.get(ConfigKey::Endpoint), "http://127.0.0.1:8545");
+        assert!(config.set(ConfigKey::Endpoint, "not a url").is_err());
+        assert_eq!(config.endpoint, "http://127.0.0.1:8545");
+
+        let checksummed = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        config
+            .set(
+                ConfigKey::OwshenContractAddress,
+                &checksummed.to_lowercase(),
+            )
+            .unwrap();
+        assert_eq!(config.get(ConfigKey::OwshenContractAddress), checksummed);
+        config
+            .set(ConfigKey::DiveContractAddress, checksummed)
+            .unwrap();
+        let err = config
+            .set(
+                ConfigKey::VerifierContractAddress,
+                "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD",
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+        assert_eq!(config.verifier_contract_address, H160::zero());
+
+        let opt = OwshenCliOpt::from_iter(["owshen", "config", "set", "endpoint", "http://x"]);
+        assert!(matches!(
+            opt.command,
+            OwshenCommand::Config(ConfigOpt {
+                action: Some(ConfigAction::Set {
+                    key: ConfigKey::Endpoint,
+                    ..
+                }),
+                ..
+            })
+        ));
+        assert!(OwshenCliOpt::from_iter_safe(["owshen", "config", "get", "api_port"]).is_err());
+    }
+
+    #[test]
+    fn test_network() {
+        let opt = OwshenCliOpt::from_iter(["owshen", "sync", "--network", "Sepolia"]);
+        assert_eq!(opt.network, Some(Network::Sepolia));
+        assert!(OwshenCliOpt::from_iter_safe(["owshen", "sync", "--network", "goerli"]).is_err());
+
+        let mut config = Config {
+            endpoint: "http://configured:8545".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(config.select_endpoint(None, None), "http://configured:8545");
+        assert_eq!(config.network, None);
+        assert_eq!(
+            config.select_endpoint(
+                Some("http://custom:8545".to_string()),
+                Some(Network::Mainnet)
+            ),
+            "http://custom:8545"
+        );
+        assert_eq!(config.network, Some(Network::Mainnet));
+        assert_eq!(
+            config.select_endpoint(None, Some(Network::Localhost)),
+            "http://127.0.0.1:8545"
+        );
+
+        assert!(check_chain_id(Network::Mainnet, 1.into()).is_ok());
+        assert!(check_chain_id(Network::Localhost, 31337.into()).is_ok());
+        let err = check_chain_id(Network::Mainnet, 11155111.into()).unwrap_err();
+        assert!(err.to_string().contains("refusing"));
+        assert!(check_chain_id(Network::Sepolia, 1337.into()).is_err());
+
+        config.set(ConfigKey::Network, "sepolia").unwrap();
+        assert_eq!(config.get(ConfigKey::Network), "sepolia");
+        assert!(config.set(ConfigKey::Network, "ropsten").is_err());
+    }
+
+    #[test]
+    fn test_scan_dump() {
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let mine = mock::deposit_event(3, 2, (&priv_key).into());
+        let mut commitments = (0..5)
+            .map(|i| {
+                let other = PrivateKey {
+                    secret: (1000 + i).into(),
+                };
+                mock::deposit_event(i, i, (&other).into())
+            })
+            .collect::<Vec<_>>();
+        commitments.insert(2, mine.clone());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.json");
+        std::fs::write(&path, serde_json::to_string(&commitments).unwrap()).unwrap();
+        let owshen_contract = H160::from_low_u64_be(2);
+        let stealth_priv = priv_key.derive(keys::EphemeralKey {
+            point: mine.ephemeral,
+        });
+        let expected = vec![CoinInfo {
+            index: U256::from(2),
+            amount: U256::from(100),
+            token: H160::from_low_u64_be(1),
+            nullifier: stealth_priv.nullifier(2, 1337, owshen_contract).into(),
+        }];
+        let scan = |chain_id, contract| {
+            scan_commitments(
+                read_commitment_dump(&path).unwrap(),
+                &priv_key,
+                chain_id,
+                contract,
+            )
+        };
+        // A bare array doesn't tell the deployment
+        assert!(scan(None, None).is_err());
+        assert_eq!(scan(Some(1337), Some(owshen_contract)).unwrap(), expected);
+
+        // A sync cache holds the same events
+        let mut state = SyncState::new(owshen_contract);
+        state.chain_id = 1337;
+        state.commitments = commitments;
+        state.save(&path).unwrap();
+        assert_eq!(scan(None, None).unwrap(), expected);
+        assert_ne!(scan(Some(1), None).unwrap(), expected);
+
+        std::fs::write(&path, "[{\"index\": 1}]").unwrap();
+        assert!(read_commitment_dump(&path).is_err());
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let to = PublicKey::from(&PrivateKey {
+            secret: 23456.into(),
+        });
+        let mut state = SyncState::new(H160::from_low_u64_be(2));
+        state.commitments.push(mock::deposit_event(1, 0, to));
+        state.commitments.push(mock::deposit_event(2, 5, to));
+        let tree = state.tree().unwrap();
+
+        let result = merkle_proof(&tree, 5).unwrap();
+        assert_eq!(
+            result.leaf,
+            Fp::try_from(state.commitments[1].commitment).unwrap()
+        );
+        assert_eq!(result.siblings.len(), MERKLE_PROOF_LENGTH);
+        assert_eq!(result.root, tree.root());
+        let proof = tree::MerkleProof {
+            value: result.leaf,
+            proof: result.siblings.clone(),
+        };
+        assert!(SparseMerkleTree::verify(result.root, MERKLE_PROOF_LENGTH, 5, &proof).unwrap());
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(
+            json["siblings"].as_array().unwrap().len(),
+            MERKLE_PROOF_LENGTH
+        );
+        assert_eq!(json["root"], serde_json::to_value(tree.root()).unwrap());
+
+        let err = merkle_proof(&tree, 1).unwrap_err();
+        assert!(err.to_string().contains("is empty"));
+        let err = merkle_proof(&tree, 1 << 32).unwrap_err();
+        assert!(err.to_string().contains("out of range"));
+    }
+
+    #[test]
+    fn test_corrupt_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        assert!(read_config(&config_path).unwrap().is_none());
+
+        std::fs::write(&config_path, "{\"name\": \"Owshen\",").unwrap();
+        let err = read_config(&config_path).unwrap_err();
+        assert!(err.to_string().contains("is corrupt"));
+
+        std::fs::write(
+            &config_path,
+            serde_json::to_string(&Config::default()).unwrap(),
+        )
+        .unwrap();
+        assert!(read_config(&config_path).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_import_payment() {
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let (ephemeral, stealth) = PublicKey::from(&priv_key).derive(&mut rand::thread_rng());
+        let request = PaymentRequest {
+            stealth_address: stealth.point,
+            ephemeral: ephemeral.point,
+            amount: U256::from(100),
+            token: H160::from_low_u64_be(1),
+        };
+        let mut state = SyncState::new(H160::from_low_u64_be(2));
+        assert!(import_payment(&state, &priv_key, &request).is_err());
+
+        let commitment: U256 = request.commitment().unwrap().into();
+        state.commitments.push(sync::CommitmentEvent {
+            block_number: 1,
+            index: U256::from(3),
+            ephemeral: ephemeral.point,
+            hint_amount: request.amount,
+            hint_token_address: h160_to_u256(request.token),
+            commitment,
+            denomination: U256::zero(),
+        });
+        let found = import_payment(&state, &priv_key, &request).unwrap();
+        assert_eq!(
+            found,
+            ImportResult {
+                index: U256::from(3),
+                commitment,
+                spent: false
+            }
+        );
+
+        let nullifier = state.coins(&priv_key)[0].nullifier;
+        state.spends.push(sync::SpendEvent {
+            block_number: 2,
+            nullifier,
+        });
+        assert!(import_payment(&state, &priv_key, &request).unwrap().spent);
+
+        let other = PrivateKey {
+            secret: 34567.into(),
+        };
+        assert!(import_payment(&state, &other, &request).is_err());
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let wallet_path = dir.path().join("wallet.json");
+        let password_file = dir.path().join("password");
+        std::fs::write(&password_file, "secret\n").unwrap();
+        let password_file = Some(password_file);
+
+        let mnemonic = Mnemonic::parse(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon art",
+        )
+        .unwrap();
+        let wallet = Wallet {
+            key: WalletKey::Entropy(Entropy::from_mnemonic(mnemonic)),
+            token_contracts: vec![],
+        };
+        let address = PublicKey::from(wallet.priv_key());
+        write_wallet(&wallet_path, &wallet, "secret").unwrap();
+        let config = Config {
+            owshen_contract_address: H160::from_low_u64_be(0x1234),
+            ..Default::default()
+        };
+        let backup = export_backup(&wallet_path, &config).unwrap();
+        let backup_path = dir.path().join("backup.json");
+        std::fs::write(&backup_path, serde_json::to_string(&backup).unwrap()).unwrap();
+
+        let restored_path = dir.path().join("restored").join("wallet.json");
+        let backup = read_backup(&backup_path).unwrap();
+        let mut restored_config = Config::default();
+        restore_backup(&backup, &restored_path, &mut restored_config, false).unwrap();
+        let restored = read_wallet(&restored_path, &password_file)
+            .unwrap()
+            .unwrap();
+        assert_eq!(PublicKey::from(restored.priv_key()), address);
+        assert_eq!(
+            restored_config.owshen_contract_address,
+            config.owshen_contract_address
+        );
+
+        assert!(restore_backup(&backup, &restored_path, &mut restored_config, false).is_err());
+        restore_backup(&backup, &restored_path, &mut restored_config, true).unwrap();
+
+        let mut newer = serde_json::to_value(&backup).unwrap();
+        newer["version"] = serde_json::json!(BACKUP_VERSION + 1);
+        std::fs::write(&backup_path, newer.to_string()).unwrap();
+        assert!(read_backup(&backup_path)
+            .unwrap_err()
+            .to_string()
+            .contains("not supported"));
+    }
+
+    #[tokio::test]
+    async fn test_fees() {
+        let parse = |args: &[&str]| {
+            OwshenCliOpt::from_iter_safe(["owshen", "deploy"].iter().chain(args)).map(|opt| {
+                match opt.command {
+                    OwshenCommand::Deploy(DeployOpt { gas, .. }) => gas,
+                    _ => unreachable!(),
+                }
+            })
+        };
+        assert_eq!(parse(&[]).unwrap(), GasOpt::default());
+        let gas = parse(&["--max-fee", "30000000000", "--priority-fee", "1000000000"]).unwrap();
+        assert_eq!(gas.max_fee, Some(U256::from(30_000_000_000u64)));
+        assert!(parse(&["--gas-price", "1", "--max-fee", "2"]).is_err());
+
+        let (provider, mock) = Provider::mocked();
+        mock.push(Block::<H256> {
+            base_fee_per_gas: Some(U256::from(7)),
+            ..Default::default()
+        })
+        .unwrap();
+        let fees = resolve_fees(&provider, &gas).await.unwrap();
+        assert!(!fees.legacy);
+        mock.push(Block::<H256>::default()).unwrap();
+        assert!(
+            resolve_fees(&provider, &GasOpt::default())
+                .await
+                .unwrap()
+                .legacy
+        );
+        let inverted = GasOpt {
+            max_fee: Some(U256::from(1)),
+            priority_fee: Some(U256::from(2)),
+            ..Default::default()
+        };
+        assert!(resolve_fees(&provider, &inverted).await.is_err());
+
+        let mut tx = TypedTransaction::Eip1559(Default::default());
+        apply_fees(&mut tx, &fees);
+        match &tx {
+            TypedTransaction::Eip1559(inner) => {
+                assert_eq!(inner.max_fee_per_gas, gas.max_fee);
+                assert_eq!(inner.max_priority_fee_per_gas, gas.priority_fee);
+            }
+            _ => panic!("expected an EIP-1559 transaction"),
+        }
+        let legacy = GasOpt {
+            gas_price: Some(U256::from(5)),
+            legacy: true,
+            ..Default::default()
+        };
+        apply_fees(&mut tx, &legacy);
+        assert!(matches!(tx, TypedTransaction::Legacy(_)));
+        assert_eq!(tx.gas_price(), Some(U256::from(5)));
+    }
+
+    #[tokio::test]
+    async fn test_owshen_code() {
+        let address = H160::from_low_u64_be(0x1234);
+        let (provider, _) = mock::MockNode::new().code(Bytes::new()).provider();
+        let err = require_owshen_code(&Owshen::new(address, Arc::new(provider)))
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Owshen contract not found or incompatible at 0x0000"));
+        assert!(err.to_string().contains("is this an account"));
+
+        // Some other contract, whose `root()` returns nothing
+        let (provider, _) = mock::MockNode::new()
+            .code(Bytes::from(vec![0x60, 0x80]))
+            .call(Bytes::new())
+            .provider();
+        let err = require_owshen_code(&Owshen::new(address, Arc::new(provider)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("root() failed"));
+
+        let (provider, _) = mock::MockNode::new()
+            .code(Bytes::from(vec![0x60, 0x80]))
+            .call(Bytes::from([0u8; 32]))
+            .provider();
+        require_owshen_code(&Owshen::new(address, Arc::new(provider)))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_doctor() {
+        let dir = tempfile::tempdir().unwrap();
+        let wallet_path = dir.path().join("wallet.json");
+        let config_path = dir.path().join("config.json");
+
+        let checks = doctor(&wallet_path, &config_path, None, None).await;
+        let names = checks.iter().map(|c| c.name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "Proving key",
+                "Wallet",
+                "Config",
+                "RPC endpoint",
+                "Owshen contract"
+            ]
+        );
+        for check in checks.iter().filter(|c| c.critical) {
+            assert!(check.result.is_err(), "{} passed", check.name);
+        }
+        assert!(checks[1]
+            .result
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("owshen init"));
+
+        std::fs::write(&wallet_path, "{").unwrap();
+        let checks = doctor(&wallet_path, &config_path, Some("not a url".into()), None).await;
+        assert!(checks[1].result.is_err());
+        assert!(checks[3]
+            .result
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("Invalid RPC endpoint"));
+        assert!(checks[4]
+            .result
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("unreachable"));
+    }
+
+    #[test]
+    fn test_verifier_address() {
+        let owshen = H160::from_str("0x5fbdb2315678afecb367f032d93f642f64180aa3").unwrap();
+        assert_eq!(
+            verifier_address(owshen),
+            ethers::utils::get_contract_address(owshen, U256::from(2))
+        );
+        assert_ne!(
+            verifier_address(owshen),
+            ethers::utils::get_contract_address(owshen, U256::from(1))
+        );
+
+        // Configs written before the verifier was recorded still load
+        let mut config = serde_json::to_value(Config::default()).unwrap();
+        config
+            .as_object_mut()
+            .unwrap()
+            .remove("verifier_contract_address");
+        let config: Config = serde_json::from_value(config).unwrap();
+        assert_eq!(config.verifier_contract_address, H160::zero());
+    }
+
+    #[test]
+    fn test_resolve_token() {
+        let config = Config {
+            dive_contract_address: H160::from_low_u64_be(0x1234),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_token(&config, "DIVE").unwrap(),
+            config.dive_contract_address
+        );
+        let address = H160::from_low_u64_be(0x5678);
+        assert_eq!(
+            resolve_token(&config, &format!("{:?}", address)).unwrap(),
+            address
+        );
+        assert!(resolve_token(&config, "WETH").is_err());
+    }
 
     #[tokio::test]
+    #[cfg_attr(
+        not(feature = "ganache-tests"),
+        ignore = "spawns Ganache, run with --features ganache-tests"
+    )]
     async fn test_poseidon() {
         let port = 8545u16;
         let url = format!("http://localhost:{}", port).to_string();
@@ -832,8 +5522,7 @@ mod tests {
         let _ganache = Ganache::new().port(port).spawn();
         let provider = Provider::<Http>::try_from(url).unwrap();
         let provider = Arc::new(provider);
-        let accounts = provider.get_accounts().await.unwrap();
-        let from = accounts[0];
+        let from = rpc_account(&provider).await.unwrap();
 
         let abi = serde_json::from_str::<Abi>(include_str!("assets/poseidon4.abi")).unwrap();
         let bytecode = Bytes::from_str(include_str!("assets/poseidon4.evm")).unwrap();
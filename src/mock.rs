@@ -0,0 +1,161 @@
+use bindings::owshen::SentFilter;
+use ethers::prelude::*;
+use serde::Serialize;
+
+use crate::fp::Fp;
+use crate::h160_to_u256;
+use crate::hash;
+use crate::keys::PublicKey;
+use crate::sync::CommitmentEvent;
+
+// Canned node answers, so that the tests don't need Ganache. Given in the order the calls are
+// made, while `MockProvider` itself pops its responses from the back
+#[derive(Default)]
+pub struct MockNode {
+    responses: Vec<MockResponse>,
+}
+
+impl MockNode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn respond<T: Serialize>(mut self, value: T) -> Self {
+        self.responses.push(MockResponse::Value(
+            serde_json::to_value(value).expect("Response is serializable!"),
+        ));
+        self
+    }
+
+    pub fn accounts(self, accounts: &[H160]) -> Self {
+        self.respond(accounts)
+    }
+
+    pub fn chain_id(self, chain_id: u64) -> Self {
+        self.respond(U256::from(chain_id))
+    }
+
+    pub fn block_number(self, number: u64) -> Self {
+        self.respond(U64::from(number))
+    }
+
+    pub fn block(self, number: u64, hash: H256) -> Self {
+        self.respond(Block::<H256> {
+            number: Some(number.into()),
+            hash: Some(hash),
+            ..Default::default()
+        })
+    }
+
+    pub fn code(self, code: Bytes) -> Self {
+        self.respond(code)
+    }
+
+    // Return data of an `eth_call`
+    pub fn call(self, output: Bytes) -> Self {
+        self.respond(output)
+    }
+
+    pub fn logs(self, logs: Vec<Log>) -> Self {
+        self.respond(logs)
+    }
+
+    // An `eth_call`, `eth_estimateGas` or `eth_sendTransaction` failing on a `require(false, reason)`
+    pub fn revert(mut self, reason: &str) -> Self {
+        let mut data = vec![0x08, 0xc3, 0x79, 0xa0];
+        data.extend(ethers::abi::encode(&[ethers::abi::Token::String(
+            reason.into(),
+        )]));
+        self.responses.push(MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: format!("execution reverted: {}", reason),
+            data: Some(Bytes::from(data).to_string().into()),
+        }));
+        self
+    }
+
+    pub fn estimate_gas(self, gas: u64) -> Self {
+        self.respond(U256::from(gas))
+    }
+
+    pub fn tx_hash(self, hash: H256) -> Self {
+        self.respond(hash)
+    }
+
+    // What a `PendingTransaction` polls for: the transaction in block `number`, then its receipt
+    pub fn mined(self, hash: H256, number: u64, status: u64) -> Self {
+        self.respond(Transaction {
+            hash,
+            block_number: Some(number.into()),
+            ..Default::default()
+        })
+        .respond(TransactionReceipt {
+            transaction_hash: hash,
+            block_number: Some(number.into()),
+            status: Some(status.into()),
+            ..Default::default()
+        })
+    }
+
+    // For a later round of calls, once `mock` has given all of its previous answers
+    pub fn push_to(self, mock: &MockProvider) {
+        for response in self.responses.into_iter().rev() {
+            mock.push_response(response);
+        }
+    }
+
+    pub fn provider(self) -> (Provider<MockProvider>, MockProvider) {
+        let (provider, mock) = Provider::mocked();
+        self.push_to(&mock);
+        (provider, mock)
+    }
+}
+
+// A deposit of 100 of token 0x..01 to `to`
+pub fn deposit_event(block_number: u64, index: u64, to: PublicKey) -> CommitmentEvent {
+    let (ephemeral, stealth_pub) = to.derive(&mut rand::thread_rng());
+    let token = h160_to_u256(H160::from_low_u64_be(1));
+    let commitment = hash::commitment(
+        stealth_pub.point.x,
+        stealth_pub.point.y,
+        Fp::from(100),
+        Fp::try_from(token).unwrap(),
+    );
+    CommitmentEvent {
+        block_number,
+        index: U256::from(index),
+        ephemeral: ephemeral.point,
+        hint_amount: U256::from(100),
+        hint_token_address: token,
+        commitment: commitment.into(),
+        denomination: U256::zero(),
+    }
+}
+
+// The `Sent` log the contract emits for `event`, in a block whose hash is its number
+pub fn sent_log(owshen_contract: H160, event: &CommitmentEvent) -> Log {
+    use ethers::abi::Token;
+    let data = ethers::abi::encode(&[
+        Token::Tuple(vec![
+            Token::Uint(event.ephemeral.x.into()),
+            Token::Uint(event.ephemeral.y.into()),
+        ]),
+        Token::Uint(event.index),
+        Token::Uint(U256::from(1234)),
+        Token::Uint(event.hint_amount),
+        Token::Uint(event.hint_token_address),
+        Token::Uint(event.commitment),
+        Token::Uint(event.denomination),
+    ]);
+    Log {
+        address: owshen_contract,
+        topics: vec![SentFilter::signature()],
+        data: data.into(),
+        block_hash: Some(H256::from_low_u64_be(event.block_number)),
+        block_number: Some(event.block_number.into()),
+        transaction_hash: Some(H256::from_low_u64_be(event.block_number * 10)),
+        transaction_index: Some(0.into()),
+        log_index: Some(event.index.low_u64().into()),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,136 @@
+use bech32::{FromBase32, ToBase32, Variant};
+use ethers::prelude::*;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::deposit_commitment;
+use crate::fp::Fp;
+use crate::keys::{EphemeralKey, Point, PrivateKey, PublicKey};
+
+const PAYMENT_HRP: &str = "owshenpay";
+
+// Out-of-band data of a payment to a stealth address. Handing it to the recipient lets them find
+// the coin by its commitment, without trying every ephemeral key of the pool
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PaymentRequest {
+    pub stealth_address: Point,
+    pub ephemeral: Point,
+    pub amount: U256,
+    pub token: H160,
+}
+
+impl PaymentRequest {
+    pub fn commitment(&self) -> Result<Fp> {
+        deposit_commitment(
+            PublicKey {
+                point: self.stealth_address,
+            },
+            self.amount,
+            self.token,
+        )
+    }
+
+    pub fn is_for(&self, priv_key: &PrivateKey) -> bool {
+        priv_key
+            .recover_stealth(&EphemeralKey {
+                point: self.ephemeral,
+            })
+            .point
+            == self.stealth_address
+    }
+}
+
+// Bech32m, so that it survives copy-pasting and (upper-cased) fits an alphanumeric QR code.
+// Layout: compressed stealth address, compressed ephemeral key, amount (32 bytes BE), token
+impl fmt::Display for PaymentRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut data = Vec::with_capacity(118);
+        data.extend(
+            PublicKey {
+                point: self.stealth_address,
+            }
+            .to_compressed_bytes(),
+        );
+        data.extend(
+            PublicKey {
+                point: self.ephemeral,
+            }
+            .to_compressed_bytes(),
+        );
+        let mut amount = [0u8; 32];
+        self.amount.to_big_endian(&mut amount);
+        data.extend(amount);
+        data.extend(self.token.as_bytes());
+        let encoded = bech32::encode(PAYMENT_HRP, data.to_base32(), Variant::Bech32m)
+            .expect("The human-readable prefix is valid!");
+        write!(f, "{}", encoded)
+    }
+}
+
+impl FromStr for PaymentRequest {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hrp, data, variant) = bech32::decode(s)
+            .map_err(|e| eyre::Report::msg(format!("Invalid payment request: {}!", e)))?;
+        if hrp != PAYMENT_HRP || variant != Variant::Bech32m {
+            return Err(eyre::Report::msg(format!(
+                "Invalid payment request: expected a bech32m {:?} string!",
+                PAYMENT_HRP
+            )));
+        }
+        let bytes = Vec::<u8>::from_base32(&data)?;
+        if bytes.len() != 118 {
+            return Err(eyre::Report::msg(format!(
+                "Invalid payment request: expected 118 bytes, got {}!",
+                bytes.len()
+            )));
+        }
+        Ok(Self {
+            stealth_address: PublicKey::from_compressed_bytes(&bytes[..33])?.point,
+            ephemeral: PublicKey::from_compressed_bytes(&bytes[33..66])?.point,
+            amount: U256::from_big_endian(&bytes[66..98]),
+            token: H160::from_slice(&bytes[98..]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payment_request() {
+        let mut rng = rand::thread_rng();
+        let priv_key = PrivateKey::generate(&mut rng);
+        let (ephemeral, stealth) = PublicKey::from(&priv_key).derive(&mut rng);
+        let request = PaymentRequest {
+            stealth_address: stealth.point,
+            ephemeral: ephemeral.point,
+            amount: U256::from_dec_str("1500000000000000000").unwrap(),
+            token: H160::from_low_u64_be(0x1234),
+        };
+
+        let encoded = request.to_string();
+        assert!(encoded.starts_with("owshenpay1"));
+        assert_eq!(PaymentRequest::from_str(&encoded).unwrap(), request);
+        assert_eq!(
+            PaymentRequest::from_str(&encoded.to_uppercase()).unwrap(),
+            request
+        );
+        assert!(request.is_for(&priv_key));
+        assert!(!request.is_for(&PrivateKey::generate(&mut rng)));
+        assert_eq!(
+            request.commitment().unwrap(),
+            deposit_commitment(stealth, request.amount, request.token).unwrap()
+        );
+
+        let address = PublicKey::from(&priv_key).to_bech32();
+        assert!(PaymentRequest::from_str(&address).is_err());
+        let mut typo = encoded.clone();
+        typo.pop();
+        assert!(PaymentRequest::from_str(&typo).is_err());
+    }
+}
@@ -1,18 +1,25 @@
 use crate::fp::Fp;
-use crate::keys::PublicKey;
+use crate::hash;
+use crate::keys::{PrivateKey, PublicKey};
+use crate::zkey::{read_witness, ProvingKey};
 
+use ark_bn254::{Bn254, Fq, Fq2, Fr, G1Affine, G1Projective, G2Affine};
+use ark_ec::pairing::Pairing;
+use ark_ec::CurveGroup;
+use ark_ff::PrimeField as _;
+use ark_ff::{Field as _, UniformRand as _, Zero as _};
+use ark_groth16::Groth16;
 use ff::PrimeField;
 
-use num_bigint::BigUint;
+use rayon::prelude::*;
 
 use ethers::prelude::*;
 
-use ethers::abi::ethabi::ethereum_types::FromStrRadixErr;
 use eyre::Result;
 
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::process::Command;
-use std::str::FromStr;
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct Proof {
     pub a: [U256; 2],
@@ -22,134 +29,1120 @@ pub struct Proof {
 }
 
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tempfile::NamedTempFile;
 
-pub fn prove<P: AsRef<Path>>(
-    params: P,
-    index: u32,
-    token_address: U256,
-    amount: U256,
-    new_amount1: U256,
-    new_amount2: U256,
-    address_1: PublicKey,
-    address_2: PublicKey,
-    secret: Fp,
-    proof: [[Fp; 3]; 16],
-) -> Result<Proof> {
-    let mut inputs_file = NamedTempFile::new()?;
-
-    println!(
-        "proof {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
-        index,
-        BigUint::from_str(&token_address.to_string()).unwrap(),
-        BigUint::from_str(&amount.to_string()).unwrap(),
-        BigUint::from_str(&new_amount1.to_string()).unwrap(),
-        BigUint::from_str(&new_amount2.to_string()).unwrap(),
-        BigUint::from_str(&U256::to_string(&address_1.point.x.into())).unwrap(),
-        BigUint::from_str(&U256::to_string(&address_1.point.y.into())).unwrap(),
-        BigUint::from_str(&U256::to_string(&address_2.point.x.into())).unwrap(),
-        BigUint::from_str(&U256::to_string(&address_2.point.y.into())).unwrap(),
-    );
+// Depth of the commitment tree, fixed by the withdraw circuit (`proof[16][3]`) and so by its zkey:
+// the trees proofs are taken from must have exactly this depth, changing it means a new circuit,
+// a new zkey and a new contract
+pub const MERKLE_PROOF_LENGTH: usize = 16;
+pub const VERIFYING_KEY_FILE: &str = "contracts/circuits/coin_withdraw_verif_key.json";
 
-    let json_input = format!(
-        "{{ \"index\": \"{:?}\", 
-        \"token_address\": \"{:?}\", 
-        \"amount\": \"{:?}\", 
-        \"new_amount1\": \"{:?}\", 
-        \"new_amount2\": \"{:?}\", 
-        \"pk_ax1\": \"{:?}\", 
-        \"pk_ay1\": \"{:?}\", 
-        \"pk_ax2\": \"{:?}\", 
-        \"pk_ay2\": \"{:?}\", 
-        \"secret\": \"{:?}\", 
-        \"proof\": [{}] }}",
-        index,
-        BigUint::from_str(&token_address.to_string()).unwrap(),
-        BigUint::from_str(&amount.to_string()).unwrap(),
-        BigUint::from_str(&new_amount1.to_string()).unwrap(),
-        BigUint::from_str(&new_amount2.to_string()).unwrap(),
-        BigUint::from_str(&U256::to_string(&address_1.point.x.into())).unwrap(),
-        BigUint::from_str(&U256::to_string(&address_1.point.y.into())).unwrap(),
-        BigUint::from_str(&U256::to_string(&address_2.point.x.into())).unwrap(),
-        BigUint::from_str(&U256::to_string(&address_2.point.y.into())).unwrap(),
-        BigUint::from_bytes_le(secret.to_repr().as_ref()),
-        proof
-            .iter()
-            .map(|p| format!(
-                "[{}]",
-                p.iter()
-                    .map(|p| format!(
-                        "\"{}\"",
-                        BigUint::from_bytes_le(p.to_repr().as_ref()).to_string()
-                    ))
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ))
-            .collect::<Vec<_>>()
-            .join(",")
-    );
+#[derive(Debug)]
+pub enum ProofError {
+    ParamsFileNotFound(PathBuf),
+    WitnessGenerationFailed(String),
+    InvalidMerkleProofLength(usize),
+    ValueMismatch(String),
+    InvalidParams(String),
+    ProverFailed(String),
+    Io(std::io::Error),
+}
 
-    write!(inputs_file, "{}", json_input)?;
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProofError::ParamsFileNotFound(path) => write!(
+                f,
+                "Proving key not found at {}, did you build the circuits?",
+                path.display()
+            ),
+            ProofError::WitnessGenerationFailed(msg) => {
+                write!(f, "Could not generate the witness: {}", msg)
+            }
+            ProofError::InvalidMerkleProofLength(len) => write!(
+                f,
+                "Merkle proof has {} levels, the circuit expects {}",
+                len, MERKLE_PROOF_LENGTH
+            ),
+            ProofError::ValueMismatch(msg) => {
+                write!(f, "The witness does not match the spent coin: {}", msg)
+            }
+            ProofError::InvalidParams(msg) => write!(f, "Invalid proving key: {}", msg),
+            ProofError::ProverFailed(msg) => write!(f, "Could not generate the proof: {}", msg),
+            ProofError::Io(e) => write!(f, "I/O error while proving: {}", e),
+        }
+    }
+}
 
-    let witness_file = NamedTempFile::new()?;
-    let wtns_gen_output = Command::new("contracts/circuits/coin_withdraw_cpp/coin_withdraw")
-        .arg(inputs_file.path())
-        .arg(witness_file.path())
-        .output()?;
+impl std::error::Error for ProofError {}
 
-    println!(
-        "STDOUT: {}",
-        String::from_utf8_lossy(&wtns_gen_output.stdout)
-    );
-    println!(
-        "STDERR: {}",
-        String::from_utf8_lossy(&wtns_gen_output.stderr)
+impl From<std::io::Error> for ProofError {
+    fn from(e: std::io::Error) -> Self {
+        ProofError::Io(e)
+    }
+}
+
+// Public signals of the coin-withdraw circuit, in the order of snarkjs and of the verifier: the
+// outputs (`root`, `nullifier`, `new_commitment1`, `new_commitment2`), then the public inputs
+#[derive(Clone, Debug)]
+pub struct PublicInputs {
+    pub root: Fp,
+    pub nullifier: Fp,
+    pub commitment1: Fp,
+    pub commitment2: Fp,
+    pub chain_id: u64,
+    pub contract: H160,
+    pub to: H160,
+    pub relayer: H160,
+    pub fee: U256,
+}
+
+impl PublicInputs {
+    pub fn to_vec(&self) -> Result<Vec<Fp>> {
+        Ok(vec![
+            self.root,
+            self.nullifier,
+            self.commitment1,
+            self.commitment2,
+            Fp::from(self.chain_id),
+            Fp::from(self.contract),
+            Fp::from(self.to),
+            Fp::from(self.relayer),
+            Fp::try_from(self.fee)?,
+        ])
+    }
+}
+
+// Inputs of the coin-withdraw circuit: the spent coin (its leaf `index`, `secret` and Merkle
+// branch), the two output coins, (`address_1`, `new_amount1`) and (`address_2`, `new_amount2`),
+// and the public ones: the deployment the nullifier is bound to, and for a withdrawal of
+// `new_amount1` its receiver `to` and the `fee` its `relayer` keeps (zero for a send)
+#[derive(Clone)]
+pub struct WithdrawWitness {
+    pub chain_id: u64,
+    pub contract: H160,
+    pub to: H160,
+    pub relayer: H160,
+    pub fee: U256,
+    pub index: u32,
+    pub token_address: U256,
+    pub amount: U256,
+    pub new_amount1: U256,
+    pub new_amount2: U256,
+    pub address_1: PublicKey,
+    pub address_2: PublicKey,
+    pub secret: Fp,
+    pub proof: Vec<[Fp; 3]>,
+    // The spent leaf as stored in the tree, not an input of the circuit
+    pub commitment: Fp,
+}
+
+// Like `PrivateKey`, the secret never shows up in logs. `ProveJob` prints its witness through this
+impl fmt::Debug for WithdrawWitness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WithdrawWitness")
+            .field("chain_id", &self.chain_id)
+            .field("contract", &self.contract)
+            .field("to", &self.to)
+            .field("relayer", &self.relayer)
+            .field("fee", &self.fee)
+            .field("index", &self.index)
+            .field("token_address", &self.token_address)
+            .field("amount", &self.amount)
+            .field("new_amount1", &self.new_amount1)
+            .field("new_amount2", &self.new_amount2)
+            .field("address_1", &self.address_1)
+            .field("address_2", &self.address_2)
+            .field("secret", &"<redacted>")
+            .field("proof", &self.proof)
+            .field("commitment", &self.commitment)
+            .finish()
+    }
+}
+
+impl WithdrawWitness {
+    fn check(&self) -> std::result::Result<(), ProofError> {
+        if self.proof.len() != MERKLE_PROOF_LENGTH {
+            return Err(ProofError::InvalidMerkleProofLength(self.proof.len()));
+        }
+        // The circuit would accept neither, but only after a long and pointless proving run
+        if self.new_amount1.checked_add(self.new_amount2) != Some(self.amount) {
+            return Err(ProofError::ValueMismatch(format!(
+                "{} + {} is not the coin amount {}",
+                self.new_amount1, self.new_amount2, self.amount
+            )));
+        }
+        // `Owshen.withdraw` would revert
+        if self.fee > self.new_amount1 {
+            return Err(ProofError::ValueMismatch(format!(
+                "fee {} exceeds the withdrawn {}",
+                self.fee, self.new_amount1
+            )));
+        }
+        let pub_key: PublicKey = (&PrivateKey {
+            secret: self.secret,
+        })
+            .into();
+        let to_fp = |v: U256| Fp::try_from(v).map_err(|e| ProofError::ValueMismatch(e.to_string()));
+        let leaf = hash::commitment(
+            pub_key.point.x,
+            pub_key.point.y,
+            to_fp(self.amount)?,
+            to_fp(self.token_address)?,
+        );
+        if leaf != self.commitment {
+            return Err(ProofError::ValueMismatch(format!(
+                "amount {} of token {} and the secret do not give commitment {}",
+                self.amount, self.token_address, self.commitment
+            )));
+        }
+        Ok(())
+    }
+
+    // Input file of the witness generator, every signal as a decimal string
+    fn input_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "chain_id": self.chain_id.to_string(),
+            "contract": Fp::from(self.contract).to_string(),
+            "to": Fp::from(self.to).to_string(),
+            "relayer": Fp::from(self.relayer).to_string(),
+            "fee": self.fee.to_string(),
+            "index": self.index.to_string(),
+            "token_address": self.token_address.to_string(),
+            "amount": self.amount.to_string(),
+            "new_amount1": self.new_amount1.to_string(),
+            "new_amount2": self.new_amount2.to_string(),
+            "pk_ax1": self.address_1.point.x.to_string(),
+            "pk_ay1": self.address_1.point.y.to_string(),
+            "pk_ax2": self.address_2.point.x.to_string(),
+            "pk_ay2": self.address_2.point.y.to_string(),
+            "secret": self.secret.to_string(),
+            "proof": self
+                .proof
+                .iter()
+                .map(|p| p.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    fn generate(&self) -> std::result::Result<Vec<Fr>, ProofError> {
+        let mut inputs_file = NamedTempFile::new()?;
+        write!(inputs_file, "{}", self.input_json())?;
+
+        let witness_file = NamedTempFile::new()?;
+        let wtns_gen_output = Command::new("contracts/circuits/coin_withdraw_cpp/coin_withdraw")
+            .arg(inputs_file.path())
+            .arg(witness_file.path())
+            .output()?;
+
+        tracing::debug!(
+            stdout = %String::from_utf8_lossy(&wtns_gen_output.stdout),
+            stderr = %String::from_utf8_lossy(&wtns_gen_output.stderr),
+            "Witness generator finished"
+        );
+
+        if !wtns_gen_output.status.success() || !wtns_gen_output.stderr.is_empty() {
+            return Err(ProofError::WitnessGenerationFailed(
+                String::from_utf8_lossy(&wtns_gen_output.stderr).to_string(),
+            ));
+        }
+
+        read_witness(&std::fs::read(witness_file.path())?)
+            .map_err(|e| ProofError::WitnessGenerationFailed(e.to_string()))
+    }
+}
+
+// Reached in this order by `prove_with_progress`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProveStage {
+    WitnessGenerated,
+    ConstraintsEvaluated,
+    ProofComputed,
+}
+
+impl fmt::Display for ProveStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProveStage::WitnessGenerated => write!(f, "witness generated"),
+            ProveStage::ConstraintsEvaluated => write!(f, "constraints evaluated"),
+            ProveStage::ProofComputed => write!(f, "proof computed"),
+        }
+    }
+}
+
+// Progress callback logging the time spent in each stage
+pub fn log_progress() -> impl Fn(ProveStage) + Clone {
+    let last = std::rc::Rc::new(std::cell::Cell::new(std::time::Instant::now()));
+    move |stage| {
+        let now = std::time::Instant::now();
+        tracing::info!(elapsed = ?now - last.get(), "Proving: {}", stage);
+        last.set(now);
+    }
+}
+
+#[allow(dead_code)]
+pub fn prove(pk: &ProvingKey, witness: &WithdrawWitness) -> std::result::Result<Proof, ProofError> {
+    prove_with_progress(pk, witness, |_| {})
+}
+
+// Only the leaf index is recorded, never the secret or the derived nullifier
+#[tracing::instrument(skip_all, fields(index = witness.index))]
+pub fn prove_with_progress(
+    pk: &ProvingKey,
+    witness: &WithdrawWitness,
+    on_progress: impl Fn(ProveStage) + Clone + 'static,
+) -> std::result::Result<Proof, ProofError> {
+    witness.check()?;
+    tracing::debug!(
+        amount = %witness.amount,
+        new_amount1 = %witness.new_amount1,
+        new_amount2 = %witness.new_amount2,
+        "Generating the witness"
     );
+    let values = witness.generate()?;
+    on_progress(ProveStage::WitnessGenerated);
+    let on_witness_map = on_progress.clone();
+    let ark_proof = pk
+        .prove_with_hook(&values, &mut rand::thread_rng(), move || {
+            on_witness_map(ProveStage::ConstraintsEvaluated)
+        })
+        .map_err(|e| ProofError::ProverFailed(e.to_string()))?;
+    on_progress(ProveStage::ProofComputed);
 
-    assert_eq!(wtns_gen_output.stdout.len(), 0);
-    assert_eq!(wtns_gen_output.stderr.len(), 0);
-
-    let proof_file = NamedTempFile::new()?;
-    let pub_inp_file = NamedTempFile::new()?;
-    let proof_gen_output = Command::new("snarkjs")
-        .arg("groth16")
-        .arg("prove")
-        .arg(params.as_ref().as_os_str())
-        .arg(witness_file.path())
-        .arg(proof_file.path())
-        .arg(pub_inp_file.path())
-        .output()?;
-
-    assert_eq!(proof_gen_output.stdout.len(), 0);
-    assert_eq!(proof_gen_output.stderr.len(), 0);
-
-    let generatecall_output = Command::new("snarkjs")
-        .arg("generatecall")
-        .arg(pub_inp_file.path())
-        .arg(proof_file.path())
-        .output()?;
-    let mut calldata = std::str::from_utf8(&generatecall_output.stdout)?.to_string();
-    calldata = calldata
-        .replace("\"", "")
-        .replace("[", "")
-        .replace("]", "")
-        .replace(" ", "")
-        .replace("\n", "");
-
-    let data = calldata
-        .split(",")
-        .map(|k| U256::from_str_radix(k, 16))
-        .collect::<Result<Vec<U256>, FromStrRadixErr>>()?;
-
-    let proof = Proof {
-        a: data[0..2].try_into()?,
-        b: [data[2..4].try_into()?, data[4..6].try_into()?],
-        c: data[6..8].try_into()?,
-        public: data[8..].to_vec(),
-    };
+    let mut proof = from_ark_proof(&ark_proof);
+    proof.public = values[1..=pk.num_public_inputs()]
+        .iter()
+        .map(|v| U256(v.into_bigint().0))
+        .collect();
 
-    println!("public {:?}", proof.public);
+    tracing::info!("Proof generated");
 
     Ok(proof)
 }
+
+// `prove` with the proving key read from a zkey file (and cached for the next calls)
+pub fn prove_from_zkey<P: AsRef<Path>>(
+    params: P,
+    witness: &WithdrawWitness,
+) -> std::result::Result<Proof, ProofError> {
+    prove_from_zkey_with_progress(params, witness, |_| {})
+}
+
+pub fn prove_from_zkey_with_progress<P: AsRef<Path>>(
+    params: P,
+    witness: &WithdrawWitness,
+    on_progress: impl Fn(ProveStage) + Clone + 'static,
+) -> std::result::Result<Proof, ProofError> {
+    if !params.as_ref().exists() {
+        return Err(ProofError::ParamsFileNotFound(
+            params.as_ref().to_path_buf(),
+        ));
+    }
+    let pk = ProvingKey::cached(params.as_ref())
+        .map_err(|e| ProofError::InvalidParams(e.to_string()))?;
+    prove_with_progress(&pk, witness, on_progress)
+}
+
+// Inputs of a single `prove_from_zkey` call
+#[derive(Clone, Debug)]
+pub struct ProveJob {
+    pub params: PathBuf,
+    pub witness: WithdrawWitness,
+}
+
+impl ProveJob {
+    pub fn prove(&self) -> std::result::Result<Proof, ProofError> {
+        prove_from_zkey(&self.params, &self.witness)
+    }
+}
+
+// Jobs are proven on the rayon thread pool, all of them reading the same cached proving key.
+// Results are in the order of the jobs
+#[allow(dead_code)]
+pub fn prove_many(jobs: Vec<ProveJob>) -> Vec<std::result::Result<Proof, ProofError>> {
+    jobs.par_iter().map(ProveJob::prove).collect()
+}
+
+// Verifying key as exported by `snarkjs zkey export verificationkey`
+#[derive(Clone, Debug, Deserialize)]
+struct SnarkjsVerifyingKey {
+    vk_alpha_1: Vec<String>,
+    vk_beta_2: Vec<Vec<String>>,
+    vk_gamma_2: Vec<Vec<String>>,
+    vk_delta_2: Vec<Vec<String>>,
+    #[serde(rename = "IC")]
+    ic: Vec<Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VerifyingKey {
+    pvk: ark_groth16::PreparedVerifyingKey<Bn254>,
+}
+
+fn fq_from_str(s: &str) -> Result<Fq> {
+    s.parse::<Fq>()
+        .map_err(|_| eyre::Report::msg(format!("Invalid base field element: {}", s)))
+}
+
+fn fq_from_u256(v: U256) -> Result<Fq> {
+    ark_ff::PrimeField::from_bigint(ark_ff::BigInt::new(v.0))
+        .ok_or(eyre::Report::msg("Proof coordinate is out of range!"))
+}
+
+fn g1_checked(x: Fq, y: Fq) -> Result<G1Affine> {
+    let p = G1Affine::new_unchecked(x, y);
+    if !p.is_on_curve() || !p.is_in_correct_subgroup_assuming_on_curve() {
+        return Err(eyre::Report::msg("Invalid G1 point!"));
+    }
+    Ok(p)
+}
+
+fn g2_checked(x: Fq2, y: Fq2) -> Result<G2Affine> {
+    let p = G2Affine::new_unchecked(x, y);
+    if !p.is_on_curve() || !p.is_in_correct_subgroup_assuming_on_curve() {
+        return Err(eyre::Report::msg("Invalid G2 point!"));
+    }
+    Ok(p)
+}
+
+fn g1_from_strs(p: &[String]) -> Result<G1Affine> {
+    if p.len() < 2 {
+        return Err(eyre::Report::msg("Invalid G1 point!"));
+    }
+    g1_checked(fq_from_str(&p[0])?, fq_from_str(&p[1])?)
+}
+
+fn g2_from_strs(p: &[Vec<String>]) -> Result<G2Affine> {
+    if p.len() < 2 || p[0].len() != 2 || p[1].len() != 2 {
+        return Err(eyre::Report::msg("Invalid G2 point!"));
+    }
+    g2_checked(
+        Fq2::new(fq_from_str(&p[0][0])?, fq_from_str(&p[0][1])?),
+        Fq2::new(fq_from_str(&p[1][0])?, fq_from_str(&p[1][1])?),
+    )
+}
+
+impl VerifyingKey {
+    pub fn from_snarkjs_json(json: &str) -> Result<Self> {
+        let vk: SnarkjsVerifyingKey = serde_json::from_str(json)?;
+        let vk = ark_groth16::VerifyingKey::<Bn254> {
+            alpha_g1: g1_from_strs(&vk.vk_alpha_1)?,
+            beta_g2: g2_from_strs(&vk.vk_beta_2)?,
+            gamma_g2: g2_from_strs(&vk.vk_gamma_2)?,
+            delta_g2: g2_from_strs(&vk.vk_delta_2)?,
+            gamma_abc_g1: vk
+                .ic
+                .iter()
+                .map(|p| g1_from_strs(p))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        Ok(Self {
+            pvk: ark_groth16::prepare_verifying_key(&vk),
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_snarkjs_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn num_public_inputs(&self) -> usize {
+        self.pvk.vk.gamma_abc_g1.len() - 1
+    }
+}
+
+fn from_ark_proof(proof: &ark_groth16::Proof<Bn254>) -> Proof {
+    let u256 = |v: &Fq| U256(v.into_bigint().0);
+    Proof {
+        a: [u256(&proof.a.x), u256(&proof.a.y)],
+        b: [
+            [u256(&proof.b.x.c1), u256(&proof.b.x.c0)],
+            [u256(&proof.b.y.c1), u256(&proof.b.y.c0)],
+        ],
+        c: [u256(&proof.c.x), u256(&proof.c.y)],
+        public: vec![],
+    }
+}
+
+// `proof.json` of `snarkjs groth16 prove`: affine points with a trailing projective coordinate of
+// 1, G2 coefficients in (c0, c1) order
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SnarkjsProof {
+    pi_a: Vec<String>,
+    pi_b: Vec<Vec<String>>,
+    pi_c: Vec<String>,
+    protocol: String,
+    curve: String,
+}
+
+fn u256_from_dec(s: &str) -> Result<U256> {
+    U256::from_dec_str(s).map_err(|_| eyre::Report::msg(format!("Invalid proof coordinate: {}", s)))
+}
+
+fn g1_snarkjs(p: &[U256; 2]) -> Vec<String> {
+    vec![p[0].to_string(), p[1].to_string(), "1".into()]
+}
+
+fn g1_from_snarkjs(p: &[String]) -> Result<[U256; 2]> {
+    match p {
+        [x, y, z] if z == "1" => Ok([u256_from_dec(x)?, u256_from_dec(y)?]),
+        _ => Err(eyre::Report::msg("Invalid G1 point in snarkjs proof!")),
+    }
+}
+
+// `{ proof, publicSignals }` as returned by snarkjs' `groth16.fullProve`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FullProof {
+    proof: SnarkjsProof,
+    public_signals: Vec<String>,
+}
+
+impl Proof {
+    fn to_snarkjs(&self) -> SnarkjsProof {
+        let b = &self.b;
+        SnarkjsProof {
+            pi_a: g1_snarkjs(&self.a),
+            pi_b: vec![
+                vec![b[0][1].to_string(), b[0][0].to_string()],
+                vec![b[1][1].to_string(), b[1][0].to_string()],
+                vec!["1".into(), "0".into()],
+            ],
+            pi_c: g1_snarkjs(&self.c),
+            protocol: "groth16".into(),
+            curve: "bn128".into(),
+        }
+    }
+
+    // Public signals are not part of snarkjs proofs, see `public.json`
+    #[allow(dead_code)]
+    pub fn to_snarkjs_json(&self) -> String {
+        serde_json::to_string(&self.to_snarkjs()).expect("Proof is serializable!")
+    }
+
+    pub fn to_full_proof(&self, public_inputs: &[Fp]) -> FullProof {
+        FullProof {
+            proof: self.to_snarkjs(),
+            public_signals: public_inputs
+                .iter()
+                .map(|v| U256::from(*v).to_string())
+                .collect(),
+        }
+    }
+
+    pub fn from_snarkjs_json(json: &str) -> Result<Self> {
+        let proof: SnarkjsProof = serde_json::from_str(json)?;
+        if proof.protocol != "groth16" || proof.curve != "bn128" {
+            return Err(eyre::Report::msg(format!(
+                "Unsupported {} proof on {}, expected groth16 on bn128!",
+                proof.protocol, proof.curve
+            )));
+        }
+        let b = match proof.pi_b.as_slice() {
+            [x, y, z] if x.len() == 2 && y.len() == 2 && z[..] == ["1", "0"] => [
+                [u256_from_dec(&x[1])?, u256_from_dec(&x[0])?],
+                [u256_from_dec(&y[1])?, u256_from_dec(&y[0])?],
+            ],
+            _ => return Err(eyre::Report::msg("Invalid G2 point in snarkjs proof!")),
+        };
+        Ok(Proof {
+            a: g1_from_snarkjs(&proof.pi_a)?,
+            b,
+            c: g1_from_snarkjs(&proof.pi_c)?,
+            public: vec![],
+        })
+    }
+}
+
+// `Proof` holds the points in Solidity calldata order, where the coefficients of G2 are swapped
+fn to_ark_proof(proof: &Proof) -> Result<ark_groth16::Proof<Bn254>> {
+    Ok(ark_groth16::Proof {
+        a: g1_checked(fq_from_u256(proof.a[0])?, fq_from_u256(proof.a[1])?)?,
+        b: g2_checked(
+            Fq2::new(fq_from_u256(proof.b[0][1])?, fq_from_u256(proof.b[0][0])?),
+            Fq2::new(fq_from_u256(proof.b[1][1])?, fq_from_u256(proof.b[1][0])?),
+        )?,
+        c: g1_checked(fq_from_u256(proof.c[0])?, fq_from_u256(proof.c[1])?)?,
+    })
+}
+
+pub fn verify_with_key(vk: &VerifyingKey, proof: &Proof, public_inputs: &[Fp]) -> Result<bool> {
+    if public_inputs.len() != vk.num_public_inputs() {
+        return Err(eyre::Report::msg(format!(
+            "Expected {} public inputs, got {}",
+            vk.num_public_inputs(),
+            public_inputs.len()
+        )));
+    }
+    let proof = match to_ark_proof(proof) {
+        Ok(proof) => proof,
+        Err(_) => return Ok(false),
+    };
+    let inputs = public_inputs
+        .iter()
+        .map(|v| <Fr as ark_ff::PrimeField>::from_le_bytes_mod_order(v.to_repr().as_ref()))
+        .collect::<Vec<_>>();
+    Groth16::<Bn254>::verify_proof(&vk.pvk, &proof, &inputs)
+        .map_err(|e| eyre::Report::msg(format!("Verification failed: {}", e)))
+}
+
+pub fn verify(proof: &Proof, public_inputs: &[Fp]) -> Result<bool> {
+    verify_with_key(
+        &VerifyingKey::load(VERIFYING_KEY_FILE)?,
+        proof,
+        public_inputs,
+    )
+}
+
+// Checks all of the proofs with a single final exponentiation. Each Groth16 equation
+// `e(A, B) = e(alpha, beta) * e(L, gamma) * e(C, delta)` is raised to a random power `r_i` and
+// the equations are multiplied together, so that the `L` and `C` terms of the batch collapse
+// into one pairing each: N + 2 Miller loops and a final exponentiation, where checking the
+// proofs one by one takes 3N and N of them. A batch with an invalid proof passes with
+// probability at most 1/|Fr|. False if any proof is invalid, without telling which one (check
+// them with `verify_with_key` for that). The on-chain verifier still checks one proof per call
+#[allow(dead_code)]
+pub fn batch_verify_with_key(vk: &VerifyingKey, proofs: &[(Proof, Vec<Fp>)]) -> Result<bool> {
+    let mut rng = rand::thread_rng();
+    let mut a = Vec::with_capacity(proofs.len() + 2);
+    let mut b = Vec::with_capacity(proofs.len() + 2);
+    let mut l_sum = G1Projective::zero();
+    let mut c_sum = G1Projective::zero();
+    let mut r_sum = Fr::zero();
+    for (proof, public_inputs) in proofs {
+        if public_inputs.len() != vk.num_public_inputs() {
+            return Err(eyre::Report::msg(format!(
+                "Expected {} public inputs, got {}",
+                vk.num_public_inputs(),
+                public_inputs.len()
+            )));
+        }
+        let proof = match to_ark_proof(proof) {
+            Ok(proof) => proof,
+            Err(_) => return Ok(false),
+        };
+        let inputs = public_inputs
+            .iter()
+            .map(|v| <Fr as ark_ff::PrimeField>::from_le_bytes_mod_order(v.to_repr().as_ref()))
+            .collect::<Vec<_>>();
+        let l = Groth16::<Bn254>::prepare_inputs(&vk.pvk, &inputs)
+            .map_err(|e| eyre::Report::msg(format!("Verification failed: {}", e)))?;
+        let r = Fr::rand(&mut rng);
+        a.push(proof.a * r);
+        b.push(<Bn254 as Pairing>::G2Prepared::from(proof.b));
+        l_sum += l * r;
+        c_sum += proof.c * r;
+        r_sum += r;
+    }
+    if proofs.is_empty() {
+        return Ok(true);
+    }
+    // The prepared key holds -gamma and -delta, moving their terms to the left-hand side
+    a.push(l_sum);
+    b.push(vk.pvk.gamma_g2_neg_pc.clone());
+    a.push(c_sum);
+    b.push(vk.pvk.delta_g2_neg_pc.clone());
+    let a = G1Projective::normalize_batch(&a);
+    let lhs = Bn254::multi_miller_loop(a, b);
+    Ok(match Bn254::final_exponentiation(lhs) {
+        Some(lhs) => lhs.0 == vk.pvk.alpha_g1_beta_g2.pow(r_sum.into_bigint()),
+        None => false,
+    })
+}
+
+#[allow(dead_code)]
+pub fn batch_verify(proofs: &[(Proof, Vec<Fp>)]) -> Result<bool> {
+    batch_verify_with_key(&VerifyingKey::load(VERIFYING_KEY_FILE)?, proofs)
+}
+
+// Either a snarkjs `proof.json` (possibly within a `FullProof`, as written by `send --output`)
+// or a `Proof` as returned by the wallet API
+pub fn read_proof_file<P: AsRef<Path>>(path: P) -> Result<Proof> {
+    let json = std::fs::read_to_string(path)?;
+    let value: serde_json::Value = serde_json::from_str(&json)?;
+    if value.get("pi_a").is_some() {
+        Proof::from_snarkjs_json(&json)
+    } else if let Some(proof) = value.get("proof") {
+        Proof::from_snarkjs_json(&proof.to_string())
+    } else {
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+// A JSON array of public signals, e.g. snarkjs' `public.json`, or the `publicSignals` of a
+// `FullProof`. Decimal and 0x-hex strings (or plain numbers) are accepted
+pub fn read_public_inputs<P: AsRef<Path>>(path: P) -> Result<Vec<Fp>> {
+    let mut value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if let Some(signals) = value.get_mut("publicSignals") {
+        value = signals.take();
+    }
+    let values: Vec<serde_json::Value> = serde_json::from_value(value)?;
+    values
+        .iter()
+        .map(|v| match v {
+            serde_json::Value::String(s) => s.parse::<Fp>(),
+            serde_json::Value::Number(n) => n.to_string().parse::<Fp>(),
+            _ => Err(eyre::Report::msg(format!("Invalid public input: {}", v))),
+        })
+        .collect()
+}
+
+// Positions where `public_inputs` differ from the signals the proof was generated with. Groth16
+// verification itself cannot tell which input is wrong, so this is only known for proofs that
+// carry their public signals (the ones produced by `prove`)
+pub fn mismatched_inputs(proof: &Proof, public_inputs: &[Fp]) -> Vec<usize> {
+    if proof.public.is_empty() {
+        return vec![];
+    }
+    (0..proof.public.len().max(public_inputs.len()))
+        .filter(|i| {
+            let expected = proof.public.get(*i).map(|v| Fp::try_from(*v).ok());
+            let given = public_inputs.get(*i).copied();
+            expected.flatten() != given
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_relations::lc;
+    use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+    use ark_snark::{CircuitSpecificSetupSNARK, SNARK};
+
+    // Proves knowledge of `a` and `b` such that `a * b == c`, where `c` is public
+    #[derive(Clone)]
+    struct MulCircuit {
+        a: Option<Fr>,
+        b: Option<Fr>,
+    }
+
+    impl ConstraintSynthesizer<Fr> for MulCircuit {
+        fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+            let a = cs.new_witness_variable(|| self.a.ok_or(SynthesisError::AssignmentMissing))?;
+            let b = cs.new_witness_variable(|| self.b.ok_or(SynthesisError::AssignmentMissing))?;
+            let c = cs.new_input_variable(|| {
+                Ok(self.a.ok_or(SynthesisError::AssignmentMissing)?
+                    * self.b.ok_or(SynthesisError::AssignmentMissing)?)
+            })?;
+            cs.enforce_constraint(lc!() + a, lc!() + b, lc!() + c)
+        }
+    }
+
+    fn fq_to_string(v: &Fq) -> String {
+        v.to_string()
+    }
+
+    fn g1_json(p: &G1Affine) -> serde_json::Value {
+        serde_json::json!([fq_to_string(&p.x), fq_to_string(&p.y), "1"])
+    }
+
+    fn g2_json(p: &G2Affine) -> serde_json::Value {
+        serde_json::json!([
+            [fq_to_string(&p.x.c0), fq_to_string(&p.x.c1)],
+            [fq_to_string(&p.y.c0), fq_to_string(&p.y.c1)],
+            ["1", "0"]
+        ])
+    }
+
+    fn to_snarkjs_vk(vk: &ark_groth16::VerifyingKey<Bn254>) -> String {
+        serde_json::json!({
+            "protocol": "groth16",
+            "curve": "bn128",
+            "nPublic": vk.gamma_abc_g1.len() - 1,
+            "vk_alpha_1": g1_json(&vk.alpha_g1),
+            "vk_beta_2": g2_json(&vk.beta_g2),
+            "vk_gamma_2": g2_json(&vk.gamma_g2),
+            "vk_delta_2": g2_json(&vk.delta_g2),
+            "IC": vk.gamma_abc_g1.iter().map(g1_json).collect::<Vec<_>>(),
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_native_verify() {
+        let mut rng = rand::thread_rng();
+        let circuit = MulCircuit {
+            a: Some(Fr::from(3u64)),
+            b: Some(Fr::from(5u64)),
+        };
+        let (pk, vk) = Groth16::<Bn254>::setup(circuit.clone(), &mut rng).unwrap();
+        let ark_proof = Groth16::<Bn254>::prove(&pk, circuit, &mut rng).unwrap();
+
+        let vk = VerifyingKey::from_snarkjs_json(&to_snarkjs_vk(&vk)).unwrap();
+        let proof = from_ark_proof(&ark_proof);
+        assert!(verify_with_key(&vk, &proof, &[Fp::from(15)]).unwrap());
+        assert!(!verify_with_key(&vk, &proof, &[Fp::from(16)]).unwrap());
+        assert!(verify_with_key(&vk, &proof, &[]).is_err());
+
+        let mut tampered = proof.clone();
+        tampered.a = proof.c;
+        assert!(!verify_with_key(&vk, &tampered, &[Fp::from(15)]).unwrap());
+        tampered.a = [U256::from(1), U256::from(3)];
+        assert!(!verify_with_key(&vk, &tampered, &[Fp::from(15)]).unwrap());
+    }
+
+    #[test]
+    fn test_batch_verify() {
+        let mut rng = rand::thread_rng();
+        let (pk, vk) = Groth16::<Bn254>::setup(
+            MulCircuit {
+                a: Some(Fr::from(1u64)),
+                b: Some(Fr::from(1u64)),
+            },
+            &mut rng,
+        )
+        .unwrap();
+        let vk = VerifyingKey::from_snarkjs_json(&to_snarkjs_vk(&vk)).unwrap();
+        let proofs = (1..=6u64)
+            .map(|i| {
+                let circuit = MulCircuit {
+                    a: Some(Fr::from(i)),
+                    b: Some(Fr::from(i + 1)),
+                };
+                let proof = Groth16::<Bn254>::prove(&pk, circuit, &mut rng).unwrap();
+                (from_ark_proof(&proof), vec![Fp::from(i * (i + 1))])
+            })
+            .collect::<Vec<_>>();
+        assert!(batch_verify_with_key(&vk, &[]).unwrap());
+        assert!(batch_verify_with_key(&vk, &proofs).unwrap());
+
+        // Every subset of valid and invalid proofs fails iff one of them is invalid
+        for mask in 0u32..(1 << proofs.len()) {
+            let mut batch = proofs.clone();
+            for (i, (proof, public_inputs)) in batch.iter_mut().enumerate() {
+                if mask & (1 << i) != 0 {
+                    match i % 3 {
+                        0 => public_inputs[0] += Fp::from(1),
+                        1 => proof.a = proof.c,
+                        _ => proof.a = [U256::from(1), U256::from(3)],
+                    }
+                }
+            }
+            let valid = batch
+                .iter()
+                .all(|(proof, inputs)| verify_with_key(&vk, proof, inputs).unwrap());
+            assert_eq!(valid, mask == 0);
+            assert_eq!(batch_verify_with_key(&vk, &batch).unwrap(), valid);
+        }
+
+        // Two invalid proofs that would cancel out in an unweighted product
+        let mut batch = proofs[..2].to_vec();
+        let first = batch[0].1.clone();
+        batch[0].1 = batch[1].1.clone();
+        batch[1].1 = first;
+        assert!(!batch_verify_with_key(&vk, &batch).unwrap());
+
+        let mut wrong_len = proofs;
+        wrong_len[3].1.push(Fp::from(1));
+        assert!(batch_verify_with_key(&vk, &wrong_len).is_err());
+    }
+
+    #[test]
+    fn test_snarkjs_proof() {
+        let proof = Proof::default();
+        let json = proof.to_snarkjs_json();
+        let parsed = Proof::from_snarkjs_json(&json).unwrap();
+        assert_eq!(parsed.a, proof.a);
+        assert_eq!(parsed.b, proof.b);
+        assert_eq!(parsed.c, proof.c);
+
+        let (zkey, witness) = crate::zkey::tests::test_setup();
+        let pk = ProvingKey::from_zkey(&zkey).unwrap();
+        let vk = VerifyingKey::from_snarkjs_json(&to_snarkjs_vk(&pk.pk.vk)).unwrap();
+        let ark_proof = pk.prove(&witness, &mut rand::thread_rng()).unwrap();
+        let proof = from_ark_proof(&ark_proof);
+        let json: serde_json::Value = serde_json::from_str(&proof.to_snarkjs_json()).unwrap();
+        assert_eq!(json["pi_a"], g1_json(&ark_proof.a));
+        assert_eq!(json["pi_b"], g2_json(&ark_proof.b));
+        assert_eq!(json["pi_c"], g1_json(&ark_proof.c));
+
+        let parsed = Proof::from_snarkjs_json(&json.to_string()).unwrap();
+        assert!(verify_with_key(&vk, &parsed, &[Fp::from(15), Fp::from(8)]).unwrap());
+
+        let mut plonk = json.clone();
+        plonk["protocol"] = "plonk".into();
+        assert!(Proof::from_snarkjs_json(&plonk.to_string()).is_err());
+        let mut projective = json;
+        projective["pi_a"][2] = "2".into();
+        assert!(Proof::from_snarkjs_json(&projective.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_cached_key_proof() {
+        let (zkey, witness) = crate::zkey::tests::test_setup();
+        let pk = ProvingKey::from_zkey(&zkey).unwrap();
+        let vk = VerifyingKey::from_snarkjs_json(&to_snarkjs_vk(&pk.pk.vk)).unwrap();
+        let proof = from_ark_proof(&pk.prove(&witness, &mut rand::thread_rng()).unwrap());
+        assert!(verify_with_key(&vk, &proof, &[Fp::from(15), Fp::from(8)]).unwrap());
+        assert!(!verify_with_key(&vk, &proof, &[Fp::from(15), Fp::from(9)]).unwrap());
+    }
+
+    #[test]
+    fn test_parallel_proving() {
+        fn assert_shareable<T: Send + Sync>() {}
+        assert_shareable::<ProvingKey>();
+
+        let (zkey, witness) = crate::zkey::tests::test_setup();
+        let pk = ProvingKey::from_zkey(&zkey).unwrap();
+        let vk = VerifyingKey::from_snarkjs_json(&to_snarkjs_vk(&pk.pk.vk)).unwrap();
+        // How proving scales with the threads is measured by the prove_many benchmark
+        let proofs = vec![witness; 4]
+            .par_iter()
+            .map(|witness| pk.prove(witness, &mut rand::thread_rng()).unwrap())
+            .collect::<Vec<_>>();
+        for proof in proofs.iter() {
+            let proof = from_ark_proof(proof);
+            assert!(verify_with_key(&vk, &proof, &[Fp::from(15), Fp::from(8)]).unwrap());
+        }
+    }
+
+    // Keys written by snarkjs rather than by `to_zkey`, they only exist once the circuit is built
+    // (see contracts/Makefile)
+    #[test]
+    fn test_snarkjs_zkey() {
+        let zkey = Path::new("contracts/circuits/coin_withdraw_0001.zkey");
+        if !zkey.exists() || !Path::new(VERIFYING_KEY_FILE).exists() {
+            eprintln!(
+                "Skipping, {} or {} is missing",
+                zkey.display(),
+                VERIFYING_KEY_FILE
+            );
+            return;
+        }
+        let pk = ProvingKey::from_zkey(&std::fs::read(zkey).unwrap()).unwrap();
+        assert_eq!(pk.num_public_inputs(), 9);
+        // `snarkjs zkey export verificationkey` exported the key found in the zkey
+        let vk = VerifyingKey::load(VERIFYING_KEY_FILE).unwrap();
+        assert!(vk.pvk.vk == pk.pk.vk);
+    }
+
+    // A valid zkey, though of another circuit, so that the witness gets checked
+    fn test_params() -> NamedTempFile {
+        let params = NamedTempFile::new().unwrap();
+        std::fs::write(params.path(), crate::zkey::tests::test_setup().0).unwrap();
+        params
+    }
+
+    // Spends an empty coin of the zero token, owned by secret 1
+    fn null_witness(proof_len: usize) -> WithdrawWitness {
+        let owner: PublicKey = (&PrivateKey {
+            secret: Fp::from(1),
+        })
+            .into();
+        WithdrawWitness {
+            chain_id: 1,
+            contract: H160::from_low_u64_be(7),
+            to: H160::zero(),
+            relayer: H160::zero(),
+            fee: U256::zero(),
+            index: 0,
+            token_address: U256::zero(),
+            amount: U256::zero(),
+            new_amount1: U256::zero(),
+            new_amount2: U256::zero(),
+            address_1: PublicKey::null(),
+            address_2: PublicKey::null(),
+            secret: Fp::from(1),
+            proof: vec![[Fp::from(0); 3]; proof_len],
+            commitment: hash::commitment(owner.point.x, owner.point.y, Fp::from(0), Fp::from(0)),
+        }
+    }
+
+    #[test]
+    fn test_prove_many_errors() {
+        let job = |params: &str, proof_len: usize| ProveJob {
+            params: PathBuf::from(params),
+            witness: null_witness(proof_len),
+        };
+        let params = test_params();
+        let results = prove_many(vec![
+            job("/non/existent.zkey", 16),
+            job(params.path().to_str().unwrap(), 15),
+            job("/non/existent.zkey", 16),
+        ]);
+        assert_eq!(results.len(), 3);
+        assert!(matches!(results[0], Err(ProofError::ParamsFileNotFound(_))));
+        assert!(matches!(
+            results[1],
+            Err(ProofError::InvalidMerkleProofLength(15))
+        ));
+        assert!(matches!(results[2], Err(ProofError::ParamsFileNotFound(_))));
+    }
+
+    #[test]
+    fn test_prove_errors() {
+        let params = NamedTempFile::new().unwrap();
+        assert!(matches!(
+            prove_from_zkey(Path::new("/non/existent.zkey"), &null_witness(16)),
+            Err(ProofError::ParamsFileNotFound(_))
+        ));
+        assert!(matches!(
+            prove_from_zkey(test_params().path(), &null_witness(15)),
+            Err(ProofError::InvalidMerkleProofLength(15))
+        ));
+        assert!(matches!(
+            prove_from_zkey(params.path(), &null_witness(16)),
+            Err(ProofError::InvalidParams(_))
+        ));
+
+        let (zkey, _) = crate::zkey::tests::test_setup();
+        let pk = ProvingKey::from_zkey(&zkey).unwrap();
+        assert!(matches!(
+            prove(&pk, &null_witness(15)),
+            Err(ProofError::InvalidMerkleProofLength(15))
+        ));
+    }
+
+    #[test]
+    fn test_value_mismatch() {
+        let params = test_params();
+        // The commitment holds 0, not 1000
+        let mut witness = null_witness(16);
+        witness.amount = U256::from(1000);
+        witness.new_amount1 = U256::from(1000);
+        assert!(matches!(
+            prove_from_zkey(params.path(), &witness),
+            Err(ProofError::ValueMismatch(_))
+        ));
+
+        let mut witness = null_witness(16);
+        witness.new_amount1 = U256::from(1);
+        witness.new_amount2 = U256::MAX;
+        assert!(matches!(
+            prove_from_zkey(params.path(), &witness),
+            Err(ProofError::ValueMismatch(_))
+        ));
+
+        let mut witness = null_witness(16);
+        witness.secret = Fp::from(2);
+        assert!(matches!(
+            prove_from_zkey(params.path(), &witness),
+            Err(ProofError::ValueMismatch(_))
+        ));
+
+        // The relayer can't keep more than the withdrawal
+        let mut witness = null_witness(16);
+        witness.relayer = H160::from_low_u64_be(0xfee);
+        witness.fee = U256::from(1);
+        assert!(matches!(
+            prove_from_zkey(params.path(), &witness),
+            Err(ProofError::ValueMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_tree_depth() {
+        use crate::tree::SparseMerkleTree;
+
+        // Smaller trees work on their own, but cannot feed the circuit
+        let mut tree = SparseMerkleTree::new(4);
+        tree.set(7, Fp::from(77)).unwrap();
+        let branch = tree.get(7);
+        assert!(SparseMerkleTree::verify(tree.root(), tree.depth(), 7, &branch).unwrap());
+        assert!(tree.get_with_proof::<MERKLE_PROOF_LENGTH>(7).is_err());
+
+        let mut witness = null_witness(MERKLE_PROOF_LENGTH);
+        witness.proof = branch.proof;
+        assert!(matches!(
+            prove_from_zkey(Path::new("/non/existent.zkey"), &witness),
+            Err(ProofError::ParamsFileNotFound(_))
+        ));
+        assert!(matches!(
+            prove_from_zkey(test_params().path(), &witness),
+            Err(ProofError::InvalidMerkleProofLength(4))
+        ));
+    }
+
+    #[test]
+    fn test_witness_debug() {
+        let mut witness = null_witness(16);
+        witness.secret = Fp::from(987654321);
+        let job = ProveJob {
+            params: PathBuf::from("coin_withdraw_0001.zkey"),
+            witness,
+        };
+        for debug in [format!("{:?}", job.witness), format!("{:?}", job)] {
+            assert!(debug.contains("<redacted>"));
+            assert!(!debug.contains("987654321"), "{}", debug);
+            assert!(!debug.contains(&format!("{:?}", Fp::from(987654321))));
+        }
+    }
+
+    #[test]
+    fn test_witness_input() {
+        let mut witness = null_witness(16);
+        witness.index = 3;
+        witness.amount = U256::from(1000);
+        witness.new_amount1 = U256::from(400);
+        witness.to = H160::from_low_u64_be(0xbeef);
+        witness.relayer = H160::from_low_u64_be(0xfee);
+        witness.fee = U256::from(5);
+        witness.secret = -Fp::from(1);
+        witness.proof[2][1] = Fp::from(7);
+        let input = witness.input_json();
+        assert_eq!(input["chain_id"], "1");
+        assert_eq!(input["contract"], "7");
+        assert_eq!(input["to"], "48879");
+        assert_eq!(input["relayer"], "4078");
+        assert_eq!(input["fee"], "5");
+        assert_eq!(input["index"], "3");
+        assert_eq!(input["amount"], "1000");
+        assert_eq!(input["new_amount1"], "400");
+        assert_eq!(input["new_amount2"], "0");
+        assert_eq!(input["pk_ax1"], "0");
+        assert_eq!(input["pk_ay1"], PublicKey::null().point.y.to_string());
+        assert_eq!(
+            input["secret"],
+            "21888242871839275222246405745257275088548364400416034343698204186575808495616"
+        );
+        assert_eq!(input["proof"].as_array().unwrap().len(), 16);
+        assert_eq!(input["proof"][2], serde_json::json!(["0", "7", "0"]));
+    }
+
+    #[test]
+    fn test_proof_files() {
+        let (zkey, witness) = crate::zkey::tests::test_setup();
+        let pk = ProvingKey::from_zkey(&zkey).unwrap();
+        let vk = VerifyingKey::from_snarkjs_json(&to_snarkjs_vk(&pk.pk.vk)).unwrap();
+        let mut proof = from_ark_proof(&pk.prove(&witness, &mut rand::thread_rng()).unwrap());
+
+        let dir = tempfile::tempdir().unwrap();
+        let snarkjs = dir.path().join("proof.json");
+        std::fs::write(&snarkjs, proof.to_snarkjs_json()).unwrap();
+        let public = dir.path().join("public.json");
+        std::fs::write(&public, r#"["15", "0x8"]"#).unwrap();
+
+        let inputs = read_public_inputs(&public).unwrap();
+        assert_eq!(inputs, vec![Fp::from(15), Fp::from(8)]);
+        let parsed = read_proof_file(&snarkjs).unwrap();
+        assert!(verify_with_key(&vk, &parsed, &inputs).unwrap());
+        assert!(mismatched_inputs(&parsed, &[Fp::from(15), Fp::from(9)]).is_empty());
+
+        proof.public = vec![U256::from(15), U256::from(8)];
+        let native = dir.path().join("native.json");
+        std::fs::write(&native, serde_json::to_string(&proof).unwrap()).unwrap();
+        let parsed = read_proof_file(&native).unwrap();
+        assert!(verify_with_key(&vk, &parsed, &inputs).unwrap());
+        assert!(mismatched_inputs(&parsed, &inputs).is_empty());
+        assert_eq!(
+            mismatched_inputs(&parsed, &[Fp::from(15), Fp::from(9)]),
+            vec![1]
+        );
+        assert_eq!(mismatched_inputs(&parsed, &[Fp::from(15)]), vec![1]);
+
+        std::fs::write(&public, r#"["15", true]"#).unwrap();
+        assert!(read_public_inputs(&public).is_err());
+
+        // Proof and public signals in a single file
+        let full = dir.path().join("full.json");
+        let full_proof = proof.to_full_proof(&inputs);
+        std::fs::write(&full, serde_json::to_string(&full_proof).unwrap()).unwrap();
+        let (parsed, parsed_inputs) = (
+            read_proof_file(&full).unwrap(),
+            read_public_inputs(&full).unwrap(),
+        );
+        assert_eq!(parsed_inputs, inputs);
+        assert!(verify_with_key(&vk, &parsed, &parsed_inputs).unwrap());
+    }
+}
@@ -0,0 +1,205 @@
+use ethers::contract::ContractError;
+use ethers::providers::{JsonRpcError, Middleware, MiddlewareError};
+use eyre::Result;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: Duration::from_secs(30),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+// Whether a failed call may succeed when made again: transport errors and the node's own server
+// errors (-32000 to -32099 and -32603, e.g. overloaded or rate limited). A revert, or a request the
+// node rejects as invalid, fails the same way every time
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+fn transient_response(err: &JsonRpcError) -> bool {
+    !err.is_revert() && (err.code == -32603 || (-32099..=-32000).contains(&err.code))
+}
+
+impl<E: MiddlewareError> Transient for E {
+    fn is_transient(&self) -> bool {
+        match self.as_error_response() {
+            Some(response) => transient_response(response),
+            // The node could not be reached, or answered with something else than JSON-RPC (e.g.
+            // a 5xx page)
+            None => true,
+        }
+    }
+}
+
+// `ContractError` wraps the error of the node's middleware, for contract calls given to `retry`
+#[derive(Debug)]
+pub struct CallError<M: Middleware>(pub ContractError<M>);
+
+impl<M: Middleware> Display for CallError<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<M: Middleware> Transient for CallError<M> {
+    fn is_transient(&self) -> bool {
+        match &self.0 {
+            ContractError::MiddlewareError { e } => e.is_transient(),
+            ContractError::ProviderError { e } => e.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+// Set once from the command line, before the first RPC call
+pub fn set_policy(policy: RetryPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+// Only meant for reads: a transaction whose submission timed out may still have been broadcast,
+// retrying it could send it twice
+pub async fn retry<T, E, F, Fut>(what: &str, f: F) -> Result<T>
+where
+    E: Display + Transient,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_with(&POLICY.get().copied().unwrap_or_default(), what, f).await
+}
+
+// Every attempt gets `policy.timeout`, the delay between attempts doubles. Only timeouts and
+// transient errors are retried
+pub async fn retry_with<T, E, F, Fut>(policy: &RetryPolicy, what: &str, mut f: F) -> Result<T>
+where
+    E: Display + Transient,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delay = policy.backoff;
+    let mut attempt = 0;
+    loop {
+        let err = match tokio::time::timeout(policy.timeout, f()).await {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(err)) if !err.is_transient() => {
+                return Err(eyre::Report::msg(format!("{} failed: {}", what, err)))
+            }
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => format!("timed out after {:?}", policy.timeout),
+        };
+        if attempt >= policy.retries {
+            return Err(eyre::Report::msg(format!(
+                "{} failed after {} attempts: {}",
+                what,
+                attempt + 1,
+                err
+            )));
+        }
+        tracing::warn!(attempt, "{} failed, retrying in {:?}: {}", what, delay, err);
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{MockResponse, Provider, ProviderError};
+    use ethers::types::U64;
+
+    fn fast() -> RetryPolicy {
+        RetryPolicy {
+            timeout: Duration::from_millis(200),
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    fn unavailable() -> MockResponse {
+        MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "service unavailable".into(),
+            data: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        // Responses are popped from the back
+        let (provider, mock) = Provider::mocked();
+        mock.push(U64::from(1234)).unwrap();
+        mock.push_response(unavailable());
+        mock.push_response(unavailable());
+        let block = retry_with(&fast(), "Getting the block number", || {
+            provider.get_block_number()
+        })
+        .await
+        .unwrap();
+        assert_eq!(block, U64::from(1234));
+
+        for _ in 0..3 {
+            mock.push_response(unavailable());
+        }
+        let err = retry_with(&fast(), "Getting the block number", || {
+            provider.get_block_number()
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("after 3 attempts"));
+        assert!(err.to_string().contains("service unavailable"));
+
+        let err = retry_with(&fast(), "Waiting", || {
+            std::future::pending::<Result<(), ProviderError>>()
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_revert_not_retried() {
+        let (provider, mock) = Provider::mocked();
+        mock.push(U64::from(1234)).unwrap();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: "execution reverted: Nullifier already spent".into(),
+            data: Some("0x08c379a0".into()),
+        }));
+        let err = retry_with(&fast(), "Calling the contract", || {
+            provider.get_block_number()
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Nullifier already spent"));
+        assert!(!err.to_string().contains("attempts"));
+        // The next response was left for the next call
+        assert_eq!(provider.get_block_number().await.unwrap(), U64::from(1234));
+
+        // Neither are requests the node rejects
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32601,
+            message: "the method eth_foo does not exist".into(),
+            data: None,
+        }));
+        let err = retry_with(&fast(), "Calling eth_foo", || provider.get_block_number())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+    }
+}
@@ -0,0 +1,66 @@
+use ethers::prelude::*;
+use eyre::Result;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+
+// Nullifiers of the coins this wallet has spent, recorded as soon as the transaction is sent.
+// Until a sync sees the `Spend` event, this is what keeps a coin from being proven again for a
+// transaction that can only revert. The log is append-only, one nullifier per line
+pub fn record(path: &Path, nullifier: U256) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", serde_json::to_string(&nullifier)?)?;
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<HashSet<U256>> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<U256>)
+        .collect::<Result<HashSet<_>, _>>()?)
+}
+
+// Checked before proving, the on-chain `isSpent` only knows about mined transactions
+pub fn check_unspent(path: &Path, index: U256, nullifier: U256) -> Result<()> {
+    if load(path)?.contains(&nullifier) {
+        return Err(eyre::Report::msg(format!(
+            "Coin #{} was already spent by this wallet!",
+            index
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spent_twice() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spent.jsonl");
+        let nullifier = U256::from(42);
+        assert!(load(&path).unwrap().is_empty());
+
+        check_unspent(&path, U256::from(3), nullifier).unwrap();
+        record(&path, nullifier).unwrap();
+        let err = check_unspent(&path, U256::from(3), nullifier).unwrap_err();
+        assert!(err.to_string().contains("already spent"));
+        check_unspent(&path, U256::from(4), U256::from(43)).unwrap();
+
+        record(&path, U256::from(43)).unwrap();
+        assert_eq!(
+            load(&path).unwrap(),
+            HashSet::from([nullifier, U256::from(43)])
+        );
+    }
+}
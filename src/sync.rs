@@ -0,0 +1,849 @@
+use bindings::owshen::{SentFilter, SpendFilter};
+use ethers::prelude::*;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::extract_token_amount;
+use crate::fp::Fp;
+use crate::h160_to_u256;
+use crate::keys::{EphemeralKey, Point, PrivateKey, PublicKey};
+use crate::proof::MERKLE_PROOF_LENGTH;
+use crate::rpc;
+use crate::tree::SparseMerkleTree;
+use crate::u256_to_h160;
+use crate::Coin;
+
+// Public data of a `Sent` event, enough to rebuild the tree and detect owned coins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitmentEvent {
+    pub block_number: u64,
+    pub index: U256,
+    pub ephemeral: Point,
+    pub hint_amount: U256,
+    pub hint_token_address: U256,
+    pub commitment: U256,
+    // Size of a deposit into the tree of that size and of `hint_token_address`, zero for the
+    // commitments of the main tree
+    #[serde(default)]
+    pub denomination: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpendEvent {
+    pub block_number: u64,
+    pub nullifier: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct SyncState {
+    pub owshen_contract: H160,
+    // Of the node the events come from, nullifiers are bound to it and to `owshen_contract`.
+    // Checked on every sync
+    #[serde(default)]
+    pub chain_id: u64,
+    pub last_synced_block: Option<u64>,
+    pub commitments: Vec<CommitmentEvent>,
+    pub spends: Vec<SpendEvent>,
+    // Hashes of the blocks holding events, plus the last synced one, to detect reorgs
+    #[serde(default)]
+    pub block_hashes: BTreeMap<u64, H256>,
+    // Index the next `Sent` event should have, one past the highest seen so far
+    #[serde(default)]
+    pub next_leaf_index: u64,
+}
+
+impl SyncState {
+    pub fn new(owshen_contract: H160) -> Self {
+        Self {
+            owshen_contract,
+            ..Default::default()
+        }
+    }
+
+    // Cached state of another contract is discarded
+    pub fn load(path: &Path, owshen_contract: H160) -> Result<Self> {
+        let state = match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str::<SyncState>(&s)?,
+            Err(_) => return Ok(Self::new(owshen_contract)),
+        };
+        if state.owshen_contract != owshen_contract {
+            return Ok(Self::new(owshen_contract));
+        }
+        Ok(state)
+    }
+
+    // Written to a temporary file first and renamed over the old state, so that an interrupted
+    // save never leaves a truncated file behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        file.as_file().sync_all()?;
+        file.persist(path)?;
+        Ok(())
+    }
+
+    // Forget everything seen at or after `block`, so that it gets scanned again
+    pub fn rewind(&mut self, block: u64) {
+        self.commitments.retain(|c| c.block_number < block);
+        self.spends.retain(|s| s.block_number < block);
+        self.block_hashes.retain(|number, _| *number < block);
+        self.last_synced_block = block.checked_sub(1);
+        self.next_leaf_index = self
+            .commitments
+            .iter()
+            .map(|c| c.index.low_u64() + 1)
+            .max()
+            .unwrap_or(0);
+    }
+
+    // Merges commitments supplied by an external indexer, e.g. for a light client without an
+    // archive node. Nothing is imported if any record is invalid or collides with a known
+    // commitment of the same index, duplicates are skipped. Whether the records are really the
+    // contract's can't be checked offline: the next sync replaces any that differ. Returns how
+    // many commitments were new
+    pub fn import_commitments(&mut self, records: Vec<CommitmentEvent>) -> Result<usize> {
+        let capacity = U256::from(4).pow(U256::from(MERKLE_PROOF_LENGTH));
+        let mut known = self
+            .commitments
+            .iter()
+            .map(|c| (c.index, c.commitment))
+            .collect::<HashMap<_, _>>();
+        let mut new = Vec::new();
+        for record in records {
+            let invalid = |why: String| {
+                eyre::Report::msg(format!("Invalid commitment #{}: {}", record.index, why))
+            };
+            if record.index >= capacity {
+                return Err(invalid("index out of the tree".into()));
+            }
+            Fp::try_from(record.commitment).map_err(|e| invalid(e.to_string()))?;
+            if !record.ephemeral.is_on_curve() {
+                return Err(invalid("ephemeral key is not on the curve".into()));
+            }
+            match known.get(&record.index) {
+                Some(commitment) if *commitment == record.commitment => continue,
+                Some(commitment) => {
+                    return Err(invalid(format!(
+                        "collides with known commitment {}",
+                        commitment
+                    )))
+                }
+                None => {}
+            }
+            known.insert(record.index, record.commitment);
+            new.push(record);
+        }
+        let added = new.len();
+        self.commitments.extend(new);
+        self.commitments.sort_by_key(|c| c.index);
+        self.next_leaf_index = self
+            .commitments
+            .iter()
+            .map(|c| c.index.low_u64() + 1)
+            .max()
+            .unwrap_or(0);
+        Ok(added)
+    }
+
+    // Only the hashes of event blocks and of the last synced block are worth keeping
+    fn prune_block_hashes(&mut self) {
+        let mut keep = self
+            .commitments
+            .iter()
+            .map(|c| c.block_number)
+            .chain(self.spends.iter().map(|s| s.block_number))
+            .collect::<HashSet<_>>();
+        keep.extend(self.last_synced_block);
+        self.block_hashes.retain(|number, _| keep.contains(number));
+    }
+
+    // A tree missing any of the commitments would not match the contract's root, so a commitment
+    // that does not fit in it is an error rather than skipped
+    pub fn tree(&self) -> Result<SparseMerkleTree> {
+        self.tree_of(|event| event.denomination.is_zero())
+    }
+
+    // Tree of the deposits of `denomination` of `token`, the main tree for a zero denomination
+    pub fn denomination_tree(&self, token: H160, denomination: U256) -> Result<SparseMerkleTree> {
+        if denomination.is_zero() {
+            return self.tree();
+        }
+        let token = h160_to_u256(token);
+        self.tree_of(|event| {
+            event.denomination == denomination && event.hint_token_address == token
+        })
+    }
+
+    fn tree_of<F: Fn(&CommitmentEvent) -> bool>(&self, filter: F) -> Result<SparseMerkleTree> {
+        let mut leaves = Vec::with_capacity(self.commitments.len());
+        for event in self.commitments.iter().filter(|event| filter(event)) {
+            let commitment = Fp::try_from(event.commitment).map_err(|err| {
+                eyre::Report::msg(format!("Invalid commitment #{}: {:?}", event.index, err))
+            })?;
+            leaves.push((event.index.low_u64(), commitment));
+        }
+        let mut tree = SparseMerkleTree::new(MERKLE_PROOF_LENGTH);
+        tree.set_batch(&leaves)?;
+        Ok(tree)
+    }
+
+    pub fn coins(&self, priv_key: &PrivateKey) -> Vec<Coin> {
+        let ephemerals = self
+            .commitments
+            .iter()
+            .map(|event| EphemeralKey {
+                point: event.ephemeral,
+            })
+            .collect::<Vec<_>>();
+        let owned = priv_key.scan(&ephemerals, |i, stealth_pub, shared_secret| {
+            let event = &self.commitments[i];
+            Fp::try_from(event.commitment)
+                .and_then(|commitment| {
+                    extract_token_amount(
+                        event.hint_token_address,
+                        event.hint_amount,
+                        shared_secret,
+                        commitment,
+                        *stealth_pub,
+                    )
+                })
+                .map(|found| found.is_some())
+                .unwrap_or(false)
+        });
+
+        let mut my_coins: Vec<Coin> = Vec::new();
+        for event in owned.into_iter().map(|i| &self.commitments[i]) {
+            match owned_coin(event, priv_key, self.chain_id, self.owshen_contract) {
+                Ok(Some(coin)) => my_coins.push(coin),
+                Ok(None) => {}
+                Err(err) => tracing::warn!("Skipping commitment: {:?}", err),
+            }
+        }
+        let nullifiers = my_coins.iter().map(|c| c.nullifier).collect::<Vec<_>>();
+        let spent = find_spent(&nullifiers, &self.spent_nullifiers());
+        my_coins
+            .into_iter()
+            .zip(spent)
+            .filter_map(|(coin, spent)| (!spent).then_some(coin))
+            .collect()
+    }
+
+    pub fn spent_nullifiers(&self) -> HashSet<U256> {
+        self.spends.iter().map(|s| s.nullifier).collect()
+    }
+}
+
+// Whether each of `nullifiers` is among the already spent ones
+pub fn find_spent(nullifiers: &[U256], spent: &HashSet<U256>) -> Vec<bool> {
+    nullifiers.iter().map(|n| spent.contains(n)).collect()
+}
+
+fn owned_coin(
+    event: &CommitmentEvent,
+    priv_key: &PrivateKey,
+    chain_id: u64,
+    owshen_contract: H160,
+) -> Result<Option<Coin>> {
+    let ephemeral = EphemeralKey {
+        point: event.ephemeral,
+    };
+    let stealth_priv = priv_key.derive(ephemeral);
+    let stealth_pub = PublicKey::from(&stealth_priv);
+    let shared_secret = stealth_priv.shared_secret(ephemeral);
+
+    let found = extract_token_amount(
+        event.hint_token_address,
+        event.hint_amount,
+        shared_secret,
+        Fp::try_from(event.commitment)?,
+        stealth_pub,
+    )?;
+    Ok(found.map(|(token_address, amount)| Coin {
+        index: event.index,
+        uint_token: u256_to_h160(token_address.into()),
+        amount: amount.into(),
+        nullifier: stealth_priv
+            .nullifier(event.index.low_u64(), chain_id, owshen_contract)
+            .into(),
+        priv_key: stealth_priv,
+        pub_key: stealth_pub,
+        commitment: event.commitment,
+        denomination: event.denomination,
+    }))
+}
+
+async fn block_hash<M: Middleware>(client: &M, number: u64) -> Result<Option<H256>> {
+    Ok(rpc::retry(&format!("Getting block {}", number), || {
+        client.get_block(number)
+    })
+    .await?
+    .and_then(|block| block.hash))
+}
+
+// Returns the first block to scan again, if the chain no longer contains the last synced block.
+// A block hash commits to all of its ancestors, so everything up to the newest recorded block
+// that is still there is untouched
+pub async fn find_reorg<M: Middleware>(client: &M, state: &SyncState) -> Result<Option<u64>> {
+    let last = match state.last_synced_block {
+        Some(last) => last,
+        None => return Ok(None),
+    };
+    match state.block_hashes.get(&last) {
+        Some(hash) if block_hash(client, last).await? != Some(*hash) => {}
+        _ => return Ok(None),
+    }
+    for (number, hash) in state.block_hashes.range(..last).rev() {
+        if block_hash(client, *number).await? == Some(*hash) {
+            return Ok(Some(number + 1));
+        }
+    }
+    Ok(Some(0))
+}
+
+// Blocks asked for in one eth_getLogs call, unless the provider refuses that many
+pub const DEFAULT_CHUNK: u64 = 10_000;
+
+static CHUNK: OnceLock<u64> = OnceLock::new();
+
+// Set once from the command line, before the first sync
+pub fn set_chunk(blocks: u64) {
+    let _ = CHUNK.set(blocks.max(1));
+}
+
+// Providers cap eth_getLogs by block range or by number of results, with their own wording
+fn is_range_limit(err: &str) -> bool {
+    let err = err.to_lowercase();
+    [
+        "more than",
+        "block range",
+        "range limit",
+        "too many",
+        "limit exceeded",
+    ]
+    .iter()
+    .any(|limit| err.contains(limit))
+}
+
+// Queries `start..=tip` in windows of `chunk` blocks. A window the provider refuses is halved
+// (and stays halved, the cap won't change) before the query is retried, other failures go
+// through the usual retries
+pub async fn fetch_windowed<T, E, F, Fut>(
+    what: &str,
+    start: u64,
+    tip: u64,
+    chunk: u64,
+    mut query: F,
+) -> Result<Vec<T>>
+where
+    E: std::fmt::Display + rpc::Transient,
+    F: FnMut(u64, u64) -> Fut,
+    Fut: Future<Output = Result<Vec<T>, E>>,
+{
+    let mut found = Vec::new();
+    let mut window = chunk.max(1);
+    let mut from = start;
+    while from <= tip {
+        let to = tip.min(from.saturating_add(window - 1));
+        match query(from, to).await {
+            Ok(events) => found.extend(events),
+            Err(err) if window > 1 && is_range_limit(&err.to_string()) => {
+                window /= 2;
+                tracing::debug!("{} refused, retrying with {} blocks: {}", what, window, err);
+                continue;
+            }
+            Err(_) => found.extend(rpc::retry(what, || query(from, to)).await?),
+        }
+        from = to + 1;
+    }
+    Ok(found)
+}
+
+async fn events<M: Middleware + 'static, D: EthEvent>(
+    contract: &Contract<M>,
+    what: &str,
+    start: u64,
+    tip: u64,
+) -> Result<Vec<(D, LogMeta)>> {
+    let chunk = CHUNK.get().copied().unwrap_or(DEFAULT_CHUNK);
+    fetch_windowed(what, start, tip, chunk, |from, to| async move {
+        contract
+            .event::<D>()
+            .from_block(from)
+            .to_block(to)
+            .address(ValueOrArray::Value(contract.address()))
+            .query_with_meta()
+            .await
+            .map_err(rpc::CallError)
+    })
+    .await
+}
+
+// Where the scan resumes: one past the cursor, or `since_block` when that is later. Blocks
+// between the two are never scanned, so if they hold commitments the local tree stops matching
+// the contract's (and its proofs are rejected). On a fresh state, `since_block` is meant to be
+// the block the contract was deployed at
+pub fn scan_start(state: &SyncState, since_block: Option<u64>) -> u64 {
+    let start = state.last_synced_block.map(|b| b + 1).unwrap_or(0);
+    match since_block {
+        Some(since) if since > start && state.last_synced_block.is_some() => {
+            tracing::warn!(
+                "Skipping blocks {} to {}, commitments in them will be missing from the tree!",
+                start,
+                since - 1
+            );
+            since
+        }
+        Some(since) if since > start => since,
+        _ => start,
+    }
+}
+
+// Blocks less than `confirmations` deep are left for a later sync
+#[tracing::instrument(skip_all, fields(from_block = ?from_block, since_block = ?since_block, confirmations))]
+pub async fn sync<M: Middleware + 'static>(
+    contract: &Contract<M>,
+    state: &mut SyncState,
+    from_block: Option<u64>,
+    since_block: Option<u64>,
+    confirmations: u64,
+) -> Result<()> {
+    if let Some(block) = from_block {
+        state.rewind(block);
+    }
+    let client = contract.client();
+    // Like the contract, a switch to another chain invalidates everything synced so far
+    let chain_id = rpc::retry("Getting the chain id", || client.get_chainid())
+        .await?
+        .as_u64();
+    if state.chain_id != chain_id {
+        if state.chain_id != 0 {
+            tracing::warn!(
+                "Chain id changed from {} to {}, syncing from scratch",
+                state.chain_id,
+                chain_id
+            );
+            *state = SyncState::new(state.owshen_contract);
+        }
+        state.chain_id = chain_id;
+    }
+    if let Some(block) = find_reorg(client.as_ref(), state).await? {
+        tracing::warn!("Chain reorganized, rescanning from block {}", block);
+        state.rewind(block);
+    }
+    let start = scan_start(state, since_block);
+    let tip = rpc::retry("Getting the chain tip", || client.get_block_number())
+        .await?
+        .as_u64();
+    let tip = match tip.checked_sub(confirmations) {
+        Some(tip) if tip >= start => tip,
+        _ => return Ok(()),
+    };
+
+    let sent_events =
+        events::<_, SentFilter>(contract, "Getting the Sent events", start, tip).await?;
+    // Positions of the commitments known so far, some may come from `import_commitments`
+    let mut known = state
+        .commitments
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.index, i))
+        .collect::<HashMap<_, _>>();
+    for (sent_event, meta) in sent_events {
+        let index = sent_event.index.low_u64();
+        let event = CommitmentEvent {
+            block_number: meta.block_number.as_u64(),
+            index: sent_event.index,
+            ephemeral: Point {
+                x: Fp::try_from(sent_event.ephemeral.x)?,
+                y: Fp::try_from(sent_event.ephemeral.y)?,
+            },
+            hint_amount: sent_event.hint_amount,
+            hint_token_address: sent_event.hint_token_address,
+            commitment: sent_event.commitment,
+            denomination: sent_event.denomination,
+        };
+        state
+            .block_hashes
+            .insert(meta.block_number.as_u64(), meta.block_hash);
+        if let Some(&i) = known.get(&sent_event.index) {
+            // The contract wins over an indexer
+            if state.commitments[i].commitment != event.commitment {
+                tracing::warn!("Imported commitment #{} is not the contract's", index);
+            }
+            state.commitments[i] = event;
+            continue;
+        }
+        if index != state.next_leaf_index {
+            tracing::warn!(
+                "Expected commitment #{}, got #{}",
+                state.next_leaf_index,
+                index
+            );
+        }
+        state.next_leaf_index = state.next_leaf_index.max(index + 1);
+        known.insert(event.index, state.commitments.len());
+        state.commitments.push(event);
+    }
+
+    let spend_events =
+        events::<_, SpendFilter>(contract, "Getting the Spend events", start, tip).await?;
+    for (spend_event, meta) in spend_events {
+        state
+            .block_hashes
+            .insert(meta.block_number.as_u64(), meta.block_hash);
+        state.spends.push(SpendEvent {
+            block_number: meta.block_number.as_u64(),
+            nullifier: spend_event.nullifier,
+        });
+    }
+
+    if let Some(hash) = block_hash(client.as_ref(), tip).await? {
+        state.block_hashes.insert(tip, hash);
+    }
+    state.last_synced_block = Some(tip);
+    state.prune_block_hashes();
+    tracing::info!(
+        commitments = state.commitments.len(),
+        spends = state.spends.len(),
+        "Synced up to block {}",
+        tip
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{deposit_event, sent_log, MockNode};
+
+    #[tokio::test]
+    async fn test_find_reorg() {
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let mut state = SyncState::new(H160::from_low_u64_be(2));
+        for (block, index) in [(3, 0), (7, 1), (7, 2), (10, 3)] {
+            state
+                .commitments
+                .push(deposit_event(block, index, (&priv_key).into()));
+            state
+                .block_hashes
+                .insert(block, H256::from_low_u64_be(block));
+        }
+        state.block_hashes.insert(12, H256::from_low_u64_be(12));
+        state.last_synced_block = Some(12);
+        state.prune_block_hashes();
+        assert_eq!(state.block_hashes.len(), 4);
+
+        let (provider, mock) = MockNode::new()
+            .block(12, H256::from_low_u64_be(12))
+            .provider();
+        assert_eq!(find_reorg(&provider, &state).await.unwrap(), None);
+
+        // Blocks 10 to 12 were replaced
+        MockNode::new()
+            .block(12, H256::from_low_u64_be(120))
+            .block(10, H256::from_low_u64_be(100))
+            .block(7, H256::from_low_u64_be(7))
+            .push_to(&mock);
+        assert_eq!(find_reorg(&provider, &state).await.unwrap(), Some(8));
+
+        let mut reorged = state.clone();
+        reorged.rewind(8);
+        assert_eq!(reorged.last_synced_block, Some(7));
+        assert_eq!(reorged.commitments.len(), 3);
+        assert_eq!(reorged.coins(&priv_key).len(), 3);
+        assert_eq!(reorged.block_hashes.keys().last(), Some(&7));
+
+        // Nothing recorded survived
+        let mut node = MockNode::new();
+        for (number, hash) in [(12, 120), (10, 100), (7, 70), (3, 30)] {
+            node = node.block(number, H256::from_low_u64_be(hash));
+        }
+        node.push_to(&mock);
+        assert_eq!(find_reorg(&provider, &state).await.unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_sync_state() {
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let other = PublicKey::from(PrivateKey {
+            secret: 34567.into(),
+        });
+
+        let mut state = SyncState::new(H160::from_low_u64_be(2));
+        state
+            .commitments
+            .push(deposit_event(1, 0, (&priv_key).into()));
+        state.commitments.push(deposit_event(2, 1, other));
+        state
+            .commitments
+            .push(deposit_event(3, 2, (&priv_key).into()));
+        state.last_synced_block = Some(3);
+
+        let coins = state.coins(&priv_key);
+        assert_eq!(coins.len(), 2);
+        assert_eq!(coins[0].amount, U256::from(100));
+
+        state.spends.push(SpendEvent {
+            block_number: 4,
+            nullifier: coins[0].nullifier,
+        });
+        assert_eq!(state.coins(&priv_key).len(), 1);
+        let nullifiers = [coins[1].nullifier, coins[0].nullifier, U256::from(1)];
+        assert_eq!(
+            find_spent(&nullifiers, &state.spent_nullifiers()),
+            vec![false, true, false]
+        );
+
+        let root = state.tree().unwrap().root();
+        state.rewind(3);
+        assert_eq!(state.last_synced_block, Some(2));
+        assert_eq!(state.commitments.len(), 2);
+        assert!(state.spends.is_empty());
+        assert_ne!(state.tree().unwrap().root(), root);
+        assert_eq!(state.coins(&priv_key).len(), 1);
+    }
+
+    #[test]
+    fn test_tree_out_of_range() {
+        let to = PublicKey::from(&PrivateKey {
+            secret: 23456.into(),
+        });
+        let mut state = SyncState::new(H160::from_low_u64_be(2));
+        state.commitments.push(deposit_event(1, 0, to));
+        state.tree().unwrap();
+
+        // More leaves than the circuit's tree holds
+        state.commitments.push(deposit_event(2, 1 << 32, to));
+        let err = state.tree().unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{}", err);
+    }
+
+    #[test]
+    fn test_denomination_tree() {
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let to = PublicKey::from(&priv_key);
+        let mut state = SyncState::new(H160::from_low_u64_be(2));
+        state.commitments.push(deposit_event(1, 0, to));
+        let mut pooled = deposit_event(1, 1, to);
+        pooled.denomination = U256::from(100);
+        state.commitments.push(pooled.clone());
+        state.commitments.push(deposit_event(2, 2, to));
+
+        // Each tree only holds its own leaves, at their global index
+        let tree_of = |leaves: &[&CommitmentEvent]| {
+            let mut tree = SparseMerkleTree::new(MERKLE_PROOF_LENGTH);
+            for event in leaves {
+                tree.set(
+                    event.index.low_u64(),
+                    Fp::try_from(event.commitment).unwrap(),
+                )
+                .unwrap();
+            }
+            tree.root()
+        };
+        let main = [&state.commitments[0], &state.commitments[2]];
+        assert_eq!(state.tree().unwrap().root(), tree_of(&main));
+        let token = H160::from_low_u64_be(1);
+        assert_eq!(
+            state
+                .denomination_tree(token, U256::from(100))
+                .unwrap()
+                .root(),
+            tree_of(&[&pooled])
+        );
+        assert_eq!(
+            state.denomination_tree(token, U256::zero()).unwrap().root(),
+            tree_of(&main)
+        );
+        assert_eq!(
+            state
+                .denomination_tree(H160::from_low_u64_be(2), U256::from(100))
+                .unwrap()
+                .root(),
+            tree_of(&[])
+        );
+
+        let coins = state.coins(&priv_key);
+        assert_eq!(
+            coins.iter().map(|c| c.denomination).collect::<Vec<_>>(),
+            vec![U256::zero(), U256::from(100), U256::zero()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_cursor() {
+        use bindings::owshen::Owshen;
+        use std::sync::Arc;
+
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let owshen_contract = H160::from_low_u64_be(2);
+        let log = sent_log(owshen_contract, &deposit_event(4, 0, (&priv_key).into()));
+
+        let (provider, mock) = MockNode::new()
+            .chain_id(1337)
+            .block_number(5)
+            .logs(vec![log])
+            .logs(vec![])
+            .block(5, H256::from_low_u64_be(5))
+            .provider();
+        let owshen = Owshen::new(owshen_contract, Arc::new(provider));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sync.json");
+        let mut state = SyncState::load(&path, owshen_contract).unwrap();
+        sync(&owshen, &mut state, None, None, 0).await.unwrap();
+        state.save(&path).unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let loaded = SyncState::load(&path, owshen_contract).unwrap();
+        assert_eq!(loaded.last_synced_block, Some(5));
+        assert_eq!(loaded.chain_id, 1337);
+        assert_eq!(loaded.next_leaf_index, 1);
+        assert_eq!(loaded.coins(&priv_key).len(), 1);
+
+        // Nothing new, the next sync only checks the chain id, block 5 for a reorg and the tip
+        let mut state = loaded.clone();
+        MockNode::new()
+            .chain_id(1337)
+            .block(5, H256::from_low_u64_be(5))
+            .block_number(5)
+            .push_to(&mock);
+        sync(&owshen, &mut state, None, None, 0).await.unwrap();
+        assert_eq!(state.last_synced_block, Some(5));
+
+        // The same RPC url now serves another chain, where the deposit never happened
+        let mut switched = state.clone();
+        MockNode::new()
+            .chain_id(1)
+            .block_number(3)
+            .logs(vec![])
+            .logs(vec![])
+            .block(3, H256::from_low_u64_be(3))
+            .push_to(&mock);
+        sync(&owshen, &mut switched, None, None, 0).await.unwrap();
+        assert_eq!(switched.chain_id, 1);
+        assert_eq!(switched.owshen_contract, owshen_contract);
+        assert_eq!(switched.last_synced_block, Some(3));
+        assert!(switched.commitments.is_empty());
+        assert_eq!(switched.next_leaf_index, 0);
+
+        state.rewind(4);
+        assert_eq!(state.next_leaf_index, 0);
+    }
+
+    #[tokio::test]
+    async fn test_import_commitments() {
+        use bindings::owshen::Owshen;
+        use std::sync::Arc;
+
+        let priv_key = PrivateKey {
+            secret: 23456.into(),
+        };
+        let other = PublicKey::from(PrivateKey {
+            secret: 34567.into(),
+        });
+        let owshen_contract = H160::from_low_u64_be(2);
+        let mine = deposit_event(4, 0, (&priv_key).into());
+        let theirs = deposit_event(4, 1, other);
+
+        let mut state = SyncState::new(owshen_contract);
+        let records = vec![theirs.clone(), mine.clone()];
+        assert_eq!(state.import_commitments(records.clone()).unwrap(), 2);
+        assert_eq!(state.import_commitments(records).unwrap(), 0);
+        assert_eq!(state.commitments[0].index, U256::zero());
+        assert_eq!(state.next_leaf_index, 2);
+        assert_eq!(state.coins(&priv_key).len(), 1);
+
+        // All or nothing
+        let mut colliding = deposit_event(4, 0, other);
+        colliding.index = U256::one();
+        let fresh = deposit_event(5, 2, (&priv_key).into());
+        let err = state
+            .import_commitments(vec![fresh.clone(), colliding])
+            .unwrap_err();
+        assert!(err.to_string().contains("collides"));
+        let mut invalid = fresh.clone();
+        invalid.commitment = U256::MAX;
+        assert!(state.import_commitments(vec![invalid]).is_err());
+        let mut off_curve = fresh.clone();
+        off_curve.ephemeral.x += Fp::from(1);
+        assert!(state.import_commitments(vec![off_curve]).is_err());
+        assert_eq!(state.commitments.len(), 2);
+
+        // The contract's events for the imported indices are not added a second time
+        let (provider, _) = MockNode::new()
+            .chain_id(1337)
+            .block_number(5)
+            .logs(vec![sent_log(owshen_contract, &mine)])
+            .logs(vec![])
+            .block(5, H256::from_low_u64_be(5))
+            .provider();
+        let owshen = Owshen::new(owshen_contract, Arc::new(provider));
+        sync(&owshen, &mut state, None, None, 0).await.unwrap();
+        assert_eq!(state.commitments.len(), 2);
+        assert_eq!(state.coins(&priv_key).len(), 1);
+    }
+
+    #[test]
+    fn test_since_block() {
+        let mut state = SyncState::new(H160::from_low_u64_be(2));
+        assert_eq!(scan_start(&state, None), 0);
+        // A fresh state starts at the deployment block
+        assert_eq!(scan_start(&state, Some(100)), 100);
+
+        // The cursor wins over an older bound, which never triggers a rescan
+        state.last_synced_block = Some(150);
+        assert_eq!(scan_start(&state, None), 151);
+        assert_eq!(scan_start(&state, Some(100)), 151);
+        assert_eq!(scan_start(&state, Some(151)), 151);
+        // A newer one skips blocks 151 to 199, and whatever commitments they hold
+        assert_eq!(scan_start(&state, Some(200)), 200);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_windowed() {
+        // A provider refusing more than 30 blocks per query, with an event every 7 blocks
+        let queried = std::cell::RefCell::new(Vec::new());
+        let query = |from: u64, to: u64| {
+            queried.borrow_mut().push((from, to));
+            async move {
+                if to - from + 1 > 30 {
+                    return Err(ProviderError::CustomError(
+                        "query returned more than 10000 results".into(),
+                    ));
+                }
+                Ok((from..=to).filter(|b| b % 7 == 0).collect::<Vec<_>>())
+            }
+        };
+        let found = fetch_windowed("Getting events", 3, 100, 100, query)
+            .await
+            .unwrap();
+        assert_eq!(found, (3..=100).filter(|b| b % 7 == 0).collect::<Vec<_>>());
+
+        // 100 and 50 blocks are refused, then the window stays at 25
+        let queried = queried.into_inner();
+        assert_eq!(&queried[..3], &[(3, 100), (3, 52), (3, 27)]);
+        assert_eq!(
+            &queried[3..],
+            &[(28, 52), (53, 77), (78, 100)],
+            "every later window is at most 25 blocks"
+        );
+    }
+}
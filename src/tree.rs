@@ -1,32 +1,144 @@
 use crate::fp::Fp;
 use crate::hash::hash4;
-use std::collections::HashMap;
+use eyre::Result;
+use ff::PrimeField;
+use std::any::TypeId;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Leaf indices are `u64`s, 32 levels of a 4-ary tree already address all of them
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeError {
+    IndexOutOfRange { index: u64, capacity: u64 },
+    DepthMismatch { expected: usize, found: usize },
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TreeError::IndexOutOfRange { index, capacity } => write!(
+                f,
+                "Leaf index {} is out of range, the tree holds {} leaves",
+                index, capacity
+            ),
+            TreeError::DepthMismatch { expected, found } => write!(
+                f,
+                "Merkle proof has {} levels, expected {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+// Compression function of the 4-ary tree, turning 4 children into their parent
+pub trait MerkleHasher: fmt::Debug + Clone + 'static {
+    fn hash(vals: [Fp; 4]) -> Fp;
+}
+
+// The hasher, and the bytes of the empty leaf
+type EmptySubtreeKey = (TypeId, [u8; 32]);
+
+lazy_static! {
+    // Hashes of the empty subtree of every layer. They only depend on the key, a deeper tree
+    // extends the list of a shallower one
+    static ref EMPTY_SUBTREES: Mutex<HashMap<EmptySubtreeKey, Vec<Fp>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn empty_subtrees<H: MerkleHasher>(depth: usize, empty_leaf: Fp) -> Vec<Fp> {
+    let mut cache = EMPTY_SUBTREES.lock().unwrap();
+    let defaults = cache
+        .entry((TypeId::of::<H>(), empty_leaf.to_bytes()))
+        .or_insert_with(|| vec![empty_leaf]);
+    while defaults.len() <= depth {
+        let below = defaults[defaults.len() - 1];
+        defaults.push(H::hash([below, below, below, below]));
+    }
+    defaults[..=depth].to_vec()
+}
+
+// The hash used by the Owshen contract and circuits
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoseidonHasher;
+
+impl MerkleHasher for PoseidonHasher {
+    fn hash(vals: [Fp; 4]) -> Fp {
+        hash4(vals)
+    }
+}
 
 #[derive(Debug, Clone)]
-pub struct SparseMerkleTree {
+pub struct MerkleTree<H: MerkleHasher = PoseidonHasher> {
     defaults: Vec<Fp>,
     layers: Vec<HashMap<u64, Fp>>,
+    hasher: PhantomData<H>,
 }
 
+pub type SparseMerkleTree = MerkleTree<PoseidonHasher>;
+
 #[derive(Debug, Clone)]
 pub struct MerkleProof {
     pub value: Fp,
     pub proof: Vec<[Fp; 3]>,
 }
 
-impl SparseMerkleTree {
+// Branches of several leaves at once, siblings shared by the branches (or computable from the
+// leaves themselves) are only included once. `nodes` are ordered by layer, then by index
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiProof {
+    pub depth: usize,
+    pub indices: Vec<u64>,
+    pub values: Vec<Fp>,
+    pub nodes: Vec<Fp>,
+}
+
+// Merkle branch of a tree with a depth of exactly `N`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedMerkleProof<const N: usize> {
+    pub proof: [[Fp; 3]; N],
+}
+
+impl<H: MerkleHasher> MerkleTree<H> {
     pub fn depth(&self) -> usize {
         self.layers.len() - 1
     }
 
+    // Any depth works for the tree itself, but the withdraw circuit only accepts branches of a
+    // tree of depth `proof::MERKLE_PROOF_LENGTH`
     pub fn new(depth: usize) -> Self {
-        let mut defaults = vec![Fp::from(0)];
-        for i in 0..depth {
-            defaults.push(hash4([defaults[i], defaults[i], defaults[i], defaults[i]]));
-        }
+        Self::new_with_empty(depth, Fp::from(0))
+    }
+
+    // Empty leaves hold `empty_leaf` instead of zero, e.g. to match a contract using a non-zero
+    // sentinel. The empty subtrees of every layer are derived from it, once per process
+    pub fn new_with_empty(depth: usize, empty_leaf: Fp) -> Self {
         Self {
-            defaults,
+            defaults: empty_subtrees::<H>(depth, empty_leaf),
             layers: vec![HashMap::new(); depth + 1],
+            hasher: PhantomData,
+        }
+    }
+
+    // Every layer has 4 times as many nodes as the one above it, `None` when the capacity does
+    // not fit in a `u64` (and every index is valid)
+    pub fn capacity(&self) -> Option<u64> {
+        4u64.checked_pow(self.depth() as u32)
+    }
+
+    fn check_index(&self, index: u64) -> Result<(), TreeError> {
+        match self.capacity() {
+            Some(capacity) if index >= capacity => {
+                Err(TreeError::IndexOutOfRange { index, capacity })
+            }
+            _ => Ok(()),
         }
     }
 
@@ -36,7 +148,8 @@ impl SparseMerkleTree {
             .unwrap_or(&self.defaults[layer])
     }
 
-    pub fn set(&mut self, mut index: u64, mut value: Fp) {
+    pub fn set(&mut self, mut index: u64, mut value: Fp) -> Result<(), TreeError> {
+        self.check_index(index)?;
         for layer in 0..self.depth() + 1 {
             self.layers[layer].insert(index, value);
 
@@ -45,9 +158,60 @@ impl SparseMerkleTree {
                 .map(|i| self.get_at_layer(layer, leftmost_leaf + i as u64))
                 .collect::<Vec<_>>();
             vals[(index % 4) as usize] = value;
-            value = hash4(vals.try_into().unwrap());
+            value = H::hash(vals.try_into().unwrap());
             index /= 4;
         }
+        Ok(())
+    }
+
+    // Same result as calling `set` for every entry in order, but each internal node is hashed
+    // only once per batch. Nothing is written if any of the indices is out of range
+    pub fn set_batch(&mut self, entries: &[(u64, Fp)]) -> Result<(), TreeError> {
+        for (index, _) in entries.iter() {
+            self.check_index(*index)?;
+        }
+        let mut dirty: BTreeMap<u64, Fp> = entries.iter().copied().collect();
+        for layer in 0..self.depth() + 1 {
+            for (index, value) in dirty.iter() {
+                self.layers[layer].insert(*index, *value);
+            }
+            if layer == self.depth() {
+                break;
+            }
+            let parents: BTreeSet<u64> = dirty.keys().map(|index| index / 4).collect();
+            dirty = parents
+                .into_iter()
+                .map(|parent| {
+                    let vals = (0..4)
+                        .map(|i| self.get_at_layer(layer, parent * 4 + i))
+                        .collect::<Vec<_>>();
+                    (parent, H::hash(vals.try_into().unwrap()))
+                })
+                .collect();
+        }
+        Ok(())
+    }
+
+    // Reset a leaf to the default value, nodes that turn back into defaults are dropped
+    #[allow(dead_code)]
+    pub fn remove(&mut self, mut index: u64) -> Result<(), TreeError> {
+        self.check_index(index)?;
+        let mut value = self.defaults[0];
+        for layer in 0..self.depth() + 1 {
+            if value == self.defaults[layer] {
+                self.layers[layer].remove(&index);
+            } else {
+                self.layers[layer].insert(index, value);
+            }
+
+            let leftmost_leaf = index - (index % 4);
+            let vals = (0..4)
+                .map(|i| self.get_at_layer(layer, leftmost_leaf + i as u64))
+                .collect::<Vec<_>>();
+            value = H::hash(vals.try_into().unwrap());
+            index /= 4;
+        }
+        Ok(())
     }
 
     pub fn get(&self, mut index: u64) -> MerkleProof {
@@ -65,21 +229,200 @@ impl SparseMerkleTree {
         MerkleProof { value, proof }
     }
 
+    // The root the tree would have once `index` holds `value`, without touching the tree: only
+    // the path of `index` changes, its siblings stay as they are
     #[allow(dead_code)]
+    pub fn root_after(&self, mut index: u64, mut value: Fp) -> Result<Fp, TreeError> {
+        self.check_index(index)?;
+        for siblings in self.get(index).proof {
+            let mut vals = siblings.to_vec();
+            vals.insert((index % 4) as usize, value);
+            value = H::hash(vals.try_into().unwrap());
+            index /= 4;
+        }
+        Ok(value)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_multiproof(&self, indices: &[u64]) -> MultiProof {
+        let indices: Vec<u64> = indices
+            .iter()
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let values = indices.iter().map(|i| self.get_at_layer(0, *i)).collect();
+        let mut nodes = vec![];
+        let mut known: BTreeSet<u64> = indices.iter().copied().collect();
+        for layer in 0..self.depth() {
+            let parents: BTreeSet<u64> = known.iter().map(|index| index / 4).collect();
+            for parent in parents.iter() {
+                for child in parent * 4..parent * 4 + 4 {
+                    if !known.contains(&child) {
+                        nodes.push(self.get_at_layer(layer, child));
+                    }
+                }
+            }
+            known = parents;
+        }
+        MultiProof {
+            depth: self.depth(),
+            indices,
+            values,
+            nodes,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn verify_multiproof(root: Fp, proof: &MultiProof) -> bool {
+        if proof.indices.len() != proof.values.len()
+            || proof.indices.windows(2).any(|w| w[0] >= w[1])
+        {
+            return false;
+        }
+        let mut nodes = proof.nodes.iter();
+        let mut known: BTreeMap<u64, Fp> = proof
+            .indices
+            .iter()
+            .copied()
+            .zip(proof.values.iter().copied())
+            .collect();
+        for _ in 0..proof.depth {
+            let parents: BTreeSet<u64> = known.keys().map(|index| index / 4).collect();
+            let mut next = BTreeMap::new();
+            for parent in parents {
+                let mut vals = [Fp::from(0); 4];
+                for (i, val) in vals.iter_mut().enumerate() {
+                    *val = match known.get(&(parent * 4 + i as u64)) {
+                        Some(v) => *v,
+                        None => match nodes.next() {
+                            Some(v) => *v,
+                            None => return false,
+                        },
+                    };
+                }
+                next.insert(parent, H::hash(vals));
+            }
+            known = next;
+        }
+        nodes.next().is_none() && known.len() == 1 && known.get(&0) == Some(&root)
+    }
+
+    pub fn get_with_proof<const N: usize>(&self, index: u64) -> Result<(Fp, FixedMerkleProof<N>)> {
+        if self.depth() != N {
+            return Err(eyre::Report::msg(format!(
+                "Tree depth is {}, expected {}",
+                self.depth(),
+                N
+            )));
+        }
+        let MerkleProof { value, proof } = self.get(index);
+        let proof: [[Fp; 3]; N] = proof
+            .try_into()
+            .map_err(|_| eyre::Report::msg("Invalid merkle proof length!"))?;
+        Ok((value, FixedMerkleProof { proof }))
+    }
+
+    // Kept up to date by every write, reading it needs no recomputation (nor `&mut self`)
     pub fn root(&self) -> Fp {
         self.get_at_layer(self.depth(), 0)
     }
 
+    // The leaves holding something other than the empty leaf, by ascending index
+    pub fn iter_set(&self) -> impl Iterator<Item = (u64, Fp)> {
+        let mut leaves = self.layers[0]
+            .iter()
+            .filter(|(_, v)| **v != self.defaults[0])
+            .map(|(i, v)| (*i, *v))
+            .collect::<Vec<_>>();
+        leaves.sort_by_key(|(i, _)| *i);
+        leaves.into_iter()
+    }
+
+    // Layout: depth (u64 LE), leaf count (u64 LE), then (index (u64 LE), value (32 bytes LE)) per leaf.
+    // The empty leaf is not stored, `load` assumes zero
+    // Not used by `owshen sync`: only the leaves are stored, so `load` hashes every path again,
+    // which is as slow as `SyncState::tree` rebuilding the tree from the cached commitments. The
+    // sync cache is what persists the tree, with the events that are needed anyway
+    #[allow(dead_code)]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let leaves = self.iter_set().collect::<Vec<_>>();
+        let mut out = Vec::with_capacity(16 + leaves.len() * 40);
+        out.extend_from_slice(&(self.depth() as u64).to_le_bytes());
+        out.extend_from_slice(&(leaves.len() as u64).to_le_bytes());
+        for (index, value) in leaves {
+            out.extend_from_slice(&index.to_le_bytes());
+            out.extend_from_slice(value.to_repr().as_ref());
+        }
+        std::fs::File::create(path)?.write_all(&out)?;
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut data = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut data)?;
+
+        let read_u64 = |offset: usize| -> Result<u64> {
+            let bytes = data
+                .get(offset..offset + 8)
+                .ok_or(eyre::Report::msg("Truncated tree file!"))?;
+            Ok(u64::from_le_bytes(bytes.try_into()?))
+        };
+        // Both come from the file, a corrupt one must neither overflow nor allocate huge layers
+        let depth = read_u64(0)?;
+        if depth > MAX_DEPTH as u64 {
+            return Err(eyre::Report::msg("Invalid tree depth in tree file!"));
+        }
+        let count = usize::try_from(read_u64(8)?)?;
+        let size = count.checked_mul(40).and_then(|len| len.checked_add(16));
+        if size != Some(data.len()) {
+            return Err(eyre::Report::msg("Invalid tree file size!"));
+        }
+        let depth = depth as usize;
+
+        let mut tree = Self::new(depth);
+        for i in 0..count {
+            let offset = 16 + i * 40;
+            let index = read_u64(offset)?;
+            let mut repr = <Fp as PrimeField>::Repr::default();
+            repr.as_mut()
+                .copy_from_slice(&data[offset + 8..offset + 40]);
+            let value = Option::<Fp>::from(Fp::from_repr(repr))
+                .ok_or(eyre::Report::msg("Invalid leaf value in tree file!"))?;
+            tree.set(index, value)?;
+        }
+        Ok(tree)
+    }
+
+    // `Ok(false)` for a well-formed proof that does not lead to `root`, an error for a proof that
+    // cannot come from a tree of `depth` at all
     #[allow(dead_code)]
-    pub fn verify(root: Fp, mut index: u64, proof: &MerkleProof) -> bool {
+    pub fn verify(
+        root: Fp,
+        depth: usize,
+        mut index: u64,
+        proof: &MerkleProof,
+    ) -> Result<bool, TreeError> {
+        if proof.proof.len() != depth {
+            return Err(TreeError::DepthMismatch {
+                expected: depth,
+                found: proof.proof.len(),
+            });
+        }
+        if let Some(capacity) = 4u64.checked_pow(depth as u32) {
+            if index >= capacity {
+                return Err(TreeError::IndexOutOfRange { index, capacity });
+            }
+        }
         let mut value = proof.value;
         for p in proof.proof.iter() {
             let mut vals = p.to_vec();
             vals.insert((index % 4) as usize, value);
-            value = hash4(vals.try_into().unwrap());
+            value = H::hash(vals.try_into().unwrap());
             index /= 4;
         }
-        value == root
+        Ok(value == root)
     }
 }
 
@@ -90,14 +433,336 @@ mod tests {
     #[test]
     fn test_merkle_trees() {
         let mut tree = SparseMerkleTree::new(16);
-        tree.set(123, Fp::from(234));
-        tree.set(345, Fp::from(456));
+        tree.set(123, Fp::from(234)).unwrap();
+        tree.set(345, Fp::from(456)).unwrap();
         let res = tree.get(123);
         let res2 = tree.get(345);
         let res3 = tree.get(200);
-        assert!(SparseMerkleTree::verify(tree.root(), 123, &res));
-        assert!(SparseMerkleTree::verify(tree.root(), 345, &res2));
-        assert!(SparseMerkleTree::verify(tree.root(), 200, &res3));
-        assert!(!SparseMerkleTree::verify(tree.root(), 123, &res2));
+        assert!(SparseMerkleTree::verify(tree.root(), 16, 123, &res).unwrap());
+        assert!(SparseMerkleTree::verify(tree.root(), 16, 345, &res2).unwrap());
+        assert!(SparseMerkleTree::verify(tree.root(), 16, 200, &res3).unwrap());
+        assert!(!SparseMerkleTree::verify(tree.root(), 16, 123, &res2).unwrap());
+
+        // Right length but a wrong sibling: simply invalid
+        let mut tampered = res.clone();
+        tampered.proof[3][1] += Fp::from(1);
+        assert_eq!(
+            SparseMerkleTree::verify(tree.root(), 16, 123, &tampered),
+            Ok(false)
+        );
+        // Too short for the tree: malformed
+        let mut truncated = res.clone();
+        truncated.proof.pop();
+        assert_eq!(
+            SparseMerkleTree::verify(tree.root(), 16, 123, &truncated),
+            Err(TreeError::DepthMismatch {
+                expected: 16,
+                found: 15
+            })
+        );
+        assert!(SparseMerkleTree::verify(tree.root(), 16, 1 << 32, &res).is_err());
+    }
+
+    #[test]
+    fn test_empty_leaf() {
+        let zero = SparseMerkleTree::new(4);
+        let sentinel = SparseMerkleTree::new_with_empty(4, Fp::from(7));
+        assert_eq!(
+            zero.root(),
+            SparseMerkleTree::new_with_empty(4, Fp::from(0)).root()
+        );
+        assert_ne!(zero.root(), sentinel.root());
+        assert_eq!(sentinel.get(5).value, Fp::from(7));
+
+        let mut expected = Fp::from(7);
+        for _ in 0..4 {
+            expected = hash4([expected; 4]);
+        }
+        assert_eq!(sentinel.root(), expected);
+
+        let mut tree = sentinel.clone();
+        tree.set(5, Fp::from(123)).unwrap();
+        assert!(SparseMerkleTree::verify(tree.root(), 4, 5, &tree.get(5)).unwrap());
+        assert!(SparseMerkleTree::verify(tree.root(), 4, 6, &tree.get(6)).unwrap());
+        tree.remove(5).unwrap();
+        assert_eq!(tree.root(), sentinel.root());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut untouched = SparseMerkleTree::new(16);
+        untouched.set(123, Fp::from(234)).unwrap();
+        untouched.set(4000000, Fp::from(567)).unwrap();
+
+        let mut tree = untouched.clone();
+        tree.set(122, Fp::from(345)).unwrap();
+        tree.set(345, Fp::from(456)).unwrap();
+        tree.remove(122).unwrap();
+        tree.remove(345).unwrap();
+        assert_eq!(tree.root(), untouched.root());
+        assert_eq!(tree.get(123).proof, untouched.get(123).proof);
+        assert_eq!(tree.layers[0].len(), 2);
+
+        tree.remove(123).unwrap();
+        tree.remove(4000000).unwrap();
+        assert_eq!(tree.root(), SparseMerkleTree::new(16).root());
+        assert!(tree.layers.iter().all(|layer| layer.is_empty()));
+    }
+
+    #[test]
+    fn test_iter_set() {
+        let mut tree = SparseMerkleTree::new(16);
+        tree.set(900, Fp::from(1)).unwrap();
+        tree.set(5, Fp::from(2)).unwrap();
+        tree.set(77, Fp::from(3)).unwrap();
+        tree.set(6, Fp::from(0)).unwrap();
+        tree.set(900, Fp::from(4)).unwrap();
+        tree.remove(77).unwrap();
+        tree.set(12, Fp::from(5)).unwrap();
+        assert_eq!(
+            tree.iter_set().collect::<Vec<_>>(),
+            vec![(5, Fp::from(2)), (12, Fp::from(5)), (900, Fp::from(4))]
+        );
+    }
+
+    #[test]
+    fn test_root_after() {
+        let mut tree = SparseMerkleTree::new(16);
+        tree.set_batch(&[(3, Fp::from(1)), (17, Fp::from(2)), (900, Fp::from(3))])
+            .unwrap();
+        let root = tree.root();
+        for (index, value) in [(4, 5), (17, 6), (900, 0), (4000000, 7)] {
+            let mut expected = tree.clone();
+            expected.set(index, Fp::from(value)).unwrap();
+            assert_eq!(
+                tree.root_after(index, Fp::from(value)).unwrap(),
+                expected.root()
+            );
+        }
+        assert_eq!(tree.root(), root);
+        assert_eq!(tree.get(17).value, Fp::from(2));
+        assert!(SparseMerkleTree::new(4)
+            .root_after(256, Fp::from(1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_set_batch() {
+        let entries: Vec<(u64, Fp)> = (0..200u64)
+            .map(|i| ((i * 7919) % 1000, Fp::from(i + 1)))
+            .chain([(4000000, Fp::from(567)), (7, Fp::from(8))])
+            .collect();
+
+        let mut sequential = SparseMerkleTree::new(16);
+        for (index, value) in entries.iter() {
+            sequential.set(*index, *value).unwrap();
+        }
+        let mut batch = SparseMerkleTree::new(16);
+        batch.set_batch(&entries).unwrap();
+
+        assert_eq!(batch.root(), sequential.root());
+        assert_eq!(batch.get(7).value, Fp::from(8));
+        assert_eq!(
+            batch.get(7919 % 1000).proof,
+            sequential.get(7919 % 1000).proof
+        );
+    }
+
+    #[test]
+    fn test_index_out_of_range() {
+        let mut tree = SparseMerkleTree::new(4);
+        assert_eq!(tree.capacity(), Some(256));
+        tree.set(255, Fp::from(1)).unwrap();
+        let root = tree.root();
+        assert_eq!(
+            tree.set(256, Fp::from(2)),
+            Err(TreeError::IndexOutOfRange {
+                index: 256,
+                capacity: 256
+            })
+        );
+        assert!(tree
+            .set_batch(&[(3, Fp::from(3)), (12345678, Fp::from(4))])
+            .is_err());
+        assert!(tree.remove(256).is_err());
+        assert_eq!(tree.root(), root);
+        assert_eq!(tree.get(3).value, Fp::from(0));
+
+        assert!(SparseMerkleTree::new(16).set(12345678, Fp::from(1)).is_ok());
+        assert_eq!(SparseMerkleTree::new(32).capacity(), None);
+    }
+
+    #[test]
+    fn test_multiproof() {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let mut tree = SparseMerkleTree::new(8);
+        for i in 0..300 {
+            tree.set(i * 7, Fp::from(i + 1)).unwrap();
+        }
+        for _ in 0..20 {
+            let start = rng.gen_range(0..60000);
+            let indices: Vec<u64> = (0..rng.gen_range(1..20))
+                .map(|_| start + rng.gen_range(0..64))
+                .collect();
+            let proof = tree.get_multiproof(&indices);
+            assert!(SparseMerkleTree::verify_multiproof(tree.root(), &proof));
+            for (index, value) in proof.indices.iter().zip(proof.values.iter()) {
+                assert_eq!(*value, tree.get(*index).value);
+            }
+            let separate = proof.indices.len() * 3 * tree.depth();
+            assert!(proof.nodes.len() < separate || proof.indices.len() == 1);
+
+            let mut tampered = proof.clone();
+            tampered.values[0] += Fp::from(1);
+            assert!(!SparseMerkleTree::verify_multiproof(tree.root(), &tampered));
+            let mut tampered = proof.clone();
+            tampered.nodes.push(Fp::from(0));
+            assert!(!SparseMerkleTree::verify_multiproof(tree.root(), &tampered));
+        }
+
+        // Four adjacent leaves only need the siblings of their common parent
+        let proof = tree.get_multiproof(&[8, 9, 10, 11]);
+        assert_eq!(proof.nodes.len(), 3 * (tree.depth() - 1));
+        assert!(!SparseMerkleTree::verify_multiproof(
+            SparseMerkleTree::new(8).root(),
+            &proof
+        ));
+    }
+
+    #[test]
+    fn test_get_with_proof() {
+        let mut tree = SparseMerkleTree::new(16);
+        tree.set(123, Fp::from(234)).unwrap();
+        let (value, proof) = tree.get_with_proof::<16>(123).unwrap();
+        let res = tree.get(123);
+        assert_eq!(value, res.value);
+        assert_eq!(proof.proof.to_vec(), res.proof);
+        assert!(tree.get_with_proof::<15>(123).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_root() {
+        use std::sync::{Arc, RwLock};
+        let tree = Arc::new(RwLock::new(SparseMerkleTree::new(16)));
+        tree.write().unwrap().set(0, Fp::from(1)).unwrap();
+        let root = tree.read().unwrap().root();
+
+        // Readers hold the read lock at the same time, the writer waits for them
+        let guard = tree.read().unwrap();
+        let readers = (0..16)
+            .map(|_| {
+                let tree = tree.clone();
+                std::thread::spawn(move || {
+                    (0..100)
+                        .map(|_| tree.read().unwrap().root())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let writer = {
+            let tree = tree.clone();
+            std::thread::spawn(move || tree.write().unwrap().set(1, Fp::from(2)).unwrap())
+        };
+        assert_eq!(guard.root(), root);
+        drop(guard);
+
+        let mut expected = SparseMerkleTree::new(16);
+        expected.set(0, Fp::from(1)).unwrap();
+        expected.set(1, Fp::from(2)).unwrap();
+        for reader in readers {
+            assert!(reader
+                .join()
+                .unwrap()
+                .iter()
+                .all(|r| *r == root || *r == expected.root()));
+        }
+        writer.join().unwrap();
+        assert_eq!(tree.read().unwrap().root(), expected.root());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut tree = SparseMerkleTree::new(16);
+        tree.set(0, Fp::from(1)).unwrap();
+        tree.set(123, Fp::from(234)).unwrap();
+        tree.set(345, Fp::from(456)).unwrap();
+        tree.set(4000000, Fp::from(567)).unwrap();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        tree.save(file.path()).unwrap();
+        assert_eq!(std::fs::metadata(file.path()).unwrap().len(), 16 + 4 * 40);
+
+        let loaded = SparseMerkleTree::load(file.path()).unwrap();
+        assert_eq!(loaded.depth(), tree.depth());
+        assert_eq!(loaded.root(), tree.root());
+
+        // Headers of a corrupt file, neither may overflow or allocate
+        for (depth, count) in [(16, u64::MAX), (16, u64::MAX / 40 + 1), (u64::MAX, 0)] {
+            let mut data = depth.to_le_bytes().to_vec();
+            data.extend_from_slice(&count.to_le_bytes());
+            std::fs::write(file.path(), &data).unwrap();
+            assert!(SparseMerkleTree::load(file.path()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_empty_subtrees() {
+        let mut fresh = vec![Fp::from(5)];
+        for _ in 0..16 {
+            let below = fresh[fresh.len() - 1];
+            fresh.push(hash4([below, below, below, below]));
+        }
+        // Cached by a shallow tree first, then extended by a deeper one
+        assert_eq!(
+            SparseMerkleTree::new_with_empty(4, Fp::from(5)).defaults,
+            fresh[..5]
+        );
+        assert_eq!(
+            SparseMerkleTree::new_with_empty(16, Fp::from(5)).defaults,
+            fresh
+        );
+        assert_eq!(
+            SparseMerkleTree::new_with_empty(2, Fp::from(5)).defaults,
+            fresh[..3]
+        );
+        assert_eq!(
+            SparseMerkleTree::new_with_empty(16, Fp::from(5)).root(),
+            fresh[16]
+        );
+        assert_ne!(SparseMerkleTree::new(16).root(), fresh[16]);
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        // Not a secure hash, only distinguishes the children's positions
+        #[derive(Debug, Clone)]
+        struct WeightedSum;
+        impl MerkleHasher for WeightedSum {
+            fn hash(vals: [Fp; 4]) -> Fp {
+                vals.iter()
+                    .zip(1..)
+                    .fold(Fp::from(1), |acc, (v, w)| acc + *v * Fp::from(w))
+            }
+        }
+
+        let mut tree = MerkleTree::<WeightedSum>::new(3);
+        assert_eq!(tree.root(), Fp::from(1 + 10 * (1 + 10)));
+        tree.set(5, Fp::from(2)).unwrap();
+        assert!(MerkleTree::<WeightedSum>::verify(tree.root(), 3, 5, &tree.get(5)).unwrap());
+        assert!(!MerkleTree::<WeightedSum>::verify(tree.root(), 3, 6, &tree.get(5)).unwrap());
+        let proof = tree.get_multiproof(&[5, 9]);
+        assert!(MerkleTree::<WeightedSum>::verify_multiproof(
+            tree.root(),
+            &proof
+        ));
+
+        let mut poseidon = SparseMerkleTree::new(3);
+        poseidon.set(5, Fp::from(2)).unwrap();
+        assert!(!SparseMerkleTree::verify(poseidon.root(), 3, 5, &tree.get(5)).unwrap());
+        assert_eq!(
+            MerkleTree::<PoseidonHasher>::new(3).root(),
+            SparseMerkleTree::new(3).root()
+        );
     }
 }
@@ -0,0 +1,122 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ethers::utils::hex;
+use eyre::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+const VAULT_VERSION: u8 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScryptParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String,
+}
+
+impl Default for ScryptParams {
+    fn default() -> Self {
+        ScryptParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+            salt: String::new(),
+        }
+    }
+}
+
+// Passphrase protected blob, encrypted with XChaCha20-Poly1305 under a scrypt derived key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Vault {
+    pub version: u8,
+    pub kdf: ScryptParams,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, params: &ScryptParams) -> Result<[u8; 32]> {
+    let salt = hex::decode(&params.salt)?;
+    let scrypt_params = scrypt::Params::new(params.log_n, params.r, params.p, 32)
+        .map_err(|e| eyre::Report::msg(format!("Invalid scrypt parameters: {}", e)))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), &salt, &scrypt_params, &mut key)
+        .map_err(|e| eyre::Report::msg(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+impl Vault {
+    pub fn encrypt(plaintext: &[u8], passphrase: &str) -> Result<Self> {
+        Self::encrypt_with_params(plaintext, passphrase, ScryptParams::default())
+    }
+
+    pub fn encrypt_with_params(
+        plaintext: &[u8],
+        passphrase: &str,
+        mut kdf: ScryptParams,
+    ) -> Result<Self> {
+        let mut rng = rand::thread_rng();
+        let salt: [u8; 32] = rng.gen();
+        let nonce: [u8; 24] = rng.gen();
+        kdf.salt = hex::encode(salt);
+
+        let key = derive_key(passphrase, &kdf)?;
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .map_err(|_| eyre::Report::msg("Failed to encrypt the wallet!"))?;
+
+        Ok(Vault {
+            version: VAULT_VERSION,
+            kdf,
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    pub fn decrypt(&self, passphrase: &str) -> Result<Vec<u8>> {
+        if self.version != VAULT_VERSION {
+            return Err(eyre::Report::msg(format!(
+                "Unsupported wallet encryption version {}!",
+                self.version
+            )));
+        }
+        let nonce = hex::decode(&self.nonce)?;
+        if nonce.len() != 24 {
+            return Err(eyre::Report::msg("Invalid wallet nonce!"));
+        }
+        let ciphertext = hex::decode(&self.ciphertext)?;
+
+        let key = derive_key(passphrase, &self.kdf)?;
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        cipher
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| eyre::Report::msg("Wrong passphrase or corrupted wallet file!"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fast_params() -> ScryptParams {
+        ScryptParams {
+            log_n: 4,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_vault_roundtrip() {
+        let vault = Vault::encrypt_with_params(b"owshen", "passphrase", fast_params()).unwrap();
+        let json = serde_json::to_string(&vault).unwrap();
+        let vault: Vault = serde_json::from_str(&json).unwrap();
+        assert_eq!(vault.decrypt("passphrase").unwrap(), b"owshen");
+    }
+
+    #[test]
+    fn test_vault_wrong_passphrase() {
+        let vault = Vault::encrypt_with_params(b"owshen", "passphrase", fast_params()).unwrap();
+        assert!(vault.decrypt("wrong").is_err());
+    }
+}
@@ -0,0 +1,628 @@
+use ark_bn254::{Bn254, Fq, Fq2, Fr, G1Affine, G2Affine};
+use ark_ff::{BigInt, Field, PrimeField, UniformRand, Zero};
+use ark_groth16::r1cs_to_qap::{evaluate_constraint, LibsnarkReduction, R1CSToQAP};
+use ark_groth16::Groth16;
+use ark_poly::EvaluationDomain;
+use ark_relations::r1cs::{ConstraintMatrices, ConstraintSystemRef, SynthesisError};
+use eyre::Result;
+use rand::Rng;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref PROVING_KEYS: Mutex<HashMap<PathBuf, Arc<ProvingKey>>> = Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    // The reduction is a set of static functions called from within arkworks, this is the only
+    // way to tell the prover of this thread that the QAP is evaluated
+    static ON_WITNESS_MAP: RefCell<Option<Rc<dyn Fn()>>> = RefCell::new(None);
+}
+
+// Groth16 proving key of a snarkjs `.zkey` file, together with the constraints needed for the
+// witness reduction
+#[derive(Debug)]
+pub struct ProvingKey {
+    pub pk: ark_groth16::ProvingKey<Bn254>,
+    pub matrices: ConstraintMatrices<Fr>,
+}
+
+// Reduction used by snarkjs, which evaluates the QAP on the odd powers of a root of unity of
+// twice the domain size (instead of on a coset of the field generator, like libsnark)
+pub struct CircomReduction;
+
+impl R1CSToQAP for CircomReduction {
+    #[allow(clippy::type_complexity)]
+    fn instance_map_with_evaluation<F: PrimeField, D: EvaluationDomain<F>>(
+        cs: ConstraintSystemRef<F>,
+        t: &F,
+    ) -> Result<(Vec<F>, Vec<F>, Vec<F>, F, usize, usize), SynthesisError> {
+        LibsnarkReduction::instance_map_with_evaluation::<F, D>(cs, t)
+    }
+
+    fn witness_map_from_matrices<F: PrimeField, D: EvaluationDomain<F>>(
+        matrices: &ConstraintMatrices<F>,
+        num_inputs: usize,
+        num_constraints: usize,
+        full_assignment: &[F],
+    ) -> Result<Vec<F>, SynthesisError> {
+        let domain =
+            D::new(num_constraints + num_inputs).ok_or(SynthesisError::PolynomialDegreeTooLarge)?;
+        let domain_size = domain.size();
+        let root_of_unity = D::new(2 * domain_size)
+            .ok_or(SynthesisError::PolynomialDegreeTooLarge)?
+            .element(1);
+
+        let mut a = vec![F::zero(); domain_size];
+        let mut b = vec![F::zero(); domain_size];
+        for i in 0..num_constraints {
+            a[i] = evaluate_constraint(&matrices.a[i], full_assignment);
+            b[i] = evaluate_constraint(&matrices.b[i], full_assignment);
+        }
+        a[num_constraints..num_constraints + num_inputs]
+            .clone_from_slice(&full_assignment[..num_inputs]);
+        // The constraints are satisfied, so C evaluates to A * B on the domain
+        let mut c = a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| *a * b)
+            .collect::<Vec<_>>();
+
+        for v in [&mut a, &mut b, &mut c] {
+            domain.ifft_in_place(v);
+            D::distribute_powers_and_mul_by_const(v, root_of_unity, F::one());
+            domain.fft_in_place(v);
+        }
+
+        let h = a
+            .iter()
+            .zip(b.iter())
+            .zip(c.iter())
+            .map(|((a, b), c)| *a * b - c)
+            .collect();
+        if let Some(hook) = ON_WITNESS_MAP.with(|hook| hook.borrow().clone()) {
+            hook();
+        }
+        Ok(h)
+    }
+
+    fn h_query_scalars<F: PrimeField, D: EvaluationDomain<F>>(
+        max_power: usize,
+        t: F,
+        _zt: F,
+        delta_inverse: F,
+    ) -> Result<Vec<F>, SynthesisError> {
+        let domain_double =
+            D::new(2 * (max_power + 1)).ok_or(SynthesisError::PolynomialDegreeTooLarge)?;
+        Ok(domain_double
+            .evaluate_all_lagrange_coefficients(t)
+            .into_iter()
+            .skip(1)
+            .step_by(2)
+            .map(|l| l * delta_inverse)
+            .collect())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let out = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or(eyre::Report::msg("Unexpected end of file!"))?;
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn bigint(&mut self) -> Result<BigInt<4>> {
+        let bytes = self.bytes(32)?;
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into()?);
+        }
+        Ok(BigInt(limbs))
+    }
+
+    // Base field elements are stored in Montgomery form
+    fn fq(&mut self) -> Result<Fq> {
+        let v = self.bigint()?;
+        if v >= Fq::MODULUS {
+            return Err(eyre::Report::msg("Invalid base field element!"));
+        }
+        Ok(Fq::new_unchecked(v))
+    }
+
+    // Constraint coefficients are stored multiplied by R twice
+    fn fr_coeff(&mut self) -> Result<Fr> {
+        let v = self.bigint()?;
+        if v >= Fr::MODULUS {
+            return Err(eyre::Report::msg("Invalid scalar field element!"));
+        }
+        Ok(Fr::new_unchecked(Fr::new_unchecked(v).into_bigint()))
+    }
+
+    fn g1(&mut self) -> Result<G1Affine> {
+        let (x, y) = (self.fq()?, self.fq()?);
+        if x.is_zero() && y.is_zero() {
+            return Ok(G1Affine::identity());
+        }
+        let p = G1Affine::new_unchecked(x, y);
+        if !p.is_on_curve() {
+            return Err(eyre::Report::msg("Invalid G1 point in proving key!"));
+        }
+        Ok(p)
+    }
+
+    fn g2(&mut self) -> Result<G2Affine> {
+        let x = Fq2::new(self.fq()?, self.fq()?);
+        let y = Fq2::new(self.fq()?, self.fq()?);
+        if x.is_zero() && y.is_zero() {
+            return Ok(G2Affine::identity());
+        }
+        let p = G2Affine::new_unchecked(x, y);
+        if !p.is_on_curve() {
+            return Err(eyre::Report::msg("Invalid G2 point in proving key!"));
+        }
+        Ok(p)
+    }
+
+    fn g1s(&mut self, n: usize) -> Result<Vec<G1Affine>> {
+        (0..n).map(|_| self.g1()).collect()
+    }
+}
+
+// Layout shared by `.zkey` and `.wtns` files: magic, version, then (type, size, data) sections
+fn sections<'a>(data: &'a [u8], magic: &[u8; 4]) -> Result<HashMap<u32, &'a [u8]>> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(4)? != magic {
+        return Err(eyre::Report::msg(format!(
+            "Not a {} file!",
+            String::from_utf8_lossy(magic)
+        )));
+    }
+    let _version = reader.u32()?;
+    let num_sections = reader.u32()?;
+    let mut sections = HashMap::new();
+    for _ in 0..num_sections {
+        let section_type = reader.u32()?;
+        let size = reader.u64()? as usize;
+        sections.insert(section_type, reader.bytes(size)?);
+    }
+    Ok(sections)
+}
+
+fn section<'a>(sections: &HashMap<u32, &'a [u8]>, section_type: u32) -> Result<Reader<'a>> {
+    sections
+        .get(&section_type)
+        .map(|data| Reader::new(data))
+        .ok_or(eyre::Report::msg(format!(
+            "Section {} is missing!",
+            section_type
+        )))
+}
+
+fn check_modulus(reader: &mut Reader, modulus: BigInt<4>) -> Result<()> {
+    let n8 = reader.u32()?;
+    if n8 != 32 || reader.bigint()? != modulus {
+        return Err(eyre::Report::msg("Only BN254 circuits are supported!"));
+    }
+    Ok(())
+}
+
+impl ProvingKey {
+    pub fn from_zkey(data: &[u8]) -> Result<Self> {
+        let sections = sections(data, b"zkey")?;
+        if section(&sections, 1)?.u32()? != 1 {
+            return Err(eyre::Report::msg(
+                "Only Groth16 proving keys are supported!",
+            ));
+        }
+
+        let mut header = section(&sections, 2)?;
+        check_modulus(&mut header, Fq::MODULUS)?;
+        check_modulus(&mut header, Fr::MODULUS)?;
+        let num_vars = header.u32()? as usize;
+        let num_public = header.u32()? as usize;
+        let domain_size = header.u32()? as usize;
+        let alpha_g1 = header.g1()?;
+        let beta_g1 = header.g1()?;
+        let beta_g2 = header.g2()?;
+        let gamma_g2 = header.g2()?;
+        let delta_g1 = header.g1()?;
+        let delta_g2 = header.g2()?;
+        if num_public + 1 > num_vars {
+            return Err(eyre::Report::msg("Invalid proving key header!"));
+        }
+
+        let mut coeffs = section(&sections, 4)?;
+        let num_coeffs = coeffs.u32()?;
+        let mut rows: [Vec<Vec<(Fr, usize)>>; 2] = [vec![], vec![]];
+        for _ in 0..num_coeffs {
+            let matrix = coeffs.u32()? as usize;
+            let constraint = coeffs.u32()? as usize;
+            let signal = coeffs.u32()? as usize;
+            let value = coeffs.fr_coeff()?;
+            if matrix > 1 || constraint >= domain_size || signal >= num_vars {
+                return Err(eyre::Report::msg("Invalid constraint coefficient!"));
+            }
+            if rows[matrix].len() <= constraint {
+                rows[matrix].resize(constraint + 1, vec![]);
+            }
+            rows[matrix][constraint].push((value, signal));
+        }
+        // snarkjs appends one constraint per public signal, the reduction adds those itself
+        let num_constraints = rows[0]
+            .len()
+            .max(rows[1].len())
+            .checked_sub(num_public + 1)
+            .ok_or(eyre::Report::msg("Invalid constraint coefficients!"))?;
+        let [mut a, mut b] = rows;
+        a.resize(num_constraints, vec![]);
+        b.resize(num_constraints, vec![]);
+
+        let ic = section(&sections, 3)?.g1s(num_public + 1)?;
+        let a_query = section(&sections, 5)?.g1s(num_vars)?;
+        let b_g1_query = section(&sections, 6)?.g1s(num_vars)?;
+        let mut b2 = section(&sections, 7)?;
+        let b_g2_query = (0..num_vars).map(|_| b2.g2()).collect::<Result<Vec<_>>>()?;
+        let l_query = section(&sections, 8)?.g1s(num_vars - num_public - 1)?;
+        let h_query = section(&sections, 9)?.g1s(domain_size)?;
+
+        let matrices = ConstraintMatrices {
+            num_instance_variables: num_public + 1,
+            num_witness_variables: num_vars - num_public - 1,
+            num_constraints,
+            a_num_non_zero: a.iter().map(|row| row.len()).sum(),
+            b_num_non_zero: b.iter().map(|row| row.len()).sum(),
+            c_num_non_zero: 0,
+            a,
+            b,
+            c: vec![],
+        };
+        let pk = ark_groth16::ProvingKey {
+            vk: ark_groth16::VerifyingKey {
+                alpha_g1,
+                beta_g2,
+                gamma_g2,
+                delta_g2,
+                gamma_abc_g1: ic,
+            },
+            beta_g1,
+            delta_g1,
+            a_query,
+            b_g1_query,
+            b_g2_query,
+            h_query,
+            l_query,
+        };
+        Ok(Self { pk, matrices })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_zkey(&std::fs::read(path)?)
+    }
+
+    // The key is parsed on first use and shared afterwards. The lock is held while parsing, so
+    // that concurrent provers wait for a single load instead of each parsing the file
+    pub fn cached<P: AsRef<Path>>(path: P) -> Result<Arc<Self>> {
+        let path = path.as_ref().to_path_buf();
+        let mut keys = PROVING_KEYS.lock().unwrap();
+        if let Some(pk) = keys.get(&path) {
+            return Ok(pk.clone());
+        }
+        let pk = Arc::new(Self::load(&path)?);
+        keys.insert(path, pk.clone());
+        Ok(pk)
+    }
+
+    pub fn num_public_inputs(&self) -> usize {
+        self.matrices.num_instance_variables - 1
+    }
+
+    // `witness` is the full assignment, starting with the constant 1 and the public signals
+    #[allow(dead_code)]
+    pub fn prove<R: Rng>(&self, witness: &[Fr], rng: &mut R) -> Result<ark_groth16::Proof<Bn254>> {
+        self.prove_with_hook(witness, rng, || {})
+    }
+
+    // `on_witness_map` is called between the two halves of the proving time: once the constraints
+    // are evaluated (the FFTs), before the multi-scalar multiplications
+    pub fn prove_with_hook<R: Rng>(
+        &self,
+        witness: &[Fr],
+        rng: &mut R,
+        on_witness_map: impl Fn() + 'static,
+    ) -> Result<ark_groth16::Proof<Bn254>> {
+        let num_vars = self.matrices.num_instance_variables + self.matrices.num_witness_variables;
+        if witness.len() != num_vars || witness.first() != Some(&Fr::ONE) {
+            return Err(eyre::Report::msg(format!(
+                "Invalid witness, expected {} signals, got {}",
+                num_vars,
+                witness.len()
+            )));
+        }
+        // Removed even if proving fails
+        struct Reset;
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                ON_WITNESS_MAP.with(|hook| *hook.borrow_mut() = None);
+            }
+        }
+        ON_WITNESS_MAP.with(|hook| *hook.borrow_mut() = Some(Rc::new(on_witness_map)));
+        let _reset = Reset;
+
+        let r = Fr::rand(rng);
+        let s = Fr::rand(rng);
+        Groth16::<Bn254, CircomReduction>::create_proof_with_reduction_and_matrices(
+            &self.pk,
+            r,
+            s,
+            &self.matrices,
+            self.matrices.num_instance_variables,
+            self.matrices.num_constraints,
+            witness,
+        )
+        .map_err(|e| eyre::Report::msg(format!("Proving failed: {}", e)))
+    }
+}
+
+// Witness as written by circom's witness generators, values are in normal form
+pub fn read_witness(data: &[u8]) -> Result<Vec<Fr>> {
+    let sections = sections(data, b"wtns")?;
+    let mut header = section(&sections, 1)?;
+    check_modulus(&mut header, Fr::MODULUS)?;
+    let num_signals = header.u32()? as usize;
+
+    let mut values = section(&sections, 2)?;
+    (0..num_signals)
+        .map(|_| {
+            Fr::from_bigint(values.bigint()?).ok_or(eyre::Report::msg("Invalid witness value!"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use ark_ec::AffineRepr;
+    use ark_relations::lc;
+    use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem};
+
+    // Proves knowledge of `a` and `b` such that `a * b == c` and `a + b == d`, `c`, `d` public
+    #[derive(Clone)]
+    pub struct TestCircuit {
+        pub a: Fr,
+        pub b: Fr,
+    }
+
+    impl ConstraintSynthesizer<Fr> for TestCircuit {
+        fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+            let c = cs.new_input_variable(|| Ok(self.a * self.b))?;
+            let d = cs.new_input_variable(|| Ok(self.a + self.b))?;
+            let a = cs.new_witness_variable(|| Ok(self.a))?;
+            let b = cs.new_witness_variable(|| Ok(self.b))?;
+            let a2 = cs.new_witness_variable(|| Ok(self.a * self.a))?;
+            cs.enforce_constraint(lc!() + a, lc!() + b, lc!() + c)?;
+            cs.enforce_constraint(
+                lc!() + a + b,
+                lc!() + (Fr::ONE, ConstraintSystem::<Fr>::one()),
+                lc!() + d,
+            )?;
+            cs.enforce_constraint(lc!() + a, lc!() + a, lc!() + a2)
+        }
+    }
+
+    fn put_section(out: &mut Vec<u8>, section_type: u32, data: Vec<u8>) {
+        out.extend_from_slice(&section_type.to_le_bytes());
+        out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        out.extend(data);
+    }
+
+    fn put_bigint(out: &mut Vec<u8>, v: BigInt<4>) {
+        for limb in v.0 {
+            out.extend_from_slice(&limb.to_le_bytes());
+        }
+    }
+
+    fn put_g1(out: &mut Vec<u8>, p: &G1Affine) {
+        let (x, y) = p.xy().unwrap_or((&Fq::ZERO, &Fq::ZERO));
+        put_bigint(out, x.0);
+        put_bigint(out, y.0);
+    }
+
+    fn put_g2(out: &mut Vec<u8>, p: &G2Affine) {
+        let (x, y) = p.xy().unwrap_or((&Fq2::ZERO, &Fq2::ZERO));
+        for v in [x.c0, x.c1, y.c0, y.c1] {
+            put_bigint(out, v.0);
+        }
+    }
+
+    // Writes the key and constraints the way `snarkjs groth16 setup` does
+    pub fn to_zkey(
+        pk: &ark_groth16::ProvingKey<Bn254>,
+        matrices: &ConstraintMatrices<Fr>,
+    ) -> Vec<u8> {
+        let num_public = matrices.num_instance_variables - 1;
+        let num_vars = matrices.num_instance_variables + matrices.num_witness_variables;
+        let domain_size = (matrices.num_constraints + num_public + 1).next_power_of_two();
+
+        let mut out = b"zkey".to_vec();
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&9u32.to_le_bytes());
+        put_section(&mut out, 1, 1u32.to_le_bytes().to_vec());
+
+        let mut header = vec![];
+        header.extend_from_slice(&32u32.to_le_bytes());
+        put_bigint(&mut header, Fq::MODULUS);
+        header.extend_from_slice(&32u32.to_le_bytes());
+        put_bigint(&mut header, Fr::MODULUS);
+        for v in [num_vars, num_public, domain_size] {
+            header.extend_from_slice(&(v as u32).to_le_bytes());
+        }
+        put_g1(&mut header, &pk.vk.alpha_g1);
+        put_g1(&mut header, &pk.beta_g1);
+        put_g2(&mut header, &pk.vk.beta_g2);
+        put_g2(&mut header, &pk.vk.gamma_g2);
+        put_g1(&mut header, &pk.delta_g1);
+        put_g2(&mut header, &pk.vk.delta_g2);
+        put_section(&mut out, 2, header);
+
+        let mut ic = vec![];
+        pk.vk.gamma_abc_g1.iter().for_each(|p| put_g1(&mut ic, p));
+        put_section(&mut out, 3, ic);
+
+        let mut coeffs = vec![];
+        let mut entries = vec![];
+        for (matrix, rows) in [&matrices.a, &matrices.b].iter().enumerate() {
+            for (constraint, row) in rows.iter().enumerate() {
+                for (value, signal) in row {
+                    entries.push((matrix, constraint, *signal, *value));
+                }
+            }
+        }
+        for signal in 0..=num_public {
+            entries.push((0, matrices.num_constraints + signal, signal, Fr::ONE));
+        }
+        coeffs.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        for (matrix, constraint, signal, value) in entries {
+            for v in [matrix, constraint, signal] {
+                coeffs.extend_from_slice(&(v as u32).to_le_bytes());
+            }
+            put_bigint(&mut coeffs, (value * Fr::from(Fr::R)).0);
+        }
+        put_section(&mut out, 4, coeffs);
+
+        for (section_type, points) in [
+            (5, &pk.a_query),
+            (6, &pk.b_g1_query),
+            (8, &pk.l_query),
+            (9, &pk.h_query),
+        ] {
+            let mut data = vec![];
+            points.iter().for_each(|p| put_g1(&mut data, p));
+            put_section(&mut out, section_type, data);
+        }
+        let mut b2 = vec![];
+        pk.b_g2_query.iter().for_each(|p| put_g2(&mut b2, p));
+        put_section(&mut out, 7, b2);
+        out
+    }
+
+    pub fn to_wtns(witness: &[Fr]) -> Vec<u8> {
+        let mut out = b"wtns".to_vec();
+        out.extend_from_slice(&2u32.to_le_bytes());
+        out.extend_from_slice(&2u32.to_le_bytes());
+        let mut header = vec![];
+        header.extend_from_slice(&32u32.to_le_bytes());
+        put_bigint(&mut header, Fr::MODULUS);
+        header.extend_from_slice(&(witness.len() as u32).to_le_bytes());
+        put_section(&mut out, 1, header);
+        let mut values = vec![];
+        witness
+            .iter()
+            .for_each(|v| put_bigint(&mut values, v.into_bigint()));
+        put_section(&mut out, 2, values);
+        out
+    }
+
+    // Test proving key in the zkey format, with a satisfying witness of `TestCircuit`
+    pub fn test_setup() -> (Vec<u8>, Vec<Fr>) {
+        let mut rng = rand::thread_rng();
+        let circuit = TestCircuit {
+            a: Fr::from(3u64),
+            b: Fr::from(5u64),
+        };
+        let pk = Groth16::<Bn254, CircomReduction>::generate_random_parameters_with_reduction(
+            circuit.clone(),
+            &mut rng,
+        )
+        .unwrap();
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        circuit.generate_constraints(cs.clone()).unwrap();
+        cs.finalize();
+        let matrices = cs.to_matrices().unwrap();
+        let cs = cs.borrow().unwrap();
+        let witness = [
+            cs.instance_assignment.as_slice(),
+            cs.witness_assignment.as_slice(),
+        ]
+        .concat();
+        (to_zkey(&pk, &matrices), witness)
+    }
+
+    #[test]
+    fn test_zkey_prove() {
+        let (zkey, witness) = test_setup();
+        let pk = ProvingKey::from_zkey(&zkey).unwrap();
+        assert_eq!(pk.num_public_inputs(), 2);
+        assert_eq!(read_witness(&to_wtns(&witness)).unwrap(), witness);
+
+        let pvk = ark_groth16::prepare_verifying_key(&pk.pk.vk);
+        let proof = pk.prove(&witness, &mut rand::thread_rng()).unwrap();
+        assert!(Groth16::<Bn254>::verify_proof(&pvk, &proof, &witness[1..3]).unwrap());
+        assert!(
+            !Groth16::<Bn254>::verify_proof(&pvk, &proof, &[Fr::from(15u64), Fr::from(9u64)])
+                .unwrap()
+        );
+
+        let mut wrong = witness.clone();
+        wrong[3] = Fr::from(4u64);
+        let proof = pk.prove(&wrong, &mut rand::thread_rng()).unwrap();
+        assert!(!Groth16::<Bn254>::verify_proof(&pvk, &proof, &witness[1..3]).unwrap());
+        assert!(pk.prove(&witness[1..], &mut rand::thread_rng()).is_err());
+    }
+
+    #[test]
+    fn test_prove_hook() {
+        let (zkey, witness) = test_setup();
+        let pk = ProvingKey::from_zkey(&zkey).unwrap();
+        let calls = Rc::new(std::cell::Cell::new(0));
+        let counter = calls.clone();
+        pk.prove_with_hook(&witness, &mut rand::thread_rng(), move || {
+            counter.set(counter.get() + 1)
+        })
+        .unwrap();
+        assert_eq!(calls.get(), 1);
+
+        // Only the call it was given to
+        pk.prove(&witness, &mut rand::thread_rng()).unwrap();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(Rc::strong_count(&calls), 1);
+    }
+
+    #[test]
+    fn test_zkey_cache() {
+        let (zkey, _) = test_setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &zkey).unwrap();
+        let first = ProvingKey::cached(file.path()).unwrap();
+        // Reading the key is skipped entirely once it is cached, even if the file goes away
+        std::fs::write(file.path(), b"").unwrap();
+        let second = ProvingKey::cached(file.path()).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        assert!(ProvingKey::from_zkey(&zkey[..zkey.len() - 1]).is_err());
+        assert!(ProvingKey::from_zkey(b"wtns").is_err());
+    }
+}